@@ -175,7 +175,7 @@ class LibraryTest {
         assertEquals(100000uL, totalBalance1)
         assertEquals(100000uL, totalBalance2)
 
-        node1.openChannel(nodeId2, listenAddress2, 50000u, null, null)
+        node1.openChannel(nodeId2, listenAddress2, 50000u, null, null, null, null)
 
         val channelPendingEvent1 = node1.waitNextEvent()
         println("Got event: $channelPendingEvent1")
@@ -236,8 +236,8 @@ class LibraryTest {
         assert(paymentReceivedEvent is Event.PaymentReceived)
         node2.eventHandled()
 
-        assert(node1.listPayments().size == 1)
-        assert(node2.listPayments().size == 1)
+        assert(node1.listPayments().filter { it.kind is PaymentKind.Bolt11 }.size == 1)
+        assert(node2.listPayments().filter { it.kind is PaymentKind.Bolt11 }.size == 1)
 
         node2.closeChannel(userChannelId, nodeId1)
 
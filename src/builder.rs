@@ -5,20 +5,24 @@
 // http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
 // accordance with one or both of these licenses.
 
+use crate::chain::{ChainSource, CustomChainSource};
+use crate::channel_history::ChannelHistoryStore;
 use crate::config::{
-	default_user_config, Config, DEFAULT_ESPLORA_CLIENT_TIMEOUT_SECS, DEFAULT_ESPLORA_SERVER_URL,
-	WALLET_KEYS_SEED_LEN,
+	default_user_config, Config, DEFAULT_ESPLORA_SERVER_URL, WALLET_KEYS_SEED_LEN,
 };
 use crate::connection::ConnectionManager;
 use crate::event::EventQueue;
-use crate::fee_estimator::OnchainFeeEstimator;
+use crate::fee_estimator::{
+	ConfirmationTarget, FeeEstimator, FeeEstimatorConfig, OnchainFeeEstimator,
+};
 use crate::gossip::GossipSource;
 use crate::io;
 use crate::io::sqlite_store::SqliteStore;
 #[cfg(any(vss, vss_test))]
 use crate::io::vss_store::VssStore;
+use crate::label_store::LabelStore;
 use crate::liquidity::LiquiditySource;
-use crate::logger::{log_error, log_info, FilesystemLogger, Logger};
+use crate::logger::{log_error, log_info, log_warn, FilesystemLogger, Logger};
 use crate::message_handler::NodeCustomMessageHandler;
 use crate::payment::store::PaymentStore;
 use crate::peer_store::PeerStore;
@@ -27,6 +31,7 @@ use crate::types::{
 	ChainMonitor, ChannelManager, DynStore, GossipSync, Graph, KeysManager, MessageRouter,
 	OnionMessenger, PeerManager,
 };
+use crate::wallet::locked_utxos::LockedUtxoStore;
 use crate::wallet::persist::KVStoreWalletPersister;
 use crate::wallet::Wallet;
 use crate::{LogLevel, Node};
@@ -52,34 +57,45 @@ use lightning::util::sweep::OutputSweeper;
 
 use lightning_persister::fs_store::FilesystemStore;
 
-use lightning_transaction_sync::EsploraSyncClient;
-
 use lightning_liquidity::lsps2::client::LSPS2ClientConfig;
 use lightning_liquidity::{LiquidityClientConfig, LiquidityManager};
 
-use bdk_wallet::template::Bip84;
-use bdk_wallet::KeychainKind;
+use bdk_chain::{BlockId, CheckPoint};
+
+use bdk_chain::miniscript::descriptor::KeyMap;
+use bdk_chain::miniscript::{Descriptor, DescriptorPublicKey};
+
+use bdk_wallet::descriptor::{DescriptorError, ExtendedDescriptor, IntoWalletDescriptor};
+use bdk_wallet::template::{Bip84, Bip86};
 use bdk_wallet::Wallet as BdkWallet;
+use bdk_wallet::{KeychainKind, Update};
 
 use bip39::Mnemonic;
 
-use bitcoin::secp256k1::PublicKey;
-use bitcoin::{BlockHash, Network};
+use bitcoin::bip32::Xpriv;
+use bitcoin::secp256k1::{All, PublicKey, Secp256k1};
+use bitcoin::{BlockHash, FeeRate, Network};
 
 #[cfg(any(vss, vss_test))]
 use bitcoin::bip32::ChildNumber;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::default::Default;
 use std::fmt;
 use std::fs;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::SystemTime;
 
 #[derive(Debug, Clone)]
 enum ChainDataSourceConfig {
-	Esplora(String),
+	Esplora { server_urls: Vec<String>, cross_check_servers: bool },
+	Electrum(String),
+	BitcoindRpc { rpc_host: String, rpc_port: u16, rpc_user: String, rpc_password: String },
+	CompactBlockFilters { peers: Vec<SocketAddress> },
+	Custom(Arc<dyn CustomChainSource>),
 }
 
 #[derive(Debug, Clone)]
@@ -101,6 +117,35 @@ struct LiquiditySourceConfig {
 	lsps2_service: Option<(SocketAddress, PublicKey, Option<String>)>,
 }
 
+/// The template used to derive the on-chain wallet's descriptors from the wallet entropy.
+///
+/// Can be set via [`Builder::set_wallet_descriptor_template`].
+///
+/// [`Builder::set_wallet_descriptor_template`]: crate::Builder::set_wallet_descriptor_template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalletDescriptorTemplate {
+	/// Derive SegWit v0 (P2WPKH) outputs as per [BIP 84].
+	///
+	/// [BIP 84]: https://github.com/bitcoin/bips/blob/master/bip-0084.mediawiki
+	Bip84,
+	/// Derive Taproot (P2TR) key-spend outputs as per [BIP 86].
+	///
+	/// [BIP 86]: https://github.com/bitcoin/bips/blob/master/bip-0086.mediawiki
+	Bip86,
+}
+
+impl Default for WalletDescriptorTemplate {
+	fn default() -> Self {
+		Self::Bip84
+	}
+}
+
+#[derive(Debug, Clone)]
+struct WatchOnlyWalletConfig {
+	descriptor: String,
+	change_descriptor: String,
+}
+
 impl Default for LiquiditySourceConfig {
 	fn default() -> Self {
 		Self { lsps2_service: None }
@@ -142,6 +187,12 @@ pub enum BuildError {
 	WalletSetupFailed,
 	/// We failed to setup the logger.
 	LoggerSetupFailed,
+	/// The given chain source configuration is invalid, e.g., no servers were given.
+	InvalidChainSourceConfig,
+	/// The given fee rate bounds are invalid, e.g., the minimum exceeds the maximum.
+	InvalidFeeRateBounds,
+	/// The given wallet descriptors are invalid, e.g., contain private keys.
+	InvalidWalletDescriptor,
 }
 
 impl fmt::Display for BuildError {
@@ -162,7 +213,12 @@ impl fmt::Display for BuildError {
 			Self::KVStoreSetupFailed => write!(f, "Failed to setup KVStore."),
 			Self::WalletSetupFailed => write!(f, "Failed to setup onchain wallet."),
 			Self::LoggerSetupFailed => write!(f, "Failed to setup the logger."),
+			Self::InvalidChainSourceConfig => {
+				write!(f, "Given chain source configuration is invalid.")
+			},
+			Self::InvalidFeeRateBounds => write!(f, "Given fee rate bounds are invalid."),
 			Self::InvalidNodeAlias => write!(f, "Given node alias is invalid."),
+			Self::InvalidWalletDescriptor => write!(f, "Given wallet descriptors are invalid."),
 		}
 	}
 }
@@ -181,8 +237,12 @@ pub struct NodeBuilder {
 	config: Config,
 	entropy_source_config: Option<EntropySourceConfig>,
 	chain_data_source_config: Option<ChainDataSourceConfig>,
+	fee_estimator_config: FeeEstimatorConfig,
 	gossip_source_config: Option<GossipSourceConfig>,
 	liquidity_source_config: Option<LiquiditySourceConfig>,
+	watch_only_wallet_config: Option<WatchOnlyWalletConfig>,
+	wallet_descriptor_template: WalletDescriptorTemplate,
+	wallet_birthday_height: Option<u32>,
 }
 
 impl NodeBuilder {
@@ -196,14 +256,22 @@ impl NodeBuilder {
 	pub fn from_config(config: Config) -> Self {
 		let entropy_source_config = None;
 		let chain_data_source_config = None;
+		let fee_estimator_config = FeeEstimatorConfig::default();
 		let gossip_source_config = None;
 		let liquidity_source_config = None;
+		let watch_only_wallet_config = None;
+		let wallet_descriptor_template = WalletDescriptorTemplate::default();
+		let wallet_birthday_height = None;
 		Self {
 			config,
 			entropy_source_config,
 			chain_data_source_config,
+			fee_estimator_config,
 			gossip_source_config,
 			liquidity_source_config,
+			watch_only_wallet_config,
+			wallet_descriptor_template,
+			wallet_birthday_height,
 		}
 	}
 
@@ -238,12 +306,180 @@ impl NodeBuilder {
 		self
 	}
 
+	/// Configures the [`Node`] instance to run its on-chain wallet in watch-only mode, tracking
+	/// the given public output descriptors rather than the ones derived from the wallet entropy.
+	///
+	/// In this mode the on-chain wallet is not able to sign transactions itself. Instead, payments
+	/// need to be created via [`OnchainPayment::create_psbt`], signed externally, and handed back
+	/// via [`OnchainPayment::finalize_and_broadcast_psbt`]. Likewise, channel funding transactions
+	/// are surfaced via [`Event::FundingPsbtReady`].
+	///
+	/// The wallet entropy is still required as it is used to derive the Lightning node's keys.
+	///
+	/// **Note:** As fee-bumping Anchor channel transactions requires signing on-chain inputs on
+	/// short notice, users should consider disabling Anchor channels or keeping a signing wallet
+	/// around when using this mode.
+	///
+	/// [`OnchainPayment::create_psbt`]: crate::payment::OnchainPayment::create_psbt
+	/// [`OnchainPayment::finalize_and_broadcast_psbt`]: crate::payment::OnchainPayment::finalize_and_broadcast_psbt
+	/// [`Event::FundingPsbtReady`]: crate::Event::FundingPsbtReady
+	pub fn set_watch_only_wallet(
+		&mut self, descriptor: String, change_descriptor: String,
+	) -> Result<&mut Self, BuildError> {
+		// Only accept descriptors that solely consist of public keys.
+		for desc in [&descriptor, &change_descriptor] {
+			Descriptor::<DescriptorPublicKey>::from_str(desc)
+				.map_err(|_| BuildError::InvalidWalletDescriptor)?;
+		}
+
+		self.watch_only_wallet_config =
+			Some(WatchOnlyWalletConfig { descriptor, change_descriptor });
+		Ok(self)
+	}
+
+	/// Configures the [`Node`] instance to derive its on-chain wallet's descriptors from the wallet
+	/// entropy using the given template, which determines the type of addresses used for receiving
+	/// and change.
+	///
+	/// Defaults to [`WalletDescriptorTemplate::Bip84`].
+	///
+	/// **Note:** This only applies to newly created wallets. Existing wallets will keep using the
+	/// descriptors they were created with.
+	pub fn set_wallet_descriptor_template(
+		&mut self, template: WalletDescriptorTemplate,
+	) -> &mut Self {
+		self.wallet_descriptor_template = template;
+		self
+	}
+
+	/// Configures the [`Node`] instance to assume its on-chain wallet didn't receive any funds
+	/// prior to the block at the given height.
+	///
+	/// When sourcing chain data via Bitcoin Core RPC or compact block filters, newly created
+	/// wallets will be synced starting from this block, which allows to discover existing funds
+	/// when restoring a wallet without having to scan the chain from genesis. If unset, newly
+	/// created wallets are assumed to be unused and will only be synced from the current chain
+	/// tip onwards.
+	///
+	/// Chain sources based on Esplora, Electrum, or a [`CustomChainSource`] look up the wallet's
+	/// full history directly and hence don't make use of the birthday height. If it's set
+	/// nevertheless, it will be ignored and a warning will be logged when building the [`Node`].
+	///
+	/// **Note:** This only applies to newly created wallets. Use
+	/// [`Node::rescan_onchain_wallet`] to rescan existing ones.
+	pub fn set_wallet_birthday_height(&mut self, height: u32) -> &mut Self {
+		self.wallet_birthday_height = Some(height);
+		self
+	}
+
 	/// Configures the [`Node`] instance to source its chain data from the given Esplora server.
 	pub fn set_esplora_server(&mut self, esplora_server_url: String) -> &mut Self {
-		self.chain_data_source_config = Some(ChainDataSourceConfig::Esplora(esplora_server_url));
+		self.chain_data_source_config = Some(ChainDataSourceConfig::Esplora {
+			server_urls: vec![esplora_server_url],
+			cross_check_servers: false,
+		});
 		self
 	}
 
+	/// Configures the [`Node`] instance to source its chain data from the given Esplora servers.
+	///
+	/// The servers are used in the given order of priority, i.e., we'll fail over to the next
+	/// server whenever a request to the current one fails or times out. If `cross_check_servers`
+	/// is set, we'll additionally compare the chain tip heights and fee rate estimates reported by
+	/// all servers, avoid servers lagging behind, and use the median of the fee rate estimates.
+	pub fn set_esplora_servers(
+		&mut self, esplora_server_urls: Vec<String>, cross_check_servers: bool,
+	) -> Result<&mut Self, BuildError> {
+		if esplora_server_urls.is_empty() {
+			return Err(BuildError::InvalidChainSourceConfig);
+		}
+
+		self.chain_data_source_config = Some(ChainDataSourceConfig::Esplora {
+			server_urls: esplora_server_urls,
+			cross_check_servers,
+		});
+		Ok(self)
+	}
+
+	/// Configures the [`Node`] instance to source its chain data from the given Electrum server.
+	pub fn set_chain_source_electrum(&mut self, server_url: String) -> &mut Self {
+		self.chain_data_source_config = Some(ChainDataSourceConfig::Electrum(server_url));
+		self
+	}
+
+	/// Configures the [`Node`] instance to source its chain data from the given Bitcoin Core RPC
+	/// endpoint.
+	pub fn set_chain_source_bitcoind_rpc(
+		&mut self, rpc_host: String, rpc_port: u16, rpc_user: String, rpc_password: String,
+	) -> &mut Self {
+		self.chain_data_source_config =
+			Some(ChainDataSourceConfig::BitcoindRpc { rpc_host, rpc_port, rpc_user, rpc_password });
+		self
+	}
+
+	/// Configures the [`Node`] instance to source its chain data from the given BIP 157 peers,
+	/// i.e., Bitcoin P2P nodes serving compact block filters.
+	///
+	/// We'll connect to several of the given peers at the same time, preferring them in the given
+	/// order, and cross-check the filter headers they serve. As a single dishonest peer could
+	/// otherwise hide transactions from us, it's strongly recommended to provide multiple peers
+	/// run by independent parties.
+	///
+	/// As BIP 157 peers don't provide fee rate estimates, a [`FeeEstimator`] or fee rate overrides
+	/// need to be configured when running on mainnet, see [`Self::set_fee_estimator`] and
+	/// [`Self::set_fee_rate_override`].
+	pub fn set_chain_source_compact_block_filters(
+		&mut self, peers: Vec<SocketAddress>,
+	) -> &mut Self {
+		self.chain_data_source_config = Some(ChainDataSourceConfig::CompactBlockFilters { peers });
+		self
+	}
+
+	/// Configures the [`Node`] instance to source its chain data from the given
+	/// [`CustomChainSource`].
+	pub fn set_chain_source_custom(
+		&mut self, custom_source: Arc<dyn CustomChainSource>,
+	) -> &mut Self {
+		self.chain_data_source_config = Some(ChainDataSourceConfig::Custom(custom_source));
+		self
+	}
+
+	/// Configures the [`Node`] instance to retrieve fee rate estimates from the given
+	/// [`FeeEstimator`] rather than from the configured chain source.
+	pub fn set_fee_estimator(&mut self, fee_estimator: Arc<dyn FeeEstimator>) -> &mut Self {
+		self.fee_estimator_config.custom_estimator = Some(fee_estimator);
+		self
+	}
+
+	/// Configures the [`Node`] instance to always use the given fee rate for the given
+	/// [`ConfirmationTarget`], regardless of any retrieved estimates.
+	///
+	/// The fee rate will still be subject to the bounds set via [`Self::set_fee_rate_bounds`].
+	pub fn set_fee_rate_override(
+		&mut self, confirmation_target: ConfirmationTarget, fee_rate: FeeRate,
+	) -> &mut Self {
+		self.fee_estimator_config.fee_rate_overrides.insert(confirmation_target, fee_rate);
+		self
+	}
+
+	/// Sets the minimum and maximum fee rates the [`Node`] instance will ever use.
+	///
+	/// Any fee rate estimate outside of these bounds will be clamped accordingly. Note that we'll
+	/// still never use a fee rate lower than the minimum relay fee of 253 sats/kwu.
+	pub fn set_fee_rate_bounds(
+		&mut self, min_fee_rate: Option<FeeRate>, max_fee_rate: Option<FeeRate>,
+	) -> Result<&mut Self, BuildError> {
+		if let (Some(min), Some(max)) = (min_fee_rate, max_fee_rate) {
+			if min > max {
+				return Err(BuildError::InvalidFeeRateBounds);
+			}
+		}
+
+		self.fee_estimator_config.min_fee_rate = min_fee_rate;
+		self.fee_estimator_config.max_fee_rate = max_fee_rate;
+		Ok(self)
+	}
+
 	/// Configures the [`Node`] instance to source its gossip data from the Lightning peer-to-peer
 	/// network.
 	pub fn set_gossip_source_p2p(&mut self) -> &mut Self {
@@ -357,8 +593,6 @@ impl NodeBuilder {
 	/// previously configured.
 	#[cfg(any(vss, vss_test))]
 	pub fn build_with_vss_store(&self, url: String, store_id: String) -> Result<Node, BuildError> {
-		use bitcoin::key::Secp256k1;
-
 		let logger = setup_logger(&self.config)?;
 
 		let seed_bytes = seed_bytes_from_config(
@@ -368,7 +602,7 @@ impl NodeBuilder {
 		)?;
 		let config = Arc::new(self.config.clone());
 
-		let xprv = bitcoin::bip32::Xpriv::new_master(config.network, &seed_bytes).map_err(|e| {
+		let xprv = Xpriv::new_master(config.network, &seed_bytes).map_err(|e| {
 			log_error!(logger, "Failed to derive master secret: {}", e);
 			BuildError::InvalidSeedBytes
 		})?;
@@ -386,8 +620,12 @@ impl NodeBuilder {
 		build_with_store_internal(
 			config,
 			self.chain_data_source_config.as_ref(),
+			&self.fee_estimator_config,
 			self.gossip_source_config.as_ref(),
 			self.liquidity_source_config.as_ref(),
+			self.watch_only_wallet_config.as_ref(),
+			self.wallet_descriptor_template,
+			self.wallet_birthday_height,
 			seed_bytes,
 			logger,
 			vss_store,
@@ -407,8 +645,12 @@ impl NodeBuilder {
 		build_with_store_internal(
 			config,
 			self.chain_data_source_config.as_ref(),
+			&self.fee_estimator_config,
 			self.gossip_source_config.as_ref(),
 			self.liquidity_source_config.as_ref(),
+			self.watch_only_wallet_config.as_ref(),
+			self.wallet_descriptor_template,
+			self.wallet_birthday_height,
 			seed_bytes,
 			logger,
 			kv_store,
@@ -465,11 +707,142 @@ impl ArcedNodeBuilder {
 		self.inner.write().unwrap().set_entropy_bip39_mnemonic(mnemonic, passphrase);
 	}
 
+	/// Configures the [`Node`] instance to run its on-chain wallet in watch-only mode, tracking
+	/// the given public output descriptors rather than the ones derived from the wallet entropy.
+	///
+	/// In this mode the on-chain wallet is not able to sign transactions itself. Instead, payments
+	/// need to be created via [`OnchainPayment::create_psbt`], signed externally, and handed back
+	/// via [`OnchainPayment::finalize_and_broadcast_psbt`]. Likewise, channel funding transactions
+	/// are surfaced via [`Event::FundingPsbtReady`].
+	///
+	/// The wallet entropy is still required as it is used to derive the Lightning node's keys.
+	///
+	/// [`OnchainPayment::create_psbt`]: crate::payment::OnchainPayment::create_psbt
+	/// [`OnchainPayment::finalize_and_broadcast_psbt`]: crate::payment::OnchainPayment::finalize_and_broadcast_psbt
+	/// [`Event::FundingPsbtReady`]: crate::Event::FundingPsbtReady
+	pub fn set_watch_only_wallet(
+		&self, descriptor: String, change_descriptor: String,
+	) -> Result<(), BuildError> {
+		self.inner.write().unwrap().set_watch_only_wallet(descriptor, change_descriptor).map(|_| ())
+	}
+
+	/// Configures the [`Node`] instance to derive its on-chain wallet's descriptors from the wallet
+	/// entropy using the given template, which determines the type of addresses used for receiving
+	/// and change.
+	///
+	/// Defaults to [`WalletDescriptorTemplate::Bip84`].
+	///
+	/// **Note:** This only applies to newly created wallets. Existing wallets will keep using the
+	/// descriptors they were created with.
+	pub fn set_wallet_descriptor_template(&self, template: WalletDescriptorTemplate) {
+		self.inner.write().unwrap().set_wallet_descriptor_template(template);
+	}
+
+	/// Configures the [`Node`] instance to assume its on-chain wallet didn't receive any funds
+	/// prior to the block at the given height.
+	///
+	/// When sourcing chain data via Bitcoin Core RPC or compact block filters, newly created
+	/// wallets will be synced starting from this block, which allows to discover existing funds
+	/// when restoring a wallet without having to scan the chain from genesis. If unset, newly
+	/// created wallets are assumed to be unused and will only be synced from the current chain
+	/// tip onwards.
+	///
+	/// Chain sources based on Esplora, Electrum, or a [`CustomChainSource`] look up the wallet's
+	/// full history directly and hence don't make use of the birthday height. If it's set
+	/// nevertheless, it will be ignored and a warning will be logged when building the [`Node`].
+	///
+	/// **Note:** This only applies to newly created wallets. Use
+	/// [`Node::rescan_onchain_wallet`] to rescan existing ones.
+	pub fn set_wallet_birthday_height(&self, height: u32) {
+		self.inner.write().unwrap().set_wallet_birthday_height(height);
+	}
+
 	/// Configures the [`Node`] instance to source its chain data from the given Esplora server.
 	pub fn set_esplora_server(&self, esplora_server_url: String) {
 		self.inner.write().unwrap().set_esplora_server(esplora_server_url);
 	}
 
+	/// Configures the [`Node`] instance to source its chain data from the given Esplora servers.
+	///
+	/// The servers are used in the given order of priority, i.e., we'll fail over to the next
+	/// server whenever a request to the current one fails or times out. If `cross_check_servers`
+	/// is set, we'll additionally compare the chain tip heights and fee rate estimates reported by
+	/// all servers, avoid servers lagging behind, and use the median of the fee rate estimates.
+	pub fn set_esplora_servers(
+		&self, esplora_server_urls: Vec<String>, cross_check_servers: bool,
+	) -> Result<(), BuildError> {
+		self.inner
+			.write()
+			.unwrap()
+			.set_esplora_servers(esplora_server_urls, cross_check_servers)
+			.map(|_| ())
+	}
+
+	/// Configures the [`Node`] instance to source its chain data from the given Electrum server.
+	pub fn set_chain_source_electrum(&self, server_url: String) {
+		self.inner.write().unwrap().set_chain_source_electrum(server_url);
+	}
+
+	/// Configures the [`Node`] instance to source its chain data from the given Bitcoin Core RPC
+	/// endpoint.
+	pub fn set_chain_source_bitcoind_rpc(
+		&self, rpc_host: String, rpc_port: u16, rpc_user: String, rpc_password: String,
+	) {
+		self.inner.write().unwrap().set_chain_source_bitcoind_rpc(
+			rpc_host,
+			rpc_port,
+			rpc_user,
+			rpc_password,
+		);
+	}
+
+	/// Configures the [`Node`] instance to source its chain data from the given BIP 157 peers,
+	/// i.e., Bitcoin P2P nodes serving compact block filters.
+	///
+	/// We'll connect to several of the given peers at the same time, preferring them in the given
+	/// order, and cross-check the filter headers they serve. As a single dishonest peer could
+	/// otherwise hide transactions from us, it's strongly recommended to provide multiple peers
+	/// run by independent parties.
+	///
+	/// As BIP 157 peers don't provide fee rate estimates, a [`FeeEstimator`] or fee rate overrides
+	/// need to be configured when running on mainnet, see [`Self::set_fee_estimator`] and
+	/// [`Self::set_fee_rate_override`].
+	pub fn set_chain_source_compact_block_filters(&self, peers: Vec<SocketAddress>) {
+		self.inner.write().unwrap().set_chain_source_compact_block_filters(peers);
+	}
+
+	/// Configures the [`Node`] instance to source its chain data from the given
+	/// [`CustomChainSource`].
+	pub fn set_chain_source_custom(&self, custom_source: Arc<dyn CustomChainSource>) {
+		self.inner.write().unwrap().set_chain_source_custom(custom_source);
+	}
+
+	/// Configures the [`Node`] instance to retrieve fee rate estimates from the given
+	/// [`FeeEstimator`] rather than from the configured chain source.
+	pub fn set_fee_estimator(&self, fee_estimator: Arc<dyn FeeEstimator>) {
+		self.inner.write().unwrap().set_fee_estimator(fee_estimator);
+	}
+
+	/// Configures the [`Node`] instance to always use the given fee rate for the given
+	/// [`ConfirmationTarget`], regardless of any retrieved estimates.
+	///
+	/// The fee rate will still be subject to the bounds set via [`Self::set_fee_rate_bounds`].
+	pub fn set_fee_rate_override(
+		&self, confirmation_target: ConfirmationTarget, fee_rate: FeeRate,
+	) {
+		self.inner.write().unwrap().set_fee_rate_override(confirmation_target, fee_rate);
+	}
+
+	/// Sets the minimum and maximum fee rates the [`Node`] instance will ever use.
+	///
+	/// Any fee rate estimate outside of these bounds will be clamped accordingly. Note that we'll
+	/// still never use a fee rate lower than the minimum relay fee of 253 sats/kwu.
+	pub fn set_fee_rate_bounds(
+		&self, min_fee_rate: Option<FeeRate>, max_fee_rate: Option<FeeRate>,
+	) -> Result<(), BuildError> {
+		self.inner.write().unwrap().set_fee_rate_bounds(min_fee_rate, max_fee_rate).map(|_| ())
+	}
+
 	/// Configures the [`Node`] instance to source its gossip data from the Lightning peer-to-peer
 	/// network.
 	pub fn set_gossip_source_p2p(&self) {
@@ -551,18 +924,66 @@ impl ArcedNodeBuilder {
 /// Builds a [`Node`] instance according to the options previously configured.
 fn build_with_store_internal(
 	config: Arc<Config>, chain_data_source_config: Option<&ChainDataSourceConfig>,
-	gossip_source_config: Option<&GossipSourceConfig>,
-	liquidity_source_config: Option<&LiquiditySourceConfig>, seed_bytes: [u8; 64],
-	logger: Arc<FilesystemLogger>, kv_store: Arc<DynStore>,
+	fee_estimator_config: &FeeEstimatorConfig, gossip_source_config: Option<&GossipSourceConfig>,
+	liquidity_source_config: Option<&LiquiditySourceConfig>,
+	watch_only_wallet_config: Option<&WatchOnlyWalletConfig>,
+	wallet_descriptor_template: WalletDescriptorTemplate, wallet_birthday_height: Option<u32>,
+	seed_bytes: [u8; 64], logger: Arc<FilesystemLogger>, kv_store: Arc<DynStore>,
 ) -> Result<Node, BuildError> {
 	// Initialize the on-chain wallet and chain access
-	let xprv = bitcoin::bip32::Xpriv::new_master(config.network, &seed_bytes).map_err(|e| {
+	let xprv = Xpriv::new_master(config.network, &seed_bytes).map_err(|e| {
 		log_error!(logger, "Failed to derive master secret: {}", e);
 		BuildError::InvalidSeedBytes
 	})?;
 
-	let descriptor = Bip84(xprv, KeychainKind::External);
-	let change_descriptor = Bip84(xprv, KeychainKind::Internal);
+	let secp = Secp256k1::new();
+	let (descriptor, change_descriptor) = match watch_only_wallet_config {
+		Some(wo_config) => {
+			let descriptor = wo_config
+				.descriptor
+				.as_str()
+				.into_wallet_descriptor(&secp, config.network)
+				.map_err(|e| {
+					log_error!(logger, "Failed to parse wallet descriptor: {}", e);
+					BuildError::InvalidWalletDescriptor
+				})?;
+			let change_descriptor = wo_config
+				.change_descriptor
+				.as_str()
+				.into_wallet_descriptor(&secp, config.network)
+				.map_err(|e| {
+					log_error!(logger, "Failed to parse wallet change descriptor: {}", e);
+					BuildError::InvalidWalletDescriptor
+				})?;
+			(descriptor, change_descriptor)
+		},
+		None => {
+			// Existing wallets keep using the template they were created with.
+			let persisted_descriptor =
+				io::utils::read_bdk_wallet_descriptor(Arc::clone(&kv_store), Arc::clone(&logger))
+					.map_err(|_| BuildError::ReadFailed)?;
+			let template = match persisted_descriptor {
+				Some(Descriptor::Tr(_)) => WalletDescriptorTemplate::Bip86,
+				Some(_) => WalletDescriptorTemplate::Bip84,
+				None => wallet_descriptor_template,
+			};
+			if template != wallet_descriptor_template {
+				log_info!(
+					logger,
+					"Keeping the existing wallet's {:?} descriptors rather than switching to {:?}",
+					template,
+					wallet_descriptor_template
+				);
+			}
+
+			wallet_descriptors_from_template(template, xprv, config.network, &secp).map_err(
+				|e| {
+					log_error!(logger, "Failed to derive wallet descriptors: {}", e);
+					BuildError::WalletSetupFailed
+				},
+			)?
+		},
+	};
 	let mut wallet_persister =
 		KVStoreWalletPersister::new(Arc::clone(&kv_store), Arc::clone(&logger));
 	let wallet_opt = BdkWallet::load()
@@ -570,15 +991,18 @@ fn build_with_store_internal(
 		.descriptor(KeychainKind::Internal, Some(change_descriptor.clone()))
 		.extract_keys()
 		.check_network(config.network)
+		.lookahead(config.onchain_wallet_stop_gap)
 		.load_wallet(&mut wallet_persister)
 		.map_err(|e| {
 			log_error!(logger, "Failed to set up wallet: {}", e);
 			BuildError::WalletSetupFailed
 		})?;
+	let is_fresh_wallet = wallet_opt.is_none();
 	let bdk_wallet = match wallet_opt {
 		Some(wallet) => wallet,
 		None => BdkWallet::create(descriptor, change_descriptor)
 			.network(config.network)
+			.lookahead(config.onchain_wallet_stop_gap)
 			.create_wallet(&mut wallet_persister)
 			.map_err(|e| {
 				log_error!(logger, "Failed to set up wallet: {}", e);
@@ -586,62 +1010,255 @@ fn build_with_store_internal(
 			})?,
 	};
 
-	let (esplora_client, tx_sync, tx_broadcaster, fee_estimator) = match chain_data_source_config {
-		Some(ChainDataSourceConfig::Esplora(server_url)) => {
-			let mut client_builder = esplora_client::Builder::new(&server_url.clone());
-			client_builder = client_builder.timeout(DEFAULT_ESPLORA_CLIENT_TIMEOUT_SECS);
-			let esplora_client = client_builder.build_async().unwrap();
-			let tx_sync = Arc::new(EsploraSyncClient::from_client(
-				esplora_client.clone(),
+	let tx_broadcaster = Arc::new(TransactionBroadcaster::new(Arc::clone(&logger)));
+	let fee_estimator = Arc::new(OnchainFeeEstimator::new(fee_estimator_config.clone()));
+
+	// Init payment info storage
+	let payment_store = match io::utils::read_payments(Arc::clone(&kv_store), Arc::clone(&logger)) {
+		Ok(payments) => {
+			Arc::new(PaymentStore::new(payments, Arc::clone(&kv_store), Arc::clone(&logger)))
+		},
+		Err(_) => {
+			return Err(BuildError::ReadFailed);
+		},
+	};
+
+	let label_store = match io::utils::read_labels(Arc::clone(&kv_store), Arc::clone(&logger)) {
+		Ok(labels) => Arc::new(LabelStore::new(labels, Arc::clone(&kv_store), Arc::clone(&logger))),
+		Err(_) => {
+			return Err(BuildError::ReadFailed);
+		},
+	};
+
+	let channel_history_store =
+		match io::utils::read_channel_history(Arc::clone(&kv_store), Arc::clone(&logger)) {
+			Ok(records) => Arc::new(ChannelHistoryStore::new(
+				records,
+				Arc::clone(&kv_store),
 				Arc::clone(&logger),
-			));
-			let tx_broadcaster = Arc::new(TransactionBroadcaster::new(
-				tx_sync.client().clone(),
+			)),
+			Err(_) => {
+				return Err(BuildError::ReadFailed);
+			},
+		};
+
+	let locked_utxo_store =
+		match io::utils::read_locked_utxos(Arc::clone(&kv_store), Arc::clone(&logger)) {
+			Ok(locked_utxo_store) => Arc::new(locked_utxo_store),
+			Err(e) => {
+				if e.kind() == std::io::ErrorKind::NotFound {
+					Arc::new(LockedUtxoStore::new(Arc::clone(&kv_store), Arc::clone(&logger)))
+				} else {
+					return Err(BuildError::ReadFailed);
+				}
+			},
+		};
+
+	let runtime = Arc::new(RwLock::new(None));
+	let wallet = Arc::new(Wallet::new(
+		bdk_wallet,
+		wallet_persister,
+		Arc::clone(&tx_broadcaster),
+		Arc::clone(&fee_estimator),
+		Arc::clone(&payment_store),
+		locked_utxo_store,
+		Arc::clone(&logger),
+	));
+
+	let latest_wallet_sync_timestamp = Arc::new(RwLock::new(None));
+	let latest_onchain_wallet_sync_timestamp = Arc::new(RwLock::new(None));
+	let latest_fee_rate_cache_update_timestamp = Arc::new(RwLock::new(None));
+	let latest_channel_monitor_archival_height = Arc::new(RwLock::new(None));
+
+	if let Some(height) = wallet_birthday_height {
+		if !matches!(
+			chain_data_source_config,
+			Some(ChainDataSourceConfig::BitcoindRpc { .. })
+				| Some(ChainDataSourceConfig::CompactBlockFilters { .. })
+		) {
+			log_warn!(
+				logger,
+				"Ignoring wallet birthday height {} as the configured chain source looks up the wallet's full history directly",
+				height
+			);
+		}
+	}
+
+	let chain_source = match chain_data_source_config {
+		Some(ChainDataSourceConfig::Esplora { server_urls, cross_check_servers }) => {
+			Arc::new(ChainSource::new_esplora(
+				server_urls.clone(),
+				*cross_check_servers,
+				Arc::clone(&wallet),
+				Arc::clone(&fee_estimator),
+				Arc::clone(&tx_broadcaster),
+				Arc::clone(&config),
 				Arc::clone(&logger),
-			));
-			let fee_estimator = Arc::new(OnchainFeeEstimator::new(
-				tx_sync.client().clone(),
+				Arc::clone(&latest_wallet_sync_timestamp),
+				Arc::clone(&latest_onchain_wallet_sync_timestamp),
+				Arc::clone(&latest_fee_rate_cache_update_timestamp),
+				Arc::clone(&latest_channel_monitor_archival_height),
+			))
+		},
+		Some(ChainDataSourceConfig::Electrum(server_url)) => Arc::new(ChainSource::new_electrum(
+			server_url.clone(),
+			Arc::clone(&wallet),
+			Arc::clone(&fee_estimator),
+			Arc::clone(&tx_broadcaster),
+			Arc::clone(&config),
+			Arc::clone(&logger),
+			Arc::clone(&latest_wallet_sync_timestamp),
+			Arc::clone(&latest_onchain_wallet_sync_timestamp),
+			Arc::clone(&latest_fee_rate_cache_update_timestamp),
+			Arc::clone(&latest_channel_monitor_archival_height),
+		)),
+		Some(ChainDataSourceConfig::BitcoindRpc { rpc_host, rpc_port, rpc_user, rpc_password }) => {
+			Arc::new(ChainSource::new_bitcoind_rpc(
+				rpc_host.clone(),
+				*rpc_port,
+				rpc_user.clone(),
+				rpc_password.clone(),
+				Arc::clone(&wallet),
+				Arc::clone(&fee_estimator),
+				Arc::clone(&tx_broadcaster),
 				Arc::clone(&config),
 				Arc::clone(&logger),
-			));
-			(esplora_client, tx_sync, tx_broadcaster, fee_estimator)
+				Arc::clone(&latest_wallet_sync_timestamp),
+				Arc::clone(&latest_onchain_wallet_sync_timestamp),
+				Arc::clone(&latest_fee_rate_cache_update_timestamp),
+				Arc::clone(&latest_channel_monitor_archival_height),
+			))
+		},
+		Some(ChainDataSourceConfig::CompactBlockFilters { peers }) => {
+			// BIP 157 peers don't provide fee rate estimates, and we really shouldn't rely on our
+			// fallback rates on mainnet.
+			if config.network == Network::Bitcoin
+				&& fee_estimator_config.custom_estimator.is_none()
+				&& fee_estimator_config.fee_rate_overrides.is_empty()
+			{
+				log_error!(
+					logger,
+					"Failed to set up compact block filter chain source: a fee estimator or fee rate overrides are required on mainnet."
+				);
+				return Err(BuildError::InvalidChainSourceConfig);
+			}
+
+			let persisted_headers =
+				io::utils::read_cbf_header_chain(Arc::clone(&kv_store), Arc::clone(&logger))
+					.map_err(|_| BuildError::ReadFailed)?;
+			Arc::new(ChainSource::new_compact_block_filters(
+				peers.clone(),
+				persisted_headers,
+				Arc::clone(&wallet),
+				Arc::clone(&fee_estimator),
+				Arc::clone(&tx_broadcaster),
+				Arc::clone(&kv_store),
+				Arc::clone(&config),
+				Arc::clone(&logger),
+				Arc::clone(&latest_wallet_sync_timestamp),
+				Arc::clone(&latest_onchain_wallet_sync_timestamp),
+				Arc::clone(&latest_fee_rate_cache_update_timestamp),
+				Arc::clone(&latest_channel_monitor_archival_height),
+			))
 		},
+		Some(ChainDataSourceConfig::Custom(custom_source)) => Arc::new(ChainSource::new_custom(
+			Arc::clone(custom_source),
+			Arc::clone(&wallet),
+			Arc::clone(&fee_estimator),
+			Arc::clone(&tx_broadcaster),
+			Arc::clone(&config),
+			Arc::clone(&logger),
+			Arc::clone(&latest_wallet_sync_timestamp),
+			Arc::clone(&latest_onchain_wallet_sync_timestamp),
+			Arc::clone(&latest_fee_rate_cache_update_timestamp),
+			Arc::clone(&latest_channel_monitor_archival_height),
+		)),
 		None => {
 			// Default to Esplora client.
 			let server_url = DEFAULT_ESPLORA_SERVER_URL.to_string();
-			let mut client_builder = esplora_client::Builder::new(&server_url);
-			client_builder = client_builder.timeout(DEFAULT_ESPLORA_CLIENT_TIMEOUT_SECS);
-			let esplora_client = client_builder.build_async().unwrap();
-			let tx_sync = Arc::new(EsploraSyncClient::from_client(
-				esplora_client.clone(),
-				Arc::clone(&logger),
-			));
-			let tx_broadcaster = Arc::new(TransactionBroadcaster::new(
-				tx_sync.client().clone(),
-				Arc::clone(&logger),
-			));
-			let fee_estimator = Arc::new(OnchainFeeEstimator::new(
-				tx_sync.client().clone(),
+			Arc::new(ChainSource::new_esplora(
+				vec![server_url],
+				false,
+				Arc::clone(&wallet),
+				Arc::clone(&fee_estimator),
+				Arc::clone(&tx_broadcaster),
 				Arc::clone(&config),
 				Arc::clone(&logger),
-			));
-			(esplora_client, tx_sync, tx_broadcaster, fee_estimator)
+				Arc::clone(&latest_wallet_sync_timestamp),
+				Arc::clone(&latest_onchain_wallet_sync_timestamp),
+				Arc::clone(&latest_fee_rate_cache_update_timestamp),
+				Arc::clone(&latest_channel_monitor_archival_height),
+			))
 		},
 	};
 
-	let runtime = Arc::new(RwLock::new(None));
-	let wallet = Arc::new(Wallet::new(
-		bdk_wallet,
-		wallet_persister,
-		esplora_client,
-		Arc::clone(&tx_broadcaster),
-		Arc::clone(&fee_estimator),
-		Arc::clone(&logger),
-	));
+	// When sourcing chain data via block polling, we avoid having to connect all blocks since
+	// genesis by starting fresh wallets and channel managers at the current chain tip. Otherwise,
+	// we leave catching up with the chain to the background sync.
+	let is_fresh_channel_manager = !kv_store
+		.list(
+			CHANNEL_MANAGER_PERSISTENCE_PRIMARY_NAMESPACE,
+			CHANNEL_MANAGER_PERSISTENCE_SECONDARY_NAMESPACE,
+		)
+		.map_or(false, |keys| keys.iter().any(|key| key == CHANNEL_MANAGER_PERSISTENCE_KEY));
+	let chain_tip_opt = match chain_source.as_ref() {
+		ChainSource::BitcoindRpc { .. } | ChainSource::CompactBlockFilters { .. }
+			if is_fresh_wallet || is_fresh_channel_manager =>
+		{
+			tokio::task::block_in_place(|| {
+				tokio::runtime::Builder::new_current_thread()
+					.enable_all()
+					.build()
+					.unwrap()
+					.block_on(chain_source.poll_best_block())
+					.ok()
+			})
+		},
+		_ => None,
+	};
+
+	if is_fresh_wallet {
+		if let Some(best_block) = chain_tip_opt {
+			// If we're given a birthday, start syncing the wallet from there rather than from the
+			// current chain tip so that we don't miss any of its funds.
+			let start_block = match wallet_birthday_height {
+				Some(height) if height < best_block.height => {
+					let block_hash = tokio::task::block_in_place(|| {
+						tokio::runtime::Builder::new_current_thread()
+							.enable_all()
+							.build()
+							.unwrap()
+							.block_on(chain_source.get_block_hash(height))
+					})
+					.map_err(|e| {
+						log_error!(
+							logger,
+							"Failed to retrieve wallet birthday block at height {}: {}",
+							height,
+							e
+						);
+						BuildError::WalletSetupFailed
+					})?;
+					BlockId { height, hash: block_hash }
+				},
+				_ => BlockId { height: best_block.height, hash: best_block.block_hash },
+			};
+			let genesis_block_hash =
+				bitcoin::blockdata::constants::genesis_block(config.network).block_hash();
+			let block_ids = [BlockId { height: 0, hash: genesis_block_hash }, start_block];
+			if let Ok(checkpoint) = CheckPoint::from_block_ids(block_ids) {
+				let update = Update { chain: Some(checkpoint), ..Default::default() };
+				wallet.apply_update(update).map_err(|e| {
+					log_error!(logger, "Failed to set up wallet: {}", e);
+					BuildError::WalletSetupFailed
+				})?;
+			}
+		}
+	}
 
 	// Initialize the ChainMonitor
 	let chain_monitor: Arc<ChainMonitor> = Arc::new(chainmonitor::ChainMonitor::new(
-		Some(Arc::clone(&tx_sync)),
+		Some(Arc::clone(&chain_source)),
 		Arc::clone(&tx_broadcaster),
 		Arc::clone(&logger),
 		Arc::clone(&fee_estimator),
@@ -768,10 +1385,8 @@ fn build_with_store_internal(
 			let genesis_block_hash =
 				bitcoin::blockdata::constants::genesis_block(config.network).block_hash();
 
-			let chain_params = ChainParameters {
-				network: config.network.into(),
-				best_block: BestBlock::new(genesis_block_hash, 0),
-			};
+			let best_block = chain_tip_opt.unwrap_or(BestBlock::new(genesis_block_hash, 0));
+			let chain_params = ChainParameters { network: config.network.into(), best_block };
 			channelmanager::ChannelManager::new(
 				Arc::clone(&fee_estimator),
 				Arc::clone(&chain_monitor),
@@ -857,7 +1472,7 @@ fn build_with_store_internal(
 			let liquidity_manager = Arc::new(LiquidityManager::new(
 				Arc::clone(&keys_manager),
 				Arc::clone(&channel_manager),
-				Some(Arc::clone(&tx_sync)),
+				Some(Arc::clone(&chain_source)),
 				None,
 				None,
 				liquidity_client_config,
@@ -925,7 +1540,7 @@ fn build_with_store_internal(
 	let output_sweeper = match io::utils::read_output_sweeper(
 		Arc::clone(&tx_broadcaster),
 		Arc::clone(&fee_estimator),
-		Arc::clone(&tx_sync),
+		Arc::clone(&chain_source),
 		Arc::clone(&keys_manager),
 		Arc::clone(&kv_store),
 		Arc::clone(&logger),
@@ -937,7 +1552,7 @@ fn build_with_store_internal(
 					channel_manager.current_best_block(),
 					Arc::clone(&tx_broadcaster),
 					Arc::clone(&fee_estimator),
-					Some(Arc::clone(&tx_sync)),
+					Some(Arc::clone(&chain_source)),
 					Arc::clone(&keys_manager),
 					Arc::clone(&keys_manager),
 					Arc::clone(&kv_store),
@@ -963,16 +1578,6 @@ fn build_with_store_internal(
 		},
 	}
 
-	// Init payment info storage
-	let payment_store = match io::utils::read_payments(Arc::clone(&kv_store), Arc::clone(&logger)) {
-		Ok(payments) => {
-			Arc::new(PaymentStore::new(payments, Arc::clone(&kv_store), Arc::clone(&logger)))
-		},
-		Err(_) => {
-			return Err(BuildError::ReadFailed);
-		},
-	};
-
 	let event_queue = match io::utils::read_event_queue(Arc::clone(&kv_store), Arc::clone(&logger))
 	{
 		Ok(event_queue) => Arc::new(event_queue),
@@ -999,13 +1604,13 @@ fn build_with_store_internal(
 	let (stop_sender, _) = tokio::sync::watch::channel(());
 	let (event_handling_stopped_sender, _) = tokio::sync::watch::channel(());
 
+	let channel_funding_params = Arc::new(Mutex::new(HashMap::new()));
+	let pending_channel_fundings = Arc::new(Mutex::new(HashMap::new()));
+	let inbound_channel_requests = Arc::new(Mutex::new(HashMap::new()));
+
 	let is_listening = Arc::new(AtomicBool::new(false));
-	let latest_wallet_sync_timestamp = Arc::new(RwLock::new(None));
-	let latest_onchain_wallet_sync_timestamp = Arc::new(RwLock::new(None));
-	let latest_fee_rate_cache_update_timestamp = Arc::new(RwLock::new(None));
 	let latest_rgs_snapshot_timestamp = Arc::new(RwLock::new(None));
 	let latest_node_announcement_broadcast_timestamp = Arc::new(RwLock::new(None));
-	let latest_channel_monitor_archival_height = Arc::new(RwLock::new(None));
 
 	Ok(Node {
 		runtime,
@@ -1013,9 +1618,8 @@ fn build_with_store_internal(
 		event_handling_stopped_sender,
 		config,
 		wallet,
-		tx_sync,
+		chain_source,
 		tx_broadcaster,
-		fee_estimator,
 		event_queue,
 		channel_manager,
 		chain_monitor,
@@ -1033,16 +1637,35 @@ fn build_with_store_internal(
 		scorer,
 		peer_store,
 		payment_store,
+		label_store,
+		channel_history_store,
+		channel_funding_params,
+		pending_channel_fundings,
+		inbound_channel_requests,
 		is_listening,
 		latest_wallet_sync_timestamp,
 		latest_onchain_wallet_sync_timestamp,
 		latest_fee_rate_cache_update_timestamp,
 		latest_rgs_snapshot_timestamp,
 		latest_node_announcement_broadcast_timestamp,
-		latest_channel_monitor_archival_height,
 	})
 }
 
+fn wallet_descriptors_from_template(
+	template: WalletDescriptorTemplate, xprv: Xpriv, network: Network, secp: &Secp256k1<All>,
+) -> Result<((ExtendedDescriptor, KeyMap), (ExtendedDescriptor, KeyMap)), DescriptorError> {
+	match template {
+		WalletDescriptorTemplate::Bip84 => Ok((
+			Bip84(xprv, KeychainKind::External).into_wallet_descriptor(secp, network)?,
+			Bip84(xprv, KeychainKind::Internal).into_wallet_descriptor(secp, network)?,
+		)),
+		WalletDescriptorTemplate::Bip86 => Ok((
+			Bip86(xprv, KeychainKind::External).into_wallet_descriptor(secp, network)?,
+			Bip86(xprv, KeychainKind::Internal).into_wallet_descriptor(secp, network)?,
+		)),
+	}
+}
+
 fn setup_logger(config: &Config) -> Result<Arc<FilesystemLogger>, BuildError> {
 	let log_dir = match &config.log_dir_path {
 		Some(log_dir) => String::from(log_dir),
@@ -0,0 +1,326 @@
+// This file is Copyright its original authors, visible in version control history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
+// accordance with one or both of these licenses.
+
+use crate::types::{ChainMonitor, ChannelHistory, ChannelManager, Sweeper, Wallet};
+
+use lightning::chain::Listen;
+
+use lightning_block_sync::http::HttpEndpoint;
+use lightning_block_sync::http::JsonResponse;
+use lightning_block_sync::poll::ValidatedBlockHeader;
+use lightning_block_sync::rpc::{RpcClient, RpcError};
+use lightning_block_sync::{
+	AsyncBlockSourceResult, BlockData, BlockHeaderData, BlockSource, Cache,
+};
+
+use bitcoin::{Amount, BlockHash, FeeRate, Transaction, Txid, Weight};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub(crate) struct BitcoindRpcClient {
+	rpc_client: Arc<RpcClient>,
+}
+
+impl BitcoindRpcClient {
+	pub(crate) fn new(host: String, port: u16, rpc_user: String, rpc_password: String) -> Self {
+		let http_endpoint = HttpEndpoint::for_host(host).with_port(port);
+		let rpc_credentials = BASE64_STANDARD.encode(format!("{}:{}", rpc_user, rpc_password));
+
+		let rpc_client = Arc::new(
+			RpcClient::new(&rpc_credentials, http_endpoint)
+				.expect("RpcClient::new is actually infallible"),
+		);
+
+		Self { rpc_client }
+	}
+
+	pub(crate) async fn broadcast_transaction(&self, tx: &Transaction) -> std::io::Result<Txid> {
+		let tx_serialized = bitcoin::consensus::encode::serialize_hex(tx);
+		let tx_json = serde_json::json!(tx_serialized);
+		self.rpc_client.call_method::<Txid>("sendrawtransaction", &[tx_json]).await
+	}
+
+	pub(crate) async fn get_fee_estimate_for_target(
+		&self, num_blocks: usize, estimation_mode: FeeRateEstimationMode,
+	) -> std::io::Result<Option<FeeRate>> {
+		let num_blocks_json = serde_json::json!(num_blocks);
+		let estimation_mode_json = serde_json::json!(estimation_mode.as_str());
+		self.rpc_client
+			.call_method::<FeeResponse>(
+				"estimatesmartfee",
+				&[num_blocks_json, estimation_mode_json],
+			)
+			.await
+			.map(|resp| resp.0)
+	}
+
+	pub(crate) async fn get_block_hash(&self, height: u32) -> std::io::Result<BlockHash> {
+		let height_json = serde_json::json!(height);
+		self.rpc_client
+			.call_method::<BlockHashResponse>("getblockhash", &[height_json])
+			.await
+			.map(|resp| resp.0)
+	}
+
+	pub(crate) async fn get_mempool_minimum_fee_rate(&self) -> std::io::Result<FeeRate> {
+		self.rpc_client
+			.call_method::<MempoolMinFeeResponse>("getmempoolinfo", &[])
+			.await
+			.map(|resp| resp.0)
+	}
+
+	/// Returns the given transaction if it's currently in the mempool.
+	pub(crate) async fn get_mempool_transaction(
+		&self, txid: &Txid,
+	) -> std::io::Result<Option<Transaction>> {
+		let txid_json = serde_json::json!(txid.to_string());
+		let res = self
+			.rpc_client
+			.call_method::<RawTransactionResponse>("getrawtransaction", &[txid_json])
+			.await;
+		not_found_as_none(res.map(|resp| resp.0))
+	}
+
+	/// Returns the mempool entry of the given transaction if it's currently in the mempool.
+	pub(crate) async fn get_mempool_entry(
+		&self, txid: &Txid,
+	) -> std::io::Result<Option<MempoolEntry>> {
+		let txid_json = serde_json::json!(txid.to_string());
+		let res =
+			self.rpc_client.call_method::<MempoolEntry>("getmempoolentry", &[txid_json]).await;
+		not_found_as_none(res)
+	}
+}
+
+// Bitcoin Core returns `RPC_INVALID_ADDRESS_OR_KEY` if it doesn't know about a transaction.
+fn not_found_as_none<T>(res: std::io::Result<T>) -> std::io::Result<Option<T>> {
+	match res {
+		Ok(value) => Ok(Some(value)),
+		Err(e) => {
+			let is_not_found = e
+				.get_ref()
+				.and_then(|inner| inner.downcast_ref::<RpcError>())
+				.map_or(false, |rpc_error| rpc_error.code == -5);
+			if is_not_found {
+				Ok(None)
+			} else {
+				Err(e)
+			}
+		},
+	}
+}
+
+impl BlockSource for BitcoindRpcClient {
+	fn get_header<'a>(
+		&'a self, header_hash: &'a BlockHash, height_hint: Option<u32>,
+	) -> AsyncBlockSourceResult<'a, BlockHeaderData> {
+		Box::pin(async move { self.rpc_client.get_header(header_hash, height_hint).await })
+	}
+
+	fn get_block<'a>(
+		&'a self, header_hash: &'a BlockHash,
+	) -> AsyncBlockSourceResult<'a, BlockData> {
+		Box::pin(async move { self.rpc_client.get_block(header_hash).await })
+	}
+
+	fn get_best_block<'a>(&'a self) -> AsyncBlockSourceResult<'a, (BlockHash, Option<u32>)> {
+		Box::pin(async move { self.rpc_client.get_best_block().await })
+	}
+}
+
+pub(crate) struct FeeResponse(pub Option<FeeRate>);
+
+impl TryInto<FeeResponse> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<FeeResponse> {
+		match self.0["feerate"].as_f64() {
+			Some(fee_rate_btc_per_kvbyte) => {
+				// Bitcoin Core gives us a feerate in BTC/KvB.
+				// Thus, we multiply by 25_000_000 (10^8 / 4) to get satoshis/kwu.
+				let fee_rate_sat_per_kwu = (fee_rate_btc_per_kvbyte * 25_000_000.0).round() as u64;
+				Ok(FeeResponse(Some(FeeRate::from_sat_per_kwu(fee_rate_sat_per_kwu))))
+			},
+			None if self.0["errors"].is_array() => {
+				// Bitcoin Core will only return an `errors` field if it doesn't have sufficient
+				// data to give an estimate, e.g., on freshly started nodes or on regtest.
+				Ok(FeeResponse(None))
+			},
+			None => Err(std::io::Error::new(
+				std::io::ErrorKind::Other,
+				"Failed to parse fee rate estimate",
+			)),
+		}
+	}
+}
+
+pub(crate) struct BlockHashResponse(pub BlockHash);
+
+impl TryInto<BlockHashResponse> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<BlockHashResponse> {
+		let block_hash =
+			self.0.as_str().and_then(|hash_str| BlockHash::from_str(hash_str).ok()).ok_or(
+				std::io::Error::new(std::io::ErrorKind::Other, "Failed to parse block hash"),
+			)?;
+		Ok(BlockHashResponse(block_hash))
+	}
+}
+
+pub(crate) struct MempoolMinFeeResponse(pub FeeRate);
+
+impl TryInto<MempoolMinFeeResponse> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<MempoolMinFeeResponse> {
+		let fee_rate_btc_per_kvbyte = self.0["mempoolminfee"].as_f64().ok_or(
+			std::io::Error::new(std::io::ErrorKind::Other, "Failed to parse mempool minimum fee"),
+		)?;
+		// Bitcoin Core gives us a feerate in BTC/KvB.
+		// Thus, we multiply by 25_000_000 (10^8 / 4) to get satoshis/kwu.
+		let fee_rate_sat_per_kwu = (fee_rate_btc_per_kvbyte * 25_000_000.0).round() as u64;
+		Ok(MempoolMinFeeResponse(FeeRate::from_sat_per_kwu(fee_rate_sat_per_kwu)))
+	}
+}
+
+pub(crate) struct RawTransactionResponse(pub Transaction);
+
+impl TryInto<RawTransactionResponse> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<RawTransactionResponse> {
+		let tx = self
+			.0
+			.as_str()
+			.and_then(|tx_hex| bitcoin::consensus::encode::deserialize_hex(tx_hex).ok())
+			.ok_or(std::io::Error::new(
+				std::io::ErrorKind::Other,
+				"Failed to parse raw transaction",
+			))?;
+		Ok(RawTransactionResponse(tx))
+	}
+}
+
+/// The aggregate fee and size of a mempool transaction and all of its unconfirmed ancestors.
+pub(crate) struct MempoolEntry {
+	pub ancestor_fee: Amount,
+	pub ancestor_weight: Weight,
+}
+
+impl TryInto<MempoolEntry> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<MempoolEntry> {
+		let ancestor_fee = self.0["fees"]["ancestor"]
+			.as_f64()
+			.and_then(|fee_btc| Amount::from_btc(fee_btc).ok())
+			.ok_or(std::io::Error::new(
+				std::io::ErrorKind::Other,
+				"Failed to parse mempool entry ancestor fee",
+			))?;
+		// Bitcoin Core gives us the ancestor size in vbytes.
+		let ancestor_weight = self.0["ancestorsize"].as_u64().and_then(Weight::from_vb).ok_or(
+			std::io::Error::new(
+				std::io::ErrorKind::Other,
+				"Failed to parse mempool entry ancestor size",
+			),
+		)?;
+		Ok(MempoolEntry { ancestor_fee, ancestor_weight })
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FeeRateEstimationMode {
+	Economical,
+	Conservative,
+}
+
+impl FeeRateEstimationMode {
+	fn as_str(&self) -> &'static str {
+		match self {
+			Self::Economical => "ECONOMICAL",
+			Self::Conservative => "CONSERVATIVE",
+		}
+	}
+}
+
+const MAX_HEADER_CACHE_ENTRIES: usize = 100;
+
+pub(crate) struct BoundedHeaderCache {
+	header_map: HashMap<BlockHash, ValidatedBlockHeader>,
+	recently_seen: VecDeque<BlockHash>,
+}
+
+impl BoundedHeaderCache {
+	pub(crate) fn new() -> Self {
+		let header_map = HashMap::new();
+		let recently_seen = VecDeque::new();
+		Self { header_map, recently_seen }
+	}
+}
+
+impl Cache for BoundedHeaderCache {
+	fn look_up(&self, block_hash: &BlockHash) -> Option<&ValidatedBlockHeader> {
+		self.header_map.get(block_hash)
+	}
+
+	fn block_connected(&mut self, block_hash: BlockHash, block_header: ValidatedBlockHeader) {
+		self.recently_seen.push_back(block_hash);
+		self.header_map.insert(block_hash, block_header);
+
+		if self.header_map.len() >= MAX_HEADER_CACHE_ENTRIES {
+			// Keep dropping old entries until we've actually removed a header entry.
+			while let Some(oldest_entry) = self.recently_seen.pop_front() {
+				if self.header_map.remove(&oldest_entry).is_some() {
+					break;
+				}
+			}
+		}
+	}
+
+	fn block_disconnected(&mut self, block_hash: &BlockHash) -> Option<ValidatedBlockHeader> {
+		self.recently_seen.retain(|e| e != block_hash);
+		self.header_map.remove(block_hash)
+	}
+}
+
+pub(crate) struct ChainListener {
+	pub(crate) onchain_wallet: Arc<Wallet>,
+	pub(crate) channel_manager: Arc<ChannelManager>,
+	pub(crate) chain_monitor: Arc<ChainMonitor>,
+	pub(crate) output_sweeper: Arc<Sweeper>,
+	pub(crate) channel_history: Arc<ChannelHistory>,
+}
+
+impl Listen for ChainListener {
+	fn filtered_block_connected(
+		&self, header: &bitcoin::block::Header,
+		txdata: &lightning::chain::transaction::TransactionData, height: u32,
+	) {
+		self.onchain_wallet.filtered_block_connected(header, txdata, height);
+		self.channel_manager.filtered_block_connected(header, txdata, height);
+		self.chain_monitor.filtered_block_connected(header, txdata, height);
+		self.output_sweeper.filtered_block_connected(header, txdata, height);
+		self.channel_history.filtered_block_connected(header, txdata, height);
+	}
+
+	fn block_connected(&self, block: &bitcoin::Block, height: u32) {
+		self.onchain_wallet.block_connected(block, height);
+		self.channel_manager.block_connected(block, height);
+		self.chain_monitor.block_connected(block, height);
+		self.output_sweeper.block_connected(block, height);
+		self.channel_history.block_connected(block, height);
+	}
+
+	fn block_disconnected(&self, header: &bitcoin::block::Header, height: u32) {
+		self.onchain_wallet.block_disconnected(header, height);
+		self.channel_manager.block_disconnected(header, height);
+		self.chain_monitor.block_disconnected(header, height);
+		self.output_sweeper.block_disconnected(header, height);
+		self.channel_history.block_disconnected(header, height);
+	}
+}
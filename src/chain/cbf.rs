@@ -0,0 +1,1136 @@
+// This file is Copyright its original authors, visible in version control history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
+// accordance with one or both of these licenses.
+
+use crate::config::{CBF_MAX_PEERS, CBF_PEER_RECONNECTION_INTERVAL_SECS, CBF_PEER_TIMEOUT_SECS};
+use crate::io::{
+	CBF_HEADER_CHAIN_PERSISTENCE_PRIMARY_NAMESPACE,
+	CBF_HEADER_CHAIN_PERSISTENCE_SECONDARY_NAMESPACE,
+};
+use crate::logger::{log_debug, log_error, log_trace, FilesystemLogger, Logger};
+use crate::types::{DynStore, Wallet};
+
+use lightning::ln::msgs::SocketAddress;
+
+use lightning_block_sync::{
+	AsyncBlockSourceResult, BlockData, BlockHeaderData, BlockSource, BlockSourceError,
+};
+
+use bitcoin::bip158::{BlockFilter, FilterHash, FilterHeader};
+use bitcoin::block::Header;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::hashes::Hash;
+use bitcoin::p2p::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::p2p::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::p2p::message_filter::{GetCFHeaders, GetCFilters};
+use bitcoin::p2p::message_network::VersionMessage;
+use bitcoin::p2p::{Address as P2PAddress, ServiceFlags};
+use bitcoin::{Block, BlockHash, CompactTarget, Network, Script, ScriptBuf, Transaction};
+
+use rand::Rng;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// The P2P protocol version we advertise. BIP 157 requires peers to at least speak 70015.
+const P2P_PROTOCOL_VERSION: u32 = 70016;
+
+// The user agent we advertise to our peers.
+const P2P_USER_AGENT: &str = "/ldk-node:0.3.0/";
+
+// The maximum number of headers a peer will send us in response to a single `getheaders`.
+const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+
+// The maximum size of a P2P message we're willing to read.
+const MAX_P2P_MESSAGE_SIZE: usize = 4_000_000;
+
+// The size of the P2P message header preceding each payload.
+const P2P_MESSAGE_HEADER_SIZE: usize = 24;
+
+// The filter type of BIP 158 basic block filters.
+const BASIC_FILTER_TYPE: u8 = 0x00;
+
+// The number of preceding blocks whose median timestamp a block's timestamp has to exceed.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+// The maximum number of seconds a block's timestamp may be ahead of our local time.
+const MAX_FUTURE_BLOCK_TIME_SECS: u64 = 2 * 60 * 60;
+
+// The number of headers we persist under a single key, i.e., one difficulty adjustment period.
+pub(crate) const HEADER_CHAIN_SEGMENT_LEN: usize = 2016;
+
+/// A [`BlockSource`] retrieving chain data from BIP 157 peers.
+///
+/// We keep a copy of the header chain with the most work any of our peers knows about, which is
+/// persisted so that we don't have to retrieve it from scratch on restart. Blocks are only
+/// downloaded if their BIP 158 filter matches any of the scripts we're watching for, otherwise
+/// listeners are merely notified about the block header.
+///
+/// As we can't verify filters without downloading the full block, we cross-check the filter
+/// headers served by all of our peers and only trust a filter if they agree on it. If they don't,
+/// we download and verify the full block instead.
+pub(crate) struct CbfBlockSource {
+	peers: Vec<SocketAddress>,
+	network: Network,
+	header_chain: Mutex<HeaderChain>,
+	connections: tokio::sync::Mutex<Vec<PeerConnection>>,
+	last_connection_attempt: Mutex<Option<Instant>>,
+	watched_scripts: Mutex<HashSet<ScriptBuf>>,
+	onchain_wallet: Arc<Wallet>,
+	kv_store: Arc<DynStore>,
+	logger: Arc<FilesystemLogger>,
+}
+
+impl CbfBlockSource {
+	pub(crate) fn new(
+		peers: Vec<SocketAddress>, network: Network, persisted_headers: Vec<Header>,
+		onchain_wallet: Arc<Wallet>, kv_store: Arc<DynStore>, logger: Arc<FilesystemLogger>,
+	) -> Self {
+		let header_chain = Mutex::new(HeaderChain::new(network, persisted_headers));
+		let connections = tokio::sync::Mutex::new(Vec::new());
+		let last_connection_attempt = Mutex::new(None);
+		let watched_scripts = Mutex::new(HashSet::new());
+		Self {
+			peers,
+			network,
+			header_chain,
+			connections,
+			last_connection_attempt,
+			watched_scripts,
+			onchain_wallet,
+			kv_store,
+			logger,
+		}
+	}
+
+	/// Registers a script we want to be notified about whenever it is paid to or spent from.
+	pub(crate) fn register_script(&self, script_pubkey: &Script) {
+		self.watched_scripts.lock().unwrap().insert(script_pubkey.to_owned());
+	}
+
+	/// Syncs our header chain up to the best tip known to any of our peers.
+	pub(crate) async fn sync_headers(&self) -> std::io::Result<BlockHeaderData> {
+		let mut locked_connections = self.connections.lock().await;
+		self.connect_peers(&mut locked_connections).await?;
+
+		// We sync headers from all of our peers, so that a single peer can't keep us from
+		// learning about the chain with the most work.
+		for mut connection in std::mem::take(&mut *locked_connections) {
+			match self.sync_headers_from_peer(&mut connection).await {
+				Ok(()) => locked_connections.push(connection),
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Failed to sync headers from peer {}: {}",
+						connection.peer,
+						e
+					);
+				},
+			}
+		}
+
+		if locked_connections.is_empty() {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::NotConnected,
+				"Failed to sync headers from any compact block filter peer",
+			));
+		}
+
+		let tip = self.header_chain.lock().unwrap().tip();
+		log_trace!(
+			self.logger,
+			"Synced header chain to tip {} at height {}",
+			tip.header.block_hash(),
+			tip.height
+		);
+		Ok(tip)
+	}
+
+	async fn sync_headers_from_peer(&self, connection: &mut PeerConnection) -> std::io::Result<()> {
+		// Any competing branch we track is specific to the peer that told us about it.
+		self.header_chain.lock().unwrap().clear_fork();
+
+		let res = async {
+			loop {
+				let locator = self.header_chain.lock().unwrap().locator();
+				let headers = connection.get_headers(locator).await?;
+				let num_headers = headers.len();
+
+				let connect_res = self.header_chain.lock().unwrap().connect_headers(headers);
+				self.persist_header_chain();
+				connect_res.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+				if num_headers < MAX_HEADERS_PER_MESSAGE {
+					break;
+				}
+			}
+			Ok(())
+		}
+		.await;
+
+		self.header_chain.lock().unwrap().clear_fork();
+		res
+	}
+
+	fn persist_header_chain(&self) {
+		let mut locked_header_chain = self.header_chain.lock().unwrap();
+		locked_header_chain.persist(&*self.kv_store).unwrap_or_else(|e| {
+			log_error!(self.logger, "Failed to persist header chain: {}", e);
+		});
+	}
+
+	/// Returns the hash of the block at the given height in our header chain, if we know about it.
+	pub(crate) fn get_block_hash(&self, height: u32) -> Option<BlockHash> {
+		let locked_header_chain = self.header_chain.lock().unwrap();
+		locked_header_chain.headers.get(height as usize).map(|h| h.header.block_hash())
+	}
+
+	/// Disconnects from all of our peers.
+	///
+	/// Note that the connections are tied to the runtime they were established on, i.e., this
+	/// needs to be called before switching runtimes.
+	pub(crate) async fn disconnect(&self) {
+		self.connections.lock().await.clear();
+	}
+
+	pub(crate) async fn broadcast_transaction(&self, tx: &Transaction) -> std::io::Result<()> {
+		let mut locked_connections = self.connections.lock().await;
+		if locked_connections.is_empty() {
+			self.connect_peers(&mut locked_connections).await?;
+		}
+
+		for mut connection in std::mem::take(&mut *locked_connections) {
+			match connection.send(NetworkMessage::Tx(tx.clone())).await {
+				Ok(()) => locked_connections.push(connection),
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Failed to broadcast transaction to peer {}: {}",
+						connection.peer,
+						e
+					);
+				},
+			}
+		}
+
+		if locked_connections.is_empty() {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::NotConnected,
+				"Failed to broadcast transaction to any compact block filter peer",
+			));
+		}
+		Ok(())
+	}
+
+	async fn get_block_data(&self, header_data: BlockHeaderData) -> std::io::Result<BlockData> {
+		let block_hash = header_data.header.block_hash();
+
+		let mut scripts = self.onchain_wallet.get_watched_scripts();
+		scripts.extend(self.watched_scripts.lock().unwrap().iter().cloned());
+		if scripts.is_empty() {
+			return Ok(BlockData::HeaderOnly(header_data.header));
+		}
+
+		let mut locked_connections = self.connections.lock().await;
+		if locked_connections.is_empty() {
+			self.connect_peers(&mut locked_connections).await?;
+		}
+
+		let filter_hash =
+			self.get_filter_hash(&mut locked_connections, header_data.height, block_hash).await?;
+		let is_match = match filter_hash {
+			Some(filter_hash) => {
+				let filter = self
+					.get_filter(
+						&mut locked_connections,
+						header_data.height,
+						block_hash,
+						filter_hash,
+					)
+					.await?;
+				filter
+					.match_any(&block_hash, scripts.iter().map(|s| s.as_bytes()))
+					.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+			},
+			None => {
+				// Our peers disagree about the filter, so we can't tell which of them to trust.
+				// Downloading the full block makes sure we don't miss anything relevant to us.
+				true
+			},
+		};
+
+		if is_match {
+			log_debug!(
+				self.logger,
+				"Filter of block {} at height {} matched, downloading block.",
+				block_hash,
+				header_data.height
+			);
+			let block = self.get_verified_block(&mut locked_connections, block_hash).await?;
+			Ok(BlockData::FullBlock(block))
+		} else {
+			Ok(BlockData::HeaderOnly(header_data.header))
+		}
+	}
+
+	/// Retrieves the hash of the block's filter as committed to by the filter header chain of each
+	/// of our peers.
+	///
+	/// Returns `None` if our peers disagree about the filter.
+	async fn get_filter_hash(
+		&self, connections: &mut Vec<PeerConnection>, height: u32, block_hash: BlockHash,
+	) -> std::io::Result<Option<FilterHash>> {
+		let mut filter_headers = Vec::new();
+		for mut connection in std::mem::take(connections) {
+			match connection.get_cfheaders(height, block_hash).await {
+				Ok(filter_header) => {
+					filter_headers.push(filter_header);
+					connections.push(connection);
+				},
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Failed to retrieve filter header of block {} from peer {}: {}",
+						block_hash,
+						connection.peer,
+						e
+					);
+				},
+			}
+		}
+
+		let (_, filter_hash) = *filter_headers.first().ok_or_else(|| {
+			std::io::Error::new(
+				std::io::ErrorKind::NotConnected,
+				"Failed to retrieve filter header from any compact block filter peer",
+			)
+		})?;
+
+		if filter_headers.iter().all(|h| *h == filter_headers[0]) {
+			Ok(Some(filter_hash))
+		} else {
+			log_error!(
+				self.logger,
+				"Our peers disagree about the filter of block {} at height {}.",
+				block_hash,
+				height
+			);
+			Ok(None)
+		}
+	}
+
+	async fn get_filter(
+		&self, connections: &mut Vec<PeerConnection>, height: u32, block_hash: BlockHash,
+		filter_hash: FilterHash,
+	) -> std::io::Result<BlockFilter> {
+		while let Some(connection) = connections.first_mut() {
+			match connection.get_cfilter(height, block_hash).await {
+				Ok(filter_bytes) if FilterHash::hash(&filter_bytes) == filter_hash => {
+					return Ok(BlockFilter::new(&filter_bytes));
+				},
+				Ok(_) => {
+					log_error!(
+						self.logger,
+						"Peer {} served a filter for block {} that doesn't match its filter header",
+						connection.peer,
+						block_hash
+					);
+				},
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Failed to retrieve filter of block {} from peer {}: {}",
+						block_hash,
+						connection.peer,
+						e
+					);
+				},
+			}
+			connections.remove(0);
+		}
+
+		Err(std::io::Error::new(
+			std::io::ErrorKind::NotConnected,
+			"Failed to retrieve filter from any compact block filter peer",
+		))
+	}
+
+	async fn get_verified_block(
+		&self, connections: &mut Vec<PeerConnection>, block_hash: BlockHash,
+	) -> std::io::Result<Block> {
+		while let Some(connection) = connections.first_mut() {
+			match connection.get_block(block_hash).await {
+				Ok(block) if block.check_merkle_root() && block.check_witness_commitment() => {
+					return Ok(block);
+				},
+				Ok(_) => {
+					log_error!(
+						self.logger,
+						"Peer {} served block {} with transactions not matching its header",
+						connection.peer,
+						block_hash
+					);
+				},
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Failed to retrieve block {} from peer {}: {}",
+						block_hash,
+						connection.peer,
+						e
+					);
+				},
+			}
+			connections.remove(0);
+		}
+
+		Err(std::io::Error::new(
+			std::io::ErrorKind::NotConnected,
+			"Failed to retrieve block from any compact block filter peer",
+		))
+	}
+
+	/// Connects to our configured peers until we reach [`CBF_MAX_PEERS`] connections.
+	///
+	/// Fails if we're not connected to any peer afterwards.
+	async fn connect_peers(&self, connections: &mut Vec<PeerConnection>) -> std::io::Result<()> {
+		let num_target_connections = cmp::min(self.peers.len(), CBF_MAX_PEERS);
+		if connections.len() >= num_target_connections {
+			return Ok(());
+		}
+
+		{
+			// If we're connected to some peers already, don't retry unreachable ones all the time.
+			let mut locked_last_attempt = self.last_connection_attempt.lock().unwrap();
+			let reconnection_interval = Duration::from_secs(CBF_PEER_RECONNECTION_INTERVAL_SECS);
+			if !connections.is_empty()
+				&& locked_last_attempt.map_or(false, |t| t.elapsed() < reconnection_interval)
+			{
+				return Ok(());
+			}
+			*locked_last_attempt = Some(Instant::now());
+		}
+
+		let start_height = self.header_chain.lock().unwrap().tip().height;
+		for peer in &self.peers {
+			if connections.len() >= num_target_connections {
+				break;
+			}
+
+			if connections.iter().any(|c| &c.peer == peer) {
+				continue;
+			}
+
+			if let Some(connection) = self.connect_to_peer(peer, start_height).await {
+				connections.push(connection);
+			}
+		}
+
+		if connections.is_empty() {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::NotConnected,
+				"Failed to connect to any compact block filter peer",
+			));
+		}
+		Ok(())
+	}
+
+	async fn connect_to_peer(
+		&self, peer: &SocketAddress, start_height: u32,
+	) -> Option<PeerConnection> {
+		let socket_addrs = match peer.to_socket_addrs() {
+			Ok(addrs) => addrs,
+			Err(e) => {
+				log_error!(self.logger, "Failed to resolve peer address {}: {}", peer, e);
+				return None;
+			},
+		};
+
+		for socket_addr in socket_addrs {
+			match PeerConnection::connect(peer.clone(), socket_addr, self.network, start_height)
+				.await
+			{
+				Ok(connection) => {
+					log_debug!(self.logger, "Connected to compact block filter peer {}", peer);
+					return Some(connection);
+				},
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Failed to connect to compact block filter peer {}: {}",
+						socket_addr,
+						e
+					);
+				},
+			}
+		}
+		None
+	}
+}
+
+impl BlockSource for CbfBlockSource {
+	fn get_header<'a>(
+		&'a self, header_hash: &'a BlockHash, _height_hint: Option<u32>,
+	) -> AsyncBlockSourceResult<'a, BlockHeaderData> {
+		Box::pin(async move {
+			self.header_chain
+				.lock()
+				.unwrap()
+				.get_header(header_hash)
+				.ok_or(BlockSourceError::persistent("Unknown block header"))
+		})
+	}
+
+	fn get_block<'a>(
+		&'a self, header_hash: &'a BlockHash,
+	) -> AsyncBlockSourceResult<'a, BlockData> {
+		Box::pin(async move {
+			let header_data_opt = self.header_chain.lock().unwrap().get_header(header_hash);
+			let header_data =
+				header_data_opt.ok_or(BlockSourceError::persistent("Unknown block header"))?;
+			self.get_block_data(header_data).await.map_err(BlockSourceError::transient)
+		})
+	}
+
+	fn get_best_block<'a>(&'a self) -> AsyncBlockSourceResult<'a, (BlockHash, Option<u32>)> {
+		Box::pin(async move {
+			let tip = self.sync_headers().await.map_err(BlockSourceError::transient)?;
+			Ok((tip.header.block_hash(), Some(tip.height)))
+		})
+	}
+}
+
+/// The header chain with the most work we know about, starting at the genesis block.
+///
+/// Headers are validated against the proof of work rules of the network, i.e., their difficulty
+/// target has to follow the retargeting rules and their timestamps have to exceed the median of
+/// their predecessors'. We only ever reorganize to a competing branch once it has more total work
+/// than our current best chain.
+struct HeaderChain {
+	network: Network,
+	headers: Vec<BlockHeaderData>,
+	height_by_hash: HashMap<BlockHash, u32>,
+	// A competing branch forking off our best chain that doesn't have more work (yet).
+	fork: Vec<BlockHeaderData>,
+	// Headers that were reorganized out of our best chain. We keep them around so that listeners
+	// can still look them up when disconnecting the corresponding blocks.
+	stale_headers: HashMap<BlockHash, BlockHeaderData>,
+	// The lowest height at which our best chain changed since we last persisted it.
+	first_unpersisted_height: Option<u32>,
+	num_persisted_segments: usize,
+}
+
+impl HeaderChain {
+	/// Restores the header chain from the given previously persisted headers.
+	///
+	/// As the headers were validated before they were persisted, we merely check that they still
+	/// connect, dropping any that don't.
+	fn new(network: Network, persisted_headers: Vec<Header>) -> Self {
+		let genesis_header = bitcoin::blockdata::constants::genesis_block(network).header;
+		let genesis_data =
+			BlockHeaderData { header: genesis_header, height: 0, chainwork: genesis_header.work() };
+		let mut height_by_hash = HashMap::new();
+		height_by_hash.insert(genesis_header.block_hash(), 0);
+		let num_persisted_headers = persisted_headers.len();
+		let num_persisted_segments =
+			(num_persisted_headers + HEADER_CHAIN_SEGMENT_LEN - 1) / HEADER_CHAIN_SEGMENT_LEN;
+		let mut header_chain = Self {
+			network,
+			headers: vec![genesis_data],
+			height_by_hash,
+			fork: Vec::new(),
+			stale_headers: HashMap::new(),
+			first_unpersisted_height: None,
+			num_persisted_segments,
+		};
+
+		let mut persisted_iter = persisted_headers.into_iter();
+		if persisted_iter.next() == Some(genesis_header) {
+			for header in persisted_iter {
+				let tip = header_chain.tip();
+				if header.prev_blockhash != tip.header.block_hash() {
+					break;
+				}
+				let chainwork = tip.chainwork + header.work();
+				let height = tip.height + 1;
+				header_chain.headers.push(BlockHeaderData { header, height, chainwork });
+				header_chain.height_by_hash.insert(header.block_hash(), height);
+			}
+		}
+
+		if header_chain.headers.len() != num_persisted_headers {
+			let first_unpersisted_height =
+				cmp::min(header_chain.headers.len(), num_persisted_headers);
+			header_chain.first_unpersisted_height = Some(first_unpersisted_height as u32);
+		}
+		header_chain
+	}
+
+	fn tip(&self) -> BlockHeaderData {
+		*self.headers.last().expect("We always have at least the genesis header")
+	}
+
+	fn get_header(&self, block_hash: &BlockHash) -> Option<BlockHeaderData> {
+		self.height_by_hash
+			.get(block_hash)
+			.map(|height| self.headers[*height as usize])
+			.or_else(|| self.stale_headers.get(block_hash).copied())
+	}
+
+	/// Returns the header at the given height, following the competing branch if we track one.
+	fn ancestor(&self, height: u32) -> &BlockHeaderData {
+		match self.fork.first() {
+			Some(fork_start) if height >= fork_start.height => {
+				&self.fork[(height - fork_start.height) as usize]
+			},
+			_ => &self.headers[height as usize],
+		}
+	}
+
+	/// Returns a block locator as described in the `getheaders` P2P message documentation.
+	///
+	/// If we track a competing branch, the locator starts at its tip so that we continue
+	/// retrieving it.
+	fn locator(&self) -> Vec<BlockHash> {
+		let mut locator = Vec::new();
+		let mut height = self.fork.last().unwrap_or(self.headers.last().unwrap()).height;
+		let mut step = 1;
+		loop {
+			locator.push(self.ancestor(height).header.block_hash());
+			if height == 0 {
+				break;
+			}
+			if locator.len() >= 10 {
+				step *= 2;
+			}
+			height = height.saturating_sub(step);
+		}
+		locator
+	}
+
+	fn clear_fork(&mut self) {
+		self.fork.clear();
+	}
+
+	fn connect_headers(&mut self, headers: Vec<Header>) -> Result<(), String> {
+		for header in headers {
+			let block_hash = header.block_hash();
+			if self.height_by_hash.contains_key(&block_hash) {
+				continue;
+			}
+
+			let fork_tip = self.fork.last().copied();
+			let prev = match fork_tip {
+				Some(fork_tip) if fork_tip.header.block_hash() == header.prev_blockhash => fork_tip,
+				_ => {
+					let prev_height = *self
+						.height_by_hash
+						.get(&header.prev_blockhash)
+						.ok_or(format!("Received unconnected header {}", block_hash))?;
+					// The header starts a new branch, so we abandon any competing branch we
+					// tracked so far.
+					self.fork.clear();
+					self.headers[prev_height as usize]
+				},
+			};
+
+			let header_data = self.validate_header(header, &prev)?;
+			if self.fork.is_empty() && prev.height == self.tip().height {
+				self.push_header(header_data);
+			} else {
+				self.fork.push(header_data);
+				if header_data.chainwork > self.tip().chainwork {
+					self.reorg_to_fork();
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn validate_header(
+		&self, header: Header, prev: &BlockHeaderData,
+	) -> Result<BlockHeaderData, String> {
+		let block_hash = header.block_hash();
+
+		let required_bits = self.next_required_bits(&header, prev);
+		if header.bits != required_bits {
+			return Err(format!(
+				"Received header {} with unexpected difficulty target {:#x}, expected {:#x}",
+				block_hash,
+				header.bits.to_consensus(),
+				required_bits.to_consensus()
+			));
+		}
+
+		header.validate_pow(header.target()).map_err(|e| {
+			format!("Received header {} with invalid proof of work: {}", block_hash, e)
+		})?;
+
+		if header.time <= self.median_time_past(prev) {
+			return Err(format!(
+				"Received header {} with timestamp too far in the past",
+				block_hash
+			));
+		}
+
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+		if header.time as u64 > now + MAX_FUTURE_BLOCK_TIME_SECS {
+			return Err(format!(
+				"Received header {} with timestamp too far in the future",
+				block_hash
+			));
+		}
+
+		let height = prev.height + 1;
+		let chainwork = prev.chainwork + header.work();
+		Ok(BlockHeaderData { header, height, chainwork })
+	}
+
+	/// Returns the difficulty target the successor of `prev` has to use, following Bitcoin Core's
+	/// `GetNextWorkRequired`.
+	fn next_required_bits(&self, header: &Header, prev: &BlockHeaderData) -> CompactTarget {
+		let params = self.network.params();
+		let interval = params.difficulty_adjustment_interval() as u32;
+		let height = prev.height + 1;
+
+		if height % interval != 0 {
+			if params.allow_min_difficulty_blocks {
+				// On test networks, blocks may be mined at minimum difficulty if they are more
+				// than twice the target spacing later than their predecessor.
+				let min_difficulty_bits = params.max_attainable_target.to_compact_lossy();
+				if header.time as u64 > prev.header.time as u64 + 2 * params.pow_target_spacing {
+					return min_difficulty_bits;
+				}
+
+				// Otherwise, they have to use the target of the last block that wasn't mined at
+				// minimum difficulty.
+				let mut ancestor = prev;
+				while ancestor.height % interval != 0 && ancestor.header.bits == min_difficulty_bits
+				{
+					ancestor = self.ancestor(ancestor.height - 1);
+				}
+				return ancestor.header.bits;
+			}
+			return prev.header.bits;
+		}
+
+		let period_start = self.ancestor(height - interval);
+		let timespan = (prev.header.time as u64).saturating_sub(period_start.header.time as u64);
+		// BIP 94 mitigates time warp attacks on testnet4 by basing the retarget on the first block
+		// of the period.
+		let last_bits = if self.network == Network::Testnet4 {
+			period_start.header.bits
+		} else {
+			prev.header.bits
+		};
+		CompactTarget::from_next_work_required(last_bits, timespan, params)
+	}
+
+	fn median_time_past(&self, tip: &BlockHeaderData) -> u32 {
+		let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+		let mut ancestor = tip;
+		loop {
+			timestamps.push(ancestor.header.time);
+			if ancestor.height == 0 || timestamps.len() == MEDIAN_TIME_SPAN {
+				break;
+			}
+			ancestor = self.ancestor(ancestor.height - 1);
+		}
+		timestamps.sort_unstable();
+		timestamps[timestamps.len() / 2]
+	}
+
+	fn push_header(&mut self, header_data: BlockHeaderData) {
+		let block_hash = header_data.header.block_hash();
+		self.stale_headers.remove(&block_hash);
+		self.height_by_hash.insert(block_hash, header_data.height);
+		self.headers.push(header_data);
+		self.first_unpersisted_height = Some(
+			self.first_unpersisted_height
+				.map_or(header_data.height, |h| cmp::min(h, header_data.height)),
+		);
+	}
+
+	fn reorg_to_fork(&mut self) {
+		let fork_height = self.fork[0].height as usize;
+		for stale in self.headers.drain(fork_height..) {
+			let stale_hash = stale.header.block_hash();
+			self.height_by_hash.remove(&stale_hash);
+			self.stale_headers.insert(stale_hash, stale);
+		}
+		for header_data in std::mem::take(&mut self.fork) {
+			self.push_header(header_data);
+		}
+	}
+
+	/// Persists all segments of our best chain that changed since we last persisted it.
+	fn persist(&mut self, kv_store: &DynStore) -> std::io::Result<()> {
+		let first_unpersisted_height = match self.first_unpersisted_height {
+			Some(height) => height as usize,
+			None => return Ok(()),
+		};
+
+		let num_segments =
+			(self.headers.len() + HEADER_CHAIN_SEGMENT_LEN - 1) / HEADER_CHAIN_SEGMENT_LEN;
+		for segment in first_unpersisted_height / HEADER_CHAIN_SEGMENT_LEN..num_segments {
+			let start = segment * HEADER_CHAIN_SEGMENT_LEN;
+			let end = cmp::min(start + HEADER_CHAIN_SEGMENT_LEN, self.headers.len());
+			let headers: Vec<Header> = self.headers[start..end].iter().map(|h| h.header).collect();
+			kv_store.write(
+				CBF_HEADER_CHAIN_PERSISTENCE_PRIMARY_NAMESPACE,
+				CBF_HEADER_CHAIN_PERSISTENCE_SECONDARY_NAMESPACE,
+				&segment.to_string(),
+				&serialize(&headers),
+			)?;
+		}
+
+		// Drop any segments that were reorganized out of our best chain.
+		for segment in num_segments..self.num_persisted_segments {
+			kv_store.remove(
+				CBF_HEADER_CHAIN_PERSISTENCE_PRIMARY_NAMESPACE,
+				CBF_HEADER_CHAIN_PERSISTENCE_SECONDARY_NAMESPACE,
+				&segment.to_string(),
+				false,
+			)?;
+		}
+
+		self.num_persisted_segments = num_segments;
+		self.first_unpersisted_height = None;
+		Ok(())
+	}
+}
+
+/// A connection to a single BIP 157 peer.
+struct PeerConnection {
+	peer: SocketAddress,
+	stream: TcpStream,
+	network: Network,
+}
+
+impl PeerConnection {
+	async fn connect(
+		peer: SocketAddress, socket_addr: SocketAddr, network: Network, start_height: u32,
+	) -> std::io::Result<Self> {
+		let stream = tokio::time::timeout(
+			Duration::from_secs(CBF_PEER_TIMEOUT_SECS),
+			TcpStream::connect(socket_addr),
+		)
+		.await
+		.map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Connection timed out"))??;
+
+		let mut connection = Self { peer, stream, network };
+		connection.handshake(socket_addr, start_height).await?;
+		Ok(connection)
+	}
+
+	async fn handshake(
+		&mut self, socket_addr: SocketAddr, start_height: u32,
+	) -> std::io::Result<()> {
+		let timestamp = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+			.as_secs() as i64;
+		let receiver = P2PAddress::new(&socket_addr, ServiceFlags::NONE);
+		let sender = P2PAddress::new(&SocketAddr::from(([0, 0, 0, 0], 0)), ServiceFlags::NONE);
+		let nonce = rand::thread_rng().gen::<u64>();
+		let mut version_msg = VersionMessage::new(
+			ServiceFlags::NONE,
+			timestamp,
+			receiver,
+			sender,
+			nonce,
+			P2P_USER_AGENT.to_string(),
+			start_height as i32,
+		);
+		version_msg.version = P2P_PROTOCOL_VERSION;
+		version_msg.relay = false;
+		self.send(NetworkMessage::Version(version_msg)).await?;
+
+		let mut received_version = false;
+		let mut received_verack = false;
+		while !(received_version && received_verack) {
+			match self.recv().await? {
+				NetworkMessage::Version(their_version) => {
+					if !their_version.services.has(ServiceFlags::COMPACT_FILTERS) {
+						return Err(std::io::Error::new(
+							std::io::ErrorKind::Unsupported,
+							"Peer doesn't serve compact block filters",
+						));
+					}
+					self.send(NetworkMessage::Verack).await?;
+					received_version = true;
+				},
+				NetworkMessage::Verack => {
+					received_verack = true;
+				},
+				_ => {},
+			}
+		}
+
+		Ok(())
+	}
+
+	async fn get_headers(&mut self, locator: Vec<BlockHash>) -> std::io::Result<Vec<Header>> {
+		let get_headers_msg = GetHeadersMessage::new(locator, BlockHash::all_zeros());
+		self.send(NetworkMessage::GetHeaders(get_headers_msg)).await?;
+		loop {
+			if let NetworkMessage::Headers(headers) = self.recv().await? {
+				return Ok(headers);
+			}
+		}
+	}
+
+	/// Returns the previous filter header and the filter hash of the given block.
+	async fn get_cfheaders(
+		&mut self, height: u32, block_hash: BlockHash,
+	) -> std::io::Result<(FilterHeader, FilterHash)> {
+		let get_cfheaders_msg = GetCFHeaders {
+			filter_type: BASIC_FILTER_TYPE,
+			start_height: height,
+			stop_hash: block_hash,
+		};
+		self.send(NetworkMessage::GetCFHeaders(get_cfheaders_msg)).await?;
+		loop {
+			if let NetworkMessage::CFHeaders(cfheaders) = self.recv().await? {
+				if cfheaders.stop_hash == block_hash && cfheaders.filter_type == BASIC_FILTER_TYPE {
+					return match cfheaders.filter_hashes.as_slice() {
+						[filter_hash] => Ok((cfheaders.previous_filter_header, *filter_hash)),
+						_ => Err(std::io::Error::new(
+							std::io::ErrorKind::InvalidData,
+							"Received unexpected number of filter headers",
+						)),
+					};
+				}
+			}
+		}
+	}
+
+	async fn get_cfilter(
+		&mut self, height: u32, block_hash: BlockHash,
+	) -> std::io::Result<Vec<u8>> {
+		let get_cfilters_msg = GetCFilters {
+			filter_type: BASIC_FILTER_TYPE,
+			start_height: height,
+			stop_hash: block_hash,
+		};
+		self.send(NetworkMessage::GetCFilters(get_cfilters_msg)).await?;
+		loop {
+			if let NetworkMessage::CFilter(cfilter) = self.recv().await? {
+				if cfilter.block_hash == block_hash && cfilter.filter_type == BASIC_FILTER_TYPE {
+					return Ok(cfilter.filter);
+				}
+			}
+		}
+	}
+
+	async fn get_block(&mut self, block_hash: BlockHash) -> std::io::Result<Block> {
+		self.send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(block_hash)])).await?;
+		loop {
+			match self.recv().await? {
+				NetworkMessage::Block(block) if block.block_hash() == block_hash => {
+					return Ok(block);
+				},
+				NetworkMessage::NotFound(_) => {
+					return Err(std::io::Error::new(
+						std::io::ErrorKind::NotFound,
+						"Peer didn't have the requested block",
+					));
+				},
+				_ => {},
+			}
+		}
+	}
+
+	async fn send(&mut self, msg: NetworkMessage) -> std::io::Result<()> {
+		let raw_msg = RawNetworkMessage::new(self.network.magic(), msg);
+		let bytes = serialize(&raw_msg);
+		tokio::time::timeout(
+			Duration::from_secs(CBF_PEER_TIMEOUT_SECS),
+			self.stream.write_all(&bytes),
+		)
+		.await
+		.map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Sending timed out"))?
+	}
+
+	/// Reads the next message from the peer, taking care of answering any pings on the way.
+	async fn recv(&mut self) -> std::io::Result<NetworkMessage> {
+		loop {
+			let msg = tokio::time::timeout(
+				Duration::from_secs(CBF_PEER_TIMEOUT_SECS),
+				self.read_message(),
+			)
+			.await
+			.map_err(|_| {
+				std::io::Error::new(std::io::ErrorKind::TimedOut, "Receiving timed out")
+			})??;
+
+			match msg {
+				NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce)).await?,
+				msg => return Ok(msg),
+			}
+		}
+	}
+
+	async fn read_message(&mut self) -> std::io::Result<NetworkMessage> {
+		let mut buf = vec![0u8; P2P_MESSAGE_HEADER_SIZE];
+		self.stream.read_exact(&mut buf).await?;
+
+		if buf[0..4] != self.network.magic().to_bytes() {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				"Received message with unexpected network magic",
+			));
+		}
+
+		let mut payload_len_bytes = [0u8; 4];
+		payload_len_bytes.copy_from_slice(&buf[16..20]);
+		let payload_len = u32::from_le_bytes(payload_len_bytes) as usize;
+		if payload_len > MAX_P2P_MESSAGE_SIZE {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				"Received oversized message",
+			));
+		}
+
+		buf.resize(P2P_MESSAGE_HEADER_SIZE + payload_len, 0);
+		self.stream.read_exact(&mut buf[P2P_MESSAGE_HEADER_SIZE..]).await?;
+
+		let raw_msg: RawNetworkMessage = deserialize(&buf)
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+		Ok(raw_msg.payload().clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::io::utils::read_cbf_header_chain;
+
+	use lightning::util::test_utils::{TestLogger, TestStore};
+
+	use bitcoin::block::Version;
+	use bitcoin::TxMerkleNode;
+
+	fn mine_header(prev: &Header, time: u32, bits: CompactTarget) -> Header {
+		let mut header = Header {
+			version: Version::TWO,
+			prev_blockhash: prev.block_hash(),
+			merkle_root: TxMerkleNode::all_zeros(),
+			time,
+			bits,
+			nonce: 0,
+		};
+		while header.validate_pow(header.target()).is_err() {
+			header.nonce += 1;
+		}
+		header
+	}
+
+	// Mines a chain of headers on top of `prev`, varying the timestamps by `time_offset` to allow
+	// for competing branches.
+	fn mine_chain(prev: &Header, len: usize, time_offset: u32) -> Vec<Header> {
+		let mut headers = Vec::new();
+		let mut prev = *prev;
+		for _ in 0..len {
+			let header = mine_header(&prev, prev.time + 600 + time_offset, prev.bits);
+			headers.push(header);
+			prev = header;
+		}
+		headers
+	}
+
+	#[test]
+	fn header_chain_rejects_invalid_headers() {
+		let mut header_chain = HeaderChain::new(Network::Regtest, Vec::new());
+		let genesis = header_chain.tip().header;
+
+		// Headers not following the network's difficulty rules are rejected.
+		let mut invalid_bits = mine_chain(&genesis, 1, 0)[0];
+		invalid_bits.bits = CompactTarget::from_consensus(0x1d00ffff);
+		assert!(header_chain.connect_headers(vec![invalid_bits]).is_err());
+
+		// As are headers not meeting their target.
+		let mut invalid_pow = mine_chain(&genesis, 1, 0)[0];
+		while invalid_pow.validate_pow(invalid_pow.target()).is_ok() {
+			invalid_pow.nonce += 1;
+		}
+		assert!(header_chain.connect_headers(vec![invalid_pow]).is_err());
+
+		// As are headers with timestamps not exceeding the median time past.
+		let invalid_time = mine_header(&genesis, genesis.time, genesis.bits);
+		assert!(header_chain.connect_headers(vec![invalid_time]).is_err());
+
+		assert_eq!(header_chain.tip().height, 0);
+
+		let valid = mine_chain(&genesis, 3, 0);
+		header_chain.connect_headers(valid.clone()).unwrap();
+		assert_eq!(header_chain.tip().height, 3);
+		assert_eq!(header_chain.tip().header, valid[2]);
+	}
+
+	#[test]
+	fn header_chain_reorgs_to_branch_with_most_work() {
+		let mut header_chain = HeaderChain::new(Network::Regtest, Vec::new());
+		let genesis = header_chain.tip().header;
+
+		let best_chain = mine_chain(&genesis, 5, 0);
+		header_chain.connect_headers(best_chain.clone()).unwrap();
+		assert_eq!(header_chain.tip().header, best_chain[4]);
+
+		// A competing branch forking off at height 2 isn't adopted as long as it has less or equal
+		// work.
+		let fork = mine_chain(&best_chain[1], 4, 1);
+		header_chain.connect_headers(fork[..3].to_vec()).unwrap();
+		assert_eq!(header_chain.tip().header, best_chain[4]);
+		assert_eq!(header_chain.locator()[0], fork[2].block_hash());
+
+		// Once it has more work, we reorganize to it.
+		header_chain.connect_headers(fork[3..].to_vec()).unwrap();
+		assert_eq!(header_chain.tip().header, fork[3]);
+		assert_eq!(header_chain.tip().height, 6);
+		assert_eq!(header_chain.headers[3].header, fork[0]);
+
+		// Stale headers can still be looked up.
+		let stale_header = header_chain.get_header(&best_chain[4].block_hash()).unwrap();
+		assert_eq!(stale_header.height, 5);
+	}
+
+	#[test]
+	fn header_chain_persistence() {
+		let store: Arc<DynStore> = Arc::new(TestStore::new(false));
+		let logger = TestLogger::new();
+
+		let mut header_chain = HeaderChain::new(Network::Regtest, Vec::new());
+		let genesis = header_chain.tip().header;
+		let best_chain = mine_chain(&genesis, 2 * HEADER_CHAIN_SEGMENT_LEN + 10, 0);
+		header_chain.connect_headers(best_chain.clone()).unwrap();
+		header_chain.persist(&*store).unwrap();
+
+		let persisted_headers = read_cbf_header_chain(Arc::clone(&store), &logger).unwrap();
+		assert_eq!(persisted_headers.len(), best_chain.len() + 1);
+		let restored_chain = HeaderChain::new(Network::Regtest, persisted_headers);
+		assert_eq!(restored_chain.tip(), header_chain.tip());
+		assert_eq!(restored_chain.first_unpersisted_height, None);
+
+		// After a reorg, the affected segments are rewritten.
+		let fork =
+			mine_chain(&best_chain[HEADER_CHAIN_SEGMENT_LEN - 10], 2 * HEADER_CHAIN_SEGMENT_LEN, 1);
+		header_chain.connect_headers(fork[..HEADER_CHAIN_SEGMENT_LEN + 100].to_vec()).unwrap();
+		assert_eq!(header_chain.tip().header, fork[HEADER_CHAIN_SEGMENT_LEN + 99]);
+		header_chain.persist(&*store).unwrap();
+
+		let persisted_headers = read_cbf_header_chain(Arc::clone(&store), &logger).unwrap();
+		let restored_chain = HeaderChain::new(Network::Regtest, persisted_headers);
+		assert_eq!(restored_chain.tip(), header_chain.tip());
+	}
+}
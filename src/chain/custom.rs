@@ -0,0 +1,334 @@
+// This file is Copyright its original authors, visible in version control history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
+// accordance with one or both of these licenses.
+
+use crate::config::{
+	BDK_WALLET_SYNC_TIMEOUT_SECS, FEE_RATE_CACHE_UPDATE_TIMEOUT_SECS, LDK_WALLET_SYNC_TIMEOUT_SECS,
+	TX_BROADCAST_TIMEOUT_SECS,
+};
+use crate::fee_estimator::{
+	apply_post_estimation_adjustments, get_all_conf_targets, get_num_block_defaults_for_target,
+	ConfirmationTarget,
+};
+use crate::logger::{log_bytes, log_error, log_trace, FilesystemLogger, Logger};
+use crate::Error;
+
+use lightning::chain::{BestBlock, Confirm, Filter};
+use lightning::util::ser::Writeable;
+
+use bdk_chain::spk_client::{FullScanRequest, FullScanResult, SyncRequest, SyncResult};
+
+use bdk_wallet::KeychainKind;
+
+use bitcoin::{FeeRate, Network, Transaction};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A user-provided source of chain data.
+///
+/// Can be used via [`Builder::set_chain_source_custom`] to have the [`Node`] retrieve chain data
+/// from a backend that isn't supported out of the box.
+///
+/// All methods are expected to block until they're done and will be called from threads that are
+/// allowed to do so. If a call doesn't return in time, we'll give up waiting for it and retry
+/// later.
+///
+/// Transactions and outputs the [`Node`] needs to track on-chain will be registered via the
+/// [`Filter`] interface. These need to be taken into account when syncing the [`Confirm`]
+/// implementations handed to [`CustomChainSource::sync_confirmables`].
+///
+/// [`Builder::set_chain_source_custom`]: crate::Builder::set_chain_source_custom
+/// [`Node`]: crate::Node
+pub trait CustomChainSource: Filter + Send + Sync {
+	/// Scans the script pubkeys of the on-chain wallet as given by the request, returning the
+	/// relevant transactions, their anchors, and the current chain tip.
+	///
+	/// For each keychain, scanning should continue until `stop_gap` consecutive script pubkeys
+	/// without any transaction history were found.
+	fn full_scan_wallet(
+		&self, request: FullScanRequest<KeychainKind>, stop_gap: usize,
+	) -> Result<FullScanResult<KeychainKind>, Error>;
+
+	/// Syncs the script pubkeys the on-chain wallet already revealed as given by the request,
+	/// returning the relevant transactions, their anchors, and the current chain tip.
+	///
+	/// In contrast to [`CustomChainSource::full_scan_wallet`], no further script pubkeys are
+	/// expected to be derived and looked up.
+	fn sync_wallet(&self, request: SyncRequest<(KeychainKind, u32)>) -> Result<SyncResult, Error>;
+
+	/// Syncs the given [`Confirm`] implementations to the current chain state.
+	///
+	/// This entails informing them about confirmed and unconfirmed transactions among the ones
+	/// registered via [`Filter`], as well as about the best block, as for example done by the
+	/// syncing clients of the `lightning-transaction-sync` crate.
+	fn sync_confirmables(
+		&self, confirmables: Vec<Arc<dyn Confirm + Sync + Send>>,
+	) -> Result<(), Error>;
+
+	/// Returns the current best block.
+	fn get_best_block(&self) -> Result<BestBlock, Error>;
+
+	/// Returns a fee rate estimate targeting confirmation within the given number of blocks.
+	///
+	/// If `None` is returned, we'll fall back to our default for the respective target, unless
+	/// we're operating on [`Network::Bitcoin`].
+	fn estimate_fee_rate(&self, num_blocks: usize) -> Result<Option<FeeRate>, Error>;
+
+	/// Broadcasts the given transaction to the network.
+	fn broadcast_transaction(&self, tx: &Transaction) -> Result<(), Error>;
+}
+
+impl fmt::Debug for dyn CustomChainSource {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "CustomChainSource")
+	}
+}
+
+pub(crate) struct CustomChainSourceClient {
+	custom_source: Arc<dyn CustomChainSource>,
+	logger: Arc<FilesystemLogger>,
+}
+
+impl CustomChainSourceClient {
+	pub(crate) fn new(
+		custom_source: Arc<dyn CustomChainSource>, logger: Arc<FilesystemLogger>,
+	) -> Self {
+		Self { custom_source, logger }
+	}
+
+	pub(crate) async fn full_scan_wallet(
+		&self, request: FullScanRequest<KeychainKind>, stop_gap: usize,
+	) -> Result<FullScanResult<KeychainKind>, Error> {
+		let custom_source = Arc::clone(&self.custom_source);
+		let spawn_fut =
+			tokio::task::spawn_blocking(move || custom_source.full_scan_wallet(request, stop_gap));
+		let wallet_sync_timeout_fut =
+			tokio::time::timeout(Duration::from_secs(BDK_WALLET_SYNC_TIMEOUT_SECS), spawn_fut);
+
+		wallet_sync_timeout_fut
+			.await
+			.map_err(|e| {
+				log_error!(self.logger, "On-chain wallet sync timed out: {}", e);
+				Error::WalletOperationTimeout
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync failed due to runtime error: {}", e);
+				Error::WalletOperationFailed
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync failed due to custom chain source error: {}", e);
+				e
+			})
+	}
+
+	pub(crate) async fn sync_wallet(
+		&self, request: SyncRequest<(KeychainKind, u32)>,
+	) -> Result<SyncResult, Error> {
+		let custom_source = Arc::clone(&self.custom_source);
+		let spawn_fut = tokio::task::spawn_blocking(move || custom_source.sync_wallet(request));
+		let wallet_sync_timeout_fut =
+			tokio::time::timeout(Duration::from_secs(BDK_WALLET_SYNC_TIMEOUT_SECS), spawn_fut);
+
+		wallet_sync_timeout_fut
+			.await
+			.map_err(|e| {
+				log_error!(self.logger, "On-chain wallet sync timed out: {}", e);
+				Error::WalletOperationTimeout
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync failed due to runtime error: {}", e);
+				Error::WalletOperationFailed
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync failed due to custom chain source error: {}", e);
+				e
+			})
+	}
+
+	pub(crate) async fn sync_confirmables(
+		&self, confirmables: Vec<Arc<dyn Confirm + Sync + Send>>,
+	) -> Result<(), Error> {
+		let custom_source = Arc::clone(&self.custom_source);
+		let spawn_fut =
+			tokio::task::spawn_blocking(move || custom_source.sync_confirmables(confirmables));
+		let timeout_fut =
+			tokio::time::timeout(Duration::from_secs(LDK_WALLET_SYNC_TIMEOUT_SECS), spawn_fut);
+
+		timeout_fut
+			.await
+			.map_err(|e| {
+				log_error!(self.logger, "Lightning wallet sync timed out: {}", e);
+				Error::TxSyncTimeout
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync failed due to runtime error: {}", e);
+				Error::TxSyncFailed
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync of Lightning wallet failed: {}", e);
+				e
+			})
+	}
+
+	pub(crate) async fn get_best_block(&self) -> Result<BestBlock, Error> {
+		let custom_source = Arc::clone(&self.custom_source);
+		let spawn_fut = tokio::task::spawn_blocking(move || custom_source.get_best_block());
+		let timeout_fut =
+			tokio::time::timeout(Duration::from_secs(LDK_WALLET_SYNC_TIMEOUT_SECS), spawn_fut);
+
+		timeout_fut
+			.await
+			.map_err(|e| {
+				log_error!(self.logger, "Retrieving chain tip timed out: {}", e);
+				Error::TxSyncTimeout
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to retrieve chain tip: {}", e);
+				Error::TxSyncFailed
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to retrieve chain tip: {}", e);
+				e
+			})
+	}
+
+	pub(crate) async fn broadcast(&self, tx: Transaction) {
+		let custom_source = Arc::clone(&self.custom_source);
+
+		let txid = tx.compute_txid();
+		let tx_bytes = tx.encode();
+
+		let spawn_fut =
+			tokio::task::spawn_blocking(move || custom_source.broadcast_transaction(&tx));
+
+		let timeout_fut =
+			tokio::time::timeout(Duration::from_secs(TX_BROADCAST_TIMEOUT_SECS), spawn_fut);
+
+		match timeout_fut.await {
+			Ok(res) => match res {
+				Ok(Ok(())) => {
+					log_trace!(self.logger, "Successfully broadcast transaction {}", txid);
+				},
+				Ok(Err(e)) => {
+					log_error!(self.logger, "Failed to broadcast transaction {}: {}", txid, e);
+					log_trace!(
+						self.logger,
+						"Failed broadcast transaction bytes: {}",
+						log_bytes!(tx_bytes)
+					);
+				},
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Failed to broadcast transaction due to runtime error {}: {}",
+						txid,
+						e
+					);
+					log_trace!(
+						self.logger,
+						"Failed broadcast transaction bytes: {}",
+						log_bytes!(tx_bytes)
+					);
+				},
+			},
+			Err(e) => {
+				log_error!(
+					self.logger,
+					"Failed to broadcast transaction due to timeout {}: {}",
+					txid,
+					e
+				);
+				log_trace!(
+					self.logger,
+					"Failed broadcast transaction bytes: {}",
+					log_bytes!(tx_bytes)
+				);
+			},
+		}
+	}
+
+	pub(crate) async fn get_fee_rate_cache_update(
+		&self, network: Network,
+	) -> Result<HashMap<ConfirmationTarget, FeeRate>, Error> {
+		let custom_source = Arc::clone(&self.custom_source);
+
+		let confirmation_targets = get_all_conf_targets();
+		let num_blocks: Vec<usize> =
+			confirmation_targets.iter().map(|t| get_num_block_defaults_for_target(*t)).collect();
+
+		let spawn_fut = tokio::task::spawn_blocking(move || {
+			num_blocks
+				.into_iter()
+				.map(|n| custom_source.estimate_fee_rate(n))
+				.collect::<Result<Vec<_>, Error>>()
+		});
+
+		let timeout_fut = tokio::time::timeout(
+			Duration::from_secs(FEE_RATE_CACHE_UPDATE_TIMEOUT_SECS),
+			spawn_fut,
+		);
+
+		let estimates = timeout_fut
+			.await
+			.map_err(|e| {
+				log_error!(self.logger, "Updating fee rate estimates timed out: {}", e);
+				Error::FeerateEstimationUpdateTimeout
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to retrieve fee rate estimates: {}", e);
+				Error::FeerateEstimationUpdateFailed
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to retrieve fee rate estimates: {}", e);
+				e
+			})?;
+
+		let mut new_fee_rate_cache = HashMap::with_capacity(10);
+		for (target, estimate_opt) in confirmation_targets.into_iter().zip(estimates.into_iter()) {
+			let fee_rate = match estimate_opt {
+				Some(fee_rate) => fee_rate,
+				None if network == Network::Bitcoin => {
+					// Ensure we fail if we didn't receive any estimates.
+					log_error!(
+						self.logger,
+						"Failed to retrieve fee rate estimates: empty fee estimates are dissallowed on Mainnet.",
+					);
+					return Err(Error::FeerateEstimationUpdateFailed);
+				},
+				None => {
+					// We'll fall back to our defaults for this target.
+					log_trace!(self.logger, "No fee rate estimate available for {:?}", target);
+					continue;
+				},
+			};
+
+			let adjusted_fee_rate = apply_post_estimation_adjustments(target, fee_rate);
+
+			new_fee_rate_cache.insert(target, adjusted_fee_rate);
+
+			log_trace!(
+				self.logger,
+				"Fee rate estimation updated for {:?}: {} sats/kwu",
+				target,
+				adjusted_fee_rate.to_sat_per_kwu(),
+			);
+		}
+
+		Ok(new_fee_rate_cache)
+	}
+}
+
+impl Filter for CustomChainSourceClient {
+	fn register_tx(&self, txid: &bitcoin::Txid, script_pubkey: &bitcoin::Script) {
+		self.custom_source.register_tx(txid, script_pubkey)
+	}
+	fn register_output(&self, output: lightning::chain::WatchedOutput) {
+		self.custom_source.register_output(output)
+	}
+}
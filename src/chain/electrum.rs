@@ -0,0 +1,393 @@
+// This file is Copyright its original authors, visible in version control history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
+// accordance with one or both of these licenses.
+
+use crate::config::{
+	BDK_ELECTRUM_CLIENT_BATCH_SIZE, BDK_WALLET_SYNC_TIMEOUT_SECS,
+	DEFAULT_ELECTRUM_CLIENT_TIMEOUT_SECS, ELECTRUM_CLIENT_NUM_RETRIES,
+	FEE_RATE_CACHE_UPDATE_TIMEOUT_SECS, LDK_WALLET_SYNC_TIMEOUT_SECS, TX_BROADCAST_TIMEOUT_SECS,
+};
+use crate::fee_estimator::{
+	apply_post_estimation_adjustments, get_all_conf_targets, get_num_block_defaults_for_target,
+	ConfirmationTarget,
+};
+use crate::logger::{log_bytes, log_error, log_trace, FilesystemLogger, Logger};
+use crate::Error;
+
+use lightning::chain::{BestBlock, Confirm, Filter, WatchedOutput};
+use lightning::util::ser::Writeable;
+
+use lightning_transaction_sync::ElectrumSyncClient;
+
+use bdk_chain::spk_client::{FullScanRequest, FullScanResult, SyncRequest, SyncResult};
+
+use bdk_electrum::BdkElectrumClient;
+
+use electrum_client::{Client as ElectrumClient, ConfigBuilder as ElectrumConfigBuilder};
+use electrum_client::{ElectrumApi, Error as ElectrumError};
+
+use bdk_wallet::KeychainKind;
+
+use bitcoin::{FeeRate, Network, Script, ScriptBuf, Transaction, Txid};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) enum ElectrumRuntimeStatus {
+	Started(Arc<ElectrumRuntimeClient>),
+	Stopped {
+		pending_registered_txs: Vec<(Txid, ScriptBuf)>,
+		pending_registered_outputs: Vec<WatchedOutput>,
+	},
+}
+
+impl ElectrumRuntimeStatus {
+	pub(crate) fn new() -> Self {
+		let pending_registered_txs = Vec::new();
+		let pending_registered_outputs = Vec::new();
+		Self::Stopped { pending_registered_txs, pending_registered_outputs }
+	}
+
+	pub(crate) fn start(
+		&mut self, server_url: String, logger: Arc<FilesystemLogger>,
+	) -> Result<(), Error> {
+		match self {
+			Self::Stopped { pending_registered_txs, pending_registered_outputs } => {
+				let client = Arc::new(ElectrumRuntimeClient::new(server_url, logger)?);
+
+				// Apply any pending `Filter` entries
+				for (txid, script_pubkey) in pending_registered_txs.drain(..) {
+					client.register_tx(&txid, &script_pubkey);
+				}
+
+				for output in pending_registered_outputs.drain(..) {
+					client.register_output(output)
+				}
+
+				*self = Self::Started(client);
+			},
+			Self::Started(_) => {
+				// Nothing to do, we're already up and running.
+			},
+		}
+		Ok(())
+	}
+
+	pub(crate) fn stop(&mut self) {
+		*self = Self::new()
+	}
+
+	pub(crate) fn client(&self) -> Option<Arc<ElectrumRuntimeClient>> {
+		match self {
+			Self::Started(client) => Some(Arc::clone(client)),
+			Self::Stopped { .. } => None,
+		}
+	}
+
+	pub(crate) fn register_tx(&mut self, txid: &Txid, script_pubkey: &Script) {
+		match self {
+			Self::Started(client) => client.register_tx(txid, script_pubkey),
+			Self::Stopped { pending_registered_txs, .. } => {
+				pending_registered_txs.push((*txid, script_pubkey.to_owned()))
+			},
+		}
+	}
+
+	pub(crate) fn register_output(&mut self, output: WatchedOutput) {
+		match self {
+			Self::Started(client) => client.register_output(output),
+			Self::Stopped { pending_registered_outputs, .. } => {
+				pending_registered_outputs.push(output)
+			},
+		}
+	}
+}
+
+pub(crate) struct ElectrumRuntimeClient {
+	electrum_client: Arc<ElectrumClient>,
+	bdk_electrum_client: Arc<BdkElectrumClient<ElectrumClient>>,
+	tx_sync: Arc<ElectrumSyncClient<Arc<FilesystemLogger>>>,
+	logger: Arc<FilesystemLogger>,
+}
+
+impl ElectrumRuntimeClient {
+	pub(crate) fn new(server_url: String, logger: Arc<FilesystemLogger>) -> Result<Self, Error> {
+		let electrum_config = ElectrumConfigBuilder::new()
+			.retry(ELECTRUM_CLIENT_NUM_RETRIES)
+			.timeout(Some(DEFAULT_ELECTRUM_CLIENT_TIMEOUT_SECS))
+			.build();
+
+		let electrum_client = Arc::new(
+			ElectrumClient::from_config(&server_url, electrum_config.clone()).map_err(|e| {
+				log_error!(logger, "Failed to connect to electrum server: {}", e);
+				Error::ConnectionFailed
+			})?,
+		);
+		let electrum_client_2 =
+			ElectrumClient::from_config(&server_url, electrum_config).map_err(|e| {
+				log_error!(logger, "Failed to connect to electrum server: {}", e);
+				Error::ConnectionFailed
+			})?;
+		let bdk_electrum_client = Arc::new(BdkElectrumClient::new(electrum_client_2));
+		let tx_sync = Arc::new(
+			ElectrumSyncClient::new(server_url.clone(), Arc::clone(&logger)).map_err(|e| {
+				log_error!(logger, "Failed to connect to electrum server: {}", e);
+				Error::ConnectionFailed
+			})?,
+		);
+		Ok(Self { electrum_client, bdk_electrum_client, tx_sync, logger })
+	}
+
+	pub(crate) async fn full_scan_wallet(
+		&self, request: FullScanRequest<KeychainKind>, stop_gap: usize,
+	) -> Result<FullScanResult<KeychainKind>, Error> {
+		let bdk_electrum_client = Arc::clone(&self.bdk_electrum_client);
+		let spawn_fut = tokio::task::spawn_blocking(move || {
+			bdk_electrum_client.full_scan(request, stop_gap, BDK_ELECTRUM_CLIENT_BATCH_SIZE, true)
+		});
+		let wallet_sync_timeout_fut =
+			tokio::time::timeout(Duration::from_secs(BDK_WALLET_SYNC_TIMEOUT_SECS), spawn_fut);
+
+		wallet_sync_timeout_fut
+			.await
+			.map_err(|e| {
+				log_error!(self.logger, "On-chain wallet sync timed out: {}", e);
+				Error::WalletOperationTimeout
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync failed due to electrum runtime error: {}", e);
+				Error::WalletOperationFailed
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync failed due to Electrum error: {}", e);
+				Error::WalletOperationFailed
+			})
+	}
+
+	pub(crate) async fn sync_wallet(
+		&self, request: SyncRequest<(KeychainKind, u32)>,
+	) -> Result<SyncResult, Error> {
+		let bdk_electrum_client = Arc::clone(&self.bdk_electrum_client);
+		let spawn_fut = tokio::task::spawn_blocking(move || {
+			bdk_electrum_client.sync(request, BDK_ELECTRUM_CLIENT_BATCH_SIZE, true)
+		});
+		let wallet_sync_timeout_fut =
+			tokio::time::timeout(Duration::from_secs(BDK_WALLET_SYNC_TIMEOUT_SECS), spawn_fut);
+
+		wallet_sync_timeout_fut
+			.await
+			.map_err(|e| {
+				log_error!(self.logger, "On-chain wallet sync timed out: {}", e);
+				Error::WalletOperationTimeout
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync failed due to electrum runtime error: {}", e);
+				Error::WalletOperationFailed
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync failed due to Electrum error: {}", e);
+				Error::WalletOperationFailed
+			})
+	}
+
+	pub(crate) async fn sync_confirmables(
+		&self, confirmables: Vec<Arc<dyn Confirm + Sync + Send>>,
+	) -> Result<(), Error> {
+		let tx_sync = Arc::clone(&self.tx_sync);
+		let spawn_fut = tokio::task::spawn_blocking(move || tx_sync.sync(confirmables));
+		let timeout_fut =
+			tokio::time::timeout(Duration::from_secs(LDK_WALLET_SYNC_TIMEOUT_SECS), spawn_fut);
+
+		timeout_fut
+			.await
+			.map_err(|e| {
+				log_error!(self.logger, "Lightning wallet sync timed out: {}", e);
+				Error::TxSyncTimeout
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync failed due to electrum runtime error: {}", e);
+				Error::TxSyncFailed
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Sync of Lightning wallet failed: {}", e);
+				e.into()
+			})
+	}
+
+	pub(crate) async fn get_best_block(&self) -> Result<BestBlock, Error> {
+		let electrum_client = Arc::clone(&self.electrum_client);
+		let spawn_fut =
+			tokio::task::spawn_blocking(move || electrum_client.block_headers_subscribe());
+		let timeout_fut =
+			tokio::time::timeout(Duration::from_secs(LDK_WALLET_SYNC_TIMEOUT_SECS), spawn_fut);
+
+		let header_notification = timeout_fut
+			.await
+			.map_err(|e| {
+				log_error!(self.logger, "Retrieving chain tip timed out: {}", e);
+				Error::TxSyncTimeout
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to retrieve chain tip: {}", e);
+				Error::TxSyncFailed
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to retrieve chain tip: {}", e);
+				Error::TxSyncFailed
+			})?;
+
+		let block_hash = header_notification.header.block_hash();
+		Ok(BestBlock::new(block_hash, header_notification.height as u32))
+	}
+
+	pub(crate) async fn broadcast(&self, tx: Transaction) {
+		let electrum_client = Arc::clone(&self.electrum_client);
+
+		let txid = tx.compute_txid();
+		let tx_bytes = tx.encode();
+
+		let spawn_fut =
+			tokio::task::spawn_blocking(move || electrum_client.transaction_broadcast(&tx));
+
+		let timeout_fut =
+			tokio::time::timeout(Duration::from_secs(TX_BROADCAST_TIMEOUT_SECS), spawn_fut);
+
+		match timeout_fut.await {
+			Ok(res) => match res {
+				Ok(Ok(_)) => {
+					log_trace!(self.logger, "Successfully broadcast transaction {}", txid);
+				},
+				Ok(Err(e)) => {
+					log_error!(self.logger, "Failed to broadcast transaction {}: {}", txid, e);
+					log_trace!(
+						self.logger,
+						"Failed broadcast transaction bytes: {}",
+						log_bytes!(tx_bytes)
+					);
+				},
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Failed to broadcast transaction due to electrum runtime error {}: {}",
+						txid,
+						e
+					);
+					log_trace!(
+						self.logger,
+						"Failed broadcast transaction bytes: {}",
+						log_bytes!(tx_bytes)
+					);
+				},
+			},
+			Err(e) => {
+				log_error!(
+					self.logger,
+					"Failed to broadcast transaction due to timeout {}: {}",
+					txid,
+					e
+				);
+				log_trace!(
+					self.logger,
+					"Failed broadcast transaction bytes: {}",
+					log_bytes!(tx_bytes)
+				);
+			},
+		}
+	}
+
+	pub(crate) async fn get_fee_rate_cache_update(
+		&self, network: Network,
+	) -> Result<HashMap<ConfirmationTarget, FeeRate>, Error> {
+		let electrum_client = Arc::clone(&self.electrum_client);
+
+		let confirmation_targets = get_all_conf_targets();
+		let num_blocks: Vec<usize> =
+			confirmation_targets.iter().map(|t| get_num_block_defaults_for_target(*t)).collect();
+
+		let spawn_fut =
+			tokio::task::spawn_blocking(move || electrum_client.batch_estimate_fee(num_blocks));
+
+		let timeout_fut = tokio::time::timeout(
+			Duration::from_secs(FEE_RATE_CACHE_UPDATE_TIMEOUT_SECS),
+			spawn_fut,
+		);
+
+		let raw_estimates_btc_kvb = timeout_fut
+			.await
+			.map_err(|e| {
+				log_error!(self.logger, "Updating fee rate estimates timed out: {}", e);
+				Error::FeerateEstimationUpdateTimeout
+			})?
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to retrieve fee rate estimates: {}", e);
+				Error::FeerateEstimationUpdateFailed
+			})?
+			.map_err(|e: ElectrumError| {
+				log_error!(self.logger, "Failed to retrieve fee rate estimates: {}", e);
+				Error::FeerateEstimationUpdateFailed
+			})?;
+
+		if raw_estimates_btc_kvb.len() != confirmation_targets.len() {
+			log_error!(
+				self.logger,
+				"Failed to retrieve fee rate estimates: Electrum server didn't return all expected results.",
+			);
+			return Err(Error::FeerateEstimationUpdateFailed);
+		}
+
+		let mut new_fee_rate_cache = HashMap::with_capacity(10);
+		for (target, raw_fee_rate_btc_per_kvb) in
+			confirmation_targets.into_iter().zip(raw_estimates_btc_kvb.into_iter())
+		{
+			// Electrum servers return `-1` if they don't have sufficient data to give an estimate,
+			// e.g., on regtest.
+			if raw_fee_rate_btc_per_kvb < 0.0 {
+				if network == Network::Bitcoin {
+					// Ensure we fail if we didn't receive any estimates.
+					log_error!(
+						self.logger,
+						"Failed to retrieve fee rate estimates: empty fee estimates are dissallowed on Mainnet.",
+					);
+					return Err(Error::FeerateEstimationUpdateFailed);
+				}
+
+				// We'll fall back to our defaults for this target.
+				log_trace!(self.logger, "No fee rate estimate available for {:?}", target);
+				continue;
+			}
+
+			// Electrum gives us a feerate in BTC/KvB.
+			// Thus, we multiply by 25_000_000 (10^8 / 4) to get satoshis/kwu.
+			let fee_rate = {
+				let fee_rate_sat_per_kwu = (raw_fee_rate_btc_per_kvb * 25_000_000.0).round() as u64;
+				FeeRate::from_sat_per_kwu(fee_rate_sat_per_kwu)
+			};
+
+			let adjusted_fee_rate = apply_post_estimation_adjustments(target, fee_rate);
+
+			new_fee_rate_cache.insert(target, adjusted_fee_rate);
+
+			log_trace!(
+				self.logger,
+				"Fee rate estimation updated for {:?}: {} sats/kwu",
+				target,
+				adjusted_fee_rate.to_sat_per_kwu(),
+			);
+		}
+
+		Ok(new_fee_rate_cache)
+	}
+}
+
+impl Filter for ElectrumRuntimeClient {
+	fn register_tx(&self, txid: &Txid, script_pubkey: &Script) {
+		self.tx_sync.register_tx(txid, script_pubkey)
+	}
+	fn register_output(&self, output: WatchedOutput) {
+		self.tx_sync.register_output(output)
+	}
+}
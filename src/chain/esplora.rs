@@ -0,0 +1,620 @@
+// This file is Copyright its original authors, visible in version control history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
+// accordance with one or both of these licenses.
+
+use crate::config::{
+	BDK_CLIENT_CONCURRENCY, BDK_WALLET_SYNC_TIMEOUT_SECS, DEFAULT_ESPLORA_CLIENT_TIMEOUT_SECS,
+	ESPLORA_CROSS_CHECK_MAX_TIP_HEIGHT_DIFFERENCE, ESPLORA_SERVER_BASE_BACKOFF_SECS,
+	ESPLORA_SERVER_MAX_BACKOFF_SECS, FEE_RATE_CACHE_UPDATE_TIMEOUT_SECS,
+	LDK_WALLET_SYNC_TIMEOUT_SECS, TX_BROADCAST_TIMEOUT_SECS,
+};
+use crate::fee_estimator::{
+	apply_post_estimation_adjustments, get_all_conf_targets, get_num_block_defaults_for_target,
+	ConfirmationTarget,
+};
+use crate::logger::{log_bytes, log_error, log_info, log_trace, FilesystemLogger, Logger};
+use crate::types::Wallet;
+use crate::Error;
+
+use lightning::chain::{BestBlock, Confirm, Filter, WatchedOutput};
+use lightning::util::ser::Writeable;
+
+use lightning_transaction_sync::EsploraSyncClient;
+
+use bdk_chain::spk_client::{FullScanResult, SyncResult};
+use bdk_esplora::EsploraAsyncExt;
+use bdk_wallet::KeychainKind;
+
+use esplora_client::AsyncClient as EsploraAsyncClient;
+
+use bitcoin::{FeeRate, Network, Script, Transaction, Txid};
+
+use reqwest::StatusCode;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A prioritized list of Esplora servers we fail over between.
+///
+/// Servers are tried in the order they were configured. Whenever a request to a server fails or
+/// times out, we back off from it for an exponentially increasing amount of time and try the next
+/// one. Servers we're currently backing off from are only tried as a last resort.
+pub(crate) struct EsploraServerPool {
+	servers: Vec<EsploraServer>,
+	cross_check: bool,
+	logger: Arc<FilesystemLogger>,
+}
+
+impl EsploraServerPool {
+	pub(crate) fn new(
+		server_urls: Vec<String>, cross_check: bool, logger: Arc<FilesystemLogger>,
+	) -> Self {
+		debug_assert!(!server_urls.is_empty(), "We need at least one Esplora server");
+		let servers = server_urls
+			.into_iter()
+			.map(|url| EsploraServer::new(url, Arc::clone(&logger)))
+			.collect();
+		Self { servers, cross_check, logger }
+	}
+
+	pub(crate) async fn full_scan_wallet(
+		&self, onchain_wallet: &Wallet, stop_gap: usize,
+	) -> Result<FullScanResult<KeychainKind>, Error> {
+		if self.cross_check {
+			self.cross_check_tip_heights().await;
+		}
+
+		let mut res = Err(Error::WalletOperationFailed);
+		for server in self.servers_by_priority() {
+			let full_scan_request = onchain_wallet.get_full_scan_request();
+			let wallet_sync_timeout_fut = tokio::time::timeout(
+				Duration::from_secs(BDK_WALLET_SYNC_TIMEOUT_SECS),
+				server.client.full_scan(full_scan_request, stop_gap, BDK_CLIENT_CONCURRENCY),
+			);
+
+			res = self.handle_wallet_sync_result(server, wallet_sync_timeout_fut.await);
+
+			if self.record_result(server, &res) {
+				break;
+			}
+		}
+		res
+	}
+
+	pub(crate) async fn sync_wallet(&self, onchain_wallet: &Wallet) -> Result<SyncResult, Error> {
+		if self.cross_check {
+			self.cross_check_tip_heights().await;
+		}
+
+		let mut res = Err(Error::WalletOperationFailed);
+		for server in self.servers_by_priority() {
+			let sync_request = onchain_wallet.get_incremental_sync_request();
+			let wallet_sync_timeout_fut = tokio::time::timeout(
+				Duration::from_secs(BDK_WALLET_SYNC_TIMEOUT_SECS),
+				server.client.sync(sync_request, BDK_CLIENT_CONCURRENCY),
+			);
+
+			res = self.handle_wallet_sync_result(server, wallet_sync_timeout_fut.await);
+
+			if self.record_result(server, &res) {
+				break;
+			}
+		}
+		res
+	}
+
+	fn handle_wallet_sync_result<T>(
+		&self, server: &EsploraServer,
+		res: Result<Result<T, Box<esplora_client::Error>>, tokio::time::error::Elapsed>,
+	) -> Result<T, Error> {
+		match res {
+			Ok(Ok(update)) => Ok(update),
+			Ok(Err(e)) => match *e {
+				esplora_client::Error::Reqwest(he) => {
+					log_error!(
+						self.logger,
+						"Sync via {} failed due to HTTP connection error: {}",
+						server.server_url,
+						he
+					);
+					Err(Error::WalletOperationFailed)
+				},
+				_ => {
+					log_error!(
+						self.logger,
+						"Sync via {} failed due to Esplora error: {}",
+						server.server_url,
+						e
+					);
+					Err(Error::WalletOperationFailed)
+				},
+			},
+			Err(e) => {
+				log_error!(
+					self.logger,
+					"On-chain wallet sync via {} timed out: {}",
+					server.server_url,
+					e
+				);
+				Err(Error::WalletOperationTimeout)
+			},
+		}
+	}
+
+	pub(crate) async fn sync_confirmables(
+		&self, confirmables: Vec<&(dyn Confirm + Sync + Send)>,
+	) -> Result<(), Error> {
+		if self.cross_check {
+			self.cross_check_tip_heights().await;
+		}
+
+		let mut res = Err(Error::TxSyncFailed);
+		for server in self.servers_by_priority() {
+			let timeout_fut = tokio::time::timeout(
+				Duration::from_secs(LDK_WALLET_SYNC_TIMEOUT_SECS),
+				server.tx_sync.sync(confirmables.clone()),
+			);
+
+			res = match timeout_fut.await {
+				Ok(Ok(())) => Ok(()),
+				Ok(Err(e)) => {
+					log_error!(
+						self.logger,
+						"Sync of Lightning wallet via {} failed: {}",
+						server.server_url,
+						e
+					);
+					Err(e.into())
+				},
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Lightning wallet sync via {} timed out: {}",
+						server.server_url,
+						e
+					);
+					Err(Error::TxSyncTimeout)
+				},
+			};
+
+			if self.record_result(server, &res) {
+				break;
+			}
+		}
+		res
+	}
+
+	pub(crate) async fn get_best_block(&self) -> Result<BestBlock, Error> {
+		if self.cross_check {
+			self.cross_check_tip_heights().await;
+		}
+
+		let mut res = Err(Error::TxSyncFailed);
+		for server in self.servers_by_priority() {
+			res = server.get_best_block().await;
+			if self.record_result(server, &res) {
+				break;
+			}
+		}
+		res
+	}
+
+	pub(crate) async fn get_fee_rate_cache_update(
+		&self, network: Network,
+	) -> Result<HashMap<ConfirmationTarget, FeeRate>, Error> {
+		let estimates = if self.cross_check {
+			self.get_cross_checked_fee_estimates().await?
+		} else {
+			let mut res = Err(Error::FeerateEstimationUpdateFailed);
+			for server in self.servers_by_priority() {
+				res = server.get_fee_estimates().await;
+				if self.record_result(server, &res) {
+					break;
+				}
+			}
+			res?
+		};
+
+		if estimates.is_empty() && network == Network::Bitcoin {
+			// Ensure we fail if we didn't receive any estimates.
+			log_error!(
+				self.logger,
+				"Failed to retrieve fee rate estimates: empty fee estimates are dissallowed on Mainnet.",
+			);
+			return Err(Error::FeerateEstimationUpdateFailed);
+		}
+
+		let confirmation_targets = get_all_conf_targets();
+
+		let mut new_fee_rate_cache = HashMap::with_capacity(10);
+		for target in confirmation_targets {
+			let num_blocks = get_num_block_defaults_for_target(target);
+
+			let converted_estimate_sat_vb =
+				esplora_client::convert_fee_rate(num_blocks, estimates.clone()).map_err(|e| {
+					log_error!(
+						self.logger,
+						"Failed to convert fee rate estimates for {:?}: {}",
+						target,
+						e
+					);
+					Error::FeerateEstimationUpdateFailed
+				})?;
+
+			let fee_rate = FeeRate::from_sat_per_kwu((converted_estimate_sat_vb * 250.0) as u64);
+
+			let adjusted_fee_rate = apply_post_estimation_adjustments(target, fee_rate);
+
+			new_fee_rate_cache.insert(target, adjusted_fee_rate);
+
+			log_trace!(
+				self.logger,
+				"Fee rate estimation updated for {:?}: {} sats/kwu",
+				target,
+				adjusted_fee_rate.to_sat_per_kwu(),
+			);
+		}
+
+		Ok(new_fee_rate_cache)
+	}
+
+	pub(crate) async fn broadcast(&self, tx: &Transaction) {
+		let txid = tx.compute_txid();
+		for server in self.servers_by_priority() {
+			let timeout_fut = tokio::time::timeout(
+				Duration::from_secs(TX_BROADCAST_TIMEOUT_SECS),
+				server.client.broadcast(tx),
+			);
+			let res = match timeout_fut.await {
+				Ok(Ok(())) => {
+					log_trace!(
+						self.logger,
+						"Successfully broadcast transaction {} via {}",
+						txid,
+						server.server_url
+					);
+					Ok(())
+				},
+				Ok(Err(esplora_client::Error::Reqwest(err)))
+					if err.status() == StatusCode::from_u16(400).ok() =>
+				{
+					// Ignore 400, as this just means bitcoind already knows the
+					// transaction.
+					// FIXME: We can further differentiate here based on the error
+					// message which will be available with rust-esplora-client 0.7 and
+					// later.
+					log_trace!(
+						self.logger,
+						"Failed broadcast transaction bytes: {}",
+						log_bytes!(tx.encode())
+					);
+					Ok(())
+				},
+				Ok(Err(esplora_client::Error::Reqwest(err))) => {
+					log_error!(
+						self.logger,
+						"Failed to broadcast via {} due to HTTP connection error: {}",
+						server.server_url,
+						err
+					);
+					Err(Error::TxSyncFailed)
+				},
+				Ok(Err(e)) => {
+					log_error!(
+						self.logger,
+						"Failed to broadcast transaction {} via {}: {}",
+						txid,
+						server.server_url,
+						e
+					);
+					Err(Error::TxSyncFailed)
+				},
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Failed to broadcast transaction {} via {} due to timeout: {}",
+						txid,
+						server.server_url,
+						e
+					);
+					Err(Error::TxSyncTimeout)
+				},
+			};
+
+			if self.record_result(server, &res) {
+				return;
+			}
+		}
+
+		log_trace!(self.logger, "Failed broadcast transaction bytes: {}", log_bytes!(tx.encode()));
+	}
+
+	/// Returns the servers in the order we should try them: all servers we're not currently
+	/// backing off from in their configured order, followed by all others.
+	fn servers_by_priority(&self) -> Vec<&EsploraServer> {
+		let now = Instant::now();
+		let (mut healthy, unhealthy): (Vec<_>, Vec<_>) =
+			self.servers.iter().partition(|s| s.is_healthy(now));
+		healthy.extend(unhealthy);
+		healthy
+	}
+
+	/// Records the result of a request to the given server, returning whether it succeeded.
+	fn record_result<T>(&self, server: &EsploraServer, res: &Result<T, Error>) -> bool {
+		if res.is_ok() {
+			server.record_success();
+			true
+		} else {
+			let backoff = server.record_failure();
+			if self.servers.len() > 1 {
+				log_info!(
+					self.logger,
+					"Backing off from Esplora server {} for {}s.",
+					server.server_url,
+					backoff.as_secs()
+				);
+			}
+			false
+		}
+	}
+
+	/// Compares the tip heights reported by all servers and backs off from any server lagging
+	/// too far behind the others.
+	async fn cross_check_tip_heights(&self) {
+		let mut heights = Vec::with_capacity(self.servers.len());
+		for server in &self.servers {
+			match server.get_height().await {
+				Ok(height) => heights.push((server, height)),
+				Err(_) => {
+					self.record_result::<()>(server, &Err(Error::TxSyncFailed));
+				},
+			}
+		}
+
+		let max_height = match heights.iter().map(|(_, h)| *h).max() {
+			Some(max_height) => max_height,
+			None => return,
+		};
+
+		for (server, height) in heights {
+			if height + ESPLORA_CROSS_CHECK_MAX_TIP_HEIGHT_DIFFERENCE < max_height {
+				log_error!(
+					self.logger,
+					"Esplora server {} reported tip height {} which lags behind the best known height {}.",
+					server.server_url,
+					height,
+					max_height
+				);
+				self.record_result::<()>(server, &Err(Error::TxSyncFailed));
+			}
+		}
+	}
+
+	/// Retrieves fee estimates from all reachable servers and returns the per-target median.
+	async fn get_cross_checked_fee_estimates(&self) -> Result<HashMap<u16, f64>, Error> {
+		let mut all_estimates = Vec::with_capacity(self.servers.len());
+		let mut last_err = Error::FeerateEstimationUpdateFailed;
+		for server in &self.servers {
+			let res = server.get_fee_estimates().await;
+			self.record_result(server, &res);
+			match res {
+				Ok(estimates) => all_estimates.push(estimates),
+				Err(e) => last_err = e,
+			}
+		}
+
+		if all_estimates.is_empty() {
+			return Err(last_err);
+		}
+
+		let mut estimates_by_target: HashMap<u16, Vec<f64>> = HashMap::new();
+		for estimates in all_estimates {
+			for (target, estimate) in estimates {
+				estimates_by_target.entry(target).or_default().push(estimate);
+			}
+		}
+
+		let mut median_estimates = HashMap::with_capacity(estimates_by_target.len());
+		for (target, mut estimates) in estimates_by_target {
+			estimates.sort_by(|a, b| a.total_cmp(b));
+			let median = estimates[estimates.len() / 2];
+			if let (Some(min), Some(max)) = (estimates.first(), estimates.last()) {
+				if *min > 0.0 && *max / *min > 2.0 {
+					log_info!(
+						self.logger,
+						"Esplora servers disagree on fee estimate for target {}: {} to {} sat/vB. Using median of {} sat/vB.",
+						target,
+						min,
+						max,
+						median
+					);
+				}
+			}
+			median_estimates.insert(target, median);
+		}
+
+		Ok(median_estimates)
+	}
+}
+
+impl Filter for EsploraServerPool {
+	fn register_tx(&self, txid: &Txid, script_pubkey: &Script) {
+		for server in &self.servers {
+			server.tx_sync.register_tx(txid, script_pubkey);
+		}
+	}
+	fn register_output(&self, output: WatchedOutput) {
+		for server in &self.servers {
+			server.tx_sync.register_output(output.clone());
+		}
+	}
+}
+
+struct EsploraServer {
+	server_url: String,
+	client: EsploraAsyncClient,
+	tx_sync: EsploraSyncClient<Arc<FilesystemLogger>>,
+	health: Mutex<EsploraServerHealth>,
+	logger: Arc<FilesystemLogger>,
+}
+
+#[derive(Default)]
+struct EsploraServerHealth {
+	consecutive_failures: u32,
+	backoff_until: Option<Instant>,
+}
+
+impl EsploraServer {
+	fn new(server_url: String, logger: Arc<FilesystemLogger>) -> Self {
+		let mut client_builder = esplora_client::Builder::new(&server_url);
+		client_builder = client_builder.timeout(DEFAULT_ESPLORA_CLIENT_TIMEOUT_SECS);
+		let client = client_builder.build_async().unwrap();
+		let tx_sync = EsploraSyncClient::from_client(client.clone(), Arc::clone(&logger));
+		let health = Mutex::new(EsploraServerHealth::default());
+		Self { server_url, client, tx_sync, health, logger }
+	}
+
+	fn is_healthy(&self, now: Instant) -> bool {
+		self.health.lock().unwrap().backoff_until.map_or(true, |until| now >= until)
+	}
+
+	fn record_success(&self) {
+		*self.health.lock().unwrap() = EsploraServerHealth::default();
+	}
+
+	fn record_failure(&self) -> Duration {
+		let mut health = self.health.lock().unwrap();
+		health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+		let exponent = (health.consecutive_failures - 1).min(16);
+		let backoff_secs = ESPLORA_SERVER_BASE_BACKOFF_SECS
+			.saturating_mul(1 << exponent)
+			.min(ESPLORA_SERVER_MAX_BACKOFF_SECS);
+		let backoff = Duration::from_secs(backoff_secs);
+		health.backoff_until = Some(Instant::now() + backoff);
+		backoff
+	}
+
+	async fn get_height(&self) -> Result<u32, Error> {
+		tokio::time::timeout(
+			Duration::from_secs(DEFAULT_ESPLORA_CLIENT_TIMEOUT_SECS),
+			self.client.get_height(),
+		)
+		.await
+		.map_err(|e| {
+			log_error!(
+				self.logger,
+				"Retrieving chain tip height from {} timed out: {}",
+				self.server_url,
+				e
+			);
+			Error::TxSyncTimeout
+		})?
+		.map_err(|e| {
+			log_error!(
+				self.logger,
+				"Failed to retrieve chain tip height from {}: {}",
+				self.server_url,
+				e
+			);
+			Error::TxSyncFailed
+		})
+	}
+
+	async fn get_best_block(&self) -> Result<BestBlock, Error> {
+		// We look up the height of the tip block by its hash, rather than querying the tip height
+		// separately, so that both are consistent even if a new block arrives in-between.
+		let block_hash = tokio::time::timeout(
+			Duration::from_secs(DEFAULT_ESPLORA_CLIENT_TIMEOUT_SECS),
+			self.client.get_tip_hash(),
+		)
+		.await
+		.map_err(|e| {
+			log_error!(
+				self.logger,
+				"Retrieving chain tip hash from {} timed out: {}",
+				self.server_url,
+				e
+			);
+			Error::TxSyncTimeout
+		})?
+		.map_err(|e| {
+			log_error!(
+				self.logger,
+				"Failed to retrieve chain tip hash from {}: {}",
+				self.server_url,
+				e
+			);
+			Error::TxSyncFailed
+		})?;
+
+		let block_status = tokio::time::timeout(
+			Duration::from_secs(DEFAULT_ESPLORA_CLIENT_TIMEOUT_SECS),
+			self.client.get_block_status(&block_hash),
+		)
+		.await
+		.map_err(|e| {
+			log_error!(
+				self.logger,
+				"Retrieving status of chain tip {} from {} timed out: {}",
+				block_hash,
+				self.server_url,
+				e
+			);
+			Error::TxSyncTimeout
+		})?
+		.map_err(|e| {
+			log_error!(
+				self.logger,
+				"Failed to retrieve status of chain tip {} from {}: {}",
+				block_hash,
+				self.server_url,
+				e
+			);
+			Error::TxSyncFailed
+		})?;
+
+		// The tip might have been reorganized out in the meantime, in which case it has no height.
+		let height =
+			block_status.height.filter(|_| block_status.in_best_chain).ok_or_else(|| {
+				log_error!(
+					self.logger,
+					"Chain tip {} retrieved from {} is no longer in the best chain",
+					block_hash,
+					self.server_url
+				);
+				Error::TxSyncFailed
+			})?;
+		Ok(BestBlock::new(block_hash, height))
+	}
+
+	async fn get_fee_estimates(&self) -> Result<HashMap<u16, f64>, Error> {
+		tokio::time::timeout(
+			Duration::from_secs(FEE_RATE_CACHE_UPDATE_TIMEOUT_SECS),
+			self.client.get_fee_estimates(),
+		)
+		.await
+		.map_err(|e| {
+			log_error!(
+				self.logger,
+				"Updating fee rate estimates via {} timed out: {}",
+				self.server_url,
+				e
+			);
+			Error::FeerateEstimationUpdateTimeout
+		})?
+		.map_err(|e| {
+			log_error!(
+				self.logger,
+				"Failed to retrieve fee rate estimates from {}: {}",
+				self.server_url,
+				e
+			);
+			Error::FeerateEstimationUpdateFailed
+		})
+	}
+}
@@ -0,0 +1,1648 @@
+// This file is Copyright its original authors, visible in version control history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
+// accordance with one or both of these licenses.
+
+mod bitcoind_rpc;
+mod cbf;
+mod custom;
+mod electrum;
+mod esplora;
+
+pub use custom::CustomChainSource;
+
+use crate::chain::bitcoind_rpc::{
+	BitcoindRpcClient, BoundedHeaderCache, ChainListener, FeeRateEstimationMode,
+};
+use crate::chain::cbf::CbfBlockSource;
+use crate::chain::custom::CustomChainSourceClient;
+use crate::chain::electrum::{ElectrumRuntimeClient, ElectrumRuntimeStatus};
+use crate::chain::esplora::EsploraServerPool;
+use crate::config::{
+	Config, BDK_WALLET_FULL_SCAN_INTERVAL_SECS, CHAIN_POLLING_INTERVAL_SECS,
+	FEE_RATE_CACHE_UPDATE_TIMEOUT_SECS, RESOLVED_CHANNEL_MONITOR_ARCHIVAL_INTERVAL,
+	TX_BROADCAST_TIMEOUT_SECS, WALLET_SYNC_INTERVAL_MINIMUM_SECS,
+};
+use crate::fee_estimator::{
+	apply_post_estimation_adjustments, get_all_conf_targets, get_num_block_defaults_for_target,
+	ConfirmationTarget, OnchainFeeEstimator,
+};
+use crate::logger::{log_bytes, log_error, log_info, log_trace, FilesystemLogger, Logger};
+use crate::types::{
+	Broadcaster, ChainMonitor, ChannelHistory, ChannelManager, DynStore, Sweeper, Wallet,
+};
+use crate::wallet::MempoolPackage;
+use crate::Error;
+
+use lightning::chain::{BestBlock, Confirm, Filter, Listen};
+use lightning::ln::msgs::SocketAddress;
+use lightning::util::ser::Writeable;
+
+use lightning_block_sync::init::{synchronize_listeners, validate_best_block_header};
+use lightning_block_sync::poll::{ChainPoller, ChainTip, ValidatedBlockHeader};
+use lightning_block_sync::{BlockSource, SpvClient};
+
+use bdk_chain::BlockId;
+
+use bitcoin::block::Header;
+use bitcoin::{BlockHash, Network, Script, Txid};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub(crate) enum WalletSyncStatus {
+	Completed,
+	InProgress { subscribers: tokio::sync::broadcast::Sender<Result<(), Error>> },
+}
+
+impl WalletSyncStatus {
+	fn register_or_subscribe_pending_sync(
+		&mut self,
+	) -> Option<tokio::sync::broadcast::Receiver<Result<(), Error>>> {
+		match self {
+			WalletSyncStatus::Completed => {
+				// We're first to register for a sync.
+				let (tx, _) = tokio::sync::broadcast::channel(1);
+				*self = WalletSyncStatus::InProgress { subscribers: tx };
+				None
+			},
+			WalletSyncStatus::InProgress { subscribers } => {
+				// A sync is in-progress, we subscribe.
+				let rx = subscribers.subscribe();
+				Some(rx)
+			},
+		}
+	}
+
+	fn propagate_result_to_subscribers(&mut self, res: Result<(), Error>) {
+		// Send the notification to any other tasks that might be waiting on it by now.
+		{
+			match self {
+				WalletSyncStatus::Completed => {
+					// No sync in-progress, do nothing.
+					return;
+				},
+				WalletSyncStatus::InProgress { subscribers } => {
+					// A sync is in-progress, we notify subscribers.
+					if subscribers.receiver_count() > 0 {
+						match subscribers.send(res) {
+							Ok(_) => (),
+							Err(e) => {
+								debug_assert!(
+									false,
+									"Failed to send wallet sync result to subscribers: {:?}",
+									e
+								);
+							},
+						}
+					}
+					*self = WalletSyncStatus::Completed;
+				},
+			}
+		}
+	}
+}
+
+pub(crate) enum ChainSource {
+	Esplora {
+		esplora_servers: EsploraServerPool,
+		onchain_wallet: Arc<Wallet>,
+		onchain_wallet_sync_status: Mutex<WalletSyncStatus>,
+		latest_onchain_wallet_full_scan: Mutex<Option<Instant>>,
+		fee_estimator: Arc<OnchainFeeEstimator>,
+		tx_broadcaster: Arc<Broadcaster>,
+		config: Arc<Config>,
+		logger: Arc<FilesystemLogger>,
+		latest_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_onchain_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_fee_rate_cache_update_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
+	},
+	Electrum {
+		server_url: String,
+		electrum_runtime_status: RwLock<ElectrumRuntimeStatus>,
+		onchain_wallet: Arc<Wallet>,
+		onchain_wallet_sync_status: Mutex<WalletSyncStatus>,
+		latest_onchain_wallet_full_scan: Mutex<Option<Instant>>,
+		fee_estimator: Arc<OnchainFeeEstimator>,
+		tx_broadcaster: Arc<Broadcaster>,
+		config: Arc<Config>,
+		logger: Arc<FilesystemLogger>,
+		latest_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_onchain_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_fee_rate_cache_update_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
+	},
+	BitcoindRpc {
+		bitcoind_rpc_client: Arc<BitcoindRpcClient>,
+		header_cache: tokio::sync::Mutex<BoundedHeaderCache>,
+		latest_chain_tip: RwLock<Option<ValidatedBlockHeader>>,
+		onchain_wallet: Arc<Wallet>,
+		wallet_polling_status: Mutex<WalletSyncStatus>,
+		fee_estimator: Arc<OnchainFeeEstimator>,
+		tx_broadcaster: Arc<Broadcaster>,
+		config: Arc<Config>,
+		logger: Arc<FilesystemLogger>,
+		latest_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_onchain_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_fee_rate_cache_update_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
+	},
+	CompactBlockFilters {
+		cbf_block_source: Arc<CbfBlockSource>,
+		header_cache: tokio::sync::Mutex<BoundedHeaderCache>,
+		latest_chain_tip: RwLock<Option<ValidatedBlockHeader>>,
+		onchain_wallet: Arc<Wallet>,
+		wallet_polling_status: Mutex<WalletSyncStatus>,
+		fee_estimator: Arc<OnchainFeeEstimator>,
+		tx_broadcaster: Arc<Broadcaster>,
+		config: Arc<Config>,
+		logger: Arc<FilesystemLogger>,
+		latest_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_onchain_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_fee_rate_cache_update_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
+	},
+	Custom {
+		custom_client: CustomChainSourceClient,
+		onchain_wallet: Arc<Wallet>,
+		onchain_wallet_sync_status: Mutex<WalletSyncStatus>,
+		latest_onchain_wallet_full_scan: Mutex<Option<Instant>>,
+		fee_estimator: Arc<OnchainFeeEstimator>,
+		tx_broadcaster: Arc<Broadcaster>,
+		config: Arc<Config>,
+		logger: Arc<FilesystemLogger>,
+		latest_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_onchain_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_fee_rate_cache_update_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
+	},
+}
+
+impl ChainSource {
+	pub(crate) fn new_esplora(
+		server_urls: Vec<String>, cross_check_servers: bool, onchain_wallet: Arc<Wallet>,
+		fee_estimator: Arc<OnchainFeeEstimator>, tx_broadcaster: Arc<Broadcaster>,
+		config: Arc<Config>, logger: Arc<FilesystemLogger>,
+		latest_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_onchain_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_fee_rate_cache_update_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
+	) -> Self {
+		let esplora_servers =
+			EsploraServerPool::new(server_urls, cross_check_servers, Arc::clone(&logger));
+		let onchain_wallet_sync_status = Mutex::new(WalletSyncStatus::Completed);
+		let latest_onchain_wallet_full_scan = Mutex::new(None);
+		Self::Esplora {
+			esplora_servers,
+			onchain_wallet,
+			onchain_wallet_sync_status,
+			latest_onchain_wallet_full_scan,
+			fee_estimator,
+			tx_broadcaster,
+			config,
+			logger,
+			latest_wallet_sync_timestamp,
+			latest_onchain_wallet_sync_timestamp,
+			latest_fee_rate_cache_update_timestamp,
+			latest_channel_monitor_archival_height,
+		}
+	}
+
+	pub(crate) fn new_electrum(
+		server_url: String, onchain_wallet: Arc<Wallet>, fee_estimator: Arc<OnchainFeeEstimator>,
+		tx_broadcaster: Arc<Broadcaster>, config: Arc<Config>, logger: Arc<FilesystemLogger>,
+		latest_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_onchain_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_fee_rate_cache_update_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
+	) -> Self {
+		let electrum_runtime_status = RwLock::new(ElectrumRuntimeStatus::new());
+		let onchain_wallet_sync_status = Mutex::new(WalletSyncStatus::Completed);
+		let latest_onchain_wallet_full_scan = Mutex::new(None);
+		Self::Electrum {
+			server_url,
+			electrum_runtime_status,
+			onchain_wallet,
+			onchain_wallet_sync_status,
+			latest_onchain_wallet_full_scan,
+			fee_estimator,
+			tx_broadcaster,
+			config,
+			logger,
+			latest_wallet_sync_timestamp,
+			latest_onchain_wallet_sync_timestamp,
+			latest_fee_rate_cache_update_timestamp,
+			latest_channel_monitor_archival_height,
+		}
+	}
+
+	pub(crate) fn new_bitcoind_rpc(
+		host: String, port: u16, rpc_user: String, rpc_password: String,
+		onchain_wallet: Arc<Wallet>, fee_estimator: Arc<OnchainFeeEstimator>,
+		tx_broadcaster: Arc<Broadcaster>, config: Arc<Config>, logger: Arc<FilesystemLogger>,
+		latest_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_onchain_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_fee_rate_cache_update_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
+	) -> Self {
+		let bitcoind_rpc_client =
+			Arc::new(BitcoindRpcClient::new(host, port, rpc_user, rpc_password));
+		let header_cache = tokio::sync::Mutex::new(BoundedHeaderCache::new());
+		let latest_chain_tip = RwLock::new(None);
+		let wallet_polling_status = Mutex::new(WalletSyncStatus::Completed);
+		Self::BitcoindRpc {
+			bitcoind_rpc_client,
+			header_cache,
+			latest_chain_tip,
+			onchain_wallet,
+			wallet_polling_status,
+			fee_estimator,
+			tx_broadcaster,
+			config,
+			logger,
+			latest_wallet_sync_timestamp,
+			latest_onchain_wallet_sync_timestamp,
+			latest_fee_rate_cache_update_timestamp,
+			latest_channel_monitor_archival_height,
+		}
+	}
+
+	pub(crate) fn new_compact_block_filters(
+		peers: Vec<SocketAddress>, persisted_headers: Vec<Header>, onchain_wallet: Arc<Wallet>,
+		fee_estimator: Arc<OnchainFeeEstimator>, tx_broadcaster: Arc<Broadcaster>,
+		kv_store: Arc<DynStore>, config: Arc<Config>, logger: Arc<FilesystemLogger>,
+		latest_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_onchain_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_fee_rate_cache_update_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
+	) -> Self {
+		let cbf_block_source = Arc::new(CbfBlockSource::new(
+			peers,
+			config.network,
+			persisted_headers,
+			Arc::clone(&onchain_wallet),
+			kv_store,
+			Arc::clone(&logger),
+		));
+		let header_cache = tokio::sync::Mutex::new(BoundedHeaderCache::new());
+		let latest_chain_tip = RwLock::new(None);
+		let wallet_polling_status = Mutex::new(WalletSyncStatus::Completed);
+		Self::CompactBlockFilters {
+			cbf_block_source,
+			header_cache,
+			latest_chain_tip,
+			onchain_wallet,
+			wallet_polling_status,
+			fee_estimator,
+			tx_broadcaster,
+			config,
+			logger,
+			latest_wallet_sync_timestamp,
+			latest_onchain_wallet_sync_timestamp,
+			latest_fee_rate_cache_update_timestamp,
+			latest_channel_monitor_archival_height,
+		}
+	}
+
+	pub(crate) fn new_custom(
+		custom_source: Arc<dyn CustomChainSource>, onchain_wallet: Arc<Wallet>,
+		fee_estimator: Arc<OnchainFeeEstimator>, tx_broadcaster: Arc<Broadcaster>,
+		config: Arc<Config>, logger: Arc<FilesystemLogger>,
+		latest_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_onchain_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_fee_rate_cache_update_timestamp: Arc<RwLock<Option<u64>>>,
+		latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
+	) -> Self {
+		let custom_client = CustomChainSourceClient::new(custom_source, Arc::clone(&logger));
+		let onchain_wallet_sync_status = Mutex::new(WalletSyncStatus::Completed);
+		let latest_onchain_wallet_full_scan = Mutex::new(None);
+		Self::Custom {
+			custom_client,
+			onchain_wallet,
+			onchain_wallet_sync_status,
+			latest_onchain_wallet_full_scan,
+			fee_estimator,
+			tx_broadcaster,
+			config,
+			logger,
+			latest_wallet_sync_timestamp,
+			latest_onchain_wallet_sync_timestamp,
+			latest_fee_rate_cache_update_timestamp,
+			latest_channel_monitor_archival_height,
+		}
+	}
+
+	pub(crate) fn start(&self) -> Result<(), Error> {
+		match self {
+			Self::Electrum { server_url, electrum_runtime_status, logger, .. } => {
+				electrum_runtime_status
+					.write()
+					.unwrap()
+					.start(server_url.clone(), Arc::clone(&logger))
+			},
+			_ => {
+				// Nothing to do for other chain sources.
+				Ok(())
+			},
+		}
+	}
+
+	pub(crate) fn stop(&self) {
+		match self {
+			Self::Electrum { electrum_runtime_status, .. } => {
+				electrum_runtime_status.write().unwrap().stop();
+			},
+			_ => {
+				// Nothing to do for other chain sources.
+			},
+		}
+	}
+
+	pub(crate) async fn continuously_sync_wallets(
+		&self, mut stop_sync_receiver: tokio::sync::watch::Receiver<()>,
+		channel_manager: Arc<ChannelManager>, chain_monitor: Arc<ChainMonitor>,
+		output_sweeper: Arc<Sweeper>, channel_history: Arc<ChannelHistory>,
+	) {
+		match self {
+			Self::Esplora { config, logger, .. }
+			| Self::Electrum { config, logger, .. }
+			| Self::Custom { config, logger, .. } => {
+				// Setup syncing intervals
+				let onchain_wallet_sync_interval_secs =
+					config.onchain_wallet_sync_interval_secs.max(WALLET_SYNC_INTERVAL_MINIMUM_SECS);
+				let mut onchain_wallet_sync_interval =
+					tokio::time::interval(Duration::from_secs(onchain_wallet_sync_interval_secs));
+				onchain_wallet_sync_interval
+					.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+				let wallet_sync_interval_secs =
+					config.wallet_sync_interval_secs.max(WALLET_SYNC_INTERVAL_MINIMUM_SECS);
+				let mut wallet_sync_interval =
+					tokio::time::interval(Duration::from_secs(wallet_sync_interval_secs));
+				wallet_sync_interval
+					.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+				// Start the syncing loop.
+				loop {
+					tokio::select! {
+						_ = stop_sync_receiver.changed() => {
+							log_trace!(
+								logger,
+								"Stopping background syncing on-chain and Lightning wallets.",
+							);
+							return;
+						}
+						_ = onchain_wallet_sync_interval.tick() => {
+							let _ = self.sync_onchain_wallet(false).await;
+						}
+						_ = wallet_sync_interval.tick() => {
+							let _ = self.sync_lightning_wallet(
+								Arc::clone(&channel_manager),
+								Arc::clone(&chain_monitor),
+								Arc::clone(&output_sweeper),
+								Arc::clone(&channel_history),
+							).await;
+						}
+					}
+				}
+			},
+			Self::BitcoindRpc { logger, .. } | Self::CompactBlockFilters { logger, .. } => {
+				let mut chain_polling_interval =
+					tokio::time::interval(Duration::from_secs(CHAIN_POLLING_INTERVAL_SECS));
+				chain_polling_interval
+					.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+				// Start the polling loop.
+				loop {
+					tokio::select! {
+						_ = stop_sync_receiver.changed() => {
+							log_trace!(
+								logger,
+								"Stopping polling for new chain data.",
+							);
+							return;
+						}
+						_ = chain_polling_interval.tick() => {
+							let _ = self.poll_and_update_listeners(
+								Arc::clone(&channel_manager),
+								Arc::clone(&chain_monitor),
+								Arc::clone(&output_sweeper),
+								Arc::clone(&channel_history),
+							).await;
+						}
+					}
+				}
+			},
+		}
+	}
+
+	/// Syncs the on-chain wallet.
+	///
+	/// After an initial full scan, we only sync the script pubkeys the wallet already revealed,
+	/// unless the last full scan is older than [`BDK_WALLET_FULL_SCAN_INTERVAL_SECS`] or
+	/// `force_full_scan` is set.
+	pub(crate) async fn sync_onchain_wallet(&self, force_full_scan: bool) -> Result<(), Error> {
+		match self {
+			Self::Esplora {
+				esplora_servers,
+				onchain_wallet,
+				onchain_wallet_sync_status,
+				latest_onchain_wallet_full_scan,
+				config,
+				logger,
+				latest_onchain_wallet_sync_timestamp,
+				..
+			} => {
+				let stop_gap = config.onchain_wallet_stop_gap as usize;
+				sync_onchain_wallet_with(
+					force_full_scan,
+					onchain_wallet_sync_status,
+					latest_onchain_wallet_full_scan,
+					latest_onchain_wallet_sync_timestamp,
+					logger,
+					|| async move {
+						esplora_servers
+							.full_scan_wallet(onchain_wallet, stop_gap)
+							.await
+							.and_then(|update| onchain_wallet.apply_update(update))
+					},
+					|| async move {
+						esplora_servers
+							.sync_wallet(onchain_wallet)
+							.await
+							.and_then(|update| onchain_wallet.apply_update(update))
+					},
+				)
+				.await
+			},
+			Self::Electrum {
+				electrum_runtime_status,
+				onchain_wallet,
+				onchain_wallet_sync_status,
+				latest_onchain_wallet_full_scan,
+				config,
+				logger,
+				latest_onchain_wallet_sync_timestamp,
+				..
+			} => {
+				let electrum_client: Arc<ElectrumRuntimeClient> = if let Some(client) =
+					electrum_runtime_status.read().unwrap().client().as_ref()
+				{
+					Arc::clone(client)
+				} else {
+					debug_assert!(
+						false,
+						"We should have started the chain source before syncing the onchain wallet"
+					);
+					return Err(Error::WalletOperationFailed);
+				};
+				let electrum_client = &electrum_client;
+				let stop_gap = config.onchain_wallet_stop_gap as usize;
+				sync_onchain_wallet_with(
+					force_full_scan,
+					onchain_wallet_sync_status,
+					latest_onchain_wallet_full_scan,
+					latest_onchain_wallet_sync_timestamp,
+					logger,
+					|| async move {
+						let full_scan_request = onchain_wallet.get_full_scan_request();
+						electrum_client
+							.full_scan_wallet(full_scan_request, stop_gap)
+							.await
+							.and_then(|update| onchain_wallet.apply_update(update))
+					},
+					|| async move {
+						let sync_request = onchain_wallet.get_incremental_sync_request();
+						electrum_client
+							.sync_wallet(sync_request)
+							.await
+							.and_then(|update| onchain_wallet.apply_update(update))
+					},
+				)
+				.await
+			},
+			Self::Custom {
+				custom_client,
+				onchain_wallet,
+				onchain_wallet_sync_status,
+				latest_onchain_wallet_full_scan,
+				config,
+				logger,
+				latest_onchain_wallet_sync_timestamp,
+				..
+			} => {
+				let stop_gap = config.onchain_wallet_stop_gap as usize;
+				sync_onchain_wallet_with(
+					force_full_scan,
+					onchain_wallet_sync_status,
+					latest_onchain_wallet_full_scan,
+					latest_onchain_wallet_sync_timestamp,
+					logger,
+					|| async move {
+						let full_scan_request = onchain_wallet.get_full_scan_request();
+						custom_client
+							.full_scan_wallet(full_scan_request, stop_gap)
+							.await
+							.and_then(|update| onchain_wallet.apply_update(update))
+					},
+					|| async move {
+						let sync_request = onchain_wallet.get_incremental_sync_request();
+						custom_client
+							.sync_wallet(sync_request)
+							.await
+							.and_then(|update| onchain_wallet.apply_update(update))
+					},
+				)
+				.await
+			},
+			Self::BitcoindRpc { .. } | Self::CompactBlockFilters { .. } => {
+				// In BitcoindRpc and CompactBlockFilters mode we sync lightning and onchain wallet
+				// in one go by via `ChainPoller`. So nothing to do here.
+				unreachable!("Onchain wallet will be synced via chain polling")
+			},
+		}
+	}
+
+	pub(crate) async fn sync_lightning_wallet(
+		&self, channel_manager: Arc<ChannelManager>, chain_monitor: Arc<ChainMonitor>,
+		output_sweeper: Arc<Sweeper>, channel_history: Arc<ChannelHistory>,
+	) -> Result<(), Error> {
+		match self {
+			Self::Esplora {
+				esplora_servers,
+				logger,
+				latest_wallet_sync_timestamp,
+				latest_channel_monitor_archival_height,
+				..
+			} => {
+				sync_lightning_wallet_with(
+					channel_manager,
+					chain_monitor,
+					output_sweeper,
+					channel_history,
+					latest_wallet_sync_timestamp,
+					latest_channel_monitor_archival_height,
+					logger,
+					|confirmables| async move {
+						let confirmables = confirmables.iter().map(|c| &**c).collect();
+						esplora_servers.sync_confirmables(confirmables).await
+					},
+				)
+				.await
+			},
+			Self::Electrum {
+				electrum_runtime_status,
+				logger,
+				latest_wallet_sync_timestamp,
+				latest_channel_monitor_archival_height,
+				..
+			} => {
+				let electrum_client: Arc<ElectrumRuntimeClient> = if let Some(client) =
+					electrum_runtime_status.read().unwrap().client().as_ref()
+				{
+					Arc::clone(client)
+				} else {
+					debug_assert!(
+						false,
+						"We should have started the chain source before syncing the lightning wallet"
+					);
+					return Err(Error::TxSyncFailed);
+				};
+
+				sync_lightning_wallet_with(
+					channel_manager,
+					chain_monitor,
+					output_sweeper,
+					channel_history,
+					latest_wallet_sync_timestamp,
+					latest_channel_monitor_archival_height,
+					logger,
+					|confirmables| async move { electrum_client.sync_confirmables(confirmables).await },
+				)
+				.await
+			},
+			Self::Custom {
+				custom_client,
+				logger,
+				latest_wallet_sync_timestamp,
+				latest_channel_monitor_archival_height,
+				..
+			} => {
+				sync_lightning_wallet_with(
+					channel_manager,
+					chain_monitor,
+					output_sweeper,
+					channel_history,
+					latest_wallet_sync_timestamp,
+					latest_channel_monitor_archival_height,
+					logger,
+					|confirmables| async move { custom_client.sync_confirmables(confirmables).await },
+				)
+				.await
+			},
+			Self::BitcoindRpc { .. } | Self::CompactBlockFilters { .. } => {
+				// In BitcoindRpc and CompactBlockFilters mode we sync lightning and onchain wallet
+				// in one go by via `ChainPoller`. So nothing to do here.
+				unreachable!("Lightning wallet will be synced via chain polling")
+			},
+		}
+	}
+
+	pub(crate) async fn poll_and_update_listeners(
+		&self, channel_manager: Arc<ChannelManager>, chain_monitor: Arc<ChainMonitor>,
+		output_sweeper: Arc<Sweeper>, channel_history: Arc<ChannelHistory>,
+	) -> Result<(), Error> {
+		match self {
+			Self::Esplora { .. } | Self::Electrum { .. } | Self::Custom { .. } => {
+				// In Esplora, Electrum, and Custom mode we sync lightning and onchain wallets via
+				// `sync_onchain_wallet` and `sync_lightning_wallet`. So nothing to do here.
+				unreachable!("Listeners will be synced via transction-based syncing")
+			},
+			Self::BitcoindRpc {
+				bitcoind_rpc_client,
+				header_cache,
+				latest_chain_tip,
+				onchain_wallet,
+				wallet_polling_status,
+				config,
+				logger,
+				latest_wallet_sync_timestamp,
+				latest_onchain_wallet_sync_timestamp,
+				latest_channel_monitor_archival_height,
+				..
+			} => {
+				poll_and_update_listeners_from_source(
+					Arc::clone(bitcoind_rpc_client),
+					header_cache,
+					latest_chain_tip,
+					onchain_wallet,
+					wallet_polling_status,
+					config,
+					logger,
+					latest_wallet_sync_timestamp,
+					latest_onchain_wallet_sync_timestamp,
+					latest_channel_monitor_archival_height,
+					channel_manager,
+					chain_monitor,
+					output_sweeper,
+					channel_history,
+				)
+				.await
+			},
+			Self::CompactBlockFilters {
+				cbf_block_source,
+				header_cache,
+				latest_chain_tip,
+				onchain_wallet,
+				wallet_polling_status,
+				config,
+				logger,
+				latest_wallet_sync_timestamp,
+				latest_onchain_wallet_sync_timestamp,
+				latest_channel_monitor_archival_height,
+				..
+			} => {
+				poll_and_update_listeners_from_source(
+					Arc::clone(cbf_block_source),
+					header_cache,
+					latest_chain_tip,
+					onchain_wallet,
+					wallet_polling_status,
+					config,
+					logger,
+					latest_wallet_sync_timestamp,
+					latest_onchain_wallet_sync_timestamp,
+					latest_channel_monitor_archival_height,
+					channel_manager,
+					chain_monitor,
+					output_sweeper,
+					channel_history,
+				)
+				.await
+			},
+		}
+	}
+
+	pub(crate) async fn poll_best_block(&self) -> Result<BestBlock, Error> {
+		match self {
+			Self::Esplora { esplora_servers, .. } => esplora_servers.get_best_block().await,
+			Self::Electrum { electrum_runtime_status, .. } => {
+				let electrum_client: Arc<ElectrumRuntimeClient> = if let Some(client) =
+					electrum_runtime_status.read().unwrap().client().as_ref()
+				{
+					Arc::clone(client)
+				} else {
+					debug_assert!(
+						false,
+						"We should have started the chain source before polling the chain tip"
+					);
+					return Err(Error::TxSyncFailed);
+				};
+				electrum_client.get_best_block().await
+			},
+			Self::Custom { custom_client, .. } => custom_client.get_best_block().await,
+			Self::BitcoindRpc { bitcoind_rpc_client, logger, .. } => {
+				let validated_header = validate_best_block_header(Arc::clone(&bitcoind_rpc_client))
+					.await
+					.map_err(|e| {
+						log_error!(logger, "Failed to retrieve chain tip: {:?}", e);
+						Error::TxSyncFailed
+					})?;
+				Ok(BestBlock::new(validated_header.block_hash, validated_header.height))
+			},
+			Self::CompactBlockFilters { cbf_block_source, logger, .. } => {
+				let res = cbf_block_source.sync_headers().await;
+
+				// We might be called from a different runtime than the one we'll be run on, so make
+				// sure to not keep the connection around.
+				cbf_block_source.disconnect().await;
+
+				let tip = res.map_err(|e| {
+					log_error!(logger, "Failed to retrieve chain tip: {}", e);
+					Error::TxSyncFailed
+				})?;
+				Ok(BestBlock::new(tip.header.block_hash(), tip.height))
+			},
+		}
+	}
+
+	/// Looks up the given unconfirmed transaction and its unconfirmed ancestors in the chain
+	/// source's mempool.
+	///
+	/// Returns `None` if the transaction isn't in the mempool or if the chain source doesn't give
+	/// us access to the mempool. In Esplora, Electrum, and Custom mode the on-chain wallet learns
+	/// about unconfirmed transactions and their inputs while syncing, so there is no need to look
+	/// them up. Compact block filters don't give us any access to unconfirmed transactions.
+	pub(crate) async fn get_mempool_package(
+		&self, txid: &Txid,
+	) -> Result<Option<MempoolPackage>, Error> {
+		match self {
+			Self::BitcoindRpc { bitcoind_rpc_client, logger, .. } => {
+				let entry = match bitcoind_rpc_client.get_mempool_entry(txid).await {
+					Ok(Some(entry)) => entry,
+					Ok(None) => return Ok(None),
+					Err(e) => {
+						log_error!(logger, "Failed to retrieve mempool entry for {}: {}", txid, e);
+						return Err(Error::WalletOperationFailed);
+					},
+				};
+				let tx = match bitcoind_rpc_client.get_mempool_transaction(txid).await {
+					Ok(Some(tx)) => tx,
+					Ok(None) => return Ok(None),
+					Err(e) => {
+						log_error!(logger, "Failed to retrieve transaction {}: {}", txid, e);
+						return Err(Error::WalletOperationFailed);
+					},
+				};
+				Ok(Some(MempoolPackage {
+					tx,
+					ancestors_fee: entry.ancestor_fee,
+					ancestors_weight: entry.ancestor_weight,
+				}))
+			},
+			Self::Esplora { .. }
+			| Self::Electrum { .. }
+			| Self::Custom { .. }
+			| Self::CompactBlockFilters { .. } => Ok(None),
+		}
+	}
+
+	/// Returns the hash of the block at the given height in the best chain known to the chain
+	/// source.
+	pub(crate) async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error> {
+		match self {
+			Self::Esplora { .. } | Self::Electrum { .. } | Self::Custom { .. } => {
+				// In Esplora, Electrum, and Custom mode we never sync block-by-block, so there is
+				// no need to look up blocks by their height.
+				unreachable!("Block hashes are only looked up when syncing via chain polling")
+			},
+			Self::BitcoindRpc { bitcoind_rpc_client, logger, .. } => {
+				bitcoind_rpc_client.get_block_hash(height).await.map_err(|e| {
+					log_error!(logger, "Failed to retrieve block hash at height {}: {}", height, e);
+					Error::TxSyncFailed
+				})
+			},
+			Self::CompactBlockFilters { cbf_block_source, logger, .. } => {
+				let res = cbf_block_source.sync_headers().await;
+
+				// We might be called from a different runtime than the one we'll be run on, so make
+				// sure to not keep the connection around.
+				cbf_block_source.disconnect().await;
+
+				res.map_err(|e| {
+					log_error!(logger, "Failed to retrieve chain tip: {}", e);
+					Error::TxSyncFailed
+				})?;
+				cbf_block_source.get_block_hash(height).ok_or_else(|| {
+					log_error!(logger, "Failed to retrieve block hash at height {}", height);
+					Error::TxSyncFailed
+				})
+			},
+		}
+	}
+
+	/// Rescans the chain for transactions relevant to the on-chain wallet, starting at the block
+	/// at the given height.
+	pub(crate) async fn rescan_onchain_wallet(&self, from_height: u32) -> Result<(), Error> {
+		match self {
+			Self::Esplora { .. } | Self::Electrum { .. } | Self::Custom { .. } => {
+				// In Esplora, Electrum, and Custom mode we look up the full history of the wallet's
+				// scripts, so there is no starting height to rewind to.
+				self.sync_onchain_wallet(true).await
+			},
+			Self::BitcoindRpc {
+				bitcoind_rpc_client,
+				header_cache,
+				onchain_wallet,
+				wallet_polling_status,
+				config,
+				logger,
+				..
+			} => {
+				if from_height > onchain_wallet.current_best_block().height {
+					// Nothing to rescan, we'll pick up any new blocks when polling.
+					return Ok(());
+				}
+				let rescan_start_height = from_height.saturating_sub(1);
+				let rescan_start = BlockId {
+					height: rescan_start_height,
+					hash: self.get_block_hash(rescan_start_height).await?,
+				};
+				rescan_onchain_wallet_from_source(
+					Arc::clone(bitcoind_rpc_client),
+					header_cache,
+					onchain_wallet,
+					wallet_polling_status,
+					config,
+					logger,
+					rescan_start,
+				)
+				.await
+			},
+			Self::CompactBlockFilters {
+				cbf_block_source,
+				header_cache,
+				onchain_wallet,
+				wallet_polling_status,
+				config,
+				logger,
+				..
+			} => {
+				if from_height > onchain_wallet.current_best_block().height {
+					// Nothing to rescan, we'll pick up any new blocks when polling.
+					return Ok(());
+				}
+				let rescan_start_height = from_height.saturating_sub(1);
+				let rescan_start = BlockId {
+					height: rescan_start_height,
+					hash: self.get_block_hash(rescan_start_height).await?,
+				};
+				rescan_onchain_wallet_from_source(
+					Arc::clone(cbf_block_source),
+					header_cache,
+					onchain_wallet,
+					wallet_polling_status,
+					config,
+					logger,
+					rescan_start,
+				)
+				.await
+			},
+		}
+	}
+
+	pub(crate) async fn update_fee_rate_estimates(&self) -> Result<(), Error> {
+		match self {
+			Self::Esplora {
+				esplora_servers,
+				fee_estimator,
+				config,
+				logger,
+				latest_fee_rate_cache_update_timestamp,
+				..
+			} => {
+				let now = Instant::now();
+				let new_fee_rate_cache =
+					esplora_servers.get_fee_rate_cache_update(config.network).await?;
+
+				fee_estimator.set_fee_rate_cache(new_fee_rate_cache);
+
+				log_info!(
+					logger,
+					"Fee rate cache update finished in {}ms.",
+					now.elapsed().as_millis()
+				);
+				let unix_time_secs_opt =
+					SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+				*latest_fee_rate_cache_update_timestamp.write().unwrap() = unix_time_secs_opt;
+
+				Ok(())
+			},
+			Self::Electrum {
+				electrum_runtime_status,
+				fee_estimator,
+				config,
+				logger,
+				latest_fee_rate_cache_update_timestamp,
+				..
+			} => {
+				let electrum_client: Arc<ElectrumRuntimeClient> = if let Some(client) =
+					electrum_runtime_status.read().unwrap().client().as_ref()
+				{
+					Arc::clone(client)
+				} else {
+					debug_assert!(
+						false,
+						"We should have started the chain source before updating fees"
+					);
+					return Err(Error::FeerateEstimationUpdateFailed);
+				};
+
+				let now = Instant::now();
+
+				let new_fee_rate_cache =
+					electrum_client.get_fee_rate_cache_update(config.network).await?;
+				fee_estimator.set_fee_rate_cache(new_fee_rate_cache);
+
+				log_info!(
+					logger,
+					"Fee rate cache update finished in {}ms.",
+					now.elapsed().as_millis()
+				);
+				let unix_time_secs_opt =
+					SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+				*latest_fee_rate_cache_update_timestamp.write().unwrap() = unix_time_secs_opt;
+
+				Ok(())
+			},
+			Self::Custom {
+				custom_client,
+				fee_estimator,
+				config,
+				logger,
+				latest_fee_rate_cache_update_timestamp,
+				..
+			} => {
+				let now = Instant::now();
+
+				let new_fee_rate_cache =
+					custom_client.get_fee_rate_cache_update(config.network).await?;
+				fee_estimator.set_fee_rate_cache(new_fee_rate_cache);
+
+				log_info!(
+					logger,
+					"Fee rate cache update finished in {}ms.",
+					now.elapsed().as_millis()
+				);
+				let unix_time_secs_opt =
+					SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+				*latest_fee_rate_cache_update_timestamp.write().unwrap() = unix_time_secs_opt;
+
+				Ok(())
+			},
+			Self::BitcoindRpc {
+				bitcoind_rpc_client,
+				fee_estimator,
+				config,
+				logger,
+				latest_fee_rate_cache_update_timestamp,
+				..
+			} => {
+				let now = Instant::now();
+				let mempool_min_fee_rate = tokio::time::timeout(
+					Duration::from_secs(FEE_RATE_CACHE_UPDATE_TIMEOUT_SECS),
+					bitcoind_rpc_client.get_mempool_minimum_fee_rate(),
+				)
+				.await
+				.map_err(|e| {
+					log_error!(logger, "Updating fee rate estimates timed out: {}", e);
+					Error::FeerateEstimationUpdateTimeout
+				})?
+				.map_err(|e| {
+					log_error!(logger, "Failed to retrieve mempool minimum fee rate: {}", e);
+					Error::FeerateEstimationUpdateFailed
+				})?;
+
+				let confirmation_targets = get_all_conf_targets();
+
+				let mut new_fee_rate_cache = HashMap::with_capacity(10);
+				for target in confirmation_targets {
+					let num_blocks = get_num_block_defaults_for_target(target);
+					let estimation_mode = match target {
+						ConfirmationTarget::Lightning(
+							lightning::chain::chaininterface::ConfirmationTarget::MaximumFeeEstimate,
+						)
+						| ConfirmationTarget::Lightning(
+							lightning::chain::chaininterface::ConfirmationTarget::UrgentOnChainSweep,
+						) => FeeRateEstimationMode::Conservative,
+						_ => FeeRateEstimationMode::Economical,
+					};
+
+					let estimate_opt = tokio::time::timeout(
+						Duration::from_secs(FEE_RATE_CACHE_UPDATE_TIMEOUT_SECS),
+						bitcoind_rpc_client
+							.get_fee_estimate_for_target(num_blocks, estimation_mode),
+					)
+					.await
+					.map_err(|e| {
+						log_error!(logger, "Updating fee rate estimates timed out: {}", e);
+						Error::FeerateEstimationUpdateTimeout
+					})?
+					.map_err(|e| {
+						log_error!(logger, "Failed to retrieve fee rate estimates: {}", e);
+						Error::FeerateEstimationUpdateFailed
+					})?;
+
+					let fee_rate = match estimate_opt {
+						Some(fee_rate) => fee_rate,
+						None if config.network == Network::Bitcoin => {
+							// Ensure we fail if we didn't receive any estimates.
+							log_error!(
+								logger,
+								"Failed to retrieve fee rate estimates: empty fee estimates are dissallowed on Mainnet.",
+							);
+							return Err(Error::FeerateEstimationUpdateFailed);
+						},
+						None => {
+							// If `bitcoind` couldn't give us an estimate we'll fall back to our
+							// defaults for this target.
+							log_trace!(logger, "No fee rate estimate available for {:?}", target);
+							continue;
+						},
+					};
+
+					// Make sure we never go below the mempool's minimum fee rate.
+					let fee_rate = fee_rate.max(mempool_min_fee_rate);
+					let adjusted_fee_rate = apply_post_estimation_adjustments(target, fee_rate);
+
+					new_fee_rate_cache.insert(target, adjusted_fee_rate);
+
+					log_trace!(
+						logger,
+						"Fee rate estimation updated for {:?}: {} sats/kwu",
+						target,
+						adjusted_fee_rate.to_sat_per_kwu(),
+					);
+				}
+
+				fee_estimator.set_fee_rate_cache(new_fee_rate_cache);
+
+				log_info!(
+					logger,
+					"Fee rate cache update finished in {}ms.",
+					now.elapsed().as_millis()
+				);
+				let unix_time_secs_opt =
+					SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+				*latest_fee_rate_cache_update_timestamp.write().unwrap() = unix_time_secs_opt;
+
+				Ok(())
+			},
+			Self::CompactBlockFilters { logger, .. } => {
+				// BIP 157 peers don't provide us with fee rate estimates, so we stick with the
+				// fallback rates. Note that on mainnet we require the user to configure a custom
+				// fee estimator or fee rate overrides instead.
+				log_trace!(
+					logger,
+					"Skipping fee rate cache update as our chain source provides no estimates."
+				);
+				Ok(())
+			},
+		}
+	}
+
+	pub(crate) async fn process_broadcast_queue(&self) {
+		match self {
+			Self::Esplora { esplora_servers, tx_broadcaster, .. } => {
+				let mut receiver = tx_broadcaster.get_broadcast_queue().await;
+				while let Some(next_package) = receiver.recv().await {
+					for tx in &next_package {
+						esplora_servers.broadcast(tx).await;
+					}
+				}
+			},
+			Self::Electrum { electrum_runtime_status, tx_broadcaster, .. } => {
+				let electrum_client: Arc<ElectrumRuntimeClient> = if let Some(client) =
+					electrum_runtime_status.read().unwrap().client().as_ref()
+				{
+					Arc::clone(client)
+				} else {
+					debug_assert!(
+						false,
+						"We should have started the chain source before broadcasting"
+					);
+					return;
+				};
+
+				let mut receiver = tx_broadcaster.get_broadcast_queue().await;
+				while let Some(next_package) = receiver.recv().await {
+					for tx in next_package {
+						electrum_client.broadcast(tx).await;
+					}
+				}
+			},
+			Self::Custom { custom_client, tx_broadcaster, .. } => {
+				let mut receiver = tx_broadcaster.get_broadcast_queue().await;
+				while let Some(next_package) = receiver.recv().await {
+					for tx in next_package {
+						custom_client.broadcast(tx).await;
+					}
+				}
+			},
+			Self::BitcoindRpc { bitcoind_rpc_client, tx_broadcaster, logger, .. } => {
+				let mut receiver = tx_broadcaster.get_broadcast_queue().await;
+				while let Some(next_package) = receiver.recv().await {
+					for tx in &next_package {
+						let txid = tx.compute_txid();
+						let timeout_fut = tokio::time::timeout(
+							Duration::from_secs(TX_BROADCAST_TIMEOUT_SECS),
+							bitcoind_rpc_client.broadcast_transaction(tx),
+						);
+						match timeout_fut.await {
+							Ok(res) => match res {
+								Ok(id) => {
+									debug_assert_eq!(id, txid);
+									log_trace!(
+										logger,
+										"Successfully broadcast transaction {}",
+										txid
+									);
+								},
+								Err(e) => {
+									log_error!(
+										logger,
+										"Failed to broadcast transaction {}: {}",
+										txid,
+										e
+									);
+									log_trace!(
+										logger,
+										"Failed broadcast transaction bytes: {}",
+										log_bytes!(tx.encode())
+									);
+								},
+							},
+							Err(e) => {
+								log_error!(
+									logger,
+									"Failed to broadcast transaction due to timeout {}: {}",
+									txid,
+									e
+								);
+								log_trace!(
+									logger,
+									"Failed broadcast transaction bytes: {}",
+									log_bytes!(tx.encode())
+								);
+							},
+						}
+					}
+				}
+			},
+			Self::CompactBlockFilters { cbf_block_source, tx_broadcaster, logger, .. } => {
+				let mut receiver = tx_broadcaster.get_broadcast_queue().await;
+				while let Some(next_package) = receiver.recv().await {
+					for tx in &next_package {
+						let txid = tx.compute_txid();
+						let timeout_fut = tokio::time::timeout(
+							Duration::from_secs(TX_BROADCAST_TIMEOUT_SECS),
+							cbf_block_source.broadcast_transaction(tx),
+						);
+						match timeout_fut.await {
+							Ok(res) => match res {
+								Ok(()) => {
+									log_trace!(
+										logger,
+										"Successfully broadcast transaction {}",
+										txid
+									);
+								},
+								Err(e) => {
+									log_error!(
+										logger,
+										"Failed to broadcast transaction {}: {}",
+										txid,
+										e
+									);
+									log_trace!(
+										logger,
+										"Failed broadcast transaction bytes: {}",
+										log_bytes!(tx.encode())
+									);
+								},
+							},
+							Err(e) => {
+								log_error!(
+									logger,
+									"Failed to broadcast transaction due to timeout {}: {}",
+									txid,
+									e
+								);
+								log_trace!(
+									logger,
+									"Failed broadcast transaction bytes: {}",
+									log_bytes!(tx.encode())
+								);
+							},
+						}
+					}
+				}
+			},
+		}
+	}
+}
+
+impl Filter for ChainSource {
+	fn register_tx(&self, txid: &Txid, script_pubkey: &Script) {
+		match self {
+			Self::Esplora { esplora_servers, .. } => {
+				esplora_servers.register_tx(txid, script_pubkey)
+			},
+			Self::Electrum { electrum_runtime_status, .. } => {
+				electrum_runtime_status.write().unwrap().register_tx(txid, script_pubkey)
+			},
+			Self::Custom { custom_client, .. } => custom_client.register_tx(txid, script_pubkey),
+			Self::BitcoindRpc { .. } => (),
+			Self::CompactBlockFilters { cbf_block_source, .. } => {
+				cbf_block_source.register_script(script_pubkey)
+			},
+		}
+	}
+	fn register_output(&self, output: lightning::chain::WatchedOutput) {
+		match self {
+			Self::Esplora { esplora_servers, .. } => esplora_servers.register_output(output),
+			Self::Electrum { electrum_runtime_status, .. } => {
+				electrum_runtime_status.write().unwrap().register_output(output)
+			},
+			Self::Custom { custom_client, .. } => custom_client.register_output(output),
+			Self::BitcoindRpc { .. } => (),
+			Self::CompactBlockFilters { cbf_block_source, .. } => {
+				cbf_block_source.register_script(&output.script_pubkey)
+			},
+		}
+	}
+}
+
+// Syncs the on-chain wallet via the given backend-specific `full_scan` or incremental `sync`,
+// taking care of deduplicating concurrent syncs and of tracking when we last did a full scan.
+async fn sync_onchain_wallet_with<FS, FSF, S, SF>(
+	force_full_scan: bool, onchain_wallet_sync_status: &Mutex<WalletSyncStatus>,
+	latest_onchain_wallet_full_scan: &Mutex<Option<Instant>>,
+	latest_onchain_wallet_sync_timestamp: &RwLock<Option<u64>>, logger: &FilesystemLogger,
+	full_scan: FS, sync: S,
+) -> Result<(), Error>
+where
+	FS: FnOnce() -> FSF,
+	FSF: Future<Output = Result<(), Error>>,
+	S: FnOnce() -> SF,
+	SF: Future<Output = Result<(), Error>>,
+{
+	if force_full_scan {
+		*latest_onchain_wallet_full_scan.lock().unwrap() = None;
+	}
+	loop {
+		let receiver_res = {
+			let mut status_lock = onchain_wallet_sync_status.lock().unwrap();
+			status_lock.register_or_subscribe_pending_sync()
+		};
+		if let Some(mut sync_receiver) = receiver_res {
+			log_info!(logger, "Sync in progress, skipping.");
+			let res = sync_receiver.recv().await.map_err(|e| {
+				debug_assert!(false, "Failed to receive wallet sync result: {:?}", e);
+				log_error!(logger, "Failed to receive wallet sync result: {:?}", e);
+				Error::WalletOperationFailed
+			})?;
+
+			// If we're asked for a full scan, retry unless the ongoing sync did one.
+			let full_scan_done = latest_onchain_wallet_full_scan.lock().unwrap().is_some();
+			if !force_full_scan || res.is_err() || full_scan_done {
+				return res;
+			}
+		} else {
+			break;
+		}
+	}
+
+	let res = {
+		let full_scan_required = onchain_wallet_full_scan_required(latest_onchain_wallet_full_scan);
+
+		let now = Instant::now();
+		let update_res = if full_scan_required { full_scan().await } else { sync().await };
+
+		if update_res.is_ok() {
+			log_info!(
+				logger,
+				"{} of on-chain wallet finished in {}ms.",
+				if full_scan_required { "Full scan" } else { "Sync" },
+				now.elapsed().as_millis()
+			);
+			if full_scan_required {
+				*latest_onchain_wallet_full_scan.lock().unwrap() = Some(now);
+			}
+			let unix_time_secs_opt =
+				SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+			*latest_onchain_wallet_sync_timestamp.write().unwrap() = unix_time_secs_opt;
+		}
+
+		update_res
+	};
+
+	onchain_wallet_sync_status.lock().unwrap().propagate_result_to_subscribers(res);
+
+	res
+}
+
+// Syncs the Lightning wallet via the given backend-specific `sync` of the confirmables, taking
+// care of tracking when we last synced and of archiving fully resolved channel monitors.
+async fn sync_lightning_wallet_with<S, SF>(
+	channel_manager: Arc<ChannelManager>, chain_monitor: Arc<ChainMonitor>,
+	output_sweeper: Arc<Sweeper>, channel_history: Arc<ChannelHistory>,
+	latest_wallet_sync_timestamp: &RwLock<Option<u64>>,
+	latest_channel_monitor_archival_height: &Arc<RwLock<Option<u32>>>, logger: &FilesystemLogger,
+	sync: S,
+) -> Result<(), Error>
+where
+	S: FnOnce(Vec<Arc<dyn Confirm + Sync + Send>>) -> SF,
+	SF: Future<Output = Result<(), Error>>,
+{
+	let confirmables = vec![
+		Arc::clone(&channel_manager) as Arc<dyn Confirm + Sync + Send>,
+		Arc::clone(&chain_monitor) as Arc<dyn Confirm + Sync + Send>,
+		output_sweeper as Arc<dyn Confirm + Sync + Send>,
+		channel_history as Arc<dyn Confirm + Sync + Send>,
+	];
+
+	let now = Instant::now();
+	let res = sync(confirmables).await;
+
+	if res.is_ok() {
+		log_info!(logger, "Sync of Lightning wallet finished in {}ms.", now.elapsed().as_millis());
+
+		let unix_time_secs_opt =
+			SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+		*latest_wallet_sync_timestamp.write().unwrap() = unix_time_secs_opt;
+
+		periodically_archive_fully_resolved_monitors(
+			channel_manager,
+			chain_monitor,
+			Arc::clone(latest_channel_monitor_archival_height),
+		);
+	}
+
+	res
+}
+
+fn onchain_wallet_full_scan_required(latest_full_scan: &Mutex<Option<Instant>>) -> bool {
+	latest_full_scan.lock().unwrap().map_or(true, |last_full_scan| {
+		last_full_scan.elapsed() >= Duration::from_secs(BDK_WALLET_FULL_SCAN_INTERVAL_SECS)
+	})
+}
+
+fn periodically_archive_fully_resolved_monitors(
+	channel_manager: Arc<ChannelManager>, chain_monitor: Arc<ChainMonitor>,
+	latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
+) {
+	let mut latest_archival_height_lock = latest_channel_monitor_archival_height.write().unwrap();
+	let cur_height = channel_manager.current_best_block().height;
+	let should_archive = latest_archival_height_lock
+		.as_ref()
+		.map_or(true, |h| cur_height >= h + RESOLVED_CHANNEL_MONITOR_ARCHIVAL_INTERVAL);
+
+	if should_archive {
+		chain_monitor.archive_fully_resolved_channel_monitors();
+		*latest_archival_height_lock = Some(cur_height);
+	}
+}
+
+async fn poll_and_update_listeners_from_source<B: Deref + Clone + Sized + Send + Sync>(
+	block_source: B, header_cache: &tokio::sync::Mutex<BoundedHeaderCache>,
+	latest_chain_tip: &RwLock<Option<ValidatedBlockHeader>>, onchain_wallet: &Arc<Wallet>,
+	wallet_polling_status: &Mutex<WalletSyncStatus>, config: &Arc<Config>,
+	logger: &Arc<FilesystemLogger>, latest_wallet_sync_timestamp: &Arc<RwLock<Option<u64>>>,
+	latest_onchain_wallet_sync_timestamp: &Arc<RwLock<Option<u64>>>,
+	latest_channel_monitor_archival_height: &Arc<RwLock<Option<u32>>>,
+	channel_manager: Arc<ChannelManager>, chain_monitor: Arc<ChainMonitor>,
+	output_sweeper: Arc<Sweeper>, channel_history: Arc<ChannelHistory>,
+) -> Result<(), Error>
+where
+	B::Target: BlockSource,
+{
+	let receiver_res = {
+		let mut status_lock = wallet_polling_status.lock().unwrap();
+		status_lock.register_or_subscribe_pending_sync()
+	};
+
+	if let Some(mut sync_receiver) = receiver_res {
+		log_info!(logger, "Sync in progress, skipping.");
+		return sync_receiver.recv().await.map_err(|e| {
+			debug_assert!(false, "Failed to receive wallet polling result: {:?}", e);
+			log_error!(logger, "Failed to receive wallet polling result: {:?}", e);
+			Error::WalletOperationFailed
+		})?;
+	}
+
+	let res = {
+		let latest_chain_tip_opt = latest_chain_tip.read().unwrap().clone();
+		let mut locked_header_cache = header_cache.lock().await;
+
+		let chain_tip_res = match latest_chain_tip_opt {
+			Some(chain_tip) => Ok(chain_tip),
+			None => {
+				// We haven't synced our listeners yet, do so now.
+				let now = Instant::now();
+				let channel_manager_best_block_hash =
+					channel_manager.current_best_block().block_hash;
+				let sweeper_best_block_hash = output_sweeper.current_best_block().block_hash;
+				let onchain_wallet_best_block_hash = onchain_wallet.current_best_block().block_hash;
+
+				let mut chain_listeners = vec![
+					(
+						onchain_wallet_best_block_hash,
+						&**onchain_wallet as &(dyn Listen + Send + Sync),
+					),
+					(
+						channel_manager_best_block_hash,
+						&*channel_manager as &(dyn Listen + Send + Sync),
+					),
+					(sweeper_best_block_hash, &*output_sweeper as &(dyn Listen + Send + Sync)),
+					// The channel history doesn't track its own best block, so we simply have it
+					// follow the channel manager.
+					(
+						channel_manager_best_block_hash,
+						&*channel_history as &(dyn Listen + Send + Sync),
+					),
+				];
+
+				// TODO: Eventually we might want to see if we can synchronize
+				// `ChannelMonitor`s before giving them to `ChainMonitor` it the first
+				// place. However, this isn't trivial as we load them on initialization
+				// (in the `Builder`) and only gain network access during `start`. For
+				// now, we just make sure we get the worst known block hash and
+				// sychronize them via `ChainMonitor`.
+				if let Some(worst_channel_monitor_block_hash) = chain_monitor
+					.list_monitors()
+					.iter()
+					.flat_map(|(txo, _)| {
+						chain_monitor.get_monitor(*txo).ok().map(|m| m.current_best_block())
+					})
+					.min_by_key(|b| b.height)
+					.map(|b| b.block_hash)
+				{
+					chain_listeners.push((
+						worst_channel_monitor_block_hash,
+						&*chain_monitor as &(dyn Listen + Send + Sync),
+					));
+				}
+
+				match synchronize_listeners(
+					block_source.clone(),
+					config.network,
+					&mut *locked_header_cache,
+					chain_listeners,
+				)
+				.await
+				{
+					Ok(chain_tip) => {
+						log_info!(
+							logger,
+							"Finished synchronizing listeners in {}ms",
+							now.elapsed().as_millis()
+						);
+						Ok(chain_tip)
+					},
+					Err(e) => {
+						log_error!(logger, "Failed to synchronize listeners: {:?}", e);
+						Err(Error::TxSyncFailed)
+					},
+				}
+			},
+		};
+
+		match chain_tip_res {
+			Ok(chain_tip) => {
+				let chain_poller = ChainPoller::new(block_source.clone(), config.network);
+				let chain_listener = ChainListener {
+					onchain_wallet: Arc::clone(&onchain_wallet),
+					channel_manager: Arc::clone(&channel_manager),
+					chain_monitor: Arc::clone(&chain_monitor),
+					output_sweeper,
+					channel_history,
+				};
+				let mut spv_client = SpvClient::new(
+					chain_tip,
+					chain_poller,
+					&mut *locked_header_cache,
+					&chain_listener,
+				);
+
+				let now = Instant::now();
+				match spv_client.poll_best_tip().await {
+					Ok((ChainTip::Better(tip), true)) => {
+						log_trace!(
+							logger,
+							"Finished polling best tip in {}ms",
+							now.elapsed().as_millis()
+						);
+						*latest_chain_tip.write().unwrap() = Some(tip);
+					},
+					Ok(_) => {
+						*latest_chain_tip.write().unwrap() = Some(chain_tip);
+					},
+					Err(e) => {
+						log_error!(logger, "Failed to poll for chain data: {:?}", e);
+						*latest_chain_tip.write().unwrap() = Some(chain_tip);
+						let res = Err(Error::TxSyncFailed);
+						wallet_polling_status.lock().unwrap().propagate_result_to_subscribers(res);
+						return res;
+					},
+				}
+
+				let unix_time_secs_opt =
+					SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+				*latest_onchain_wallet_sync_timestamp.write().unwrap() = unix_time_secs_opt;
+				*latest_wallet_sync_timestamp.write().unwrap() = unix_time_secs_opt;
+
+				periodically_archive_fully_resolved_monitors(
+					channel_manager,
+					chain_monitor,
+					Arc::clone(&latest_channel_monitor_archival_height),
+				);
+				Ok(())
+			},
+			Err(e) => Err(e),
+		}
+	};
+
+	wallet_polling_status.lock().unwrap().propagate_result_to_subscribers(res);
+
+	res
+}
+
+async fn rescan_onchain_wallet_from_source<B: Deref + Clone + Sized + Send + Sync>(
+	block_source: B, header_cache: &tokio::sync::Mutex<BoundedHeaderCache>,
+	onchain_wallet: &Arc<Wallet>, wallet_polling_status: &Mutex<WalletSyncStatus>,
+	config: &Arc<Config>, logger: &Arc<FilesystemLogger>, rescan_start: BlockId,
+) -> Result<(), Error>
+where
+	B::Target: BlockSource,
+{
+	// Wait for any ongoing polling to finish, as we'll rewind the on-chain wallet below.
+	loop {
+		let receiver_res = {
+			let mut status_lock = wallet_polling_status.lock().unwrap();
+			status_lock.register_or_subscribe_pending_sync()
+		};
+
+		match receiver_res {
+			Some(mut sync_receiver) => {
+				log_info!(logger, "Sync in progress, waiting for it to finish before rescanning.");
+				let _ = sync_receiver.recv().await;
+			},
+			None => break,
+		}
+	}
+
+	let now = Instant::now();
+
+	// Have the wallet's local chain include the block we start from, so that the blocks connected
+	// below build on it.
+	let res = match onchain_wallet.insert_checkpoint(rescan_start) {
+		Ok(()) => {
+			let mut locked_header_cache = header_cache.lock().await;
+			let chain_listeners =
+				vec![(rescan_start.hash, &**onchain_wallet as &(dyn Listen + Send + Sync))];
+			match synchronize_listeners(
+				block_source,
+				config.network,
+				&mut *locked_header_cache,
+				chain_listeners,
+			)
+			.await
+			{
+				Ok(_) => {
+					log_info!(
+						logger,
+						"Rescan of on-chain wallet finished in {}ms.",
+						now.elapsed().as_millis()
+					);
+					Ok(())
+				},
+				Err(e) => {
+					log_error!(logger, "Failed to rescan on-chain wallet: {:?}", e);
+					Err(Error::WalletOperationFailed)
+				},
+			}
+		},
+		Err(e) => Err(e),
+	};
+
+	wallet_polling_status.lock().unwrap().propagate_result_to_subscribers(res);
+
+	res
+}
@@ -0,0 +1,478 @@
+// This file is Copyright its original authors, visible in version control history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
+// accordance with one or both of these licenses.
+
+use crate::io::{
+	CHANNEL_HISTORY_PERSISTENCE_PRIMARY_NAMESPACE, CHANNEL_HISTORY_PERSISTENCE_SECONDARY_NAMESPACE,
+};
+use crate::logger::{log_error, Logger};
+use crate::types::{DynStore, UserChannelId};
+use crate::Error;
+
+use lightning::chain::transaction::TransactionData;
+use lightning::chain::{Confirm, Listen};
+use lightning::events::ClosureReason;
+use lightning::impl_writeable_tlv_based;
+use lightning::ln::types::ChannelId;
+use lightning::util::ser::Writeable;
+
+use bitcoin::block::Header;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{BlockHash, OutPoint, Txid};
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// Details of a closed channel as returned by [`Node::list_closed_channels`].
+///
+/// [`Node::list_closed_channels`]: crate::Node::list_closed_channels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosedChannelDetails {
+	/// The channel's ID.
+	pub channel_id: ChannelId,
+	/// The local identifier of the channel.
+	pub user_channel_id: UserChannelId,
+	/// The node ID of the channel's counterparty.
+	///
+	/// Will only be `None` for channels opened with LDK versions prior to 0.0.117.
+	pub counterparty_node_id: Option<PublicKey>,
+	/// The value, in satoshis, of the channel as it appeared in the funding transaction.
+	pub channel_capacity_sats: Option<u64>,
+	/// The channel's funding transaction output.
+	pub funding_txo: Option<OutPoint>,
+	/// The ID of the transaction spending the channel's funding output, i.e., the cooperative
+	/// closing transaction or the commitment transaction broadcast on force-closure.
+	///
+	/// Will be `None` until the closing transaction has been confirmed on-chain.
+	pub closing_txid: Option<Txid>,
+	/// The reason for the channel's closure.
+	pub closure_reason: Option<ClosureReason>,
+	/// Our balance in the channel at the time of its closure, in satoshis.
+	///
+	/// This is the amount we expected to claim on-chain as reported by the channel monitor when
+	/// the channel was closed, i.e., before paying any on-chain fees for claiming it. Will be
+	/// `None` if the channel monitor wasn't available at that time.
+	pub final_balance_sats: Option<u64>,
+	/// The timestamp, in seconds since start of the UNIX epoch, when the channel was opened.
+	///
+	/// Will be `None` for channels opened before the channel history was tracked.
+	pub opened_at: Option<u64>,
+	/// The timestamp, in seconds since start of the UNIX epoch, when the channel was closed.
+	pub closed_at: u64,
+	/// Indicates whether we initiated the channel's closure.
+	pub is_locally_initiated: bool,
+}
+
+/// The data we track for each channel, allowing to reconstruct its history once it's closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChannelRecord {
+	pub channel_id: ChannelId,
+	pub user_channel_id: UserChannelId,
+	pub counterparty_node_id: Option<PublicKey>,
+	pub channel_capacity_sats: Option<u64>,
+	pub funding_txo: Option<OutPoint>,
+	pub closing_txid: Option<Txid>,
+	pub closing_tx_height: Option<u32>,
+	pub closing_tx_block_hash: Option<BlockHash>,
+	pub closure_reason: Option<ClosureReason>,
+	pub final_balance_sats: Option<u64>,
+	pub opened_at: Option<u64>,
+	pub closed_at: Option<u64>,
+	pub is_locally_initiated: bool,
+}
+
+impl_writeable_tlv_based!(ChannelRecord, {
+	(0, channel_id, required),
+	(2, user_channel_id, required),
+	(4, counterparty_node_id, option),
+	(6, channel_capacity_sats, option),
+	(8, funding_txo, option),
+	(10, closing_txid, option),
+	(12, closing_tx_height, option),
+	(14, closing_tx_block_hash, option),
+	(16, closure_reason, upgradable_option),
+	(18, final_balance_sats, option),
+	(20, opened_at, option),
+	(22, closed_at, option),
+	(24, is_locally_initiated, required),
+});
+
+impl ChannelRecord {
+	fn closed_channel_details(&self) -> Option<ClosedChannelDetails> {
+		Some(ClosedChannelDetails {
+			channel_id: self.channel_id,
+			user_channel_id: self.user_channel_id,
+			counterparty_node_id: self.counterparty_node_id,
+			channel_capacity_sats: self.channel_capacity_sats,
+			funding_txo: self.funding_txo,
+			closing_txid: self.closing_txid,
+			closure_reason: self.closure_reason.clone(),
+			final_balance_sats: self.final_balance_sats,
+			opened_at: self.opened_at,
+			closed_at: self.closed_at?,
+			is_locally_initiated: self.is_locally_initiated,
+		})
+	}
+}
+
+/// Returns whether the closure of a channel for the given reason was initiated by us.
+pub(crate) fn is_locally_initiated_closure(reason: &ClosureReason) -> bool {
+	match reason {
+		ClosureReason::HolderForceClosed { .. }
+		| ClosureReason::LocallyInitiatedCooperativeClosure
+		| ClosureReason::ProcessingError { .. }
+		| ClosureReason::OutdatedChannelManager
+		| ClosureReason::HTLCsTimedOut
+		| ClosureReason::PeerFeerateTooLow { .. } => true,
+		_ => false,
+	}
+}
+
+/// Keeps track of our channels' history, in particular of the details of closed channels.
+///
+/// To learn about the closing transactions, the store listens for any confirmed transactions
+/// spending a tracked channel's funding output.
+pub(crate) struct ChannelHistoryStore<L: Deref>
+where
+	L::Target: Logger,
+{
+	records: Mutex<HashMap<ChannelId, ChannelRecord>>,
+	kv_store: Arc<DynStore>,
+	logger: L,
+}
+
+impl<L: Deref> ChannelHistoryStore<L>
+where
+	L::Target: Logger,
+{
+	pub(crate) fn new(records: Vec<ChannelRecord>, kv_store: Arc<DynStore>, logger: L) -> Self {
+		let records = Mutex::new(records.into_iter().map(|r| (r.channel_id, r)).collect());
+		Self { records, kv_store, logger }
+	}
+
+	/// Starts tracking a newly opened channel.
+	pub(crate) fn channel_opened(
+		&self, channel_id: ChannelId, user_channel_id: UserChannelId,
+		counterparty_node_id: PublicKey, funding_txo: OutPoint, opened_at: u64,
+	) -> Result<(), Error> {
+		let mut locked_records = self.records.lock().unwrap();
+		if locked_records.contains_key(&channel_id) {
+			return Ok(());
+		}
+
+		let record = ChannelRecord {
+			channel_id,
+			user_channel_id,
+			counterparty_node_id: Some(counterparty_node_id),
+			channel_capacity_sats: None,
+			funding_txo: Some(funding_txo),
+			closing_txid: None,
+			closing_tx_height: None,
+			closing_tx_block_hash: None,
+			closure_reason: None,
+			final_balance_sats: None,
+			opened_at: Some(opened_at),
+			closed_at: None,
+			is_locally_initiated: false,
+		};
+		self.persist_record(&record)?;
+		locked_records.insert(channel_id, record);
+		Ok(())
+	}
+
+	/// Records the closure of a channel, creating a new record if we didn't track it before.
+	///
+	/// Any previously tracked opening timestamp and closing transaction will be retained. Closures
+	/// of channels that were never funded, e.g., inbound channel requests we rejected, won't be
+	/// recorded.
+	pub(crate) fn channel_closed(&self, details: ClosedChannelDetails) -> Result<(), Error> {
+		let mut locked_records = self.records.lock().unwrap();
+		let tracked_record = locked_records.get(&details.channel_id);
+		if tracked_record.is_none() && details.funding_txo.is_none() {
+			return Ok(());
+		}
+		let record = ChannelRecord {
+			channel_id: details.channel_id,
+			user_channel_id: details.user_channel_id,
+			counterparty_node_id: details
+				.counterparty_node_id
+				.or(tracked_record.and_then(|r| r.counterparty_node_id)),
+			channel_capacity_sats: details.channel_capacity_sats,
+			funding_txo: details.funding_txo.or(tracked_record.and_then(|r| r.funding_txo)),
+			closing_txid: tracked_record.and_then(|r| r.closing_txid),
+			closing_tx_height: tracked_record.and_then(|r| r.closing_tx_height),
+			closing_tx_block_hash: tracked_record.and_then(|r| r.closing_tx_block_hash),
+			closure_reason: details.closure_reason,
+			final_balance_sats: details.final_balance_sats,
+			opened_at: tracked_record.and_then(|r| r.opened_at),
+			closed_at: Some(details.closed_at),
+			is_locally_initiated: details.is_locally_initiated,
+		};
+
+		self.persist_record(&record)?;
+		locked_records.insert(record.channel_id, record);
+		Ok(())
+	}
+
+	pub(crate) fn list_closed_channels(&self) -> Vec<ClosedChannelDetails> {
+		self.records
+			.lock()
+			.unwrap()
+			.values()
+			.filter_map(|record| record.closed_channel_details())
+			.collect()
+	}
+
+	fn persist_record(&self, record: &ChannelRecord) -> Result<(), Error> {
+		let store_key = record.channel_id.to_string();
+		let data = record.encode();
+		self.kv_store
+			.write(
+				CHANNEL_HISTORY_PERSISTENCE_PRIMARY_NAMESPACE,
+				CHANNEL_HISTORY_PERSISTENCE_SECONDARY_NAMESPACE,
+				&store_key,
+				&data,
+			)
+			.map_err(|e| {
+				log_error!(
+					self.logger,
+					"Write for key {}/{}/{} failed due to: {}",
+					CHANNEL_HISTORY_PERSISTENCE_PRIMARY_NAMESPACE,
+					CHANNEL_HISTORY_PERSISTENCE_SECONDARY_NAMESPACE,
+					store_key,
+					e
+				);
+				Error::PersistenceFailed
+			})?;
+		Ok(())
+	}
+
+	fn update_records<F: Fn(&mut ChannelRecord) -> bool>(&self, update: F) {
+		let mut locked_records = self.records.lock().unwrap();
+		for record in locked_records.values_mut() {
+			if update(record) {
+				// We'll retry persisting the record on the next update if this fails.
+				self.persist_record(record).unwrap_or_else(|e| {
+					log_error!(
+						self.logger,
+						"Failed to persist record of channel {}: {}",
+						record.channel_id,
+						e
+					)
+				});
+			}
+		}
+	}
+}
+
+impl<L: Deref> Confirm for ChannelHistoryStore<L>
+where
+	L::Target: Logger,
+{
+	fn transactions_confirmed(&self, header: &Header, txdata: &TransactionData, height: u32) {
+		let block_hash = header.block_hash();
+		for (_, tx) in txdata.iter() {
+			let txid = tx.compute_txid();
+			self.update_records(|record| {
+				let spends_funding_output = record.funding_txo.map_or(false, |funding_txo| {
+					tx.input.iter().any(|input| input.previous_output == funding_txo)
+				});
+				if !spends_funding_output || record.closing_tx_block_hash == Some(block_hash) {
+					return false;
+				}
+				record.closing_txid = Some(txid);
+				record.closing_tx_height = Some(height);
+				record.closing_tx_block_hash = Some(block_hash);
+				true
+			});
+		}
+	}
+
+	fn transaction_unconfirmed(&self, txid: &Txid) {
+		self.update_records(|record| {
+			if record.closing_txid != Some(*txid) {
+				return false;
+			}
+			record.closing_txid = None;
+			record.closing_tx_height = None;
+			record.closing_tx_block_hash = None;
+			true
+		});
+	}
+
+	fn best_block_updated(&self, _header: &Header, _height: u32) {}
+
+	fn get_relevant_txids(&self) -> Vec<(Txid, u32, Option<BlockHash>)> {
+		self.records
+			.lock()
+			.unwrap()
+			.values()
+			.filter_map(|record| {
+				Some((
+					record.closing_txid?,
+					record.closing_tx_height?,
+					record.closing_tx_block_hash,
+				))
+			})
+			.collect()
+	}
+}
+
+impl<L: Deref> Listen for ChannelHistoryStore<L>
+where
+	L::Target: Logger,
+{
+	fn filtered_block_connected(&self, header: &Header, txdata: &TransactionData, height: u32) {
+		self.transactions_confirmed(header, txdata, height);
+	}
+
+	fn block_disconnected(&self, header: &Header, _height: u32) {
+		let block_hash = header.block_hash();
+		self.update_records(|record| {
+			if record.closing_tx_block_hash != Some(block_hash) {
+				return false;
+			}
+			record.closing_txid = None;
+			record.closing_tx_height = None;
+			record.closing_tx_block_hash = None;
+			true
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use lightning::util::ser::Readable;
+	use lightning::util::test_utils::{TestLogger, TestStore};
+
+	use bitcoin::absolute::LockTime;
+	use bitcoin::block::Version;
+	use bitcoin::hashes::Hash;
+	use bitcoin::io::Cursor;
+	use bitcoin::transaction::Version as TxVersion;
+	use bitcoin::{CompactTarget, Transaction, TxIn, TxMerkleNode};
+
+	use std::str::FromStr;
+
+	#[test]
+	fn closed_channels_are_tracked() {
+		let store: Arc<DynStore> = Arc::new(TestStore::new(false));
+		let logger = Arc::new(TestLogger::new());
+		let history_store = ChannelHistoryStore::new(Vec::new(), Arc::clone(&store), logger);
+
+		let channel_id = ChannelId([42u8; 32]);
+		let user_channel_id = UserChannelId(42);
+		let counterparty_node_id = PublicKey::from_str(
+			"02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619",
+		)
+		.unwrap();
+		let funding_txid =
+			Txid::from_str("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+				.unwrap();
+		let funding_txo = OutPoint::new(funding_txid, 0);
+
+		history_store
+			.channel_opened(channel_id, user_channel_id, counterparty_node_id, funding_txo, 1000)
+			.unwrap();
+		assert!(history_store.list_closed_channels().is_empty());
+
+		// Confirming a transaction spending the funding output marks it as the closing
+		// transaction.
+		let closing_tx = Transaction {
+			version: TxVersion::TWO,
+			lock_time: LockTime::ZERO,
+			input: vec![TxIn { previous_output: funding_txo, ..Default::default() }],
+			output: Vec::new(),
+		};
+		let header = Header {
+			version: Version::from_consensus(0),
+			prev_blockhash: BlockHash::all_zeros(),
+			merkle_root: TxMerkleNode::all_zeros(),
+			time: 0,
+			bits: CompactTarget::from_consensus(0),
+			nonce: 0,
+		};
+		history_store.transactions_confirmed(&header, &[(0, &closing_tx)], 100);
+		assert_eq!(
+			history_store.get_relevant_txids(),
+			vec![(closing_tx.compute_txid(), 100, Some(header.block_hash()))]
+		);
+
+		let closure_reason = ClosureReason::LocallyInitiatedCooperativeClosure;
+		let mut expected_details = ClosedChannelDetails {
+			channel_id,
+			user_channel_id,
+			counterparty_node_id: Some(counterparty_node_id),
+			channel_capacity_sats: Some(100_000),
+			funding_txo: Some(funding_txo),
+			closing_txid: None,
+			closure_reason: Some(closure_reason.clone()),
+			final_balance_sats: Some(50_000),
+			opened_at: None,
+			closed_at: 2000,
+			is_locally_initiated: is_locally_initiated_closure(&closure_reason),
+		};
+		history_store.channel_closed(expected_details.clone()).unwrap();
+
+		// The previously tracked opening timestamp and closing transaction are retained.
+		expected_details.closing_txid = Some(closing_tx.compute_txid());
+		expected_details.opened_at = Some(1000);
+		assert!(expected_details.is_locally_initiated);
+		assert_eq!(history_store.list_closed_channels(), vec![expected_details.clone()]);
+
+		// Check we can read back what we persisted.
+		let persisted_bytes = store
+			.read(
+				CHANNEL_HISTORY_PERSISTENCE_PRIMARY_NAMESPACE,
+				CHANNEL_HISTORY_PERSISTENCE_SECONDARY_NAMESPACE,
+				&channel_id.to_string(),
+			)
+			.unwrap();
+		let deser_record = ChannelRecord::read(&mut Cursor::new(persisted_bytes)).unwrap();
+		assert_eq!(deser_record.closed_channel_details(), Some(expected_details));
+
+		// Reorging out the closing transaction forgets about it again.
+		history_store.block_disconnected(&header, 100);
+		assert_eq!(history_store.list_closed_channels()[0].closing_txid, None);
+		assert!(history_store.get_relevant_txids().is_empty());
+	}
+
+	#[test]
+	fn unfunded_channel_closures_are_not_tracked() {
+		let store: Arc<DynStore> = Arc::new(TestStore::new(false));
+		let logger = Arc::new(TestLogger::new());
+		let history_store = ChannelHistoryStore::new(Vec::new(), Arc::clone(&store), logger);
+
+		// E.g., an inbound channel request we rejected.
+		let closure_reason =
+			ClosureReason::HolderForceClosed { broadcasted_latest_txn: Some(false) };
+		let details = ClosedChannelDetails {
+			channel_id: ChannelId([42u8; 32]),
+			user_channel_id: UserChannelId(42),
+			counterparty_node_id: None,
+			channel_capacity_sats: Some(100_000),
+			funding_txo: None,
+			closing_txid: None,
+			closure_reason: Some(closure_reason.clone()),
+			final_balance_sats: None,
+			opened_at: None,
+			closed_at: 2000,
+			is_locally_initiated: is_locally_initiated_closure(&closure_reason),
+		};
+		history_store.channel_closed(details).unwrap();
+
+		assert!(history_store.list_closed_channels().is_empty());
+		assert!(store
+			.list(
+				CHANNEL_HISTORY_PERSISTENCE_PRIMARY_NAMESPACE,
+				CHANNEL_HISTORY_PERSISTENCE_SECONDARY_NAMESPACE
+			)
+			.unwrap()
+			.is_empty());
+	}
+}
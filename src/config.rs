@@ -7,6 +7,8 @@
 
 use crate::payment::SendingParameters;
 
+use lightning::impl_writeable_tlv_based_enum;
+use lightning::ln::features::ChannelTypeFeatures;
 use lightning::ln::msgs::SocketAddress;
 use lightning::routing::gossip::NodeAlias;
 use lightning::util::config::UserConfig;
@@ -27,9 +29,11 @@ const DEFAULT_PROBING_LIQUIDITY_LIMIT_MULTIPLIER: u64 = 3;
 const DEFAULT_LOG_LEVEL: LogLevel = LogLevel::Debug;
 const DEFAULT_ANCHOR_PER_CHANNEL_RESERVE_SATS: u64 = 25_000;
 
-// The 'stop gap' parameter used by BDK's wallet sync. This seems to configure the threshold
-// number of derivation indexes after which BDK stops looking for new scripts belonging to the wallet.
-pub(crate) const BDK_CLIENT_STOP_GAP: usize = 20;
+const DEFAULT_ONCHAIN_WALLET_STOP_GAP: u32 = 20;
+
+// The time in-between full scans of the on-chain wallet. In-between, we only sync the scripts the
+// wallet already revealed.
+pub(crate) const BDK_WALLET_FULL_SCAN_INTERVAL_SECS: u64 = 60 * 60 * 24;
 
 // The number of concurrent requests made against the API provider.
 pub(crate) const BDK_CLIENT_CONCURRENCY: usize = 4;
@@ -40,6 +44,40 @@ pub(crate) const DEFAULT_ESPLORA_SERVER_URL: &str = "https://blockstream.info/ap
 // The default Esplora client timeout we're using.
 pub(crate) const DEFAULT_ESPLORA_CLIENT_TIMEOUT_SECS: u64 = 10;
 
+// The time we initially back off from an Esplora server after a failed request. Doubles with each
+// consecutive failure.
+pub(crate) const ESPLORA_SERVER_BASE_BACKOFF_SECS: u64 = 10;
+
+// The maximum time we back off from an Esplora server after failed requests.
+pub(crate) const ESPLORA_SERVER_MAX_BACKOFF_SECS: u64 = 600;
+
+// The number of blocks an Esplora server's tip may lag behind the others' before we stop using it
+// when cross-checking is enabled.
+pub(crate) const ESPLORA_CROSS_CHECK_MAX_TIP_HEIGHT_DIFFERENCE: u32 = 2;
+
+// The default Electrum client timeout we're using.
+pub(crate) const DEFAULT_ELECTRUM_CLIENT_TIMEOUT_SECS: u8 = 10;
+
+// The number of times the Electrum client retries a failed request.
+pub(crate) const ELECTRUM_CLIENT_NUM_RETRIES: u8 = 3;
+
+// The number of requests the BDK Electrum client batches together when syncing the wallet.
+pub(crate) const BDK_ELECTRUM_CLIENT_BATCH_SIZE: usize = 5;
+
+// The time in-between chain polling attempts when sourcing chain data from a `bitcoind` RPC
+// interface or BIP 157 peers.
+pub(crate) const CHAIN_POLLING_INTERVAL_SECS: u64 = 2;
+
+// The timeout after which we give up waiting on a BIP 157 peer to respond.
+pub(crate) const CBF_PEER_TIMEOUT_SECS: u64 = 10;
+
+// The maximum number of BIP 157 peers we stay connected to at the same time.
+pub(crate) const CBF_MAX_PEERS: usize = 4;
+
+// The time in-between attempts to connect to further BIP 157 peers while we're already connected
+// to some.
+pub(crate) const CBF_PEER_RECONNECTION_INTERVAL_SECS: u64 = 60;
+
 // The timeout after which we abandon retrying failed payments.
 pub(crate) const LDK_PAYMENT_RETRY_TIMEOUT: Duration = Duration::from_secs(10);
 
@@ -92,14 +130,17 @@ pub(crate) const WALLET_KEYS_SEED_LEN: usize = 64;
 /// | `onchain_wallet_sync_interval_secs`    | 80                 |
 /// | `wallet_sync_interval_secs`            | 30                 |
 /// | `fee_rate_cache_update_interval_secs`  | 600                |
+/// | `onchain_wallet_stop_gap`              | 20                 |
 /// | `trusted_peers_0conf`                  | []                 |
 /// | `probing_liquidity_limit_multiplier`   | 3                  |
 /// | `log_level`                            | Debug              |
 /// | `anchor_channels_config`               | Some(..)           |
 /// | `sending_parameters`                   | None               |
+/// | `inbound_channel_policy`               | Default            |
+/// | `commit_upfront_shutdown_script`       | true               |
 ///
-/// See [`AnchorChannelsConfig`] and [`SendingParameters`] for more information regarding their
-/// respective default values.
+/// See [`AnchorChannelsConfig`], [`SendingParameters`], and [`InboundChannelPolicy`] for more
+/// information regarding their respective default values.
 ///
 /// [`Node`]: crate::Node
 pub struct Config {
@@ -135,6 +176,15 @@ pub struct Config {
 	///
 	/// **Note:** A minimum of 10 seconds is always enforced.
 	pub fee_rate_cache_update_interval_secs: u64,
+	/// The number of consecutive unused addresses after which we stop looking for further
+	/// transactions belonging to the on-chain wallet.
+	///
+	/// When sourcing chain data via Bitcoin Core RPC or compact block filters, this is the number
+	/// of addresses we keep watching beyond the last used one.
+	///
+	/// **Note:** If you restore a wallet that previously handed out more unused addresses in a row
+	/// than this value, you'll need to increase it to discover all of its funds.
+	pub onchain_wallet_stop_gap: u32,
 	/// A list of peers that we allow to establish zero confirmation channels to us.
 	///
 	/// **Note:** Allowing payments via zero-confirmation channels is potentially insecure if the
@@ -175,6 +225,20 @@ pub struct Config {
 	/// **Note:** If unset, default parameters will be used, and you will be able to override the
 	/// parameters on a per-payment basis in the corresponding method calls.
 	pub sending_parameters: Option<SendingParameters>,
+	/// The policy determining which inbound channels we accept.
+	///
+	/// Please refer to [`InboundChannelPolicy`] for further information.
+	pub inbound_channel_policy: InboundChannelPolicy,
+	/// Whether we commit to paying out our balance to our on-chain wallet when opening or
+	/// accepting channels.
+	///
+	/// Committing to a shutdown script upfront ensures our funds will end up in our wallet even if
+	/// our node gets compromised before the channel is closed. However, it prevents paying out our
+	/// balance to a different address via [`ChannelCloseOptions::destination_address`]. This only
+	/// applies to channels opened or accepted while the option is set.
+	///
+	/// [`ChannelCloseOptions::destination_address`]: crate::ChannelCloseOptions::destination_address
+	pub commit_upfront_shutdown_script: bool,
 }
 
 impl Default for Config {
@@ -187,12 +251,15 @@ impl Default for Config {
 			onchain_wallet_sync_interval_secs: DEFAULT_BDK_WALLET_SYNC_INTERVAL_SECS,
 			wallet_sync_interval_secs: DEFAULT_LDK_WALLET_SYNC_INTERVAL_SECS,
 			fee_rate_cache_update_interval_secs: DEFAULT_FEE_RATE_CACHE_UPDATE_INTERVAL_SECS,
+			onchain_wallet_stop_gap: DEFAULT_ONCHAIN_WALLET_STOP_GAP,
 			trusted_peers_0conf: Vec::new(),
 			probing_liquidity_limit_multiplier: DEFAULT_PROBING_LIQUIDITY_LIMIT_MULTIPLIER,
 			log_level: DEFAULT_LOG_LEVEL,
 			anchor_channels_config: Some(AnchorChannelsConfig::default()),
 			sending_parameters: None,
 			node_alias: None,
+			inbound_channel_policy: InboundChannelPolicy::default(),
+			commit_upfront_shutdown_script: true,
 		}
 	}
 }
@@ -268,6 +335,209 @@ impl Default for AnchorChannelsConfig {
 	}
 }
 
+/// A feature that may be negotiated as part of a channel's type.
+///
+/// See [BOLT 2] for more technical details on channel types.
+///
+/// [BOLT 2]: https://github.com/lightning/bolts/blob/master/02-peer-protocol.md#channel-establishment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelTypeFeature {
+	/// The `option_static_remotekey` feature.
+	StaticRemoteKey,
+	/// The `option_anchors_zero_fee_htlc_tx` feature, i.e., the channel is an Anchor channel.
+	AnchorsZeroFeeHtlcTx,
+	/// The `option_scid_alias` feature, i.e., the channel's real short channel id is never
+	/// revealed to third parties.
+	ScidPrivacy,
+	/// The `option_zeroconf` feature, i.e., the channel may be used before its funding
+	/// transaction confirmed.
+	ZeroConf,
+}
+
+impl ChannelTypeFeature {
+	pub(crate) fn from_channel_type(channel_type: &ChannelTypeFeatures) -> Vec<Self> {
+		[Self::StaticRemoteKey, Self::AnchorsZeroFeeHtlcTx, Self::ScidPrivacy, Self::ZeroConf]
+			.into_iter()
+			.filter(|f| f.is_required_by(channel_type))
+			.collect()
+	}
+
+	fn is_required_by(&self, channel_type: &ChannelTypeFeatures) -> bool {
+		match self {
+			Self::StaticRemoteKey => channel_type.requires_static_remote_key(),
+			Self::AnchorsZeroFeeHtlcTx => channel_type.requires_anchors_zero_fee_htlc_tx(),
+			Self::ScidPrivacy => channel_type.requires_scid_privacy(),
+			Self::ZeroConf => channel_type.requires_zero_conf(),
+		}
+	}
+}
+
+impl_writeable_tlv_based_enum!(ChannelTypeFeature,
+	(0, StaticRemoteKey) => {},
+	(2, AnchorsZeroFeeHtlcTx) => {},
+	(4, ScidPrivacy) => {},
+	(6, ZeroConf) => {}
+);
+
+/// The policy determining which inbound channels we accept.
+///
+/// Inbound channel requests failing any of the configured checks are rejected. Note that
+/// independently of this policy, inbound Anchor channels are still subject to the on-chain
+/// reserve requirements configured via [`AnchorChannelsConfig`].
+///
+/// ### Defaults
+///
+/// | Parameter                       | Value  |
+/// |---------------------------------|--------|
+/// | `min_funding_sats`              | None   |
+/// | `max_funding_sats`              | None   |
+/// | `max_channels_per_peer`         | None   |
+/// | `max_total_channels`            | None   |
+/// | `allowed_peers`                 | None   |
+/// | `denied_peers`                  | []     |
+/// | `accept_announced_channels`     | true   |
+/// | `accept_unannounced_channels`   | true   |
+/// | `required_channel_features`     | []     |
+/// | `require_manual_approval`       | false  |
+#[derive(Debug, Clone)]
+pub struct InboundChannelPolicy {
+	/// The minimum channel size we accept, in satoshis.
+	///
+	/// If set to `None`, no lower bound is enforced.
+	pub min_funding_sats: Option<u64>,
+	/// The maximum channel size we accept, in satoshis.
+	///
+	/// If set to `None`, no upper bound is enforced.
+	pub max_funding_sats: Option<u64>,
+	/// The maximum number of channels we maintain with any single peer, including the
+	/// requested one.
+	///
+	/// If set to `None`, the number of channels per peer is not limited.
+	pub max_channels_per_peer: Option<u32>,
+	/// The maximum number of channels we maintain in total, including the requested one.
+	///
+	/// If set to `None`, the total number of channels is not limited.
+	pub max_total_channels: Option<u32>,
+	/// A list of peers from which we exclusively accept inbound channels.
+	///
+	/// If set to `None`, channels from any peer not listed in
+	/// [`InboundChannelPolicy::denied_peers`] are accepted.
+	pub allowed_peers: Option<Vec<PublicKey>>,
+	/// A list of peers from which we never accept inbound channels.
+	pub denied_peers: Vec<PublicKey>,
+	/// Whether we accept channels the counterparty wants to announce to the gossip network.
+	///
+	/// **Note:** Announced channels are always rejected if [`Config::listening_addresses`] or
+	/// [`Config::node_alias`] are unset.
+	pub accept_announced_channels: bool,
+	/// Whether we accept channels that won't be announced to the gossip network.
+	pub accept_unannounced_channels: bool,
+	/// The channel type features any accepted channel is required to negotiate.
+	pub required_channel_features: Vec<ChannelTypeFeature>,
+	/// Whether inbound channel requests need to be approved manually.
+	///
+	/// If set, any channel request passing the remaining checks will be surfaced as an
+	/// [`Event::ChannelOpenRequest`] rather than being accepted automatically. The request then
+	/// needs to be handled via [`Node::accept_inbound_channel`] or
+	/// [`Node::reject_inbound_channel`].
+	///
+	/// [`Event::ChannelOpenRequest`]: crate::Event::ChannelOpenRequest
+	/// [`Node::accept_inbound_channel`]: crate::Node::accept_inbound_channel
+	/// [`Node::reject_inbound_channel`]: crate::Node::reject_inbound_channel
+	pub require_manual_approval: bool,
+}
+
+impl Default for InboundChannelPolicy {
+	fn default() -> Self {
+		Self {
+			min_funding_sats: None,
+			max_funding_sats: None,
+			max_channels_per_peer: None,
+			max_total_channels: None,
+			allowed_peers: None,
+			denied_peers: Vec::new(),
+			accept_announced_channels: true,
+			accept_unannounced_channels: true,
+			required_channel_features: Vec::new(),
+			require_manual_approval: false,
+		}
+	}
+}
+
+impl InboundChannelPolicy {
+	/// Checks the given inbound channel request against the policy, returning the reason for
+	/// rejection if it violates any of the configured checks.
+	///
+	/// The channel counts are expected to *exclude* the requested channel.
+	pub(crate) fn check_channel_request(
+		&self, counterparty_node_id: &PublicKey, funding_sats: u64,
+		channel_type: &ChannelTypeFeatures, is_announced: bool, num_peer_channels: usize,
+		num_total_channels: usize,
+	) -> Result<(), String> {
+		if self.denied_peers.contains(counterparty_node_id) {
+			return Err("the peer being denied".to_string());
+		}
+
+		if let Some(allowed_peers) = self.allowed_peers.as_ref() {
+			if !allowed_peers.contains(counterparty_node_id) {
+				return Err("the peer not being allowed".to_string());
+			}
+		}
+
+		if let Some(min_funding_sats) = self.min_funding_sats {
+			if funding_sats < min_funding_sats {
+				return Err(format!(
+					"the channel size of {}sats being below the minimum of {}sats",
+					funding_sats, min_funding_sats
+				));
+			}
+		}
+
+		if let Some(max_funding_sats) = self.max_funding_sats {
+			if funding_sats > max_funding_sats {
+				return Err(format!(
+					"the channel size of {}sats exceeding the maximum of {}sats",
+					funding_sats, max_funding_sats
+				));
+			}
+		}
+
+		if is_announced && !self.accept_announced_channels {
+			return Err("announced channels not being accepted".to_string());
+		}
+
+		if !is_announced && !self.accept_unannounced_channels {
+			return Err("unannounced channels not being accepted".to_string());
+		}
+
+		if let Some(feature) =
+			self.required_channel_features.iter().find(|f| !f.is_required_by(channel_type))
+		{
+			return Err(format!("the channel type lacking the required {:?} feature", feature));
+		}
+
+		if let Some(max_channels_per_peer) = self.max_channels_per_peer {
+			if num_peer_channels >= max_channels_per_peer as usize {
+				return Err(format!(
+					"the maximum of {} channels per peer being reached",
+					max_channels_per_peer
+				));
+			}
+		}
+
+		if let Some(max_total_channels) = self.max_total_channels {
+			if num_total_channels >= max_total_channels as usize {
+				return Err(format!(
+					"the maximum of {} channels in total being reached",
+					max_total_channels
+				));
+			}
+		}
+
+		Ok(())
+	}
+}
+
 /// Returns a [`Config`] object populated with default values.
 ///
 /// See the documentation of [`Config`] for more information on the used defaults.
@@ -294,6 +564,8 @@ pub(crate) fn default_user_config(config: &Config) -> UserConfig {
 	user_config.manually_accept_inbound_channels = true;
 	user_config.channel_handshake_config.negotiate_anchors_zero_fee_htlc_tx =
 		config.anchor_channels_config.is_some();
+	user_config.channel_handshake_config.commit_upfront_shutdown_pubkey =
+		config.commit_upfront_shutdown_script;
 
 	if !may_announce_channel(config) {
 		user_config.accept_forwards_to_priv_channels = false;
@@ -309,8 +581,12 @@ mod tests {
 	use std::str::FromStr;
 
 	use super::may_announce_channel;
+	use super::ChannelTypeFeature;
+	use super::ChannelTypeFeatures;
 	use super::Config;
+	use super::InboundChannelPolicy;
 	use super::NodeAlias;
+	use super::PublicKey;
 	use super::SocketAddress;
 
 	#[test]
@@ -340,4 +616,63 @@ mod tests {
 		}
 		assert!(may_announce_channel(&node_config));
 	}
+
+	#[test]
+	fn inbound_channel_policy_checks() {
+		let peer_a = PublicKey::from_str(
+			"02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619",
+		)
+		.unwrap();
+		let peer_b = PublicKey::from_str(
+			"0324653eac434488002cc06bbfb7f10fe18991e35f9fe4302dbea6d2353dc0ab1c",
+		)
+		.unwrap();
+		let mut anchor_type = ChannelTypeFeatures::only_static_remote_key();
+		anchor_type.set_anchors_zero_fee_htlc_tx_required();
+		let legacy_type = ChannelTypeFeatures::only_static_remote_key();
+
+		// The default policy accepts everything.
+		let mut policy = InboundChannelPolicy::default();
+		assert!(policy.check_channel_request(&peer_a, 1000, &legacy_type, true, 10, 100).is_ok());
+		assert!(policy.check_channel_request(&peer_a, 1000, &legacy_type, false, 10, 100).is_ok());
+
+		policy.min_funding_sats = Some(100_000);
+		policy.max_funding_sats = Some(1_000_000);
+		assert!(policy.check_channel_request(&peer_a, 99_999, &anchor_type, false, 0, 0).is_err());
+		assert!(policy.check_channel_request(&peer_a, 100_000, &anchor_type, false, 0, 0).is_ok());
+		assert!(policy
+			.check_channel_request(&peer_a, 1_000_000, &anchor_type, false, 0, 0)
+			.is_ok());
+		assert!(policy
+			.check_channel_request(&peer_a, 1_000_001, &anchor_type, false, 0, 0)
+			.is_err());
+
+		policy.max_channels_per_peer = Some(1);
+		policy.max_total_channels = Some(2);
+		assert!(policy.check_channel_request(&peer_a, 100_000, &anchor_type, false, 1, 1).is_err());
+		assert!(policy.check_channel_request(&peer_a, 100_000, &anchor_type, false, 0, 1).is_ok());
+		assert!(policy.check_channel_request(&peer_a, 100_000, &anchor_type, false, 0, 2).is_err());
+
+		policy.accept_announced_channels = false;
+		assert!(policy.check_channel_request(&peer_a, 100_000, &anchor_type, true, 0, 0).is_err());
+		assert!(policy.check_channel_request(&peer_a, 100_000, &anchor_type, false, 0, 0).is_ok());
+		policy.accept_announced_channels = true;
+		policy.accept_unannounced_channels = false;
+		assert!(policy.check_channel_request(&peer_a, 100_000, &anchor_type, true, 0, 0).is_ok());
+		assert!(policy.check_channel_request(&peer_a, 100_000, &anchor_type, false, 0, 0).is_err());
+		policy.accept_unannounced_channels = true;
+
+		policy.required_channel_features = vec![ChannelTypeFeature::AnchorsZeroFeeHtlcTx];
+		assert!(policy.check_channel_request(&peer_a, 100_000, &anchor_type, false, 0, 0).is_ok());
+		assert!(policy.check_channel_request(&peer_a, 100_000, &legacy_type, false, 0, 0).is_err());
+
+		policy.denied_peers = vec![peer_b];
+		assert!(policy.check_channel_request(&peer_a, 100_000, &anchor_type, false, 0, 0).is_ok());
+		assert!(policy.check_channel_request(&peer_b, 100_000, &anchor_type, false, 0, 0).is_err());
+
+		policy.denied_peers = Vec::new();
+		policy.allowed_peers = Some(vec![peer_b]);
+		assert!(policy.check_channel_request(&peer_a, 100_000, &anchor_type, false, 0, 0).is_err());
+		assert!(policy.check_channel_request(&peer_b, 100_000, &anchor_type, false, 0, 0).is_ok());
+	}
 }
@@ -103,6 +103,14 @@ pub enum Error {
 	InvalidQuantity,
 	/// The given node alias is invalid.
 	InvalidNodeAlias,
+	/// The given PSBT is invalid.
+	InvalidPsbt,
+	/// The given transaction is invalid.
+	InvalidTransaction,
+	/// The given label is invalid.
+	InvalidLabel,
+	/// The given fee rate is invalid.
+	InvalidFeeRate,
 	/// A payment with the given hash has already been initiated.
 	DuplicatePayment,
 	/// The provided offer was denonminated in an unsupported currency.
@@ -113,6 +121,14 @@ pub enum Error {
 	LiquiditySourceUnavailable,
 	/// The given operation failed due to the LSP's required opening fee being too high.
 	LiquidityFeeTooHigh,
+	/// The given transaction could not be found in the on-chain wallet.
+	TransactionNotFound,
+	/// The given transaction has already been confirmed.
+	TransactionAlreadyConfirmed,
+	/// The given UTXO could not be found in the on-chain wallet or is already spent.
+	UtxoNotFound,
+	/// The given UTXO is locked and can't be spent.
+	UtxoLocked,
 }
 
 impl fmt::Display for Error {
@@ -168,6 +184,10 @@ impl fmt::Display for Error {
 			Self::InvalidUri => write!(f, "The given URI is invalid."),
 			Self::InvalidQuantity => write!(f, "The given quantity is invalid."),
 			Self::InvalidNodeAlias => write!(f, "The given node alias is invalid."),
+			Self::InvalidPsbt => write!(f, "The given PSBT is invalid."),
+			Self::InvalidTransaction => write!(f, "The given transaction is invalid."),
+			Self::InvalidLabel => write!(f, "The given label is invalid."),
+			Self::InvalidFeeRate => write!(f, "The given fee rate is invalid."),
 			Self::DuplicatePayment => {
 				write!(f, "A payment with the given hash has already been initiated.")
 			},
@@ -183,6 +203,19 @@ impl fmt::Display for Error {
 			Self::LiquidityFeeTooHigh => {
 				write!(f, "The given operation failed due to the LSP's required opening fee being too high.")
 			},
+			Self::TransactionNotFound => {
+				write!(f, "The given transaction could not be found in the on-chain wallet.")
+			},
+			Self::TransactionAlreadyConfirmed => {
+				write!(f, "The given transaction has already been confirmed.")
+			},
+			Self::UtxoNotFound => {
+				write!(
+					f,
+					"The given UTXO could not be found in the on-chain wallet or is already spent."
+				)
+			},
+			Self::UtxoLocked => write!(f, "The given UTXO is locked and can't be spent."),
 		}
 	}
 }
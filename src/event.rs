@@ -5,15 +5,20 @@
 // http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
 // accordance with one or both of these licenses.
 
-use crate::types::{DynStore, Sweeper, Wallet};
+use crate::types::{ChainMonitor, DynStore, Sweeper, Wallet};
 
 use crate::{
 	hex_utils, BumpTransactionEventHandler, ChannelManager, Config, Error, Graph, PeerInfo,
 	PeerStore, UserChannelId,
 };
 
+use crate::channel_history::{
+	is_locally_initiated_closure, ChannelHistoryStore, ClosedChannelDetails,
+};
+use crate::config::{may_announce_channel, ChannelTypeFeature};
 use crate::connection::ConnectionManager;
 use crate::fee_estimator::ConfirmationTarget;
+use crate::wallet::{ChannelFundingParams, PendingChannelFunding};
 
 use crate::payment::store::{
 	PaymentDetails, PaymentDetailsUpdate, PaymentDirection, PaymentKind, PaymentStatus,
@@ -31,6 +36,7 @@ use lightning::events::{ClosureReason, PaymentPurpose, ReplayEvent};
 use lightning::events::{Event as LdkEvent, PaymentFailureReason};
 use lightning::impl_writeable_tlv_based_enum;
 use lightning::ln::channelmanager::PaymentId;
+use lightning::ln::features::ChannelTypeFeatures;
 use lightning::ln::types::ChannelId;
 use lightning::ln::PaymentHash;
 use lightning::routing::gossip::NodeId;
@@ -41,16 +47,16 @@ use lightning_liquidity::lsps2::utils::compute_opening_fee;
 
 use bitcoin::blockdata::locktime::absolute::LockTime;
 use bitcoin::secp256k1::PublicKey;
-use bitcoin::{Amount, OutPoint};
+use bitcoin::{Amount, OutPoint, ScriptBuf};
 
 use rand::{thread_rng, Rng};
 
 use core::future::Future;
 use core::task::{Poll, Waker};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::ops::Deref;
 use std::sync::{Arc, Condvar, Mutex, RwLock};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// An event emitted by [`Node`], which should be handled by the user.
 ///
@@ -156,6 +162,71 @@ pub enum Event {
 		/// This will be `None` for events serialized by LDK Node v0.2.1 and prior.
 		reason: Option<ClosureReason>,
 	},
+	/// The funding transaction of a channel we initiated needs to be signed externally.
+	///
+	/// This will only be emitted if the on-chain wallet is watch-only, see
+	/// [`Builder::set_watch_only_wallet`]. The unsigned funding PSBT can be retrieved via
+	/// [`OnchainPayment::funding_psbt`] and, once signed, needs to be handed back via
+	/// [`OnchainPayment::finalize_and_broadcast_psbt`]. If this doesn't happen in time, the
+	/// channel will be closed and a [`Event::ChannelClosed`] will be emitted.
+	///
+	/// [`Builder::set_watch_only_wallet`]: crate::Builder::set_watch_only_wallet
+	/// [`OnchainPayment::funding_psbt`]: crate::payment::OnchainPayment::funding_psbt
+	/// [`OnchainPayment::finalize_and_broadcast_psbt`]: crate::payment::OnchainPayment::finalize_and_broadcast_psbt
+	FundingPsbtReady {
+		/// The `user_channel_id` of the channel.
+		user_channel_id: UserChannelId,
+		/// The `node_id` of the channel counterparty.
+		counterparty_node_id: PublicKey,
+		/// The value of the channel, in satoshis.
+		channel_value_sats: u64,
+	},
+	/// A channel opened via [`Node::open_externally_funded_channel`] or
+	/// [`Node::open_externally_funded_announced_channel`] is ready to be funded.
+	///
+	/// The user is expected to create a transaction paying exactly `channel_value_sats` to
+	/// `output_script`, and hand it back via [`Node::fund_channel_with_transaction`] or
+	/// [`Node::fund_channel_with_psbt`]. The funding transaction must not be broadcast by the user,
+	/// as we'll do so once it's safe. If the funding transaction isn't supplied in time, the
+	/// channel will be closed and a [`Event::ChannelClosed`] will be emitted.
+	///
+	/// [`Node::open_externally_funded_channel`]: crate::Node::open_externally_funded_channel
+	/// [`Node::open_externally_funded_announced_channel`]: crate::Node::open_externally_funded_announced_channel
+	/// [`Node::fund_channel_with_transaction`]: crate::Node::fund_channel_with_transaction
+	/// [`Node::fund_channel_with_psbt`]: crate::Node::fund_channel_with_psbt
+	FundingTransactionRequired {
+		/// The `user_channel_id` of the channel.
+		user_channel_id: UserChannelId,
+		/// The `node_id` of the channel counterparty.
+		counterparty_node_id: PublicKey,
+		/// The script the funding output needs to pay to.
+		output_script: ScriptBuf,
+		/// The value of the channel, i.e., the amount the funding output needs to pay, in
+		/// satoshis.
+		channel_value_sats: u64,
+	},
+	/// A peer requested to open a channel to us that awaits manual approval.
+	///
+	/// This will only be emitted if [`InboundChannelPolicy::require_manual_approval`] is set. The
+	/// request needs to be handled via [`Node::accept_inbound_channel`] or
+	/// [`Node::reject_inbound_channel`]. If neither happens in time, the counterparty will
+	/// eventually give up on the channel.
+	///
+	/// [`InboundChannelPolicy::require_manual_approval`]: crate::InboundChannelPolicy::require_manual_approval
+	/// [`Node::accept_inbound_channel`]: crate::Node::accept_inbound_channel
+	/// [`Node::reject_inbound_channel`]: crate::Node::reject_inbound_channel
+	ChannelOpenRequest {
+		/// The temporary id of the requested channel.
+		temporary_channel_id: ChannelId,
+		/// The `node_id` of the channel counterparty.
+		counterparty_node_id: PublicKey,
+		/// The value of the requested channel, in satoshis.
+		funding_sats: u64,
+		/// The features of the channel type the counterparty proposed.
+		channel_type: Vec<ChannelTypeFeature>,
+		/// Whether the counterparty wants to announce the channel to the gossip network.
+		is_announced: bool,
+	},
 }
 
 impl_writeable_tlv_based_enum!(Event,
@@ -197,6 +268,24 @@ impl_writeable_tlv_based_enum!(Event,
 		(2, payment_id, required),
 		(4, claimable_amount_msat, required),
 		(6, claim_deadline, option),
+	},
+	(7, FundingPsbtReady) => {
+		(0, user_channel_id, required),
+		(2, counterparty_node_id, required),
+		(4, channel_value_sats, required),
+	},
+	(8, FundingTransactionRequired) => {
+		(0, user_channel_id, required),
+		(2, counterparty_node_id, required),
+		(4, output_script, required),
+		(6, channel_value_sats, required),
+	},
+	(9, ChannelOpenRequest) => {
+		(0, temporary_channel_id, required),
+		(2, counterparty_node_id, required),
+		(4, funding_sats, required),
+		(6, channel_type, required_vec),
+		(8, is_announced, required),
 	}
 );
 
@@ -354,6 +443,66 @@ impl Future for EventFuture {
 	}
 }
 
+/// An inbound channel request, as tracked while awaiting manual approval.
+#[derive(Clone, Debug)]
+pub(crate) struct InboundChannelRequest {
+	pub counterparty_node_id: PublicKey,
+	pub funding_sats: u64,
+	pub channel_type: ChannelTypeFeatures,
+	pub is_announced: bool,
+}
+
+impl InboundChannelRequest {
+	/// Checks whether we can accept the request given our config and the current state of our
+	/// channels and on-chain funds, returning the reason for rejection otherwise.
+	pub(crate) fn check(
+		&self, channel_manager: &ChannelManager, wallet: &Wallet, config: &Config,
+	) -> Result<(), String> {
+		if self.is_announced && !may_announce_channel(config) {
+			return Err("us not being configured to announce channels".to_string());
+		}
+
+		let channels = channel_manager.list_channels();
+		let num_peer_channels =
+			channels.iter().filter(|c| c.counterparty.node_id == self.counterparty_node_id).count();
+		config.inbound_channel_policy.check_channel_request(
+			&self.counterparty_node_id,
+			self.funding_sats,
+			&self.channel_type,
+			self.is_announced,
+			num_peer_channels,
+			channels.len(),
+		)?;
+
+		if self.channel_type.requires_anchors_zero_fee_htlc_tx() {
+			let anchor_channels_config = config
+				.anchor_channels_config
+				.as_ref()
+				.ok_or_else(|| "Anchor channels being disabled".to_string())?;
+
+			let cur_anchor_reserve_sats =
+				crate::total_anchor_channels_reserve_sats(channel_manager, config);
+			let spendable_amount_sats =
+				wallet.get_spendable_amount_sats(cur_anchor_reserve_sats).unwrap_or(0);
+
+			let required_amount_sats = if anchor_channels_config
+				.trusted_peers_no_reserve
+				.contains(&self.counterparty_node_id)
+			{
+				0
+			} else {
+				anchor_channels_config.per_channel_reserve_sats
+			};
+
+			if spendable_amount_sats < required_amount_sats {
+				return Err("insufficient available on-chain reserves".to_string());
+			}
+		}
+
+		Ok(())
+	}
+}
+
 pub(crate) struct EventHandler<L: Deref + Clone + Sync + Send + 'static>
 where
 	L::Target: Logger,
@@ -363,10 +512,15 @@ where
 	bump_tx_event_handler: Arc<BumpTransactionEventHandler>,
 	channel_manager: Arc<ChannelManager>,
 	connection_manager: Arc<ConnectionManager<L>>,
+	chain_monitor: Arc<ChainMonitor>,
 	output_sweeper: Arc<Sweeper>,
 	network_graph: Arc<Graph>,
 	payment_store: Arc<PaymentStore<L>>,
+	channel_history_store: Arc<ChannelHistoryStore<L>>,
 	peer_store: Arc<PeerStore<L>>,
+	channel_funding_params: Arc<Mutex<HashMap<u128, ChannelFundingParams>>>,
+	pending_channel_fundings: Arc<Mutex<HashMap<u128, PendingChannelFunding>>>,
+	inbound_channel_requests: Arc<Mutex<HashMap<ChannelId, InboundChannelRequest>>>,
 	runtime: Arc<RwLock<Option<Arc<tokio::runtime::Runtime>>>>,
 	logger: L,
 	config: Arc<Config>,
@@ -380,8 +534,12 @@ where
 		event_queue: Arc<EventQueue<L>>, wallet: Arc<Wallet>,
 		bump_tx_event_handler: Arc<BumpTransactionEventHandler>,
 		channel_manager: Arc<ChannelManager>, connection_manager: Arc<ConnectionManager<L>>,
-		output_sweeper: Arc<Sweeper>, network_graph: Arc<Graph>,
-		payment_store: Arc<PaymentStore<L>>, peer_store: Arc<PeerStore<L>>,
+		chain_monitor: Arc<ChainMonitor>, output_sweeper: Arc<Sweeper>, network_graph: Arc<Graph>,
+		payment_store: Arc<PaymentStore<L>>, channel_history_store: Arc<ChannelHistoryStore<L>>,
+		peer_store: Arc<PeerStore<L>>,
+		channel_funding_params: Arc<Mutex<HashMap<u128, ChannelFundingParams>>>,
+		pending_channel_fundings: Arc<Mutex<HashMap<u128, PendingChannelFunding>>>,
+		inbound_channel_requests: Arc<Mutex<HashMap<ChannelId, InboundChannelRequest>>>,
 		runtime: Arc<RwLock<Option<Arc<tokio::runtime::Runtime>>>>, logger: L, config: Arc<Config>,
 	) -> Self {
 		Self {
@@ -390,16 +548,40 @@ where
 			bump_tx_event_handler,
 			channel_manager,
 			connection_manager,
+			chain_monitor,
 			output_sweeper,
 			network_graph,
 			payment_store,
+			channel_history_store,
 			peer_store,
+			channel_funding_params,
+			pending_channel_fundings,
+			inbound_channel_requests,
 			logger,
 			runtime,
 			config,
 		}
 	}
 
+	fn fail_funding_generation(
+		&self, temporary_channel_id: &ChannelId, counterparty_node_id: &PublicKey,
+	) {
+		self.channel_manager
+			.force_close_without_broadcasting_txn(
+				temporary_channel_id,
+				counterparty_node_id,
+				"Failed to create funding transaction".to_string(),
+			)
+			.unwrap_or_else(|e| {
+				log_error!(
+					self.logger,
+					"Failed to force close channel after funding generation failed: {:?}",
+					e
+				);
+				panic!("Failed to force close channel after funding generation failed");
+			});
+	}
+
 	pub async fn handle_event(&self, event: LdkEvent) -> Result<(), ReplayEvent> {
 		match event {
 			LdkEvent::FundingGenerationReady {
@@ -407,22 +589,192 @@ where
 				counterparty_node_id,
 				channel_value_satoshis,
 				output_script,
-				..
+				user_channel_id,
 			} => {
 				// Construct the raw transaction with the output that is paid the amount of the
 				// channel.
 				let confirmation_target = ConfirmationTarget::ChannelFunding;
+				let funding_params = self
+					.channel_funding_params
+					.lock()
+					.unwrap()
+					.get(&user_channel_id)
+					.cloned()
+					.unwrap_or_default();
 
 				// We set nLockTime to the current height to discourage fee sniping.
 				let cur_height = self.channel_manager.current_best_block().height;
 				let locktime = LockTime::from_height(cur_height).unwrap_or(LockTime::ZERO);
 
-				// Sign the final funding transaction and broadcast it.
 				let channel_amount = Amount::from_sat(channel_value_satoshis);
+
+				// If the channel is part of a batch, we only fund it once all channels of the batch
+				// are ready.
+				if let Some(funding_batch) = funding_params.funding_batch.as_ref() {
+					let mut locked_batch = funding_batch.lock().unwrap();
+					if locked_batch.failed {
+						log_error!(
+							self.logger,
+							"Abandoning channel with user_channel_id {} as its funding batch failed",
+							user_channel_id
+						);
+						self.fail_funding_generation(&temporary_channel_id, &counterparty_node_id);
+						return Ok(());
+					}
+
+					locked_batch.ready_channels.insert(
+						user_channel_id,
+						(temporary_channel_id, counterparty_node_id, output_script, channel_amount),
+					);
+					if locked_batch.ready_channels.len() < locked_batch.user_channel_ids.len() {
+						return Ok(());
+					}
+
+					let outputs = locked_batch
+						.ready_channels
+						.values()
+						.map(|(_, _, script, amount)| (script.clone(), *amount))
+						.collect();
+					match self.wallet.create_funding_transaction(
+						outputs,
+						confirmation_target,
+						funding_params.fee_rate,
+						funding_params.utxo_selection.as_ref(),
+						locktime,
+					) {
+						Ok(final_tx) => {
+							let channels = locked_batch
+								.ready_channels
+								.values()
+								.map(|(channel_id, node_id, _, _)| (channel_id, node_id))
+								.collect::<Vec<_>>();
+							match self
+								.channel_manager
+								.batch_funding_transaction_generated(&channels, final_tx)
+							{
+								Ok(()) => {},
+								Err(APIError::APIMisuseError { err }) => {
+									log_error!(
+										self.logger,
+										"Panicking due to APIMisuseError: {}",
+										err
+									);
+									panic!("APIMisuseError: {}", err);
+								},
+								Err(err) => {
+									log_error!(
+										self.logger,
+										"Failed to process batch funding transaction: {:?}",
+										err
+									)
+								},
+							}
+
+							// From here on LDK takes care of the batch, including closing all of
+							// its channels if one of them fails.
+							let mut locked_funding_params =
+								self.channel_funding_params.lock().unwrap();
+							for id in &locked_batch.user_channel_ids {
+								locked_funding_params.remove(id);
+							}
+						},
+						Err(err) => {
+							log_error!(
+								self.logger,
+								"Failed to create batch funding transaction: {}",
+								err
+							);
+							locked_batch.failed = true;
+							for (channel_id, node_id, _, _) in
+								locked_batch.ready_channels.drain().map(|(_, c)| c)
+							{
+								self.fail_funding_generation(&channel_id, &node_id);
+							}
+						},
+					}
+					return Ok(());
+				}
+
+				// If the user asked to fund the channel themselves, we hand them the funding output
+				// and continue once they supply the funding transaction.
+				if funding_params.external_funding {
+					let pending_funding = PendingChannelFunding {
+						temporary_channel_id,
+						counterparty_node_id,
+						output_script: output_script.clone(),
+						channel_value_sats: channel_value_satoshis,
+						psbt: None,
+					};
+					self.pending_channel_fundings
+						.lock()
+						.unwrap()
+						.insert(user_channel_id, pending_funding);
+
+					self.event_queue
+						.add_event(Event::FundingTransactionRequired {
+							user_channel_id: UserChannelId(user_channel_id),
+							counterparty_node_id,
+							output_script,
+							channel_value_sats: channel_value_satoshis,
+						})
+						.unwrap_or_else(|e| {
+							log_error!(self.logger, "Failed to push to event queue: {}", e);
+							panic!("Failed to push to event queue");
+						});
+					return Ok(());
+				}
+
+				// If we can't sign ourselves, we hand the funding PSBT to the user to be signed
+				// externally, and continue once it's handed back.
+				if self.wallet.is_watch_only() {
+					match self.wallet.create_funding_psbt(
+						vec![(output_script.clone(), channel_amount)],
+						confirmation_target,
+						funding_params.fee_rate,
+						funding_params.utxo_selection.as_ref(),
+						locktime,
+					) {
+						Ok(psbt) => {
+							let pending_funding = PendingChannelFunding {
+								temporary_channel_id,
+								counterparty_node_id,
+								output_script,
+								channel_value_sats: channel_value_satoshis,
+								psbt: Some(psbt),
+							};
+							self.pending_channel_fundings
+								.lock()
+								.unwrap()
+								.insert(user_channel_id, pending_funding);
+
+							self.event_queue
+								.add_event(Event::FundingPsbtReady {
+									user_channel_id: UserChannelId(user_channel_id),
+									counterparty_node_id,
+									channel_value_sats: channel_value_satoshis,
+								})
+								.unwrap_or_else(|e| {
+									log_error!(self.logger, "Failed to push to event queue: {}", e);
+									panic!("Failed to push to event queue");
+								});
+						},
+						Err(err) => {
+							log_error!(self.logger, "Failed to create funding PSBT: {}", err);
+							self.fail_funding_generation(
+								&temporary_channel_id,
+								&counterparty_node_id,
+							);
+						},
+					}
+					return Ok(());
+				}
+
+				// Sign the final funding transaction and broadcast it.
 				match self.wallet.create_funding_transaction(
-					output_script,
-					channel_amount,
+					vec![(output_script, channel_amount)],
 					confirmation_target,
+					funding_params.fee_rate,
+					funding_params.utxo_selection.as_ref(),
 					locktime,
 				) {
 					Ok(final_tx) => {
@@ -455,18 +807,7 @@ where
 					},
 					Err(err) => {
 						log_error!(self.logger, "Failed to create funding transaction: {}", err);
-						self.channel_manager
-							.force_close_without_broadcasting_txn(
-								&temporary_channel_id,
-								&counterparty_node_id,
-								"Failed to create funding transaction".to_string(),
-							)
-							.unwrap_or_else(|e| {
-								log_error!(self.logger, "Failed to force close channel after funding generation failed: {:?}", e);
-								panic!(
-									"Failed to force close channel after funding generation failed"
-								);
-							});
+						self.fail_funding_generation(&temporary_channel_id, &counterparty_node_id);
 					},
 				}
 			},
@@ -828,6 +1169,7 @@ where
 				let update = PaymentDetailsUpdate {
 					hash: Some(Some(payment_hash)),
 					preimage: Some(Some(payment_preimage)),
+					fee_paid_msat: Some(fee_paid_msat),
 					status: Some(PaymentStatus::Succeeded),
 					..PaymentDetailsUpdate::new(payment_id)
 				};
@@ -928,71 +1270,67 @@ where
 				funding_satoshis,
 				channel_type,
 				push_msat: _,
-				is_announced: _,
+				is_announced,
 				params: _,
 			} => {
 				let anchor_channel = channel_type.requires_anchors_zero_fee_htlc_tx();
 
-				// TODO: We should use `is_announced` flag above and reject announced channels if
-				// we're not a forwading node, once we add a 'forwarding mode' based on listening
-				// address / node alias being set.
+				let reject_channel = |reason: &str| {
+					log_error!(
+						self.logger,
+						"Rejecting inbound channel from peer {} due to {}.",
+						counterparty_node_id,
+						reason,
+					);
+					self.channel_manager
+						.force_close_without_broadcasting_txn(
+							&temporary_channel_id,
+							&counterparty_node_id,
+							"Channel request rejected".to_string(),
+						)
+						.unwrap_or_else(|e| {
+							log_error!(self.logger, "Failed to reject channel: {:?}", e)
+						});
+				};
 
-				if anchor_channel {
-					if let Some(anchor_channels_config) =
-						self.config.anchor_channels_config.as_ref()
-					{
-						let cur_anchor_reserve_sats = crate::total_anchor_channels_reserve_sats(
-							&self.channel_manager,
-							&self.config,
-						);
-						let spendable_amount_sats = self
-							.wallet
-							.get_spendable_amount_sats(cur_anchor_reserve_sats)
-							.unwrap_or(0);
-
-						let required_amount_sats = if anchor_channels_config
-							.trusted_peers_no_reserve
-							.contains(&counterparty_node_id)
-						{
-							0
-						} else {
-							anchor_channels_config.per_channel_reserve_sats
-						};
+				let request = InboundChannelRequest {
+					counterparty_node_id,
+					funding_sats: funding_satoshis,
+					channel_type,
+					is_announced,
+				};
+				if let Err(reason) =
+					request.check(&self.channel_manager, &self.wallet, &self.config)
+				{
+					reject_channel(&reason);
+					return Ok(());
+				}
 
-						if spendable_amount_sats < required_amount_sats {
-							log_error!(
-								self.logger,
-								"Rejecting inbound Anchor channel from peer {} due to insufficient available on-chain reserves.",
-								counterparty_node_id,
-							);
-							self.channel_manager
-								.force_close_without_broadcasting_txn(
-									&temporary_channel_id,
-									&counterparty_node_id,
-									"Channel request rejected".to_string(),
-								)
-								.unwrap_or_else(|e| {
-									log_error!(self.logger, "Failed to reject channel: {:?}", e)
-								});
-							return Ok(());
-						}
-					} else {
-						log_error!(
-							self.logger,
-							"Rejecting inbound channel from peer {} due to Anchor channels being disabled.",
+				if self.config.inbound_channel_policy.require_manual_approval {
+					log_info!(
+						self.logger,
+						"Awaiting manual approval of inbound channel of {}sats from peer {}",
+						funding_satoshis,
+						counterparty_node_id,
+					);
+					let channel_type = ChannelTypeFeature::from_channel_type(&request.channel_type);
+					self.inbound_channel_requests
+						.lock()
+						.unwrap()
+						.insert(temporary_channel_id, request);
+					self.event_queue
+						.add_event(Event::ChannelOpenRequest {
+							temporary_channel_id,
 							counterparty_node_id,
-						);
-						self.channel_manager
-							.force_close_without_broadcasting_txn(
-								&temporary_channel_id,
-								&counterparty_node_id,
-								"Channel request rejected".to_string(),
-							)
-							.unwrap_or_else(|e| {
-								log_error!(self.logger, "Failed to reject channel: {:?}", e)
-							});
-						return Ok(());
-					}
+							funding_sats: funding_satoshis,
+							channel_type,
+							is_announced,
+						})
+						.unwrap_or_else(|e| {
+							log_error!(self.logger, "Failed to push to event queue: {}", e);
+							panic!("Failed to push to event queue");
+						});
+					return Ok(());
 				}
 
 				let user_channel_id: u128 = rand::thread_rng().gen::<u128>();
@@ -1105,12 +1443,37 @@ where
 				funding_txo,
 				..
 			} => {
+				self.channel_funding_params.lock().unwrap().remove(&user_channel_id);
+				self.pending_channel_fundings.lock().unwrap().remove(&user_channel_id);
+
 				log_info!(
 					self.logger,
 					"New channel {} with counterparty {} has been created and is pending confirmation on chain.",
 					channel_id,
 					counterparty_node_id,
 				);
+
+				let now = SystemTime::now()
+					.duration_since(UNIX_EPOCH)
+					.unwrap_or(Duration::from_secs(0))
+					.as_secs();
+				self.channel_history_store
+					.channel_opened(
+						channel_id,
+						UserChannelId(user_channel_id),
+						counterparty_node_id,
+						funding_txo,
+						now,
+					)
+					.unwrap_or_else(|e| {
+						log_error!(
+							self.logger,
+							"Failed to track channel {} in channel history: {}",
+							channel_id,
+							e
+						);
+					});
+
 				self.event_queue
 					.add_event(Event::ChannelPending {
 						channel_id,
@@ -1180,9 +1543,80 @@ where
 				reason,
 				user_channel_id,
 				counterparty_node_id,
+				channel_capacity_sats,
+				channel_funding_txo,
 				..
 			} => {
 				log_info!(self.logger, "Channel {} closed due to: {}", channel_id, reason);
+
+				// The balances left in the monitor are what we'll eventually be able to claim
+				// on-chain from the closed channel.
+				let final_balance_sats = channel_funding_txo.and_then(|funding_txo| {
+					self.chain_monitor.get_monitor(funding_txo).ok().map(|monitor| {
+						monitor
+							.get_claimable_balances()
+							.iter()
+							.map(|b| b.claimable_amount_satoshis())
+							.sum::<u64>()
+					})
+				});
+				let closed_at = SystemTime::now()
+					.duration_since(UNIX_EPOCH)
+					.unwrap_or(Duration::from_secs(0))
+					.as_secs();
+				let closed_channel = ClosedChannelDetails {
+					channel_id,
+					user_channel_id: UserChannelId(user_channel_id),
+					counterparty_node_id,
+					channel_capacity_sats,
+					funding_txo: channel_funding_txo.map(|o| o.into_bitcoin_outpoint()),
+					closing_txid: None,
+					closure_reason: Some(reason.clone()),
+					final_balance_sats,
+					opened_at: None,
+					closed_at,
+					is_locally_initiated: is_locally_initiated_closure(&reason),
+				};
+				self.channel_history_store.channel_closed(closed_channel).unwrap_or_else(|e| {
+					log_error!(
+						self.logger,
+						"Failed to record closure of channel {} in channel history: {}",
+						channel_id,
+						e
+					);
+				});
+
+				// Drop any funding parameters in case the channel never got funded.
+				let funding_params =
+					self.channel_funding_params.lock().unwrap().remove(&user_channel_id);
+				self.pending_channel_fundings.lock().unwrap().remove(&user_channel_id);
+
+				// If the channel was part of a batch that wasn't funded yet, the remaining
+				// channels of the batch can't be funded either.
+				if let Some(funding_batch) = funding_params.and_then(|p| p.funding_batch) {
+					let mut locked_batch = funding_batch.lock().unwrap();
+					locked_batch.failed = true;
+					locked_batch.ready_channels.remove(&user_channel_id);
+					for (channel_id, node_id, _, _) in
+						locked_batch.ready_channels.drain().map(|(_, c)| c)
+					{
+						self.channel_manager
+							.force_close_without_broadcasting_txn(
+								&channel_id,
+								&node_id,
+								"Channel funding batch failed".to_string(),
+							)
+							.unwrap_or_else(|e| {
+								log_error!(
+									self.logger,
+									"Failed to close channel {} of failed funding batch: {:?}",
+									channel_id,
+									e
+								)
+							});
+					}
+				}
+
 				self.event_queue
 					.add_event(Event::ChannelClosed {
 						channel_id,
@@ -5,26 +5,19 @@
 // http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
 // accordance with one or both of these licenses.
 
-use crate::config::FEE_RATE_CACHE_UPDATE_TIMEOUT_SECS;
-use crate::logger::{log_error, log_trace, Logger};
-use crate::{Config, Error};
-
 use lightning::chain::chaininterface::ConfirmationTarget as LdkConfirmationTarget;
 use lightning::chain::chaininterface::FeeEstimator as LdkFeeEstimator;
 use lightning::chain::chaininterface::FEERATE_FLOOR_SATS_PER_KW;
 
 use bitcoin::FeeRate;
-use esplora_client::AsyncClient as EsploraClient;
-
-use bitcoin::Network;
 
 use std::collections::HashMap;
-use std::ops::Deref;
+use std::fmt;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
 
+/// The confirmation target a fee rate estimate is requested for.
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
-pub(crate) enum ConfirmationTarget {
+pub enum ConfirmationTarget {
 	/// The default target for onchain payments.
 	OnchainPayment,
 	/// The target used for funding transactions.
@@ -33,131 +26,66 @@ pub(crate) enum ConfirmationTarget {
 	Lightning(LdkConfirmationTarget),
 }
 
-pub(crate) trait FeeEstimator {
+/// A source of fee rate estimates.
+///
+/// Can be used via [`Builder::set_fee_estimator`] to have the [`Node`] use fee rate estimates from
+/// a custom source rather than the ones retrieved from the configured chain source.
+///
+/// [`Builder::set_fee_estimator`]: crate::Builder::set_fee_estimator
+/// [`Node`]: crate::Node
+pub trait FeeEstimator: Send + Sync {
+	/// Returns the fee rate to use for the given [`ConfirmationTarget`].
+	///
+	/// This method is expected to return immediately, i.e., implementations should keep a cache
+	/// of recent estimates rather than querying a remote backend on every call.
 	fn estimate_fee_rate(&self, confirmation_target: ConfirmationTarget) -> FeeRate;
 }
 
+impl fmt::Debug for dyn FeeEstimator {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "FeeEstimator")
+	}
+}
+
 impl From<LdkConfirmationTarget> for ConfirmationTarget {
 	fn from(value: LdkConfirmationTarget) -> Self {
 		Self::Lightning(value)
 	}
 }
 
-pub(crate) struct OnchainFeeEstimator<L: Deref>
-where
-	L::Target: Logger,
-{
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FeeEstimatorConfig {
+	pub custom_estimator: Option<Arc<dyn FeeEstimator>>,
+	pub fee_rate_overrides: HashMap<ConfirmationTarget, FeeRate>,
+	pub min_fee_rate: Option<FeeRate>,
+	pub max_fee_rate: Option<FeeRate>,
+}
+
+pub(crate) struct OnchainFeeEstimator {
 	fee_rate_cache: RwLock<HashMap<ConfirmationTarget, FeeRate>>,
-	esplora_client: EsploraClient,
-	config: Arc<Config>,
-	logger: L,
+	config: FeeEstimatorConfig,
 }
 
-impl<L: Deref> OnchainFeeEstimator<L>
-where
-	L::Target: Logger,
-{
-	pub(crate) fn new(esplora_client: EsploraClient, config: Arc<Config>, logger: L) -> Self {
+impl OnchainFeeEstimator {
+	pub(crate) fn new(config: FeeEstimatorConfig) -> Self {
 		let fee_rate_cache = RwLock::new(HashMap::new());
-		Self { fee_rate_cache, esplora_client, config, logger }
+		Self { fee_rate_cache, config }
 	}
 
-	pub(crate) async fn update_fee_estimates(&self) -> Result<(), Error> {
-		let estimates = tokio::time::timeout(
-			Duration::from_secs(FEE_RATE_CACHE_UPDATE_TIMEOUT_SECS),
-			self.esplora_client.get_fee_estimates(),
-		)
-		.await
-		.map_err(|e| {
-			log_error!(self.logger, "Updating fee rate estimates timed out: {}", e);
-			Error::FeerateEstimationUpdateTimeout
-		})?
-		.map_err(|e| {
-			log_error!(self.logger, "Failed to retrieve fee rate estimates: {}", e);
-			Error::FeerateEstimationUpdateFailed
-		})?;
-
-		if estimates.is_empty() && self.config.network == Network::Bitcoin {
-			// Ensure we fail if we didn't receive any estimates.
-			log_error!(
-				self.logger,
-				"Failed to retrieve fee rate estimates: empty fee estimates are dissallowed on Mainnet.",
-			);
-			return Err(Error::FeerateEstimationUpdateFailed);
-		}
-
-		let confirmation_targets = vec![
-			ConfirmationTarget::OnchainPayment,
-			ConfirmationTarget::ChannelFunding,
-			LdkConfirmationTarget::MaximumFeeEstimate.into(),
-			LdkConfirmationTarget::UrgentOnChainSweep.into(),
-			LdkConfirmationTarget::MinAllowedAnchorChannelRemoteFee.into(),
-			LdkConfirmationTarget::MinAllowedNonAnchorChannelRemoteFee.into(),
-			LdkConfirmationTarget::AnchorChannelFee.into(),
-			LdkConfirmationTarget::NonAnchorChannelFee.into(),
-			LdkConfirmationTarget::ChannelCloseMinimum.into(),
-			LdkConfirmationTarget::OutputSpendingFee.into(),
-		];
-
-		for target in confirmation_targets {
-			let num_blocks = match target {
-				ConfirmationTarget::OnchainPayment => 6,
-				ConfirmationTarget::ChannelFunding => 12,
-				ConfirmationTarget::Lightning(ldk_target) => match ldk_target {
-					LdkConfirmationTarget::MaximumFeeEstimate => 1,
-					LdkConfirmationTarget::UrgentOnChainSweep => 6,
-					LdkConfirmationTarget::MinAllowedAnchorChannelRemoteFee => 1008,
-					LdkConfirmationTarget::MinAllowedNonAnchorChannelRemoteFee => 144,
-					LdkConfirmationTarget::AnchorChannelFee => 1008,
-					LdkConfirmationTarget::NonAnchorChannelFee => 12,
-					LdkConfirmationTarget::ChannelCloseMinimum => 144,
-					LdkConfirmationTarget::OutputSpendingFee => 12,
-				},
-			};
-
-			let converted_estimate_sat_vb =
-				esplora_client::convert_fee_rate(num_blocks, estimates.clone()).map_err(|e| {
-					log_error!(
-						self.logger,
-						"Failed to convert fee rate estimates for {:?}: {}",
-						target,
-						e
-					);
-					Error::FeerateEstimationUpdateFailed
-				})?;
-
-			let fee_rate = FeeRate::from_sat_per_kwu((converted_estimate_sat_vb * 250.0) as u64);
-
-			// LDK 0.0.118 introduced changes to the `ConfirmationTarget` semantics that
-			// require some post-estimation adjustments to the fee rates, which we do here.
-			let adjusted_fee_rate = match target {
-				ConfirmationTarget::Lightning(
-					LdkConfirmationTarget::MinAllowedNonAnchorChannelRemoteFee,
-				) => {
-					let slightly_less_than_background = fee_rate.to_sat_per_kwu() - 250;
-					FeeRate::from_sat_per_kwu(slightly_less_than_background)
-				},
-				_ => fee_rate,
-			};
-
-			let mut locked_fee_rate_cache = self.fee_rate_cache.write().unwrap();
-			locked_fee_rate_cache.insert(target, adjusted_fee_rate);
-			log_trace!(
-				self.logger,
-				"Fee rate estimation updated for {:?}: {} sats/kwu",
-				target,
-				adjusted_fee_rate.to_sat_per_kwu(),
-			);
+	// Updates the fee rate cache and returns if the new values changed.
+	pub(crate) fn set_fee_rate_cache(
+		&self, fee_rate_cache_update: HashMap<ConfirmationTarget, FeeRate>,
+	) -> bool {
+		let mut locked_fee_rate_cache = self.fee_rate_cache.write().unwrap();
+		if fee_rate_cache_update != *locked_fee_rate_cache {
+			*locked_fee_rate_cache = fee_rate_cache_update;
+			true
+		} else {
+			false
 		}
-		Ok(())
 	}
-}
 
-impl<L: Deref> FeeEstimator for OnchainFeeEstimator<L>
-where
-	L::Target: Logger,
-{
-	fn estimate_fee_rate(&self, confirmation_target: ConfirmationTarget) -> FeeRate {
+	fn estimate_fee_rate_from_cache(&self, confirmation_target: ConfirmationTarget) -> FeeRate {
 		let locked_fee_rate_cache = self.fee_rate_cache.read().unwrap();
 
 		let fallback_sats_kwu = match confirmation_target {
@@ -182,7 +110,28 @@ where
 		// We'll fall back on this, if we really don't have any other information.
 		let fallback_rate = FeeRate::from_sat_per_kwu(fallback_sats_kwu as u64);
 
-		let estimate = *locked_fee_rate_cache.get(&confirmation_target).unwrap_or(&fallback_rate);
+		*locked_fee_rate_cache.get(&confirmation_target).unwrap_or(&fallback_rate)
+	}
+}
+
+impl FeeEstimator for OnchainFeeEstimator {
+	fn estimate_fee_rate(&self, confirmation_target: ConfirmationTarget) -> FeeRate {
+		let mut estimate =
+			if let Some(fee_rate) = self.config.fee_rate_overrides.get(&confirmation_target) {
+				*fee_rate
+			} else if let Some(custom_estimator) = self.config.custom_estimator.as_ref() {
+				custom_estimator.estimate_fee_rate(confirmation_target)
+			} else {
+				self.estimate_fee_rate_from_cache(confirmation_target)
+			};
+
+		// Apply the user-configured bounds, if any.
+		if let Some(max_fee_rate) = self.config.max_fee_rate {
+			estimate = estimate.min(max_fee_rate);
+		}
+		if let Some(min_fee_rate) = self.config.min_fee_rate {
+			estimate = estimate.max(min_fee_rate);
+		}
 
 		// Currently we assume every transaction needs to at least be relayable, which is why we
 		// enforce a lower bound of `FEERATE_FLOOR_SATS_PER_KW`.
@@ -190,11 +139,119 @@ where
 	}
 }
 
-impl<L: Deref> LdkFeeEstimator for OnchainFeeEstimator<L>
-where
-	L::Target: Logger,
-{
+impl LdkFeeEstimator for OnchainFeeEstimator {
 	fn get_est_sat_per_1000_weight(&self, confirmation_target: LdkConfirmationTarget) -> u32 {
 		self.estimate_fee_rate(confirmation_target.into()).to_sat_per_kwu() as u32
 	}
 }
+
+pub(crate) fn get_num_block_defaults_for_target(target: ConfirmationTarget) -> usize {
+	match target {
+		ConfirmationTarget::OnchainPayment => 6,
+		ConfirmationTarget::ChannelFunding => 12,
+		ConfirmationTarget::Lightning(ldk_target) => match ldk_target {
+			LdkConfirmationTarget::MaximumFeeEstimate => 1,
+			LdkConfirmationTarget::UrgentOnChainSweep => 6,
+			LdkConfirmationTarget::MinAllowedAnchorChannelRemoteFee => 1008,
+			LdkConfirmationTarget::MinAllowedNonAnchorChannelRemoteFee => 144,
+			LdkConfirmationTarget::AnchorChannelFee => 1008,
+			LdkConfirmationTarget::NonAnchorChannelFee => 12,
+			LdkConfirmationTarget::ChannelCloseMinimum => 144,
+			LdkConfirmationTarget::OutputSpendingFee => 12,
+		},
+	}
+}
+
+pub(crate) fn get_all_conf_targets() -> [ConfirmationTarget; 10] {
+	[
+		ConfirmationTarget::OnchainPayment,
+		ConfirmationTarget::ChannelFunding,
+		LdkConfirmationTarget::MaximumFeeEstimate.into(),
+		LdkConfirmationTarget::UrgentOnChainSweep.into(),
+		LdkConfirmationTarget::MinAllowedAnchorChannelRemoteFee.into(),
+		LdkConfirmationTarget::MinAllowedNonAnchorChannelRemoteFee.into(),
+		LdkConfirmationTarget::AnchorChannelFee.into(),
+		LdkConfirmationTarget::NonAnchorChannelFee.into(),
+		LdkConfirmationTarget::ChannelCloseMinimum.into(),
+		LdkConfirmationTarget::OutputSpendingFee.into(),
+	]
+}
+
+pub(crate) fn apply_post_estimation_adjustments(
+	target: ConfirmationTarget, estimated_rate: FeeRate,
+) -> FeeRate {
+	// LDK 0.0.118 introduced changes to the `ConfirmationTarget` semantics that
+	// require some post-estimation adjustments to the fee rates, which we do here.
+	match target {
+		ConfirmationTarget::Lightning(
+			LdkConfirmationTarget::MinAllowedNonAnchorChannelRemoteFee,
+		) => {
+			let slightly_less_than_background = estimated_rate.to_sat_per_kwu().saturating_sub(250);
+			FeeRate::from_sat_per_kwu(slightly_less_than_background)
+		},
+		_ => estimated_rate,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fee_rate_overrides_and_bounds_are_applied() {
+		let mut fee_rate_overrides = HashMap::new();
+		fee_rate_overrides
+			.insert(ConfirmationTarget::OnchainPayment, FeeRate::from_sat_per_kwu(20_000));
+		fee_rate_overrides
+			.insert(ConfirmationTarget::ChannelFunding, FeeRate::from_sat_per_kwu(2_000));
+		let config = FeeEstimatorConfig {
+			custom_estimator: None,
+			fee_rate_overrides,
+			min_fee_rate: Some(FeeRate::from_sat_per_kwu(750)),
+			max_fee_rate: Some(FeeRate::from_sat_per_kwu(10_000)),
+		};
+		let fee_estimator = OnchainFeeEstimator::new(config);
+
+		// Overrides are still subject to the bounds.
+		assert_eq!(
+			fee_estimator.estimate_fee_rate(ConfirmationTarget::OnchainPayment),
+			FeeRate::from_sat_per_kwu(10_000)
+		);
+		assert_eq!(
+			fee_estimator.estimate_fee_rate(ConfirmationTarget::ChannelFunding),
+			FeeRate::from_sat_per_kwu(2_000)
+		);
+
+		// Cached estimates take precedence over our fallbacks, but are clamped, too.
+		let mut fee_rate_cache_update = HashMap::new();
+		let anchor_target = LdkConfirmationTarget::AnchorChannelFee.into();
+		fee_rate_cache_update.insert(anchor_target, FeeRate::from_sat_per_kwu(300));
+		assert!(fee_estimator.set_fee_rate_cache(fee_rate_cache_update));
+		assert_eq!(fee_estimator.estimate_fee_rate(anchor_target), FeeRate::from_sat_per_kwu(750));
+
+		let sweep_target = LdkConfirmationTarget::UrgentOnChainSweep.into();
+		assert_eq!(fee_estimator.estimate_fee_rate(sweep_target), FeeRate::from_sat_per_kwu(5_000));
+	}
+
+	#[test]
+	fn custom_fee_estimator_is_used() {
+		struct StaticFeeEstimator;
+		impl FeeEstimator for StaticFeeEstimator {
+			fn estimate_fee_rate(&self, _confirmation_target: ConfirmationTarget) -> FeeRate {
+				FeeRate::from_sat_per_kwu(100)
+			}
+		}
+
+		let config = FeeEstimatorConfig {
+			custom_estimator: Some(Arc::new(StaticFeeEstimator)),
+			..Default::default()
+		};
+		let fee_estimator = OnchainFeeEstimator::new(config);
+
+		// We still enforce the relay floor on custom estimates.
+		assert_eq!(
+			fee_estimator.estimate_fee_rate(ConfirmationTarget::OnchainPayment),
+			FeeRate::from_sat_per_kwu(FEERATE_FLOOR_SATS_PER_KW as u64)
+		);
+	}
+}
@@ -24,10 +24,28 @@ pub(crate) const PEER_INFO_PERSISTENCE_PRIMARY_NAMESPACE: &str = "";
 pub(crate) const PEER_INFO_PERSISTENCE_SECONDARY_NAMESPACE: &str = "";
 pub(crate) const PEER_INFO_PERSISTENCE_KEY: &str = "peers";
 
+/// The locked UTXOs will be persisted under this key.
+pub(crate) const LOCKED_UTXOS_PERSISTENCE_PRIMARY_NAMESPACE: &str = "";
+pub(crate) const LOCKED_UTXOS_PERSISTENCE_SECONDARY_NAMESPACE: &str = "";
+pub(crate) const LOCKED_UTXOS_PERSISTENCE_KEY: &str = "locked_utxos";
+
 /// The payment information will be persisted under this prefix.
 pub(crate) const PAYMENT_INFO_PERSISTENCE_PRIMARY_NAMESPACE: &str = "payments";
 pub(crate) const PAYMENT_INFO_PERSISTENCE_SECONDARY_NAMESPACE: &str = "";
 
+/// The labels will be persisted under this prefix.
+pub(crate) const LABEL_PERSISTENCE_PRIMARY_NAMESPACE: &str = "labels";
+pub(crate) const LABEL_PERSISTENCE_SECONDARY_NAMESPACE: &str = "";
+
+/// The channel history will be persisted under this prefix.
+pub(crate) const CHANNEL_HISTORY_PERSISTENCE_PRIMARY_NAMESPACE: &str = "channel_history";
+pub(crate) const CHANNEL_HISTORY_PERSISTENCE_SECONDARY_NAMESPACE: &str = "";
+
+/// The header chain retrieved from BIP 157 peers will be persisted under this prefix, keyed by the
+/// index of each segment of headers.
+pub(crate) const CBF_HEADER_CHAIN_PERSISTENCE_PRIMARY_NAMESPACE: &str = "cbf_header_chain";
+pub(crate) const CBF_HEADER_CHAIN_PERSISTENCE_SECONDARY_NAMESPACE: &str = "";
+
 /// The spendable output information used to persisted under this prefix until LDK Node v0.3.0.
 pub(crate) const DEPRECATED_SPENDABLE_OUTPUT_INFO_PERSISTENCE_PRIMARY_NAMESPACE: &str =
 	"spendable_outputs";
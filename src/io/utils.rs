@@ -6,12 +6,15 @@
 // accordance with one or both of these licenses.
 
 use super::*;
+use crate::channel_history::ChannelRecord;
 use crate::config::WALLET_KEYS_SEED_LEN;
 
+use crate::label_store::Label;
 use crate::logger::{log_error, FilesystemLogger};
 use crate::peer_store::PeerStore;
 use crate::sweep::DeprecatedSpendableOutputInfo;
 use crate::types::{Broadcaster, ChainSource, DynStore, FeeEstimator, KeysManager, Sweeper};
+use crate::wallet::locked_utxos::LockedUtxoStore;
 use crate::wallet::ser::{ChangeSetDeserWrapper, ChangeSetSerWrapper};
 use crate::{Error, EventQueue, PaymentDetails};
 
@@ -39,6 +42,7 @@ use bdk_chain::ConfirmationBlockTime;
 use bdk_wallet::ChangeSet as BdkWalletChangeSet;
 
 use bip39::Mnemonic;
+use bitcoin::block::Header;
 use bitcoin::Network;
 use rand::{thread_rng, RngCore};
 
@@ -187,6 +191,27 @@ where
 	})
 }
 
+/// Read previously persisted locked UTXOs from the store.
+pub(crate) fn read_locked_utxos<L: Deref + Clone>(
+	kv_store: Arc<DynStore>, logger: L,
+) -> Result<LockedUtxoStore<L>, std::io::Error>
+where
+	L::Target: Logger,
+{
+	let mut reader = Cursor::new(kv_store.read(
+		LOCKED_UTXOS_PERSISTENCE_PRIMARY_NAMESPACE,
+		LOCKED_UTXOS_PERSISTENCE_SECONDARY_NAMESPACE,
+		LOCKED_UTXOS_PERSISTENCE_KEY,
+	)?);
+	LockedUtxoStore::read(&mut reader, (kv_store, logger.clone())).map_err(|e| {
+		log_error!(logger, "Failed to deserialize locked UTXO store: {}", e);
+		std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			"Failed to deserialize LockedUtxoStore",
+		)
+	})
+}
+
 /// Read previously persisted payments information from the store.
 pub(crate) fn read_payments<L: Deref>(
 	kv_store: Arc<DynStore>, logger: L,
@@ -217,6 +242,104 @@ where
 	Ok(res)
 }
 
+/// Read previously persisted labels from the store.
+pub(crate) fn read_labels<L: Deref>(
+	kv_store: Arc<DynStore>, logger: L,
+) -> Result<Vec<Label>, std::io::Error>
+where
+	L::Target: Logger,
+{
+	let mut res = Vec::new();
+
+	for stored_key in
+		kv_store.list(LABEL_PERSISTENCE_PRIMARY_NAMESPACE, LABEL_PERSISTENCE_SECONDARY_NAMESPACE)?
+	{
+		let mut reader = Cursor::new(kv_store.read(
+			LABEL_PERSISTENCE_PRIMARY_NAMESPACE,
+			LABEL_PERSISTENCE_SECONDARY_NAMESPACE,
+			&stored_key,
+		)?);
+		let label = Label::read(&mut reader).map_err(|e| {
+			log_error!(logger, "Failed to deserialize Label: {}", e);
+			std::io::Error::new(std::io::ErrorKind::InvalidData, "Failed to deserialize Label")
+		})?;
+		res.push(label);
+	}
+	Ok(res)
+}
+
+/// Read previously persisted channel history records from the store.
+pub(crate) fn read_channel_history<L: Deref>(
+	kv_store: Arc<DynStore>, logger: L,
+) -> Result<Vec<ChannelRecord>, std::io::Error>
+where
+	L::Target: Logger,
+{
+	let mut res = Vec::new();
+
+	for stored_key in kv_store.list(
+		CHANNEL_HISTORY_PERSISTENCE_PRIMARY_NAMESPACE,
+		CHANNEL_HISTORY_PERSISTENCE_SECONDARY_NAMESPACE,
+	)? {
+		let mut reader = Cursor::new(kv_store.read(
+			CHANNEL_HISTORY_PERSISTENCE_PRIMARY_NAMESPACE,
+			CHANNEL_HISTORY_PERSISTENCE_SECONDARY_NAMESPACE,
+			&stored_key,
+		)?);
+		let record = ChannelRecord::read(&mut reader).map_err(|e| {
+			log_error!(logger, "Failed to deserialize ChannelRecord: {}", e);
+			std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				"Failed to deserialize ChannelRecord",
+			)
+		})?;
+		res.push(record);
+	}
+	Ok(res)
+}
+
+/// Read the previously persisted header chain retrieved from BIP 157 peers.
+///
+/// The persisted segments are returned in order, stopping at the first missing one.
+pub(crate) fn read_cbf_header_chain<L: Deref>(
+	kv_store: Arc<DynStore>, logger: L,
+) -> Result<Vec<Header>, std::io::Error>
+where
+	L::Target: Logger,
+{
+	let mut segments: Vec<usize> = kv_store
+		.list(
+			CBF_HEADER_CHAIN_PERSISTENCE_PRIMARY_NAMESPACE,
+			CBF_HEADER_CHAIN_PERSISTENCE_SECONDARY_NAMESPACE,
+		)?
+		.iter()
+		.filter_map(|key| key.parse().ok())
+		.collect();
+	segments.sort_unstable();
+
+	let mut res = Vec::new();
+	for (expected_segment, segment) in segments.into_iter().enumerate() {
+		if segment != expected_segment {
+			break;
+		}
+
+		let data = kv_store.read(
+			CBF_HEADER_CHAIN_PERSISTENCE_PRIMARY_NAMESPACE,
+			CBF_HEADER_CHAIN_PERSISTENCE_SECONDARY_NAMESPACE,
+			&segment.to_string(),
+		)?;
+		let headers: Vec<Header> = bitcoin::consensus::encode::deserialize(&data).map_err(|e| {
+			log_error!(logger, "Failed to deserialize header chain segment {}: {}", segment, e);
+			std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				"Failed to deserialize header chain segment",
+			)
+		})?;
+		res.extend(headers);
+	}
+	Ok(res)
+}
+
 /// Read `OutputSweeper` state from the store.
 pub(crate) fn read_output_sweeper(
 	broadcaster: Arc<Broadcaster>, fee_estimator: Arc<FeeEstimator>,
@@ -0,0 +1,434 @@
+// This file is Copyright its original authors, visible in version control history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
+// accordance with one or both of these licenses.
+
+use crate::hex_utils;
+use crate::io::{LABEL_PERSISTENCE_PRIMARY_NAMESPACE, LABEL_PERSISTENCE_SECONDARY_NAMESPACE};
+use crate::logger::{log_error, Logger};
+use crate::types::DynStore;
+use crate::Error;
+
+use lightning::ln::channelmanager::PaymentId;
+use lightning::ln::msgs::DecodeError;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use lightning::{_init_and_read_len_prefixed_tlv_fields, write_tlv_fields};
+
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::{Address, Network, OutPoint, Txid};
+
+use std::collections::HashMap;
+use std::iter::FromIterator;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+const BIP329_TYPE_TX: &str = "tx";
+const BIP329_TYPE_ADDR: &str = "addr";
+const BIP329_TYPE_OUTPUT: &str = "output";
+// Lightning payments are not covered by BIP 329, so we only use this type internally.
+const PAYMENT_LABEL_TYPE: &str = "payment";
+
+/// The object a [`Label`] is attached to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LabelRef {
+	/// An on-chain transaction.
+	Transaction {
+		/// The transaction's id.
+		txid: Txid,
+	},
+	/// An on-chain address.
+	Address {
+		/// The address.
+		address: Address,
+	},
+	/// A transaction output, e.g., one of the UTXOs of our on-chain wallet.
+	Output {
+		/// The output's outpoint.
+		outpoint: OutPoint,
+	},
+	/// A payment as tracked in the payment store.
+	///
+	/// **Note:** As [BIP 329] doesn't specify labels for Lightning payments, labels of this kind
+	/// are not included when exporting labels.
+	///
+	/// [BIP 329]: https://github.com/bitcoin/bips/blob/master/bip-0329.mediawiki
+	Payment {
+		/// The payment's id.
+		payment_id: PaymentId,
+	},
+}
+
+impl LabelRef {
+	fn to_type_and_ref(&self) -> (&'static str, String) {
+		match self {
+			Self::Transaction { txid } => (BIP329_TYPE_TX, txid.to_string()),
+			Self::Address { address } => (BIP329_TYPE_ADDR, address.to_string()),
+			Self::Output { outpoint } => (BIP329_TYPE_OUTPUT, outpoint.to_string()),
+			Self::Payment { payment_id } => {
+				(PAYMENT_LABEL_TYPE, hex_utils::to_string(&payment_id.0))
+			},
+		}
+	}
+
+	/// Parses the reference from its type and string representation, returning `Ok(None)` for
+	/// types we don't support.
+	fn from_type_and_ref(
+		label_type: &str, reference: &str, network: Option<Network>,
+	) -> Result<Option<Self>, ()> {
+		match label_type {
+			BIP329_TYPE_TX => {
+				let txid = Txid::from_str(reference).map_err(|_| ())?;
+				Ok(Some(Self::Transaction { txid }))
+			},
+			BIP329_TYPE_ADDR => {
+				let unchecked_address =
+					Address::<NetworkUnchecked>::from_str(reference).map_err(|_| ())?;
+				let address = match network {
+					Some(network) => unchecked_address.require_network(network).map_err(|_| ())?,
+					None => unchecked_address.assume_checked(),
+				};
+				Ok(Some(Self::Address { address }))
+			},
+			BIP329_TYPE_OUTPUT => {
+				let outpoint = OutPoint::from_str(reference).map_err(|_| ())?;
+				Ok(Some(Self::Output { outpoint }))
+			},
+			PAYMENT_LABEL_TYPE => {
+				let bytes = hex_utils::to_vec(reference).ok_or(())?;
+				let payment_id = PaymentId(bytes.try_into().map_err(|_| ())?);
+				Ok(Some(Self::Payment { payment_id }))
+			},
+			_ => Ok(None),
+		}
+	}
+
+	fn store_key(&self) -> String {
+		// Outpoints are formatted as `txid:vout`, but colons aren't allowed in store keys.
+		let (label_type, reference) = self.to_type_and_ref();
+		format!("{}_{}", label_type, reference.replace(':', "_"))
+	}
+}
+
+/// A user-provided label, e.g., used for accounting purposes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Label {
+	/// The object the label is attached to.
+	pub label_ref: LabelRef,
+	/// The label.
+	pub label: String,
+}
+
+impl Writeable for Label {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		let (label_type, reference) = self.label_ref.to_type_and_ref();
+		let label_type = label_type.to_string();
+		write_tlv_fields!(writer, {
+			(0, label_type, required),
+			(2, reference, required),
+			(4, self.label, required),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for Label {
+	fn read<R: lightning::io::Read>(reader: &mut R) -> Result<Label, DecodeError> {
+		_init_and_read_len_prefixed_tlv_fields!(reader, {
+			(0, label_type, required),
+			(2, reference, required),
+			(4, label, required),
+		});
+
+		let label_type: String = label_type.0.ok_or(DecodeError::InvalidValue)?;
+		let reference: String = reference.0.ok_or(DecodeError::InvalidValue)?;
+		let label: String = label.0.ok_or(DecodeError::InvalidValue)?;
+
+		let label_ref = LabelRef::from_type_and_ref(&label_type, &reference, None)
+			.map_err(|()| DecodeError::InvalidValue)?
+			.ok_or(DecodeError::UnknownRequiredFeature)?;
+		Ok(Label { label_ref, label })
+	}
+}
+
+/// Serializes the given labels into the [BIP 329] JSON Lines format.
+///
+/// Labels for objects not covered by BIP 329, i.e., Lightning payments, are skipped. Output
+/// records carry a `spendable` flag reflecting whether the output is part of `locked_outpoints`,
+/// and locked outputs without a label are exported as output records without a label.
+///
+/// [BIP 329]: https://github.com/bitcoin/bips/blob/master/bip-0329.mediawiki
+pub(crate) fn labels_to_bip329(labels: &[Label], locked_outpoints: &[OutPoint]) -> String {
+	let mut res = String::new();
+	for label in labels {
+		if let LabelRef::Payment { .. } = label.label_ref {
+			continue;
+		}
+		let (label_type, reference) = label.label_ref.to_type_and_ref();
+		let mut record = serde_json::json!({
+			"type": label_type,
+			"ref": reference,
+			"label": label.label,
+		});
+		if let LabelRef::Output { outpoint } = label.label_ref {
+			record["spendable"] = serde_json::Value::Bool(!locked_outpoints.contains(&outpoint));
+		}
+		res.push_str(&record.to_string());
+		res.push('\n');
+	}
+
+	for outpoint in locked_outpoints {
+		let label_ref = LabelRef::Output { outpoint: *outpoint };
+		if labels.iter().any(|label| label.label_ref == label_ref) {
+			continue;
+		}
+		let record = serde_json::json!({
+			"type": BIP329_TYPE_OUTPUT,
+			"ref": outpoint.to_string(),
+			"spendable": false,
+		});
+		res.push_str(&record.to_string());
+		res.push('\n');
+	}
+	res
+}
+
+/// Parses labels from the [BIP 329] JSON Lines format.
+///
+/// Returns the parsed labels, along with the outputs for which a `spendable` flag was given.
+/// Records of types we don't support, as well as records without a label, are skipped, unless
+/// they are output records carrying a `spendable` flag. Fails if any of the records is malformed
+/// or refers to an address on a different network.
+///
+/// [BIP 329]: https://github.com/bitcoin/bips/blob/master/bip-0329.mediawiki
+pub(crate) fn labels_from_bip329(
+	bip329_labels: &str, network: Network,
+) -> Result<(Vec<Label>, Vec<(OutPoint, bool)>), ()> {
+	let mut labels = Vec::new();
+	let mut outputs_spendable = Vec::new();
+	for line in bip329_labels.lines() {
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+
+		let record: serde_json::Value = serde_json::from_str(line).map_err(|_| ())?;
+		let label_type = record["type"].as_str().ok_or(())?;
+		let reference = record["ref"].as_str().ok_or(())?;
+
+		if label_type == PAYMENT_LABEL_TYPE {
+			// Don't let imported records masquerade as our internal payment labels.
+			continue;
+		}
+
+		let label_ref = match LabelRef::from_type_and_ref(label_type, reference, Some(network))? {
+			Some(label_ref) => label_ref,
+			None => continue,
+		};
+
+		if let LabelRef::Output { outpoint } = label_ref {
+			if let Some(spendable) = record.get("spendable") {
+				outputs_spendable.push((outpoint, spendable.as_bool().ok_or(())?));
+			}
+		}
+
+		if let Some(label) = record["label"].as_str() {
+			labels.push(Label { label_ref, label: label.to_string() });
+		}
+	}
+	Ok((labels, outputs_spendable))
+}
+
+pub(crate) struct LabelStore<L: Deref>
+where
+	L::Target: Logger,
+{
+	labels: Mutex<HashMap<LabelRef, String>>,
+	kv_store: Arc<DynStore>,
+	logger: L,
+}
+
+impl<L: Deref> LabelStore<L>
+where
+	L::Target: Logger,
+{
+	pub(crate) fn new(labels: Vec<Label>, kv_store: Arc<DynStore>, logger: L) -> Self {
+		let labels = Mutex::new(HashMap::from_iter(
+			labels.into_iter().map(|label| (label.label_ref, label.label)),
+		));
+		Self { labels, kv_store, logger }
+	}
+
+	pub(crate) fn insert(&self, label: Label) -> Result<(), Error> {
+		let mut locked_labels = self.labels.lock().unwrap();
+		self.persist_label(&label)?;
+		locked_labels.insert(label.label_ref, label.label);
+		Ok(())
+	}
+
+	pub(crate) fn remove(&self, label_ref: &LabelRef) -> Result<(), Error> {
+		let mut locked_labels = self.labels.lock().unwrap();
+		let store_key = label_ref.store_key();
+		self.kv_store
+			.remove(
+				LABEL_PERSISTENCE_PRIMARY_NAMESPACE,
+				LABEL_PERSISTENCE_SECONDARY_NAMESPACE,
+				&store_key,
+				false,
+			)
+			.map_err(|e| {
+				log_error!(
+					self.logger,
+					"Removing label data for key {}/{}/{} failed due to: {}",
+					LABEL_PERSISTENCE_PRIMARY_NAMESPACE,
+					LABEL_PERSISTENCE_SECONDARY_NAMESPACE,
+					store_key,
+					e
+				);
+				Error::PersistenceFailed
+			})?;
+		locked_labels.remove(label_ref);
+		Ok(())
+	}
+
+	pub(crate) fn get(&self, label_ref: &LabelRef) -> Option<String> {
+		self.labels.lock().unwrap().get(label_ref).cloned()
+	}
+
+	pub(crate) fn list(&self) -> Vec<Label> {
+		self.labels
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(label_ref, label)| Label { label_ref: label_ref.clone(), label: label.clone() })
+			.collect()
+	}
+
+	fn persist_label(&self, label: &Label) -> Result<(), Error> {
+		let store_key = label.label_ref.store_key();
+		let data = label.encode();
+		self.kv_store
+			.write(
+				LABEL_PERSISTENCE_PRIMARY_NAMESPACE,
+				LABEL_PERSISTENCE_SECONDARY_NAMESPACE,
+				&store_key,
+				&data,
+			)
+			.map_err(|e| {
+				log_error!(
+					self.logger,
+					"Write for key {}/{}/{} failed due to: {}",
+					LABEL_PERSISTENCE_PRIMARY_NAMESPACE,
+					LABEL_PERSISTENCE_SECONDARY_NAMESPACE,
+					store_key,
+					e
+				);
+				Error::PersistenceFailed
+			})?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use lightning::util::test_utils::{TestLogger, TestStore};
+
+	use bitcoin::io::Cursor;
+
+	#[test]
+	fn labels_are_persisted() {
+		let store: Arc<DynStore> = Arc::new(TestStore::new(false));
+		let logger = Arc::new(TestLogger::new());
+		let label_store = LabelStore::new(Vec::new(), Arc::clone(&store), logger);
+
+		let txid =
+			Txid::from_str("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+				.unwrap();
+		let label_ref = LabelRef::Output { outpoint: OutPoint::new(txid, 1) };
+		assert_eq!(label_store.get(&label_ref), None);
+
+		let label = Label { label_ref: label_ref.clone(), label: "cold storage".to_string() };
+		label_store.insert(label.clone()).unwrap();
+		assert_eq!(label_store.get(&label_ref), Some("cold storage".to_string()));
+
+		// Check we can read back what we persisted.
+		let persisted_bytes = store
+			.read(
+				LABEL_PERSISTENCE_PRIMARY_NAMESPACE,
+				LABEL_PERSISTENCE_SECONDARY_NAMESPACE,
+				&label_ref.store_key(),
+			)
+			.unwrap();
+		let deser_label = Label::read(&mut Cursor::new(persisted_bytes)).unwrap();
+		assert_eq!(deser_label, label);
+
+		label_store.remove(&label_ref).unwrap();
+		assert_eq!(label_store.get(&label_ref), None);
+		assert!(store
+			.read(
+				LABEL_PERSISTENCE_PRIMARY_NAMESPACE,
+				LABEL_PERSISTENCE_SECONDARY_NAMESPACE,
+				&label_ref.store_key(),
+			)
+			.is_err());
+	}
+
+	#[test]
+	fn bip329_roundtrip() {
+		let bip329_labels = concat!(
+			r#"{"type":"tx","ref":"f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd","label":"Transaction","origin":"wpkh([d34db33f/84'/0'/0'])"}"#,
+			"\n",
+			r#"{"type":"addr","ref":"bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c","label":"Address"}"#,
+			"\n",
+			r#"{"type":"pubkey","ref":"0283409659355b6d1cc3c32decd5d561abaac86c37a353b52895a5e6c196d6f448","label":"Public Key"}"#,
+			"\n",
+			r#"{"type":"output","ref":"f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:1","label":"Output","spendable":false}"#,
+			"\n",
+		);
+
+		let (labels, outputs_spendable) =
+			labels_from_bip329(bip329_labels, Network::Bitcoin).unwrap();
+		assert_eq!(labels.len(), 3);
+		assert!(matches!(labels[0].label_ref, LabelRef::Transaction { .. }));
+		assert_eq!(labels[0].label, "Transaction");
+		assert!(matches!(labels[1].label_ref, LabelRef::Address { .. }));
+		assert!(matches!(labels[2].label_ref, LabelRef::Output { .. }));
+
+		let txid =
+			Txid::from_str("f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd")
+				.unwrap();
+		let labeled_outpoint = OutPoint::new(txid, 1);
+		assert_eq!(outputs_spendable, vec![(labeled_outpoint, false)]);
+
+		let payment_label = Label {
+			label_ref: LabelRef::Payment { payment_id: PaymentId([42u8; 32]) },
+			label: "Coffee".to_string(),
+		};
+		let mut all_labels = labels.clone();
+		all_labels.push(payment_label);
+
+		// Locked outputs are exported as not spendable, even if they don't have a label.
+		let unlabeled_outpoint = OutPoint::new(txid, 2);
+		let exported = labels_to_bip329(&all_labels, &[labeled_outpoint, unlabeled_outpoint]);
+		assert_eq!(exported.lines().count(), 4);
+		assert_eq!(
+			labels_from_bip329(&exported, Network::Bitcoin).unwrap(),
+			(labels.clone(), vec![(labeled_outpoint, false), (unlabeled_outpoint, false)])
+		);
+
+		// Unlocked outputs are exported as spendable.
+		let exported = labels_to_bip329(&all_labels, &[]);
+		assert_eq!(exported.lines().count(), 3);
+		assert_eq!(
+			labels_from_bip329(&exported, Network::Bitcoin).unwrap(),
+			(labels, vec![(labeled_outpoint, true)])
+		);
+
+		// Addresses need to match our network.
+		assert!(labels_from_bip329(bip329_labels, Network::Testnet).is_err());
+		assert!(labels_from_bip329("not json", Network::Bitcoin).is_err());
+	}
+}
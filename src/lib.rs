@@ -46,7 +46,7 @@
 //!
 //! 	let node_id = PublicKey::from_str("NODE_ID").unwrap();
 //! 	let node_addr = SocketAddress::from_str("IP_ADDR:PORT").unwrap();
-//! 	node.open_channel(node_id, node_addr, 10000, None, None).unwrap();
+//! 	node.open_channel(node_id, node_addr, 10000, None, None, None, None).unwrap();
 //!
 //! 	let event = node.wait_next_event();
 //! 	println!("EVENT: {:?}", event);
@@ -74,6 +74,8 @@
 
 mod balance;
 mod builder;
+mod chain;
+mod channel_history;
 mod config;
 mod connection;
 mod error;
@@ -83,6 +85,7 @@ mod gossip;
 pub mod graph;
 mod hex_utils;
 pub mod io;
+mod label_store;
 mod liquidity;
 mod logger;
 mod message_handler;
@@ -95,17 +98,25 @@ mod types;
 mod uniffi_types;
 mod wallet;
 
+pub use bdk_chain;
+pub use bdk_wallet::KeychainKind;
 pub use bip39;
 pub use bitcoin;
 pub use lightning;
 pub use lightning_invoice;
 
 pub use balance::{BalanceDetails, LightningBalance, PendingSweepBalance};
-pub use config::{default_config, AnchorChannelsConfig, Config};
+pub use chain::CustomChainSource;
+pub use channel_history::ClosedChannelDetails;
+pub use config::{
+	default_config, AnchorChannelsConfig, ChannelTypeFeature, Config, InboundChannelPolicy,
+};
 pub use error::Error as NodeError;
 use error::Error;
 
 pub use event::Event;
+pub use fee_estimator::{ConfirmationTarget, FeeEstimator};
+pub use label_store::{Label, LabelRef};
 pub use types::{ChannelConfig, MaxDustHTLCExposure};
 
 pub use io::utils::generate_entropy_mnemonic;
@@ -115,52 +126,60 @@ use uniffi_types::*;
 
 #[cfg(feature = "uniffi")]
 pub use builder::ArcedNodeBuilder as Builder;
-pub use builder::BuildError;
 #[cfg(not(feature = "uniffi"))]
 pub use builder::NodeBuilder as Builder;
+pub use builder::{BuildError, WalletDescriptorTemplate};
 
+use chain::ChainSource;
 use config::{
-	default_user_config, may_announce_channel, LDK_WALLET_SYNC_TIMEOUT_SECS,
-	NODE_ANN_BCAST_INTERVAL, PEER_RECONNECTION_INTERVAL,
-	RESOLVED_CHANNEL_MONITOR_ARCHIVAL_INTERVAL, RGS_SYNC_INTERVAL,
-	WALLET_SYNC_INTERVAL_MINIMUM_SECS,
+	default_user_config, may_announce_channel, NODE_ANN_BCAST_INTERVAL, PEER_RECONNECTION_INTERVAL,
+	RGS_SYNC_INTERVAL, WALLET_SYNC_INTERVAL_MINIMUM_SECS,
 };
 use connection::ConnectionManager;
-use event::{EventHandler, EventQueue};
+use event::{EventHandler, EventQueue, InboundChannelRequest};
 use gossip::GossipSource;
 use graph::NetworkGraph;
+use label_store::LabelStore;
 use liquidity::LiquiditySource;
 use payment::store::PaymentStore;
 use payment::{
 	Bolt11Payment, Bolt12Payment, OnchainPayment, PaymentDetails, SpontaneousPayment,
-	UnifiedQrPayment,
+	UnifiedQrPayment, UtxoSelection,
 };
 use peer_store::{PeerInfo, PeerStore};
 use types::{
-	Broadcaster, BumpTransactionEventHandler, ChainMonitor, ChannelManager, DynStore, FeeEstimator,
-	Graph, KeysManager, OnionMessenger, PeerManager, Router, Scorer, Sweeper, Wallet,
+	Broadcaster, BumpTransactionEventHandler, ChainMonitor, ChannelHistory, ChannelManager,
+	DynStore, Graph, KeysManager, OnionMessenger, PeerManager, Router, Scorer, Sweeper, Wallet,
 };
-pub use types::{ChannelDetails, PeerDetails, UserChannelId};
+pub use types::{ChannelCloseOptions, ChannelDetails, PeerDetails, UserChannelId};
+use wallet::{ChannelFundingParams, FundingBatch, PendingChannelFunding};
 
 use logger::{log_error, log_info, log_trace, FilesystemLogger, Logger};
 
-use lightning::chain::{BestBlock, Confirm};
+use lightning::chain::BestBlock;
 use lightning::events::bump_transaction::Wallet as LdkWallet;
 use lightning::ln::channel_state::ChannelShutdownState;
 use lightning::ln::channelmanager::PaymentId;
 use lightning::ln::msgs::SocketAddress;
+use lightning::ln::script::ShutdownScript;
+use lightning::ln::types::ChannelId;
 use lightning::routing::gossip::NodeAlias;
+use lightning::util::config::UserConfig;
+use lightning::util::errors::APIError;
 
 pub use lightning::util::logger::Level as LogLevel;
 
 use lightning_background_processor::process_events_async;
 
-use lightning_transaction_sync::EsploraSyncClient;
+use bdk_chain::miniscript::psbt::PsbtExt;
 
-use bitcoin::secp256k1::PublicKey;
+use bitcoin::psbt::Psbt;
+use bitcoin::secp256k1::{PublicKey, Secp256k1};
+use bitcoin::{FeeRate, Transaction};
 
 use rand::Rng;
 
+use std::collections::HashMap;
 use std::default::Default;
 use std::net::ToSocketAddrs;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -179,9 +198,8 @@ pub struct Node {
 	event_handling_stopped_sender: tokio::sync::watch::Sender<()>,
 	config: Arc<Config>,
 	wallet: Arc<Wallet>,
-	tx_sync: Arc<EsploraSyncClient<Arc<FilesystemLogger>>>,
+	chain_source: Arc<ChainSource>,
 	tx_broadcaster: Arc<Broadcaster>,
-	fee_estimator: Arc<FeeEstimator>,
 	event_queue: Arc<EventQueue<Arc<FilesystemLogger>>>,
 	channel_manager: Arc<ChannelManager>,
 	chain_monitor: Arc<ChainMonitor>,
@@ -199,13 +217,17 @@ pub struct Node {
 	scorer: Arc<Mutex<Scorer>>,
 	peer_store: Arc<PeerStore<Arc<FilesystemLogger>>>,
 	payment_store: Arc<PaymentStore<Arc<FilesystemLogger>>>,
+	label_store: Arc<LabelStore<Arc<FilesystemLogger>>>,
+	channel_history_store: Arc<ChannelHistory>,
+	channel_funding_params: Arc<Mutex<HashMap<u128, ChannelFundingParams>>>,
+	pending_channel_fundings: Arc<Mutex<HashMap<u128, PendingChannelFunding>>>,
+	inbound_channel_requests: Arc<Mutex<HashMap<ChannelId, InboundChannelRequest>>>,
 	is_listening: Arc<AtomicBool>,
 	latest_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
 	latest_onchain_wallet_sync_timestamp: Arc<RwLock<Option<u64>>>,
 	latest_fee_rate_cache_update_timestamp: Arc<RwLock<Option<u64>>>,
 	latest_rgs_snapshot_timestamp: Arc<RwLock<Option<u64>>>,
 	latest_node_announcement_broadcast_timestamp: Arc<RwLock<Option<u64>>>,
-	latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
 }
 
 impl Node {
@@ -243,88 +265,41 @@ impl Node {
 			self.config.network
 		);
 
+		// Start up any runtime-dependant chain sources (e.g. Electrum)
+		self.chain_source.start().map_err(|e| {
+			log_error!(self.logger, "Failed to start chain syncing: {}", e);
+			e
+		})?;
+
 		// Block to ensure we update our fee rate cache once on startup
-		let fee_estimator = Arc::clone(&self.fee_estimator);
-		let sync_logger = Arc::clone(&self.logger);
-		let sync_fee_rate_update_timestamp =
-			Arc::clone(&self.latest_fee_rate_cache_update_timestamp);
+		let chain_source = Arc::clone(&self.chain_source);
 		let runtime_ref = &runtime;
 		tokio::task::block_in_place(move || {
-			runtime_ref.block_on(async move {
-				let now = Instant::now();
-				match fee_estimator.update_fee_estimates().await {
-					Ok(()) => {
-						log_info!(
-							sync_logger,
-							"Initial fee rate cache update finished in {}ms.",
-							now.elapsed().as_millis()
-						);
-						let unix_time_secs_opt =
-							SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
-						*sync_fee_rate_update_timestamp.write().unwrap() = unix_time_secs_opt;
-						Ok(())
-					},
-					Err(e) => {
-						log_error!(sync_logger, "Initial fee rate cache update failed: {}", e,);
-						Err(e)
-					},
-				}
-			})
+			runtime_ref.block_on(async move { chain_source.update_fee_rate_estimates().await })
 		})?;
 
-		// Setup wallet sync
-		let wallet = Arc::clone(&self.wallet);
-		let sync_logger = Arc::clone(&self.logger);
-		let sync_onchain_wallet_timestamp = Arc::clone(&self.latest_onchain_wallet_sync_timestamp);
-		let mut stop_sync = self.stop_sender.subscribe();
-		let onchain_wallet_sync_interval_secs = self
-			.config
-			.onchain_wallet_sync_interval_secs
-			.max(config::WALLET_SYNC_INTERVAL_MINIMUM_SECS);
+		// Spawn background task continuously syncing onchain, lightning, and fee rate cache.
+		let stop_sync_receiver = self.stop_sender.subscribe();
+		let chain_source = Arc::clone(&self.chain_source);
+		let sync_cman = Arc::clone(&self.channel_manager);
+		let sync_cmon = Arc::clone(&self.chain_monitor);
+		let sync_sweeper = Arc::clone(&self.output_sweeper);
+		let sync_channel_history = Arc::clone(&self.channel_history_store);
 		runtime.spawn(async move {
-			let mut onchain_wallet_sync_interval =
-				tokio::time::interval(Duration::from_secs(onchain_wallet_sync_interval_secs));
-			onchain_wallet_sync_interval
-				.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-			loop {
-				tokio::select! {
-					_ = stop_sync.changed() => {
-						log_trace!(
-							sync_logger,
-							"Stopping background syncing on-chain wallet.",
-						);
-						return;
-					}
-					_ = onchain_wallet_sync_interval.tick() => {
-						let now = Instant::now();
-						match wallet.sync().await {
-							Ok(()) => {
-								log_trace!(
-									sync_logger,
-									"Background sync of on-chain wallet finished in {}ms.",
-									now.elapsed().as_millis()
-								);
-								let unix_time_secs_opt =
-									SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
-								*sync_onchain_wallet_timestamp.write().unwrap() = unix_time_secs_opt;
-							}
-							Err(err) => {
-								log_error!(
-									sync_logger,
-									"Background sync of on-chain wallet failed: {}",
-									err
-								)
-							}
-						}
-					}
-				}
-			}
+			chain_source
+				.continuously_sync_wallets(
+					stop_sync_receiver,
+					sync_cman,
+					sync_cmon,
+					sync_sweeper,
+					sync_channel_history,
+				)
+				.await;
 		});
 
 		let mut stop_fee_updates = self.stop_sender.subscribe();
 		let fee_update_logger = Arc::clone(&self.logger);
-		let fee_update_timestamp = Arc::clone(&self.latest_fee_rate_cache_update_timestamp);
-		let fee_estimator = Arc::clone(&self.fee_estimator);
+		let chain_source = Arc::clone(&self.chain_source);
 		let fee_rate_cache_update_interval_secs =
 			self.config.fee_rate_cache_update_interval_secs.max(WALLET_SYNC_INTERVAL_MINIMUM_SECS);
 		runtime.spawn(async move {
@@ -344,90 +319,7 @@ impl Node {
 						return;
 					}
 					_ = fee_rate_update_interval.tick() => {
-						let now = Instant::now();
-						match fee_estimator.update_fee_estimates().await {
-							Ok(()) => {
-								log_trace!(
-								fee_update_logger,
-								"Background update of fee rate cache finished in {}ms.",
-								now.elapsed().as_millis()
-								);
-								let unix_time_secs_opt =
-									SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
-								*fee_update_timestamp.write().unwrap() = unix_time_secs_opt;
-							}
-							Err(err) => {
-								log_error!(
-									fee_update_logger,
-									"Background update of fee rate cache failed: {}",
-									err
-									)
-							}
-						}
-					}
-				}
-			}
-		});
-
-		let tx_sync = Arc::clone(&self.tx_sync);
-		let sync_cman = Arc::clone(&self.channel_manager);
-		let archive_cman = Arc::clone(&self.channel_manager);
-		let sync_cmon = Arc::clone(&self.chain_monitor);
-		let archive_cmon = Arc::clone(&self.chain_monitor);
-		let sync_sweeper = Arc::clone(&self.output_sweeper);
-		let sync_logger = Arc::clone(&self.logger);
-		let sync_wallet_timestamp = Arc::clone(&self.latest_wallet_sync_timestamp);
-		let sync_monitor_archival_height = Arc::clone(&self.latest_channel_monitor_archival_height);
-		let mut stop_sync = self.stop_sender.subscribe();
-		let wallet_sync_interval_secs =
-			self.config.wallet_sync_interval_secs.max(WALLET_SYNC_INTERVAL_MINIMUM_SECS);
-		runtime.spawn(async move {
-			let mut wallet_sync_interval =
-				tokio::time::interval(Duration::from_secs(wallet_sync_interval_secs));
-			wallet_sync_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-			loop {
-				tokio::select! {
-					_ = stop_sync.changed() => {
-						log_trace!(
-							sync_logger,
-							"Stopping background syncing Lightning wallet.",
-						);
-						return;
-					}
-					_ = wallet_sync_interval.tick() => {
-						let confirmables = vec![
-							&*sync_cman as &(dyn Confirm + Sync + Send),
-							&*sync_cmon as &(dyn Confirm + Sync + Send),
-							&*sync_sweeper as &(dyn Confirm + Sync + Send),
-						];
-						let now = Instant::now();
-						let timeout_fut = tokio::time::timeout(Duration::from_secs(LDK_WALLET_SYNC_TIMEOUT_SECS), tx_sync.sync(confirmables));
-						match timeout_fut.await {
-							Ok(res) => match res {
-								Ok(()) => {
-									log_trace!(
-										sync_logger,
-										"Background sync of Lightning wallet finished in {}ms.",
-										now.elapsed().as_millis()
-										);
-									let unix_time_secs_opt =
-										SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
-									*sync_wallet_timestamp.write().unwrap() = unix_time_secs_opt;
-
-									periodically_archive_fully_resolved_monitors(
-										Arc::clone(&archive_cman),
-										Arc::clone(&archive_cmon),
-										Arc::clone(&sync_monitor_archival_height)
-									);
-								}
-								Err(e) => {
-									log_error!(sync_logger, "Background sync of Lightning wallet failed: {}", e)
-								}
-							}
-							Err(e) => {
-								log_error!(sync_logger, "Background sync of Lightning wallet timed out: {}", e)
-							}
-						}
+						let _ = chain_source.update_fee_rate_estimates().await;
 					}
 				}
 			}
@@ -676,7 +568,7 @@ impl Node {
 		}
 
 		let mut stop_tx_bcast = self.stop_sender.subscribe();
-		let tx_bcaster = Arc::clone(&self.tx_broadcaster);
+		let chain_source = Arc::clone(&self.chain_source);
 		let tx_bcast_logger = Arc::clone(&self.logger);
 		runtime.spawn(async move {
 			// Every second we try to clear our broadcasting queue.
@@ -692,7 +584,7 @@ impl Node {
 							return;
 						}
 						_ = interval.tick() => {
-							tx_bcaster.process_queue().await;
+							chain_source.process_broadcast_queue().await;
 						}
 				}
 			}
@@ -711,10 +603,15 @@ impl Node {
 			bump_tx_event_handler,
 			Arc::clone(&self.channel_manager),
 			Arc::clone(&self.connection_manager),
+			Arc::clone(&self.chain_monitor),
 			Arc::clone(&self.output_sweeper),
 			Arc::clone(&self.network_graph),
 			Arc::clone(&self.payment_store),
+			Arc::clone(&self.channel_history_store),
 			Arc::clone(&self.peer_store),
+			Arc::clone(&self.channel_funding_params),
+			Arc::clone(&self.pending_channel_fundings),
+			Arc::clone(&self.inbound_channel_requests),
 			Arc::clone(&self.runtime),
 			Arc::clone(&self.logger),
 			Arc::clone(&self.config),
@@ -839,6 +736,9 @@ impl Node {
 		// Disconnect all peers.
 		self.peer_manager.disconnect_all_peers();
 
+		// Stop any runtime-dependant chain sources.
+		self.chain_source.stop();
+
 		// Wait until event handling stopped, at least until a timeout is reached.
 		let event_handling_stopped_logger = Arc::clone(&self.logger);
 		let mut event_handling_stopped_receiver = self.event_handling_stopped_sender.subscribe();
@@ -1069,7 +969,10 @@ impl Node {
 		OnchainPayment::new(
 			Arc::clone(&self.runtime),
 			Arc::clone(&self.wallet),
+			Arc::clone(&self.chain_source),
 			Arc::clone(&self.channel_manager),
+			Arc::clone(&self.channel_funding_params),
+			Arc::clone(&self.pending_channel_fundings),
 			Arc::clone(&self.config),
 			Arc::clone(&self.logger),
 		)
@@ -1081,7 +984,10 @@ impl Node {
 		Arc::new(OnchainPayment::new(
 			Arc::clone(&self.runtime),
 			Arc::clone(&self.wallet),
+			Arc::clone(&self.chain_source),
 			Arc::clone(&self.channel_manager),
+			Arc::clone(&self.channel_funding_params),
+			Arc::clone(&self.pending_channel_fundings),
 			Arc::clone(&self.config),
 			Arc::clone(&self.logger),
 		))
@@ -1126,6 +1032,15 @@ impl Node {
 		self.channel_manager.list_channels().into_iter().map(|c| c.into()).collect()
 	}
 
+	/// Retrieve a list of channels that have been closed.
+	///
+	/// This allows to review the details of channels after they were removed from
+	/// [`Node::list_channels`], e.g., to determine where their funds went. Channels that were
+	/// closed before being funded, e.g., rejected inbound channel requests, won't be included.
+	pub fn list_closed_channels(&self) -> Vec<ClosedChannelDetails> {
+		self.channel_history_store.list_closed_channels()
+	}
+
 	/// Connect to a node on the peer-to-peer network.
 	///
 	/// If `persist` is set to `true`, we'll remember the peer and reconnect to it on restart.
@@ -1184,10 +1099,47 @@ impl Node {
 		Ok(())
 	}
 
+	/// Returns the on-chain reserve we need to retain for a new channel with the given connected
+	/// peer, if any.
+	fn new_channel_anchor_reserve_sats(&self, node_id: &PublicKey) -> Result<u64, Error> {
+		let init_features = self
+			.peer_manager
+			.peer_by_node_id(node_id)
+			.ok_or(Error::ConnectionFailed)?
+			.init_features;
+		Ok(self.config.anchor_channels_config.as_ref().map_or(0, |c| {
+			if init_features.requires_anchors_zero_fee_htlc_tx()
+				&& !c.trusted_peers_no_reserve.contains(node_id)
+			{
+				c.per_channel_reserve_sats
+			} else {
+				0
+			}
+		}))
+	}
+
+	fn new_channel_user_config(
+		&self, channel_config: Option<ChannelConfig>, announce_for_forwarding: bool,
+	) -> UserConfig {
+		let mut user_config = default_user_config(&self.config);
+		user_config.channel_handshake_config.announce_for_forwarding = announce_for_forwarding;
+		user_config.channel_config = (channel_config.unwrap_or_default()).clone().into();
+		// We set the max inflight to 100% for private channels.
+		// FIXME: LDK will default to this behavior soon, too, at which point we should drop this
+		// manual override.
+		if !announce_for_forwarding {
+			user_config
+				.channel_handshake_config
+				.max_inbound_htlc_value_in_flight_percent_of_channel = 100;
+		}
+		user_config
+	}
+
 	fn open_channel_inner(
 		&self, node_id: PublicKey, address: SocketAddress, channel_amount_sats: u64,
 		push_to_counterparty_msat: Option<u64>, channel_config: Option<ChannelConfig>,
-		announce_for_forwarding: bool,
+		fee_rate: Option<FeeRate>, utxo_selection: Option<UtxoSelection>,
+		announce_for_forwarding: bool, external_funding: bool,
 	) -> Result<UserChannelId, Error> {
 		let rt_lock = self.runtime.read().unwrap();
 		if rt_lock.is_none() {
@@ -1206,11 +1158,14 @@ impl Node {
 		let spendable_amount_sats =
 			self.wallet.get_spendable_amount_sats(cur_anchor_reserve_sats).unwrap_or(0);
 
+		// The channel value doesn't come out of our wallet if the user funds the channel.
+		let required_channel_funds_sats = if external_funding { 0 } else { channel_amount_sats };
+
 		// Fail early if we have less than the channel value available.
-		if spendable_amount_sats < channel_amount_sats {
+		if spendable_amount_sats < required_channel_funds_sats {
 			log_error!(self.logger,
 				"Unable to create channel due to insufficient funds. Available: {}sats, Required: {}sats",
-				spendable_amount_sats, channel_amount_sats
+				spendable_amount_sats, required_channel_funds_sats
 			);
 			return Err(Error::InsufficientFunds);
 		}
@@ -1224,21 +1179,8 @@ impl Node {
 		})?;
 
 		// Fail if we have less than the channel value + anchor reserve available (if applicable).
-		let init_features = self
-			.peer_manager
-			.peer_by_node_id(&node_id)
-			.ok_or(Error::ConnectionFailed)?
-			.init_features;
-		let required_funds_sats = channel_amount_sats
-			+ self.config.anchor_channels_config.as_ref().map_or(0, |c| {
-				if init_features.requires_anchors_zero_fee_htlc_tx()
-					&& !c.trusted_peers_no_reserve.contains(&node_id)
-				{
-					c.per_channel_reserve_sats
-				} else {
-					0
-				}
-			});
+		let required_funds_sats =
+			required_channel_funds_sats + self.new_channel_anchor_reserve_sats(&node_id)?;
 
 		if spendable_amount_sats < required_funds_sats {
 			log_error!(self.logger,
@@ -1248,21 +1190,21 @@ impl Node {
 			return Err(Error::InsufficientFunds);
 		}
 
-		let mut user_config = default_user_config(&self.config);
-		user_config.channel_handshake_config.announce_for_forwarding = announce_for_forwarding;
-		user_config.channel_config = (channel_config.unwrap_or_default()).clone().into();
-		// We set the max inflight to 100% for private channels.
-		// FIXME: LDK will default to this behavior soon, too, at which point we should drop this
-		// manual override.
-		if !announce_for_forwarding {
-			user_config
-				.channel_handshake_config
-				.max_inbound_htlc_value_in_flight_percent_of_channel = 100;
-		}
+		let user_config = self.new_channel_user_config(channel_config, announce_for_forwarding);
 
 		let push_msat = push_to_counterparty_msat.unwrap_or(0);
 		let user_channel_id: u128 = rand::thread_rng().gen::<u128>();
 
+		if fee_rate.is_some() || utxo_selection.is_some() || external_funding {
+			let funding_params = ChannelFundingParams {
+				fee_rate,
+				utxo_selection,
+				external_funding,
+				funding_batch: None,
+			};
+			self.channel_funding_params.lock().unwrap().insert(user_channel_id, funding_params);
+		}
+
 		match self.channel_manager.create_channel(
 			peer_info.node_id,
 			channel_amount_sats,
@@ -1282,6 +1224,7 @@ impl Node {
 			},
 			Err(e) => {
 				log_error!(self.logger, "Failed to initiate channel creation: {:?}", e);
+				self.channel_funding_params.lock().unwrap().remove(&user_channel_id);
 				Err(Error::ChannelCreationFailed)
 			},
 		}
@@ -1297,6 +1240,12 @@ impl Node {
 	/// channel counterparty on channel open. This can be useful to start out with the balance not
 	/// entirely shifted to one side, therefore allowing to receive payments from the getgo.
 	///
+	/// If `fee_rate` is set, it will be used for the funding transaction instead of our current fee
+	/// rate estimate for channel funding.
+	///
+	/// If `utxo_selection` is set, it will restrict which of our UTXOs are used to fund the
+	/// channel.
+	///
 	/// If Anchor channels are enabled, this will ensure the configured
 	/// [`AnchorChannelsConfig::per_channel_reserve_sats`] is available and will be retained before
 	/// opening the channel.
@@ -1305,6 +1254,7 @@ impl Node {
 	pub fn open_channel(
 		&self, node_id: PublicKey, address: SocketAddress, channel_amount_sats: u64,
 		push_to_counterparty_msat: Option<u64>, channel_config: Option<ChannelConfig>,
+		fee_rate: Option<FeeRate>, utxo_selection: Option<UtxoSelection>,
 	) -> Result<UserChannelId, Error> {
 		self.open_channel_inner(
 			node_id,
@@ -1312,6 +1262,9 @@ impl Node {
 			channel_amount_sats,
 			push_to_counterparty_msat,
 			channel_config,
+			fee_rate,
+			utxo_selection,
+			false,
 			false,
 		)
 	}
@@ -1330,6 +1283,12 @@ impl Node {
 	/// channel counterparty on channel open. This can be useful to start out with the balance not
 	/// entirely shifted to one side, therefore allowing to receive payments from the getgo.
 	///
+	/// If `fee_rate` is set, it will be used for the funding transaction instead of our current fee
+	/// rate estimate for channel funding.
+	///
+	/// If `utxo_selection` is set, it will restrict which of our UTXOs are used to fund the
+	/// channel.
+	///
 	/// If Anchor channels are enabled, this will ensure the configured
 	/// [`AnchorChannelsConfig::per_channel_reserve_sats`] is available and will be retained before
 	/// opening the channel.
@@ -1338,6 +1297,7 @@ impl Node {
 	pub fn open_announced_channel(
 		&self, node_id: PublicKey, address: SocketAddress, channel_amount_sats: u64,
 		push_to_counterparty_msat: Option<u64>, channel_config: Option<ChannelConfig>,
+		fee_rate: Option<FeeRate>, utxo_selection: Option<UtxoSelection>,
 	) -> Result<UserChannelId, Error> {
 		if may_announce_channel(&self.config) {
 			self.open_channel_inner(
@@ -1346,7 +1306,10 @@ impl Node {
 				channel_amount_sats,
 				push_to_counterparty_msat,
 				channel_config,
+				fee_rate,
+				utxo_selection,
 				true,
+				false,
 			)
 		} else {
 			log_error!(self.logger, "Failed to open announced channel as the node hasn't been sufficiently configured to act as a forwarding node. Please make sure to configure listening addreesses and node alias");
@@ -1354,6 +1317,334 @@ impl Node {
 		}
 	}
 
+	/// Connect to the given nodes and open new unannounced channels with all of them, funding the
+	/// channels with a single on-chain transaction.
+	///
+	/// Each of the given `channels` consists of the counterparty's node id and address, the
+	/// channel value in satoshis, and optionally the amount to push to the counterparty and the
+	/// [`ChannelConfig`] to use, as for [`Node::open_channel`]. This allows to save on-chain fees,
+	/// e.g., when bootstrapping a routing node's initial set of channels.
+	///
+	/// The funding transaction will only be created once all counterparties accepted their
+	/// channel. If any of the channels fails before that, all channels of the batch will be
+	/// closed and a [`Event::ChannelClosed`] will be emitted for each of them.
+	///
+	/// If `fee_rate` is set, it will be used for the funding transaction instead of our current fee
+	/// rate estimate for channel funding.
+	///
+	/// If `utxo_selection` is set, it will restrict which of our UTXOs are used to fund the
+	/// channels.
+	///
+	/// If Anchor channels are enabled, this will ensure the configured
+	/// [`AnchorChannelsConfig::per_channel_reserve_sats`] is available and will be retained for each
+	/// of the channels.
+	///
+	/// Returns the [`UserChannelId`]s of the channels, in the order they were given.
+	pub fn open_channels(
+		&self, channels: Vec<(PublicKey, SocketAddress, u64, Option<u64>, Option<ChannelConfig>)>,
+		fee_rate: Option<FeeRate>, utxo_selection: Option<UtxoSelection>,
+	) -> Result<Vec<UserChannelId>, Error> {
+		let rt_lock = self.runtime.read().unwrap();
+		if rt_lock.is_none() {
+			return Err(Error::NotRunning);
+		}
+		let runtime = rt_lock.as_ref().unwrap();
+
+		if channels.is_empty() {
+			log_error!(self.logger, "Failed to open channels as none were given");
+			return Err(Error::ChannelCreationFailed);
+		}
+
+		if self.wallet.is_watch_only() {
+			log_error!(
+				self.logger,
+				"Failed to open channels as batch funding isn't supported for watch-only wallets"
+			);
+			return Err(Error::ChannelCreationFailed);
+		}
+
+		let total_channel_amount_sats = channels
+			.iter()
+			.try_fold(0u64, |acc, (_, _, amount_sats, _, _)| acc.checked_add(*amount_sats))
+			.ok_or(Error::InvalidAmount)?;
+
+		let cur_anchor_reserve_sats =
+			total_anchor_channels_reserve_sats(&self.channel_manager, &self.config);
+		let spendable_amount_sats =
+			self.wallet.get_spendable_amount_sats(cur_anchor_reserve_sats).unwrap_or(0);
+
+		// Fail early if we have less than the total channel value available.
+		if spendable_amount_sats < total_channel_amount_sats {
+			log_error!(self.logger,
+				"Unable to create channels due to insufficient funds. Available: {}sats, Required: {}sats",
+				spendable_amount_sats, total_channel_amount_sats
+			);
+			return Err(Error::InsufficientFunds);
+		}
+
+		let mut required_funds_sats = total_channel_amount_sats;
+		for (node_id, address, _, _, _) in &channels {
+			let con_node_id = *node_id;
+			let con_addr = address.clone();
+			let con_cm = Arc::clone(&self.connection_manager);
+
+			// We need to use our main runtime here as a local runtime might not be around to poll
+			// connection futures going forward.
+			tokio::task::block_in_place(move || {
+				runtime.block_on(async move {
+					con_cm.connect_peer_if_necessary(con_node_id, con_addr).await
+				})
+			})?;
+
+			required_funds_sats += self.new_channel_anchor_reserve_sats(node_id)?;
+		}
+
+		// Fail if we have less than the channel values + anchor reserves available (if applicable).
+		if spendable_amount_sats < required_funds_sats {
+			log_error!(self.logger,
+				"Unable to create channels due to insufficient funds. Available: {}sats, Required: {}sats",
+				spendable_amount_sats, required_funds_sats
+			);
+			return Err(Error::InsufficientFunds);
+		}
+
+		let user_channel_ids: Vec<u128> =
+			channels.iter().map(|_| rand::thread_rng().gen::<u128>()).collect();
+		let funding_batch = Arc::new(Mutex::new(FundingBatch::new(user_channel_ids.clone())));
+		{
+			let mut locked_funding_params = self.channel_funding_params.lock().unwrap();
+			for user_channel_id in &user_channel_ids {
+				let funding_params = ChannelFundingParams {
+					fee_rate,
+					utxo_selection: utxo_selection.clone(),
+					external_funding: false,
+					funding_batch: Some(Arc::clone(&funding_batch)),
+				};
+				locked_funding_params.insert(*user_channel_id, funding_params);
+			}
+		}
+
+		let mut created_channels = Vec::with_capacity(channels.len());
+		for (
+			(node_id, address, channel_amount_sats, push_to_counterparty_msat, channel_config),
+			user_channel_id,
+		) in channels.into_iter().zip(user_channel_ids.iter())
+		{
+			let user_config = self.new_channel_user_config(channel_config, false);
+			let push_msat = push_to_counterparty_msat.unwrap_or(0);
+
+			match self.channel_manager.create_channel(
+				node_id,
+				channel_amount_sats,
+				push_msat,
+				*user_channel_id,
+				None,
+				Some(user_config),
+			) {
+				Ok(temporary_channel_id) => {
+					log_info!(self.logger, "Initiated channel creation with peer {}. ", node_id);
+					created_channels.push((temporary_channel_id, PeerInfo { node_id, address }));
+				},
+				Err(e) => {
+					log_error!(self.logger, "Failed to initiate channel creation: {:?}", e);
+
+					// Abandon the channels of the batch we already initiated.
+					funding_batch.lock().unwrap().failed = true;
+					for (temporary_channel_id, peer_info) in created_channels {
+						self.channel_manager
+							.force_close_without_broadcasting_txn(
+								&temporary_channel_id,
+								&peer_info.node_id,
+								"Channel funding batch failed".to_string(),
+							)
+							.unwrap_or_else(|e| {
+								log_error!(
+									self.logger,
+									"Failed to close channel {} of failed funding batch: {:?}",
+									temporary_channel_id,
+									e
+								)
+							});
+					}
+
+					let mut locked_funding_params = self.channel_funding_params.lock().unwrap();
+					for user_channel_id in &user_channel_ids {
+						locked_funding_params.remove(user_channel_id);
+					}
+					return Err(Error::ChannelCreationFailed);
+				},
+			}
+		}
+
+		for (_, peer_info) in created_channels {
+			self.peer_store.add_peer(peer_info)?;
+		}
+
+		Ok(user_channel_ids.into_iter().map(UserChannelId).collect())
+	}
+
+	/// Connect to a node and open a new unannounced channel that will be funded by a transaction
+	/// supplied by the user rather than by our on-chain wallet.
+	///
+	/// Once the channel counterparty accepted the channel, an [`Event::FundingTransactionRequired`]
+	/// will be emitted, detailing the funding output the user needs to create. The signed funding
+	/// transaction then needs to be handed back via [`Node::fund_channel_with_transaction`] or
+	/// [`Node::fund_channel_with_psbt`]. This allows to fund channels directly from, e.g., cold
+	/// storage or an exchange wallet.
+	///
+	/// To open an announced channel, see [`Node::open_externally_funded_announced_channel`].
+	///
+	/// If `push_to_counterparty_msat` is set, the given value will be pushed (read: sent) to the
+	/// channel counterparty on channel open.
+	///
+	/// If Anchor channels are enabled, this will still ensure the configured
+	/// [`AnchorChannelsConfig::per_channel_reserve_sats`] is available in our on-chain wallet.
+	///
+	/// Returns a [`UserChannelId`] allowing to locally keep track of the channel.
+	pub fn open_externally_funded_channel(
+		&self, node_id: PublicKey, address: SocketAddress, channel_amount_sats: u64,
+		push_to_counterparty_msat: Option<u64>, channel_config: Option<ChannelConfig>,
+	) -> Result<UserChannelId, Error> {
+		self.open_channel_inner(
+			node_id,
+			address,
+			channel_amount_sats,
+			push_to_counterparty_msat,
+			channel_config,
+			None,
+			None,
+			false,
+			true,
+		)
+	}
+
+	/// Connect to a node and open a new announced channel that will be funded by a transaction
+	/// supplied by the user rather than by our on-chain wallet.
+	///
+	/// This will return an error if the node has not been sufficiently configured to operate as a
+	/// forwarding node that can properly announce its existence to the publip network graph, i.e.,
+	/// [`Config::listening_addresses`] and [`Config::node_alias`] are unset.
+	///
+	/// See [`Node::open_externally_funded_channel`] for more information.
+	pub fn open_externally_funded_announced_channel(
+		&self, node_id: PublicKey, address: SocketAddress, channel_amount_sats: u64,
+		push_to_counterparty_msat: Option<u64>, channel_config: Option<ChannelConfig>,
+	) -> Result<UserChannelId, Error> {
+		if may_announce_channel(&self.config) {
+			self.open_channel_inner(
+				node_id,
+				address,
+				channel_amount_sats,
+				push_to_counterparty_msat,
+				channel_config,
+				None,
+				None,
+				true,
+				true,
+			)
+		} else {
+			log_error!(self.logger, "Failed to open announced channel as the node hasn't been sufficiently configured to act as a forwarding node. Please make sure to configure listening addreesses and node alias");
+			return Err(Error::ChannelCreationFailed);
+		}
+	}
+
+	/// Funds a channel opened via [`Node::open_externally_funded_channel`] with the given signed
+	/// transaction.
+	///
+	/// The transaction needs to pay exactly the amount given in the respective
+	/// [`Event::FundingTransactionRequired`] to its `output_script` and must only spend SegWit
+	/// inputs. It must not have been broadcast yet, as we'll only do so once it's safe.
+	pub fn fund_channel_with_transaction(
+		&self, user_channel_id: &UserChannelId, funding_tx: Transaction,
+	) -> Result<(), Error> {
+		let rt_lock = self.runtime.read().unwrap();
+		if rt_lock.is_none() {
+			return Err(Error::NotRunning);
+		}
+
+		let pending_funding = self
+			.pending_channel_fundings
+			.lock()
+			.unwrap()
+			.get(&user_channel_id.0)
+			.cloned()
+			.ok_or_else(|| {
+				log_error!(
+					self.logger,
+					"No channel with user_channel_id {} is awaiting funding",
+					user_channel_id.0
+				);
+				Error::ChannelCreationFailed
+			})?;
+
+		let pays_funding_output = funding_tx.output.iter().any(|o| {
+			o.script_pubkey == pending_funding.output_script
+				&& o.value.to_sat() == pending_funding.channel_value_sats
+		});
+		if !pays_funding_output {
+			log_error!(
+				self.logger,
+				"Funding transaction doesn't pay {}sats to the channel's funding output",
+				pending_funding.channel_value_sats
+			);
+			return Err(Error::InvalidTransaction);
+		}
+
+		let txid = funding_tx.compute_txid();
+		self.channel_manager
+			.funding_transaction_generated(
+				pending_funding.temporary_channel_id,
+				pending_funding.counterparty_node_id,
+				funding_tx,
+			)
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to process funding transaction: {:?}", e);
+				match e {
+					APIError::APIMisuseError { .. } => Error::InvalidTransaction,
+					_ => Error::ChannelCreationFailed,
+				}
+			})?;
+
+		self.pending_channel_fundings.lock().unwrap().remove(&user_channel_id.0);
+		log_info!(
+			self.logger,
+			"Handed funding transaction {} of channel with user_channel_id {} to LDK",
+			txid,
+			user_channel_id.0
+		);
+		Ok(())
+	}
+
+	/// Funds a channel opened via [`Node::open_externally_funded_channel`] with the transaction
+	/// of the given signed PSBT.
+	///
+	/// Any inputs that are signed but not yet finalized will be finalized before the transaction
+	/// is extracted. See [`Node::fund_channel_with_transaction`] for the requirements the
+	/// transaction needs to satisfy.
+	pub fn fund_channel_with_psbt(
+		&self, user_channel_id: &UserChannelId, mut psbt: Psbt,
+	) -> Result<(), Error> {
+		let secp = Secp256k1::verification_only();
+		for index in 0..psbt.inputs.len() {
+			let input = &psbt.inputs[index];
+			if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+				continue;
+			}
+
+			psbt.finalize_inp_mut(&secp, index).map_err(|e| {
+				log_error!(self.logger, "Failed to finalize PSBT input {}: {}", index, e);
+				Error::InvalidPsbt
+			})?;
+		}
+
+		let funding_tx = psbt.extract_tx().map_err(|e| {
+			log_error!(self.logger, "Failed to extract funding transaction from PSBT: {}", e);
+			Error::InvalidPsbt
+		})?;
+
+		self.fund_channel_with_transaction(user_channel_id, funding_tx)
+	}
+
 	/// Manually sync the LDK and BDK wallets with the current chain state and update the fee rate
 	/// cache.
 	///
@@ -1367,110 +1658,92 @@ impl Node {
 			return Err(Error::NotRunning);
 		}
 
-		let wallet = Arc::clone(&self.wallet);
-		let tx_sync = Arc::clone(&self.tx_sync);
+		let chain_source = Arc::clone(&self.chain_source);
 		let sync_cman = Arc::clone(&self.channel_manager);
-		let archive_cman = Arc::clone(&self.channel_manager);
 		let sync_cmon = Arc::clone(&self.chain_monitor);
-		let archive_cmon = Arc::clone(&self.chain_monitor);
-		let fee_estimator = Arc::clone(&self.fee_estimator);
 		let sync_sweeper = Arc::clone(&self.output_sweeper);
-		let sync_logger = Arc::clone(&self.logger);
-		let confirmables = vec![
-			&*sync_cman as &(dyn Confirm + Sync + Send),
-			&*sync_cmon as &(dyn Confirm + Sync + Send),
-			&*sync_sweeper as &(dyn Confirm + Sync + Send),
-		];
-		let sync_wallet_timestamp = Arc::clone(&self.latest_wallet_sync_timestamp);
-		let sync_fee_rate_update_timestamp =
-			Arc::clone(&self.latest_fee_rate_cache_update_timestamp);
-		let sync_onchain_wallet_timestamp = Arc::clone(&self.latest_onchain_wallet_sync_timestamp);
-		let sync_monitor_archival_height = Arc::clone(&self.latest_channel_monitor_archival_height);
-
+		let sync_channel_history = Arc::clone(&self.channel_history_store);
 		tokio::task::block_in_place(move || {
 			tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap().block_on(
 				async move {
-					let now = Instant::now();
-					// We don't add an additional timeout here, as `Wallet::sync` already returns
-					// after a timeout.
-					match wallet.sync().await {
-						Ok(()) => {
-							log_info!(
-								sync_logger,
-								"Sync of on-chain wallet finished in {}ms.",
-								now.elapsed().as_millis()
-							);
-							let unix_time_secs_opt = SystemTime::now()
-								.duration_since(UNIX_EPOCH)
-								.ok()
-								.map(|d| d.as_secs());
-							*sync_onchain_wallet_timestamp.write().unwrap() = unix_time_secs_opt;
-						},
-						Err(e) => {
-							log_error!(sync_logger, "Sync of on-chain wallet failed: {}", e);
-							return Err(e);
+					match chain_source.as_ref() {
+						ChainSource::Esplora { .. }
+						| ChainSource::Electrum { .. }
+						| ChainSource::Custom { .. } => {
+							chain_source.sync_onchain_wallet(false).await?;
+							chain_source.update_fee_rate_estimates().await?;
+							chain_source
+								.sync_lightning_wallet(
+									sync_cman,
+									sync_cmon,
+									sync_sweeper,
+									sync_channel_history,
+								)
+								.await?;
 						},
-					};
-
-					let now = Instant::now();
-					// We don't add an additional timeout here, as
-					// `FeeEstimator::update_fee_estimates` already returns after a timeout.
-					match fee_estimator.update_fee_estimates().await {
-						Ok(()) => {
-							log_info!(
-								sync_logger,
-								"Fee rate cache update finished in {}ms.",
-								now.elapsed().as_millis()
-							);
-							let unix_time_secs_opt = SystemTime::now()
-								.duration_since(UNIX_EPOCH)
-								.ok()
-								.map(|d| d.as_secs());
-							*sync_fee_rate_update_timestamp.write().unwrap() = unix_time_secs_opt;
+						ChainSource::BitcoindRpc { .. } => {
+							chain_source.update_fee_rate_estimates().await?;
+							chain_source
+								.poll_and_update_listeners(
+									sync_cman,
+									sync_cmon,
+									sync_sweeper,
+									sync_channel_history,
+								)
+								.await?;
 						},
-						Err(e) => {
-							log_error!(sync_logger, "Fee rate cache update failed: {}", e,);
-							return Err(e);
+						ChainSource::CompactBlockFilters { cbf_block_source, .. } => {
+							let res = chain_source
+								.poll_and_update_listeners(
+									sync_cman,
+									sync_cmon,
+									sync_sweeper,
+									sync_channel_history,
+								)
+								.await;
+							// The peer connection is bound to this temporary runtime, so make
+							// sure we don't keep it around.
+							cbf_block_source.disconnect().await;
+							res?;
 						},
 					}
+					Ok(())
+				},
+			)
+		})
+	}
 
-					let now = Instant::now();
-					let tx_sync_timeout_fut = tokio::time::timeout(
-						Duration::from_secs(LDK_WALLET_SYNC_TIMEOUT_SECS),
-						tx_sync.sync(confirmables),
-					);
-					match tx_sync_timeout_fut.await {
-						Ok(res) => match res {
-							Ok(()) => {
-								log_info!(
-									sync_logger,
-									"Sync of Lightning wallet finished in {}ms.",
-									now.elapsed().as_millis()
-								);
-
-								let unix_time_secs_opt = SystemTime::now()
-									.duration_since(UNIX_EPOCH)
-									.ok()
-									.map(|d| d.as_secs());
-								*sync_wallet_timestamp.write().unwrap() = unix_time_secs_opt;
+	/// Rescans the chain for transactions relevant to the on-chain wallet, starting at the block
+	/// at the given height.
+	///
+	/// This allows to discover funds the wallet missed, e.g., as it was restored with a birthday
+	/// height later than its first transaction (see [`Builder::set_wallet_birthday_height`]), or
+	/// as [`Config::onchain_wallet_stop_gap`] was previously set too low.
+	///
+	/// **Note:** When sourcing chain data from Esplora, Electrum, or a custom chain source, the
+	/// wallet's history is looked up per script rather than per block, i.e., `from_height` is
+	/// ignored and this will trigger a full scan of the wallet's scripts, including the ones it
+	/// didn't reveal yet. Regular syncs only do so periodically and otherwise only query the
+	/// scripts the wallet already revealed.
+	pub fn rescan_onchain_wallet(&self, from_height: u32) -> Result<(), Error> {
+		let rt_lock = self.runtime.read().unwrap();
+		if rt_lock.is_none() {
+			return Err(Error::NotRunning);
+		}
 
-								periodically_archive_fully_resolved_monitors(
-									archive_cman,
-									archive_cmon,
-									sync_monitor_archival_height,
-								);
-								Ok(())
-							},
-							Err(e) => {
-								log_error!(sync_logger, "Sync of Lightning wallet failed: {}", e);
-								Err(e.into())
-							},
-						},
-						Err(e) => {
-							log_error!(sync_logger, "Sync of Lightning wallet timed out: {}", e);
-							Err(Error::TxSyncTimeout)
-						},
+		let chain_source = Arc::clone(&self.chain_source);
+		tokio::task::block_in_place(move || {
+			tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap().block_on(
+				async move {
+					let res = chain_source.rescan_onchain_wallet(from_height).await;
+					if let ChainSource::CompactBlockFilters { cbf_block_source, .. } =
+						chain_source.as_ref()
+					{
+						// The peer connection is bound to this temporary runtime, so make sure we
+						// don't keep it around.
+						cbf_block_source.disconnect().await;
 					}
+					res
 				},
 			)
 		})
@@ -1483,7 +1756,32 @@ impl Node {
 	pub fn close_channel(
 		&self, user_channel_id: &UserChannelId, counterparty_node_id: PublicKey,
 	) -> Result<(), Error> {
-		self.close_channel_internal(user_channel_id, counterparty_node_id, false, None)
+		self.close_channel_internal(
+			user_channel_id,
+			counterparty_node_id,
+			false,
+			None,
+			ChannelCloseOptions::default(),
+		)
+	}
+
+	/// Close a previously opened channel, using the given [`ChannelCloseOptions`].
+	///
+	/// Allows to choose the address the channel balance is paid out to and the fee rate we
+	/// propose for the closing transaction. If [`ChannelCloseOptions::force_close_timeout_secs`]
+	/// is set and the cooperative closure didn't complete in time, we'll escalate to
+	/// force-closing the channel as per [`Node::force_close_channel`].
+	pub fn close_channel_with_options(
+		&self, user_channel_id: &UserChannelId, counterparty_node_id: PublicKey,
+		close_options: ChannelCloseOptions,
+	) -> Result<(), Error> {
+		self.close_channel_internal(
+			user_channel_id,
+			counterparty_node_id,
+			false,
+			None,
+			close_options,
+		)
 	}
 
 	/// Force-close a previously opened channel.
@@ -1501,59 +1799,147 @@ impl Node {
 		&self, user_channel_id: &UserChannelId, counterparty_node_id: PublicKey,
 		reason: Option<String>,
 	) -> Result<(), Error> {
-		self.close_channel_internal(user_channel_id, counterparty_node_id, true, reason)
+		self.close_channel_internal(
+			user_channel_id,
+			counterparty_node_id,
+			true,
+			reason,
+			ChannelCloseOptions::default(),
+		)
 	}
 
 	fn close_channel_internal(
 		&self, user_channel_id: &UserChannelId, counterparty_node_id: PublicKey, force: bool,
-		force_close_reason: Option<String>,
+		force_close_reason: Option<String>, close_options: ChannelCloseOptions,
 	) -> Result<(), Error> {
 		debug_assert!(
 			force_close_reason.is_none() || force,
 			"Reason can only be set for force closures"
 		);
+		debug_assert!(
+			close_options == ChannelCloseOptions::default() || !force,
+			"Close options can only be set for cooperative closures"
+		);
 		let open_channels =
 			self.channel_manager.list_channels_with_counterparty(&counterparty_node_id);
 		if let Some(channel_details) =
 			open_channels.iter().find(|c| c.user_channel_id == user_channel_id.0)
 		{
-			if force {
-				if self.config.anchor_channels_config.as_ref().map_or(false, |acc| {
+			let broadcast_latest_txn =
+				!self.config.anchor_channels_config.as_ref().map_or(false, |acc| {
 					acc.trusted_peers_no_reserve.contains(&counterparty_node_id)
-				}) {
-					self.channel_manager
-						.force_close_without_broadcasting_txn(
-							&channel_details.channel_id,
-							&counterparty_node_id,
-							force_close_reason.unwrap_or_default(),
-						)
-						.map_err(|e| {
+				});
+			if force {
+				force_close_channel(
+					&self.channel_manager,
+					&channel_details.channel_id,
+					&counterparty_node_id,
+					broadcast_latest_txn,
+					force_close_reason.unwrap_or_default(),
+				)
+				.map_err(|e| {
+					log_error!(self.logger, "Failed to force-close channel: {:?}", e);
+					Error::ChannelClosingFailed
+				})?;
+			} else {
+				let shutdown_script = close_options
+					.destination_address
+					.map(|address| {
+						if !address.as_unchecked().is_valid_for_network(self.config.network) {
 							log_error!(
 								self.logger,
-								"Failed to force-close channel to trusted peer: {:?}",
-								e
+								"Failed to close channel: address {} is not valid for network {}",
+								address,
+								self.config.network
 							);
-							Error::ChannelClosingFailed
-						})?;
-				} else {
-					self.channel_manager
-						.force_close_broadcasting_latest_txn(
-							&channel_details.channel_id,
-							&counterparty_node_id,
-							force_close_reason.unwrap_or_default(),
-						)
-						.map_err(|e| {
-							log_error!(self.logger, "Failed to force-close channel: {:?}", e);
-							Error::ChannelClosingFailed
-						})?;
-				}
-			} else {
+							return Err(Error::InvalidAddress);
+						}
+						ShutdownScript::try_from(address.script_pubkey()).map_err(|_| {
+							log_error!(
+								self.logger,
+								"Failed to close channel: address {} is not a SegWit address",
+								address
+							);
+							Error::InvalidAddress
+						})
+					})
+					.transpose()?;
+				let target_feerate_sat_per_1000_weight = close_options
+					.target_fee_rate
+					.map(|fee_rate| {
+						u32::try_from(fee_rate.to_sat_per_kwu()).map_err(|_| {
+							log_error!(
+								self.logger,
+								"Failed to close channel: fee rate {} sat/kwu is too high",
+								fee_rate.to_sat_per_kwu()
+							);
+							Error::InvalidFeeRate
+						})
+					})
+					.transpose()?;
+
+				let force_close_timeout =
+					if let Some(timeout_secs) = close_options.force_close_timeout_secs {
+						let rt_lock = self.runtime.read().unwrap();
+						if rt_lock.is_none() {
+							return Err(Error::NotRunning);
+						}
+						Some((Arc::clone(rt_lock.as_ref().unwrap()), timeout_secs))
+					} else {
+						None
+					};
+
 				self.channel_manager
-					.close_channel(&channel_details.channel_id, &counterparty_node_id)
+					.close_channel_with_feerate_and_script(
+						&channel_details.channel_id,
+						&counterparty_node_id,
+						target_feerate_sat_per_1000_weight,
+						shutdown_script,
+					)
 					.map_err(|e| {
 						log_error!(self.logger, "Failed to close channel: {:?}", e);
 						Error::ChannelClosingFailed
 					})?;
+
+				if let Some((runtime, timeout_secs)) = force_close_timeout {
+					let channel_manager = Arc::clone(&self.channel_manager);
+					let logger = Arc::clone(&self.logger);
+					let channel_id = channel_details.channel_id;
+					let mut stop_force_close = self.stop_sender.subscribe();
+					runtime.spawn(async move {
+						tokio::select! {
+							_ = stop_force_close.changed() => {
+								return;
+							}
+							_ = tokio::time::sleep(Duration::from_secs(timeout_secs)) => {}
+						}
+
+						let still_open = channel_manager
+							.list_channels_with_counterparty(&counterparty_node_id)
+							.iter()
+							.any(|c| c.channel_id == channel_id);
+						if !still_open {
+							return;
+						}
+
+						log_info!(
+							logger,
+							"Cooperative closure of channel {} didn't complete within {}s, force-closing it.",
+							channel_id,
+							timeout_secs
+						);
+						force_close_channel(
+							&channel_manager,
+							&channel_id,
+							&counterparty_node_id,
+							broadcast_latest_txn,
+							"Cooperative closure timed out".to_string(),
+						)
+						.unwrap_or_else(|e| {
+							log_error!(logger, "Failed to force-close channel: {:?}", e)
+						});
+					});
+				}
 			}
 
 			// Check if this was the last open channel, if so, forget the peer.
@@ -1565,6 +1951,139 @@ impl Node {
 		Ok(())
 	}
 
+	/// Accept a channel request previously surfaced via [`Event::ChannelOpenRequest`].
+	///
+	/// If `channel_config` is set, it will be used for the accepted channel instead of the
+	/// defaults. If `allow_0conf` is set, the channel may be used before its funding transaction
+	/// is confirmed, which should only be allowed for trusted peers, see
+	/// [`Config::trusted_peers_0conf`].
+	///
+	/// As our channels and on-chain funds might have changed since the request was surfaced, it
+	/// will be checked against the [`InboundChannelPolicy`] and the Anchor channel reserve again,
+	/// failing with [`Error::ChannelCreationFailed`] if it can't be accepted anymore.
+	///
+	/// Returns a [`UserChannelId`] allowing to locally keep track of the channel.
+	pub fn accept_inbound_channel(
+		&self, temporary_channel_id: &ChannelId, counterparty_node_id: PublicKey,
+		channel_config: Option<ChannelConfig>, allow_0conf: bool,
+	) -> Result<UserChannelId, Error> {
+		let rt_lock = self.runtime.read().unwrap();
+		if rt_lock.is_none() {
+			return Err(Error::NotRunning);
+		}
+
+		// Our channels and funds might have changed since the request was surfaced, so we check
+		// it again before accepting.
+		let request = self
+			.inbound_channel_requests
+			.lock()
+			.unwrap()
+			.get(temporary_channel_id)
+			.filter(|r| r.counterparty_node_id == counterparty_node_id)
+			.cloned()
+			.ok_or_else(|| {
+				log_error!(
+					self.logger,
+					"Failed to accept inbound channel: unknown channel request {} from peer {}",
+					temporary_channel_id,
+					counterparty_node_id
+				);
+				Error::ChannelCreationFailed
+			})?;
+		request.check(&self.channel_manager, &self.wallet, &self.config).map_err(|reason| {
+			log_error!(
+				self.logger,
+				"Unable to accept inbound channel from peer {} due to {}.",
+				counterparty_node_id,
+				reason
+			);
+			Error::ChannelCreationFailed
+		})?;
+
+		let user_channel_id: u128 = rand::thread_rng().gen::<u128>();
+		let res = if allow_0conf {
+			self.channel_manager.accept_inbound_channel_from_trusted_peer_0conf(
+				temporary_channel_id,
+				&counterparty_node_id,
+				user_channel_id,
+			)
+		} else {
+			self.channel_manager.accept_inbound_channel(
+				temporary_channel_id,
+				&counterparty_node_id,
+				user_channel_id,
+			)
+		};
+		res.map_err(|e| {
+			log_error!(self.logger, "Failed to accept inbound channel: {:?}", e);
+			Error::ChannelCreationFailed
+		})?;
+		self.inbound_channel_requests.lock().unwrap().remove(temporary_channel_id);
+
+		if let Some(channel_config) = channel_config {
+			if let Err(e) = self.channel_manager.update_channel_config(
+				&counterparty_node_id,
+				&[*temporary_channel_id],
+				&channel_config.into(),
+			) {
+				log_error!(
+					self.logger,
+					"Failed to apply config to accepted inbound channel, rejecting it: {:?}",
+					e
+				);
+				self.channel_manager
+					.force_close_without_broadcasting_txn(
+						temporary_channel_id,
+						&counterparty_node_id,
+						"Channel request rejected".to_string(),
+					)
+					.unwrap_or_else(|e| {
+						log_error!(self.logger, "Failed to reject channel: {:?}", e)
+					});
+				return Err(Error::ChannelCreationFailed);
+			}
+		}
+
+		log_info!(
+			self.logger,
+			"Accepting inbound{} channel {} from peer {}",
+			if allow_0conf { " 0conf" } else { "" },
+			temporary_channel_id,
+			counterparty_node_id,
+		);
+		Ok(UserChannelId(user_channel_id))
+	}
+
+	/// Reject a channel request previously surfaced via [`Event::ChannelOpenRequest`].
+	pub fn reject_inbound_channel(
+		&self, temporary_channel_id: &ChannelId, counterparty_node_id: PublicKey,
+	) -> Result<(), Error> {
+		let rt_lock = self.runtime.read().unwrap();
+		if rt_lock.is_none() {
+			return Err(Error::NotRunning);
+		}
+
+		self.channel_manager
+			.force_close_without_broadcasting_txn(
+				temporary_channel_id,
+				&counterparty_node_id,
+				"Channel request rejected".to_string(),
+			)
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to reject inbound channel: {:?}", e);
+				Error::ChannelClosingFailed
+			})?;
+		self.inbound_channel_requests.lock().unwrap().remove(temporary_channel_id);
+
+		log_info!(
+			self.logger,
+			"Rejected inbound channel {} from peer {}",
+			temporary_channel_id,
+			counterparty_node_id
+		);
+		Ok(())
+	}
+
 	/// Update the config for a previously opened channel.
 	pub fn update_channel_config(
 		&self, user_channel_id: &UserChannelId, counterparty_node_id: PublicKey,
@@ -1599,6 +2118,79 @@ impl Node {
 		self.payment_store.remove(&payment_id)
 	}
 
+	/// Attaches the given label to the referenced transaction, address, output, or payment,
+	/// replacing any label previously attached to it.
+	pub fn set_label(&self, label_ref: LabelRef, label: String) -> Result<(), Error> {
+		if let LabelRef::Address { ref address } = label_ref {
+			if !address.as_unchecked().is_valid_for_network(self.config.network) {
+				return Err(Error::InvalidAddress);
+			}
+		}
+		self.label_store.insert(Label { label_ref, label })
+	}
+
+	/// Retrieves the label attached to the referenced object.
+	///
+	/// Returns `Some` if a label was set and `None` otherwise.
+	pub fn label(&self, label_ref: &LabelRef) -> Option<String> {
+		self.label_store.get(label_ref)
+	}
+
+	/// Removes the label attached to the referenced object, if any.
+	pub fn remove_label(&self, label_ref: &LabelRef) -> Result<(), Error> {
+		self.label_store.remove(label_ref)
+	}
+
+	/// Retrieves all labels.
+	pub fn list_labels(&self) -> Vec<Label> {
+		self.label_store.list()
+	}
+
+	/// Exports all labels in the [BIP 329] JSON Lines format, allowing to import them into other
+	/// wallets.
+	///
+	/// Output records are marked as not `spendable` if the output was locked via
+	/// [`OnchainPayment::lock_utxo`]. Locked outputs are exported even if they don't have a label.
+	///
+	/// **Note:** As BIP 329 doesn't cover Lightning payments, labels attached to
+	/// [`LabelRef::Payment`]s are not included.
+	///
+	/// [BIP 329]: https://github.com/bitcoin/bips/blob/master/bip-0329.mediawiki
+	pub fn export_labels(&self) -> String {
+		label_store::labels_to_bip329(&self.label_store.list(), &self.wallet.list_locked_utxos())
+	}
+
+	/// Imports labels given in the [BIP 329] JSON Lines format, e.g., as exported by other
+	/// wallets.
+	///
+	/// Imported labels replace any labels previously attached to the same objects. Records of
+	/// types we don't support, i.e., `pubkey`, `input`, and `xpub` records, are ignored. Fails
+	/// without importing any labels if any of the records is malformed.
+	///
+	/// Outputs marked as not `spendable` will be locked, as if via [`OnchainPayment::lock_utxo`],
+	/// while outputs marked as `spendable` will be unlocked. Outputs may be locked before our
+	/// on-chain wallet learned about them, e.g., when importing labels prior to the initial sync.
+	///
+	/// [BIP 329]: https://github.com/bitcoin/bips/blob/master/bip-0329.mediawiki
+	pub fn import_labels(&self, bip329_labels: String) -> Result<(), Error> {
+		let (labels, outputs_spendable) =
+			label_store::labels_from_bip329(&bip329_labels, self.config.network).map_err(|()| {
+				log_error!(self.logger, "Failed to import labels: invalid BIP 329 record");
+				Error::InvalidLabel
+			})?;
+		for label in labels {
+			self.label_store.insert(label)?;
+		}
+		for (outpoint, spendable) in outputs_spendable {
+			if spendable {
+				self.wallet.unlock_utxo(&outpoint)?;
+			} else {
+				self.wallet.lock_utxo_unchecked(outpoint)?;
+			}
+		}
+		Ok(())
+	}
+
 	/// Retrieves an overview of all known balances.
 	pub fn list_balances(&self) -> BalanceDetails {
 		let cur_anchor_reserve_sats =
@@ -1788,6 +2380,25 @@ pub struct NodeStatus {
 	pub latest_node_announcement_broadcast_timestamp: Option<u64>,
 }
 
+fn force_close_channel(
+	channel_manager: &ChannelManager, channel_id: &ChannelId, counterparty_node_id: &PublicKey,
+	broadcast_latest_txn: bool, reason: String,
+) -> Result<(), APIError> {
+	if broadcast_latest_txn {
+		channel_manager.force_close_broadcasting_latest_txn(
+			channel_id,
+			counterparty_node_id,
+			reason,
+		)
+	} else {
+		channel_manager.force_close_without_broadcasting_txn(
+			channel_id,
+			counterparty_node_id,
+			reason,
+		)
+	}
+}
+
 pub(crate) fn total_anchor_channels_reserve_sats(
 	channel_manager: &ChannelManager, config: &Config,
 ) -> u64 {
@@ -1807,19 +2418,3 @@ pub(crate) fn total_anchor_channels_reserve_sats(
 			* anchor_channels_config.per_channel_reserve_sats
 	})
 }
-
-fn periodically_archive_fully_resolved_monitors(
-	channel_manager: Arc<ChannelManager>, chain_monitor: Arc<ChainMonitor>,
-	latest_channel_monitor_archival_height: Arc<RwLock<Option<u32>>>,
-) {
-	let mut latest_archival_height_lock = latest_channel_monitor_archival_height.write().unwrap();
-	let cur_height = channel_manager.current_best_block().height;
-	let should_archive = latest_archival_height_lock
-		.as_ref()
-		.map_or(true, |h| cur_height >= h + RESOLVED_CHANNEL_MONITOR_ARCHIVAL_INTERVAL);
-
-	if should_archive {
-		chain_monitor.archive_fully_resolved_channel_monitors();
-		*latest_archival_height_lock = Some(cur_height);
-	}
-}
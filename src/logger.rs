@@ -6,7 +6,7 @@
 // accordance with one or both of these licenses.
 
 pub(crate) use lightning::util::logger::Logger;
-pub(crate) use lightning::{log_bytes, log_debug, log_error, log_info, log_trace};
+pub(crate) use lightning::{log_bytes, log_debug, log_error, log_info, log_trace, log_warn};
 
 use lightning::util::logger::{Level, Record};
 
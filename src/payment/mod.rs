@@ -16,9 +16,11 @@ mod unified_qr;
 
 pub use bolt11::Bolt11Payment;
 pub use bolt12::Bolt12Payment;
-pub use onchain::OnchainPayment;
+pub use onchain::{OnchainFeeEstimate, OnchainPayment, OnchainUtxo, UtxoSelection};
 pub use spontaneous::SpontaneousPayment;
-pub use store::{LSPFeeLimits, PaymentDetails, PaymentDirection, PaymentKind, PaymentStatus};
+pub use store::{
+	ConfirmationStatus, LSPFeeLimits, PaymentDetails, PaymentDirection, PaymentKind, PaymentStatus,
+};
 pub use unified_qr::{QrPaymentResult, UnifiedQrPayment};
 
 /// Represents information used to send a payment.
@@ -7,14 +7,82 @@
 
 //! Holds a payment handler allowing to send and receive on-chain payments.
 
+use crate::chain::ChainSource;
 use crate::config::Config;
 use crate::error::Error;
 use crate::logger::{log_error, log_info, FilesystemLogger, Logger};
+use crate::payment::store::ConfirmationStatus;
 use crate::types::{ChannelManager, Wallet};
+use crate::wallet::{ChannelFundingParams, PendingChannelFunding};
+use crate::UserChannelId;
 
-use bitcoin::{Address, Amount, Txid};
+use lightning::util::errors::APIError;
 
-use std::sync::{Arc, RwLock};
+use bdk_wallet::KeychainKind;
+
+use bitcoin::psbt::Psbt;
+use bitcoin::{Address, Amount, FeeRate, OutPoint, ScriptBuf, Txid};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The estimated cost of an on-chain transaction.
+///
+/// Returned by [`OnchainPayment::estimate_send_to_address_fee`] and
+/// [`OnchainPayment::estimate_send_all_to_address_fee`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OnchainFeeEstimate {
+	/// The absolute fee the transaction would pay, in satoshis.
+	pub fee_sats: u64,
+	/// The virtual size of the transaction, in vbytes.
+	///
+	/// As the transaction isn't signed, this is an upper bound assuming maximum-size signatures.
+	pub vsize: u64,
+	/// The fee rate the transaction was built with.
+	pub fee_rate: FeeRate,
+}
+
+/// An unspent output held by our on-chain wallet.
+///
+/// Returned by [`OnchainPayment::list_utxos`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OnchainUtxo {
+	/// The outpoint of the output.
+	pub outpoint: OutPoint,
+	/// The value of the output, in satoshis.
+	pub value_sats: u64,
+	/// The confirmation status of the transaction creating the output.
+	pub status: ConfirmationStatus,
+	/// The address the output pays to.
+	pub address: Address,
+	/// Whether the output was received on an address we handed out or on one of our change
+	/// addresses.
+	pub keychain: KeychainKind,
+	/// Whether the output is locked, i.e., won't be spent.
+	///
+	/// See [`OnchainPayment::lock_utxo`].
+	pub is_locked: bool,
+}
+
+/// Restricts which of our UTXOs may be used to fund an on-chain transaction.
+///
+/// UTXOs can be retrieved via [`OnchainPayment::list_utxos`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UtxoSelection {
+	/// Spend all of the given UTXOs and no others.
+	///
+	/// The transaction will fail to be created if any of them is unknown, already spent, or locked,
+	/// or if they don't suffice to cover the amount and fees.
+	SpendOnly {
+		/// The UTXOs to spend.
+		utxos: Vec<OutPoint>,
+	},
+	/// Never spend any of the given UTXOs, but otherwise select UTXOs as usual.
+	Avoid {
+		/// The UTXOs to avoid.
+		utxos: Vec<OutPoint>,
+	},
+}
 
 /// A payment handler allowing to send and receive on-chain payments.
 ///
@@ -24,7 +92,10 @@ use std::sync::{Arc, RwLock};
 pub struct OnchainPayment {
 	runtime: Arc<RwLock<Option<Arc<tokio::runtime::Runtime>>>>,
 	wallet: Arc<Wallet>,
+	chain_source: Arc<ChainSource>,
 	channel_manager: Arc<ChannelManager>,
+	channel_funding_params: Arc<Mutex<HashMap<u128, ChannelFundingParams>>>,
+	pending_channel_fundings: Arc<Mutex<HashMap<u128, PendingChannelFunding>>>,
 	config: Arc<Config>,
 	logger: Arc<FilesystemLogger>,
 }
@@ -32,9 +103,21 @@ pub struct OnchainPayment {
 impl OnchainPayment {
 	pub(crate) fn new(
 		runtime: Arc<RwLock<Option<Arc<tokio::runtime::Runtime>>>>, wallet: Arc<Wallet>,
-		channel_manager: Arc<ChannelManager>, config: Arc<Config>, logger: Arc<FilesystemLogger>,
+		chain_source: Arc<ChainSource>, channel_manager: Arc<ChannelManager>,
+		channel_funding_params: Arc<Mutex<HashMap<u128, ChannelFundingParams>>>,
+		pending_channel_fundings: Arc<Mutex<HashMap<u128, PendingChannelFunding>>>,
+		config: Arc<Config>, logger: Arc<FilesystemLogger>,
 	) -> Self {
-		Self { runtime, wallet, channel_manager, config, logger }
+		Self {
+			runtime,
+			wallet,
+			chain_source,
+			channel_manager,
+			channel_funding_params,
+			pending_channel_fundings,
+			config,
+			logger,
+		}
 	}
 
 	/// Retrieve a new on-chain/funding address.
@@ -44,35 +127,99 @@ impl OnchainPayment {
 		Ok(funding_address)
 	}
 
+	/// Returns the unspent outputs currently held by our on-chain wallet.
+	pub fn list_utxos(&self) -> Vec<OnchainUtxo> {
+		self.wallet.list_utxos()
+	}
+
+	/// Locks the given UTXO, i.e., ensures we never spend it.
+	///
+	/// Locked UTXOs won't be used to fund on-chain payments or channels, and won't be accounted
+	/// for in [`BalanceDetails::spendable_onchain_balance_sats`] or when checking whether we have
+	/// sufficient funds to retain the Anchor channel reserve. Locks are persisted across restarts
+	/// until the UTXO is unlocked via [`OnchainPayment::unlock_utxo`].
+	///
+	/// [`BalanceDetails::spendable_onchain_balance_sats`]: crate::BalanceDetails::spendable_onchain_balance_sats
+	pub fn lock_utxo(&self, outpoint: OutPoint) -> Result<(), Error> {
+		self.wallet.lock_utxo(outpoint)
+	}
+
+	/// Unlocks the given UTXO, allowing it to be spent again.
+	pub fn unlock_utxo(&self, outpoint: OutPoint) -> Result<(), Error> {
+		self.wallet.unlock_utxo(&outpoint)
+	}
+
+	/// Returns the UTXOs currently locked via [`OnchainPayment::lock_utxo`].
+	pub fn list_locked_utxos(&self) -> Vec<OutPoint> {
+		self.wallet.list_locked_utxos()
+	}
+
 	/// Send an on-chain payment to the given address.
 	///
 	/// This will respect any on-chain reserve we need to keep, i.e., won't allow to cut into
 	/// [`BalanceDetails::total_anchor_channels_reserve_sats`].
 	///
+	/// If `fee_rate` is set, it will be used instead of our current fee rate estimate for on-chain
+	/// payments.
+	///
+	/// If `utxo_selection` is set, it will restrict which of our UTXOs are used to fund the
+	/// payment.
+	///
 	/// [`BalanceDetails::total_anchor_channels_reserve_sats`]: crate::BalanceDetails::total_anchor_channels_reserve_sats
 	pub fn send_to_address(
-		&self, address: &bitcoin::Address, amount_sats: u64,
+		&self, address: &bitcoin::Address, amount_sats: u64, fee_rate: Option<FeeRate>,
+		utxo_selection: Option<UtxoSelection>,
 	) -> Result<Txid, Error> {
 		let rt_lock = self.runtime.read().unwrap();
 		if rt_lock.is_none() {
 			return Err(Error::NotRunning);
 		}
 
-		let cur_anchor_reserve_sats =
-			crate::total_anchor_channels_reserve_sats(&self.channel_manager, &self.config);
-		let spendable_amount_sats =
-			self.wallet.get_spendable_amount_sats(cur_anchor_reserve_sats).unwrap_or(0);
+		self.check_spendable_amount(amount_sats)?;
 
-		if spendable_amount_sats < amount_sats {
-			log_error!(self.logger,
-				"Unable to send payment due to insufficient funds. Available: {}sats, Required: {}sats",
-				spendable_amount_sats, amount_sats
-			);
-			return Err(Error::InsufficientFunds);
+		let amount = Amount::from_sat(amount_sats);
+		self.wallet.send_to_address(address, Some(amount), fee_rate, utxo_selection.as_ref())
+	}
+
+	/// Send an on-chain payment to multiple recipients in a single transaction.
+	///
+	/// Any change will be returned to our on-chain wallet. Batching payments this way is
+	/// considerably cheaper than sending them one by one.
+	///
+	/// This will respect any on-chain reserve we need to keep, i.e., won't allow to cut into
+	/// [`BalanceDetails::total_anchor_channels_reserve_sats`].
+	///
+	/// If `fee_rate` is set, it will be used instead of our current fee rate estimate for on-chain
+	/// payments.
+	///
+	/// If `utxo_selection` is set, it will restrict which of our UTXOs are used to fund the
+	/// payments.
+	///
+	/// [`BalanceDetails::total_anchor_channels_reserve_sats`]: crate::BalanceDetails::total_anchor_channels_reserve_sats
+	pub fn send_to_outputs(
+		&self, outputs: Vec<(Address, Amount)>, fee_rate: Option<FeeRate>,
+		utxo_selection: Option<UtxoSelection>,
+	) -> Result<Txid, Error> {
+		let rt_lock = self.runtime.read().unwrap();
+		if rt_lock.is_none() {
+			return Err(Error::NotRunning);
 		}
 
-		let amount = Amount::from_sat(amount_sats);
-		self.wallet.send_to_address(address, Some(amount))
+		if outputs.is_empty() {
+			log_error!(self.logger, "Unable to send payment as no outputs were given");
+			return Err(Error::InvalidAmount);
+		}
+
+		let total_amount = outputs
+			.iter()
+			.try_fold(Amount::ZERO, |total, (_, amount)| total.checked_add(*amount))
+			.ok_or_else(|| {
+				log_error!(self.logger, "Unable to send payment as the total amount overflowed");
+				Error::InvalidAmount
+			})?;
+		self.check_spendable_amount(total_amount.to_sat())?;
+
+		self.wallet.send_to_outputs(&outputs, fee_rate, utxo_selection.as_ref())
 	}
 
 	/// Send an on-chain payment to the given address, draining all the available funds.
@@ -80,15 +227,277 @@ impl OnchainPayment {
 	/// This is useful if you have closed all channels and want to migrate funds to another
 	/// on-chain wallet.
 	///
+	/// If `fee_rate` is set, it will be used instead of our current fee rate estimate for on-chain
+	/// payments.
+	///
+	/// If `utxo_selection` is set, only the selected UTXOs will be swept, or the avoided ones will
+	/// be left untouched, respectively. This allows to consolidate a specific set of UTXOs.
+	///
 	/// Please note that this will **not** retain any on-chain reserves, which might be potentially
 	/// dangerous if you have open Anchor channels for which you can't trust the counterparty to
 	/// spend the Anchor output after channel closure.
-	pub fn send_all_to_address(&self, address: &bitcoin::Address) -> Result<Txid, Error> {
+	pub fn send_all_to_address(
+		&self, address: &bitcoin::Address, fee_rate: Option<FeeRate>,
+		utxo_selection: Option<UtxoSelection>,
+	) -> Result<Txid, Error> {
+		let rt_lock = self.runtime.read().unwrap();
+		if rt_lock.is_none() {
+			return Err(Error::NotRunning);
+		}
+
+		self.wallet.send_to_address(address, None, fee_rate, utxo_selection.as_ref())
+	}
+
+	/// Creates an unsigned PSBT paying the given amount to the given address.
+	///
+	/// This allows to sign spends externally, e.g., on a hardware or air-gapped signer, which is
+	/// required if the on-chain wallet is watch-only, see [`Builder::set_watch_only_wallet`]. Once
+	/// signed, the PSBT needs to be handed to [`OnchainPayment::finalize_and_broadcast_psbt`].
+	///
+	/// This will respect any on-chain reserve we need to keep, i.e., won't allow to cut into
+	/// [`BalanceDetails::total_anchor_channels_reserve_sats`].
+	///
+	/// If `fee_rate` is set, it will be used instead of our current fee rate estimate for on-chain
+	/// payments.
+	///
+	/// If `utxo_selection` is set, it will restrict which of our UTXOs are used to fund the
+	/// payment.
+	///
+	/// [`Builder::set_watch_only_wallet`]: crate::Builder::set_watch_only_wallet
+	/// [`BalanceDetails::total_anchor_channels_reserve_sats`]: crate::BalanceDetails::total_anchor_channels_reserve_sats
+	pub fn create_psbt(
+		&self, address: &bitcoin::Address, amount_sats: u64, fee_rate: Option<FeeRate>,
+		utxo_selection: Option<UtxoSelection>,
+	) -> Result<Psbt, Error> {
+		let rt_lock = self.runtime.read().unwrap();
+		if rt_lock.is_none() {
+			return Err(Error::NotRunning);
+		}
+
+		self.check_spendable_amount(amount_sats)?;
+
+		let amount = Amount::from_sat(amount_sats);
+		self.wallet.create_psbt(address, amount, fee_rate, utxo_selection.as_ref())
+	}
+
+	/// Returns the unsigned funding PSBT of the channel with the given [`UserChannelId`], if it's
+	/// awaiting an external signature.
+	///
+	/// See [`Event::FundingPsbtReady`] for more information.
+	///
+	/// [`Event::FundingPsbtReady`]: crate::Event::FundingPsbtReady
+	pub fn funding_psbt(&self, user_channel_id: UserChannelId) -> Option<Psbt> {
+		self.pending_channel_fundings
+			.lock()
+			.unwrap()
+			.get(&user_channel_id.0)
+			.and_then(|p| p.psbt.clone())
+	}
+
+	/// Finalizes the given signed PSBT and broadcasts the resulting transaction.
+	///
+	/// We'll add our own signatures if the on-chain wallet isn't watch-only. If the PSBT funds a
+	/// channel as retrieved via [`OnchainPayment::funding_psbt`], the transaction will be handed
+	/// to the channel counterparty and only broadcast once it's safe to do so.
+	///
+	/// Returns the [`Txid`] of the final transaction.
+	pub fn finalize_and_broadcast_psbt(&self, psbt: Psbt) -> Result<Txid, Error> {
+		let rt_lock = self.runtime.read().unwrap();
+		if rt_lock.is_none() {
+			return Err(Error::NotRunning);
+		}
+
+		let unsigned_txid = psbt.unsigned_tx.compute_txid();
+		let pending_funding = self
+			.pending_channel_fundings
+			.lock()
+			.unwrap()
+			.iter()
+			.find(|(_, p)| {
+				p.psbt.as_ref().map_or(false, |p| p.unsigned_tx.compute_txid() == unsigned_txid)
+			})
+			.map(|(user_channel_id, p)| (*user_channel_id, p.clone()));
+
+		let tx = self.wallet.finalize_psbt(psbt)?;
+
+		match pending_funding {
+			Some((user_channel_id, pending_funding)) => {
+				let txid = tx.compute_txid();
+				self.channel_manager
+					.funding_transaction_generated(
+						pending_funding.temporary_channel_id,
+						pending_funding.counterparty_node_id,
+						tx,
+					)
+					.map_err(|e| {
+						log_error!(self.logger, "Failed to process funding transaction: {:?}", e);
+						match e {
+							APIError::ChannelUnavailable { .. } => Error::ChannelCreationFailed,
+							_ => Error::OnchainTxCreationFailed,
+						}
+					})?;
+				self.pending_channel_fundings.lock().unwrap().remove(&user_channel_id);
+				log_info!(self.logger, "Handed signed funding transaction {} to LDK", txid);
+				Ok(txid)
+			},
+			None => self.wallet.broadcast_transaction(tx),
+		}
+	}
+
+	/// Bumps the fee of the given unconfirmed on-chain transaction by replacing it with one paying
+	/// the given `new_fee_rate`, as per [BIP 125].
+	///
+	/// The replacement will spend the same inputs and pay the same recipients, with the additional
+	/// fee being deducted from our change output. It will be persisted and broadcast right away.
+	/// If additional inputs need to be spent, this will respect any on-chain reserve we need to
+	/// keep, i.e., won't allow to cut into [`BalanceDetails::total_anchor_channels_reserve_sats`].
+	///
+	/// Channel funding transactions can't be replaced, as this would invalidate the channel.
+	///
+	/// The replacement will be tracked as a new payment, while the original payment will point
+	/// to it via [`PaymentKind::Onchain::replaced_by`].
+	///
+	/// Returns the [`Txid`] of the replacement transaction.
+	///
+	/// [BIP 125]: https://github.com/bitcoin/bips/blob/master/bip-0125.mediawiki
+	/// [`BalanceDetails::total_anchor_channels_reserve_sats`]: crate::BalanceDetails::total_anchor_channels_reserve_sats
+	/// [`PaymentKind::Onchain::replaced_by`]: crate::payment::PaymentKind::Onchain::replaced_by
+	pub fn bump_fee_rbf(&self, txid: Txid, new_fee_rate: FeeRate) -> Result<Txid, Error> {
+		let rt_lock = self.runtime.read().unwrap();
+		if rt_lock.is_none() {
+			return Err(Error::NotRunning);
+		}
+
+		let is_channel_funding =
+			self.channel_manager
+				.list_channels()
+				.iter()
+				.any(|c| c.funding_txo.map_or(false, |o| o.txid == txid))
+				|| self.pending_channel_fundings.lock().unwrap().values().any(|p| {
+					p.psbt.as_ref().map_or(false, |p| p.unsigned_tx.compute_txid() == txid)
+				});
+		if is_channel_funding {
+			log_error!(
+				self.logger,
+				"Refusing to bump fee of transaction {} as it funds a channel",
+				txid
+			);
+			return Err(Error::InvalidTransaction);
+		}
+
+		let cur_anchor_reserve_sats =
+			crate::total_anchor_channels_reserve_sats(&self.channel_manager, &self.config);
+		self.wallet.bump_fee_rbf(
+			txid,
+			new_fee_rate,
+			&self.channel_funding_scripts(),
+			cur_anchor_reserve_sats,
+		)
+	}
+
+	/// Bumps the fee of the given unconfirmed on-chain transaction by spending one of our outputs
+	/// in a child transaction, i.e., via Child-Pays-For-Parent (CPFP).
+	///
+	/// The child will pay enough fees to lift the combined fee rate of the parent, any of its
+	/// unconfirmed ancestors, and the child to the given `fee_rate`, taking into account the fees
+	/// they already pay. If the fee paid by the parent or any of these ancestors can't be
+	/// determined, e.g., as we don't know all of its inputs, this will fail rather than risk
+	/// overpaying. The child's fee will respect any on-chain reserve we need to keep, i.e., won't
+	/// allow to cut into [`BalanceDetails::total_anchor_channels_reserve_sats`].
+	///
+	/// This allows to speed up the confirmation of incoming payments, as well as of transactions
+	/// that can't be replaced via [`OnchainPayment::bump_fee_rbf`].
+	///
+	/// **Note:** When sourcing chain data via compact block filters, we don't learn about
+	/// unconfirmed transactions we didn't create ourselves, i.e., incoming payments can only be
+	/// bumped once they're confirmed, which is too late. When sourcing chain data via Bitcoin
+	/// Core RPC, the parent and its unconfirmed ancestors will be looked up in the mempool.
+	///
+	/// Returns the [`Txid`] of the child transaction.
+	///
+	/// [`BalanceDetails::total_anchor_channels_reserve_sats`]: crate::BalanceDetails::total_anchor_channels_reserve_sats
+	pub fn bump_fee_cpfp(&self, txid: Txid, fee_rate: FeeRate) -> Result<Txid, Error> {
 		let rt_lock = self.runtime.read().unwrap();
 		if rt_lock.is_none() {
 			return Err(Error::NotRunning);
 		}
 
-		self.wallet.send_to_address(address, None)
+		let runtime = Arc::clone(rt_lock.as_ref().unwrap());
+		let chain_source = Arc::clone(&self.chain_source);
+		let mempool_package = tokio::task::block_in_place(move || {
+			runtime.block_on(async move { chain_source.get_mempool_package(&txid).await })
+		})?;
+
+		let cur_anchor_reserve_sats =
+			crate::total_anchor_channels_reserve_sats(&self.channel_manager, &self.config);
+		self.wallet.bump_fee_cpfp(txid, fee_rate, mempool_package, cur_anchor_reserve_sats)
+	}
+
+	/// Returns the fee and size of the transaction [`OnchainPayment::send_to_address`] would
+	/// create if called with the same arguments.
+	///
+	/// Nothing will be broadcast, which allows to preview the cost of a payment at different fee
+	/// rates before actually sending it.
+	pub fn estimate_send_to_address_fee(
+		&self, address: &bitcoin::Address, amount_sats: u64, fee_rate: Option<FeeRate>,
+		utxo_selection: Option<UtxoSelection>,
+	) -> Result<OnchainFeeEstimate, Error> {
+		self.check_spendable_amount(amount_sats)?;
+
+		let amount = Amount::from_sat(amount_sats);
+		self.wallet.estimate_send_to_address_fee(
+			address,
+			Some(amount),
+			fee_rate,
+			utxo_selection.as_ref(),
+		)
+	}
+
+	/// Returns the fee and size of the transaction [`OnchainPayment::send_all_to_address`] would
+	/// create if called with the same arguments.
+	///
+	/// Nothing will be broadcast, which allows to preview the cost of a payment at different fee
+	/// rates before actually sending it.
+	pub fn estimate_send_all_to_address_fee(
+		&self, address: &bitcoin::Address, fee_rate: Option<FeeRate>,
+		utxo_selection: Option<UtxoSelection>,
+	) -> Result<OnchainFeeEstimate, Error> {
+		self.wallet.estimate_send_to_address_fee(address, None, fee_rate, utxo_selection.as_ref())
+	}
+
+	// Returns the funding output scripts of all channels that are still awaiting their funding
+	// transaction.
+	fn channel_funding_scripts(&self) -> Vec<ScriptBuf> {
+		let mut scripts: Vec<ScriptBuf> = self
+			.pending_channel_fundings
+			.lock()
+			.unwrap()
+			.values()
+			.map(|p| p.output_script.clone())
+			.collect();
+		for funding_params in self.channel_funding_params.lock().unwrap().values() {
+			if let Some(funding_batch) = funding_params.funding_batch.as_ref() {
+				let locked_batch = funding_batch.lock().unwrap();
+				scripts.extend(locked_batch.ready_channels.values().map(|(_, _, s, _)| s.clone()));
+			}
+		}
+		scripts
+	}
+
+	fn check_spendable_amount(&self, amount_sats: u64) -> Result<(), Error> {
+		let cur_anchor_reserve_sats =
+			crate::total_anchor_channels_reserve_sats(&self.channel_manager, &self.config);
+		let spendable_amount_sats =
+			self.wallet.get_spendable_amount_sats(cur_anchor_reserve_sats).unwrap_or(0);
+
+		if spendable_amount_sats < amount_sats {
+			log_error!(self.logger,
+				"Unable to send payment due to insufficient funds. Available: {}sats, Required: {}sats",
+				spendable_amount_sats, amount_sats
+			);
+			return Err(Error::InsufficientFunds);
+		}
+
+		Ok(())
 	}
 }
@@ -19,6 +19,8 @@ use lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
 use lightning::offers::offer::OfferId;
 use lightning::util::ser::{Readable, Writeable};
 use lightning::util::string::UntrustedString;
+
+use bitcoin::{BlockHash, Txid};
 use lightning::{
 	_init_and_read_len_prefixed_tlv_fields, impl_writeable_tlv_based,
 	impl_writeable_tlv_based_enum, write_tlv_fields,
@@ -39,6 +41,11 @@ pub struct PaymentDetails {
 	pub kind: PaymentKind,
 	/// The amount transferred.
 	pub amount_msat: Option<u64>,
+	/// The fee that was paid for this payment, if known.
+	///
+	/// For on-chain payments, this will only be set for outbound payments, i.e., if we paid the
+	/// fee of the transaction.
+	pub fee_paid_msat: Option<u64>,
 	/// The direction of the payment.
 	pub direction: PaymentDirection,
 	/// The status of the payment.
@@ -56,7 +63,8 @@ impl PaymentDetails {
 			.duration_since(UNIX_EPOCH)
 			.unwrap_or(Duration::from_secs(0))
 			.as_secs();
-		Self { id, kind, amount_msat, direction, status, latest_update_timestamp }
+		let fee_paid_msat = None;
+		Self { id, kind, amount_msat, fee_paid_msat, direction, status, latest_update_timestamp }
 	}
 }
 
@@ -74,6 +82,7 @@ impl Writeable for PaymentDetails {
 			(4, None::<Option<PaymentSecret>>, required),
 			(5, self.latest_update_timestamp, required),
 			(6, self.amount_msat, required),
+			(7, self.fee_paid_msat, option),
 			(8, self.direction, required),
 			(10, self.status, required)
 		});
@@ -95,6 +104,7 @@ impl Readable for PaymentDetails {
 			(4, secret, required),
 			(5, latest_update_timestamp, (default_value, unix_time_secs)),
 			(6, amount_msat, required),
+			(7, fee_paid_msat, option),
 			(8, direction, required),
 			(10, status, required)
 		});
@@ -135,7 +145,15 @@ impl Readable for PaymentDetails {
 			}
 		};
 
-		Ok(PaymentDetails { id, kind, amount_msat, direction, status, latest_update_timestamp })
+		Ok(PaymentDetails {
+			id,
+			kind,
+			amount_msat,
+			fee_paid_msat,
+			direction,
+			status,
+			latest_update_timestamp,
+		})
 	}
 }
 
@@ -174,7 +192,25 @@ impl_writeable_tlv_based_enum!(PaymentStatus,
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum PaymentKind {
 	/// An on-chain payment.
-	Onchain,
+	Onchain {
+		/// The transaction identifier of this payment.
+		txid: Txid,
+		/// The confirmation status of the transaction.
+		status: ConfirmationStatus,
+		/// The address of the counterparty, if known.
+		///
+		/// For outbound payments, this is the address we sent the funds to. As the sender's address
+		/// can't be reliably determined, this will be `None` for inbound payments.
+		counterparty_address: Option<String>,
+		/// The transaction identifier of the transaction that replaced this one, if any.
+		///
+		/// This will be set if we replaced the transaction via [`OnchainPayment::bump_fee_rbf`].
+		/// The replacement is tracked as a separate payment, and the status of this payment
+		/// follows the status of the replacement.
+		///
+		/// [`OnchainPayment::bump_fee_rbf`]: crate::payment::OnchainPayment::bump_fee_rbf
+		replaced_by: Option<Txid>,
+	},
 	/// A [BOLT 11] payment.
 	///
 	/// [BOLT 11]: https://github.com/lightning/bolts/blob/master/11-payment-encoding.md
@@ -263,7 +299,12 @@ pub enum PaymentKind {
 }
 
 impl_writeable_tlv_based_enum!(PaymentKind,
-	(0, Onchain) => {},
+	(0, Onchain) => {
+		(0, txid, required),
+		(1, counterparty_address, option),
+		(2, status, required),
+		(3, replaced_by, option),
+	},
 	(2, Bolt11) => {
 		(0, hash, required),
 		(2, preimage, option),
@@ -296,6 +337,32 @@ impl_writeable_tlv_based_enum!(PaymentKind,
 	}
 );
 
+/// Represents the confirmation status of a transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfirmationStatus {
+	/// The transaction is confirmed in the best chain.
+	Confirmed {
+		/// The hash of the block in which the transaction was confirmed.
+		block_hash: BlockHash,
+		/// The height of the block in which the transaction was confirmed.
+		height: u32,
+		/// The timestamp, in seconds since start of the UNIX epoch, of the block in which the
+		/// transaction was confirmed.
+		timestamp: u64,
+	},
+	/// The transaction is unconfirmed.
+	Unconfirmed,
+}
+
+impl_writeable_tlv_based_enum!(ConfirmationStatus,
+	(0, Confirmed) => {
+		(0, block_hash, required),
+		(2, height, required),
+		(4, timestamp, required),
+	},
+	(2, Unconfirmed) => {}
+);
+
 /// Limits applying to how much fee we allow an LSP to deduct from the payment amount.
 ///
 /// See [`LdkChannelConfig::accept_underpaying_htlcs`] for more information.
@@ -323,8 +390,10 @@ pub(crate) struct PaymentDetailsUpdate {
 	pub preimage: Option<Option<PaymentPreimage>>,
 	pub secret: Option<Option<PaymentSecret>>,
 	pub amount_msat: Option<Option<u64>>,
+	pub fee_paid_msat: Option<Option<u64>>,
 	pub direction: Option<PaymentDirection>,
 	pub status: Option<PaymentStatus>,
+	pub replaced_by: Option<Option<Txid>>,
 }
 
 impl PaymentDetailsUpdate {
@@ -335,8 +404,10 @@ impl PaymentDetailsUpdate {
 			preimage: None,
 			secret: None,
 			amount_msat: None,
+			fee_paid_msat: None,
 			direction: None,
 			status: None,
+			replaced_by: None,
 		}
 	}
 }
@@ -443,10 +514,20 @@ where
 				payment.amount_msat = amount_opt;
 			}
 
+			if let Some(fee_paid_msat_opt) = update.fee_paid_msat {
+				payment.fee_paid_msat = fee_paid_msat_opt;
+			}
+
 			if let Some(status) = update.status {
 				payment.status = status;
 			}
 
+			if let Some(replaced_by_opt) = update.replaced_by {
+				if let PaymentKind::Onchain { ref mut replaced_by, .. } = payment.kind {
+					*replaced_by = replaced_by_opt;
+				}
+			}
+
 			payment.latest_update_timestamp = SystemTime::now()
 				.duration_since(UNIX_EPOCH)
 				.unwrap_or(Duration::from_secs(0))
@@ -5,6 +5,7 @@
 // http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
 // accordance with one or both of these licenses.
 
+use crate::channel_history::ChannelHistoryStore;
 use crate::logger::FilesystemLogger;
 use crate::message_handler::NodeCustomMessageHandler;
 
@@ -24,10 +25,9 @@ use lightning::util::persist::KVStore;
 use lightning::util::ser::{Readable, Writeable, Writer};
 use lightning::util::sweep::OutputSweeper;
 use lightning_net_tokio::SocketDescriptor;
-use lightning_transaction_sync::EsploraSyncClient;
 
 use bitcoin::secp256k1::PublicKey;
-use bitcoin::OutPoint;
+use bitcoin::{Address, FeeRate, OutPoint};
 
 use std::sync::{Arc, Mutex};
 
@@ -52,7 +52,7 @@ pub(crate) type PeerManager = lightning::ln::peer_handler::PeerManager<
 	Arc<KeysManager>,
 >;
 
-pub(crate) type ChainSource = EsploraSyncClient<Arc<FilesystemLogger>>;
+pub(crate) type ChainSource = crate::chain::ChainSource;
 
 pub(crate) type LiquidityManager =
 	lightning_liquidity::LiquidityManager<Arc<KeysManager>, Arc<ChannelManager>, Arc<ChainSource>>;
@@ -70,7 +70,7 @@ pub(crate) type ChannelManager = lightning::ln::channelmanager::ChannelManager<
 
 pub(crate) type Broadcaster = crate::tx_broadcaster::TransactionBroadcaster<Arc<FilesystemLogger>>;
 
-pub(crate) type FeeEstimator = crate::fee_estimator::OnchainFeeEstimator<Arc<FilesystemLogger>>;
+pub(crate) type FeeEstimator = crate::fee_estimator::OnchainFeeEstimator;
 
 pub(crate) type Wallet =
 	crate::wallet::Wallet<Arc<Broadcaster>, Arc<FeeEstimator>, Arc<FilesystemLogger>>;
@@ -122,6 +122,8 @@ pub(crate) type MessageRouter = lightning::onion_message::messenger::DefaultMess
 	Arc<KeysManager>,
 >;
 
+pub(crate) type ChannelHistory = ChannelHistoryStore<Arc<FilesystemLogger>>;
+
 pub(crate) type Sweeper = OutputSweeper<
 	Arc<Broadcaster>,
 	Arc<KeysManager>,
@@ -335,6 +337,42 @@ impl From<LdkChannelDetails> for ChannelDetails {
 	}
 }
 
+/// Options for cooperatively closing a channel via [`Node::close_channel_with_options`].
+///
+/// [`Node::close_channel_with_options`]: crate::Node::close_channel_with_options
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelCloseOptions {
+	/// The address our channel balance will be paid out to.
+	///
+	/// If set to `None`, the balance will be paid to our on-chain wallet.
+	///
+	/// **Note:** This needs to be a SegWit address. It can't be set for channels for which we
+	/// committed to a shutdown script upfront, which is the default. Set
+	/// [`Config::commit_upfront_shutdown_script`] to `false` before opening or accepting channels
+	/// you want to close to a custom address.
+	///
+	/// [`Config::commit_upfront_shutdown_script`]: crate::config::Config::commit_upfront_shutdown_script
+	pub destination_address: Option<Address>,
+	/// The fee rate we'll propose for the closing transaction.
+	///
+	/// If set to `None`, our current fee rate estimate will be used. Note that we'll still accept
+	/// somewhat higher fee rates proposed by the counterparty, bounded by the
+	/// [`ChannelConfig::force_close_avoidance_max_fee_satoshis`] configured for the channel.
+	pub target_fee_rate: Option<FeeRate>,
+	/// The time after which we'll resort to force-closing the channel if the cooperative closure
+	/// hasn't completed by then, in seconds.
+	///
+	/// If set to `None`, we'll never force-close the channel on our own.
+	///
+	/// **Note:** The timeout isn't persisted, i.e., it will be dropped if the [`Node`] is stopped
+	/// before it expires. In that case, [`Node::force_close_channel`] needs to be called manually
+	/// if the cooperative closure doesn't complete after restarting.
+	///
+	/// [`Node`]: crate::Node
+	/// [`Node::force_close_channel`]: crate::Node::force_close_channel
+	pub force_close_timeout_secs: Option<u64>,
+}
+
 /// Details of a known Lightning peer as returned by [`Node::list_peers`].
 ///
 /// [`Node::list_peers`]: crate::Node::list_peers
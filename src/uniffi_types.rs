@@ -11,8 +11,13 @@
 // Make sure to add any re-exported items that need to be used in uniffi below.
 
 pub use crate::graph::{ChannelInfo, ChannelUpdateInfo, NodeAnnouncementInfo, NodeInfo};
-pub use crate::payment::store::{LSPFeeLimits, PaymentDirection, PaymentKind, PaymentStatus};
-pub use crate::payment::{MaxTotalRoutingFeeLimit, QrPaymentResult, SendingParameters};
+pub use crate::payment::store::{
+	ConfirmationStatus, LSPFeeLimits, PaymentDirection, PaymentKind, PaymentStatus,
+};
+pub use crate::payment::{
+	MaxTotalRoutingFeeLimit, OnchainFeeEstimate, OnchainUtxo, QrPaymentResult, SendingParameters,
+	UtxoSelection,
+};
 
 pub use lightning::chain::channelmonitor::BalanceSource;
 pub use lightning::events::{ClosureReason, PaymentFailureReason};
@@ -25,7 +30,9 @@ pub use lightning::util::string::UntrustedString;
 
 pub use lightning_invoice::Bolt11Invoice;
 
-pub use bitcoin::{Address, BlockHash, Network, OutPoint, Txid};
+pub use bitcoin::{
+	Address, BlockHash, FeeRate, Network, OutPoint, Psbt, ScriptBuf, Transaction, Txid,
+};
 
 pub use bip39::Mnemonic;
 
@@ -36,6 +43,9 @@ use crate::error::Error;
 use crate::hex_utils;
 use crate::{SocketAddress, UserChannelId};
 
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::secp256k1::PublicKey;
@@ -283,6 +293,56 @@ impl UniffiCustomTypeConverter for Txid {
 	}
 }
 
+impl UniffiCustomTypeConverter for Psbt {
+	type Builtin = String;
+	fn into_custom(val: Self::Builtin) -> uniffi::Result<Self> {
+		let bytes = BASE64_STANDARD.decode(val).map_err(|_| Error::InvalidPsbt)?;
+		Ok(Psbt::deserialize(&bytes).map_err(|_| Error::InvalidPsbt)?)
+	}
+
+	fn from_custom(obj: Self) -> Self::Builtin {
+		BASE64_STANDARD.encode(obj.serialize())
+	}
+}
+
+impl UniffiCustomTypeConverter for ScriptBuf {
+	type Builtin = String;
+	fn into_custom(val: Self::Builtin) -> uniffi::Result<Self> {
+		Ok(ScriptBuf::from_hex(&val).map_err(|_| Error::InvalidTransaction)?)
+	}
+
+	fn from_custom(obj: Self) -> Self::Builtin {
+		obj.to_hex_string()
+	}
+}
+
+impl UniffiCustomTypeConverter for Transaction {
+	type Builtin = String;
+	fn into_custom(val: Self::Builtin) -> uniffi::Result<Self> {
+		if let Some(bytes_vec) = hex_utils::to_vec(&val) {
+			if let Ok(tx) = deserialize::<Transaction>(&bytes_vec) {
+				return Ok(tx);
+			}
+		}
+		Err(Error::InvalidTransaction.into())
+	}
+
+	fn from_custom(obj: Self) -> Self::Builtin {
+		hex_utils::to_string(&serialize(&obj))
+	}
+}
+
+impl UniffiCustomTypeConverter for FeeRate {
+	type Builtin = u64;
+	fn into_custom(val: Self::Builtin) -> uniffi::Result<Self> {
+		Ok(FeeRate::from_sat_per_kwu(val))
+	}
+
+	fn from_custom(obj: Self) -> Self::Builtin {
+		obj.to_sat_per_kwu()
+	}
+}
+
 impl UniffiCustomTypeConverter for BlockHash {
 	type Builtin = String;
 	fn into_custom(val: Self::Builtin) -> uniffi::Result<Self> {
@@ -0,0 +1,185 @@
+// This file is Copyright its original authors, visible in version control history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
+// accordance with one or both of these licenses.
+
+use crate::io::{
+	LOCKED_UTXOS_PERSISTENCE_KEY, LOCKED_UTXOS_PERSISTENCE_PRIMARY_NAMESPACE,
+	LOCKED_UTXOS_PERSISTENCE_SECONDARY_NAMESPACE,
+};
+use crate::logger::{log_error, Logger};
+use crate::types::DynStore;
+use crate::Error;
+
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use bitcoin::OutPoint;
+
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+
+/// Keeps track of the UTXOs the user locked, i.e., asked us to never spend.
+pub(crate) struct LockedUtxoStore<L: Deref>
+where
+	L::Target: Logger,
+{
+	utxos: RwLock<HashSet<OutPoint>>,
+	kv_store: Arc<DynStore>,
+	logger: L,
+}
+
+impl<L: Deref> LockedUtxoStore<L>
+where
+	L::Target: Logger,
+{
+	pub(crate) fn new(kv_store: Arc<DynStore>, logger: L) -> Self {
+		let utxos = RwLock::new(HashSet::new());
+		Self { utxos, kv_store, logger }
+	}
+
+	pub(crate) fn lock_utxo(&self, outpoint: OutPoint) -> Result<(), Error> {
+		let mut locked_utxos = self.utxos.write().unwrap();
+
+		if !locked_utxos.insert(outpoint) {
+			return Ok(());
+		}
+
+		self.persist_utxos(&*locked_utxos)
+	}
+
+	pub(crate) fn unlock_utxo(&self, outpoint: &OutPoint) -> Result<(), Error> {
+		let mut locked_utxos = self.utxos.write().unwrap();
+
+		if !locked_utxos.remove(outpoint) {
+			return Ok(());
+		}
+
+		self.persist_utxos(&*locked_utxos)
+	}
+
+	pub(crate) fn is_locked(&self, outpoint: &OutPoint) -> bool {
+		self.utxos.read().unwrap().contains(outpoint)
+	}
+
+	pub(crate) fn list_locked_utxos(&self) -> Vec<OutPoint> {
+		self.utxos.read().unwrap().iter().cloned().collect()
+	}
+
+	fn persist_utxos(&self, locked_utxos: &HashSet<OutPoint>) -> Result<(), Error> {
+		let data = LockedUtxoStoreSerWrapper(&*locked_utxos).encode();
+		self.kv_store
+			.write(
+				LOCKED_UTXOS_PERSISTENCE_PRIMARY_NAMESPACE,
+				LOCKED_UTXOS_PERSISTENCE_SECONDARY_NAMESPACE,
+				LOCKED_UTXOS_PERSISTENCE_KEY,
+				&data,
+			)
+			.map_err(|e| {
+				log_error!(
+					self.logger,
+					"Write for key {}/{}/{} failed due to: {}",
+					LOCKED_UTXOS_PERSISTENCE_PRIMARY_NAMESPACE,
+					LOCKED_UTXOS_PERSISTENCE_SECONDARY_NAMESPACE,
+					LOCKED_UTXOS_PERSISTENCE_KEY,
+					e
+				);
+				Error::PersistenceFailed
+			})?;
+		Ok(())
+	}
+}
+
+impl<L: Deref> ReadableArgs<(Arc<DynStore>, L)> for LockedUtxoStore<L>
+where
+	L::Target: Logger,
+{
+	#[inline]
+	fn read<R: lightning::io::Read>(
+		reader: &mut R, args: (Arc<DynStore>, L),
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let (kv_store, logger) = args;
+		let read_utxos: LockedUtxoStoreDeserWrapper = Readable::read(reader)?;
+		let utxos = RwLock::new(read_utxos.0);
+		Ok(Self { utxos, kv_store, logger })
+	}
+}
+
+pub(crate) struct LockedUtxoStoreDeserWrapper(HashSet<OutPoint>);
+
+impl Readable for LockedUtxoStoreDeserWrapper {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let len: u32 = Readable::read(reader)?;
+		let mut utxos = HashSet::with_capacity(len as usize);
+		for _ in 0..len {
+			let outpoint: OutPoint = Readable::read(reader)?;
+			utxos.insert(outpoint);
+		}
+		Ok(Self(utxos))
+	}
+}
+
+pub(crate) struct LockedUtxoStoreSerWrapper<'a>(&'a HashSet<OutPoint>);
+
+impl Writeable for LockedUtxoStoreSerWrapper<'_> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		(self.0.len() as u32).write(writer)?;
+		for outpoint in self.0.iter() {
+			outpoint.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use lightning::util::test_utils::{TestLogger, TestStore};
+
+	use bitcoin::Txid;
+
+	use std::str::FromStr;
+	use std::sync::Arc;
+
+	#[test]
+	fn locked_utxo_persistence() {
+		let store: Arc<DynStore> = Arc::new(TestStore::new(false));
+		let logger = Arc::new(TestLogger::new());
+		let locked_utxo_store = LockedUtxoStore::new(Arc::clone(&store), Arc::clone(&logger));
+
+		let txid =
+			Txid::from_str("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+				.unwrap();
+		let locked_outpoint = OutPoint::new(txid, 0);
+		let unlocked_outpoint = OutPoint::new(txid, 1);
+		assert!(store
+			.read(
+				LOCKED_UTXOS_PERSISTENCE_PRIMARY_NAMESPACE,
+				LOCKED_UTXOS_PERSISTENCE_SECONDARY_NAMESPACE,
+				LOCKED_UTXOS_PERSISTENCE_KEY,
+			)
+			.is_err());
+		locked_utxo_store.lock_utxo(locked_outpoint).unwrap();
+		locked_utxo_store.lock_utxo(unlocked_outpoint).unwrap();
+		locked_utxo_store.unlock_utxo(&unlocked_outpoint).unwrap();
+
+		// Check we can read back what we persisted.
+		let persisted_bytes = store
+			.read(
+				LOCKED_UTXOS_PERSISTENCE_PRIMARY_NAMESPACE,
+				LOCKED_UTXOS_PERSISTENCE_SECONDARY_NAMESPACE,
+				LOCKED_UTXOS_PERSISTENCE_KEY,
+			)
+			.unwrap();
+		let deser_locked_utxo_store =
+			LockedUtxoStore::read(&mut &persisted_bytes[..], (Arc::clone(&store), logger)).unwrap();
+
+		assert_eq!(deser_locked_utxo_store.list_locked_utxos(), vec![locked_outpoint]);
+		assert!(deser_locked_utxo_store.is_locked(&locked_outpoint));
+		assert!(!deser_locked_utxo_store.is_locked(&unlocked_outpoint));
+	}
+}
@@ -5,19 +5,29 @@
 // http://opensource.org/licenses/MIT>, at your option. You may not use this file except in
 // accordance with one or both of these licenses.
 
+use locked_utxos::LockedUtxoStore;
 use persist::KVStoreWalletPersister;
 
-use crate::logger::{log_error, log_info, log_trace, Logger};
+use crate::logger::{log_debug, log_error, log_info, log_trace, Logger};
 
-use crate::config::{BDK_CLIENT_CONCURRENCY, BDK_CLIENT_STOP_GAP, BDK_WALLET_SYNC_TIMEOUT_SECS};
 use crate::fee_estimator::{ConfirmationTarget, FeeEstimator};
+use crate::hex_utils;
+use crate::payment::store::{
+	ConfirmationStatus, PaymentDetails, PaymentDetailsUpdate, PaymentDirection, PaymentKind,
+	PaymentStatus, PaymentStore,
+};
+use crate::payment::{OnchainFeeEstimate, OnchainUtxo, UtxoSelection};
 use crate::Error;
 
 use lightning::chain::chaininterface::BroadcasterInterface;
+use lightning::chain::channelmonitor::ANTI_REORG_DELAY;
+use lightning::chain::{BestBlock, Listen};
 
 use lightning::events::bump_transaction::{Utxo, WalletSource};
+use lightning::ln::channelmanager::PaymentId;
 use lightning::ln::msgs::{DecodeError, UnsignedGossipMessage};
 use lightning::ln::script::ShutdownScript;
+use lightning::ln::types::ChannelId;
 use lightning::sign::{
 	ChangeDestinationSource, EntropySource, InMemorySigner, KeyMaterial, KeysManager, NodeSigner,
 	OutputSpender, Recipient, SignerProvider, SpendableOutputDescriptor,
@@ -26,11 +36,12 @@ use lightning::sign::{
 use lightning::util::message_signing;
 use lightning_invoice::RawBolt11Invoice;
 
-use bdk_chain::ChainPosition;
-use bdk_esplora::EsploraAsyncExt;
-use bdk_wallet::{KeychainKind, PersistedWallet, SignOptions};
+use bdk_chain::spk_client::{FullScanRequest, SyncRequest};
+use bdk_chain::{BlockId, ChainPosition};
+use bdk_wallet::error::BuildFeeBumpError;
+use bdk_wallet::{KeychainKind, PersistedWallet, SignOptions, TxBuilder, Update};
 
-use bitcoin::blockdata::constants::WITNESS_SCALE_FACTOR;
+use bitcoin::blockdata::constants::{COINBASE_MATURITY, WITNESS_SCALE_FACTOR};
 use bitcoin::blockdata::locktime::absolute::LockTime;
 use bitcoin::hashes::Hash;
 use bitcoin::key::XOnlyPublicKey;
@@ -39,21 +50,68 @@ use bitcoin::secp256k1::ecdh::SharedSecret;
 use bitcoin::secp256k1::ecdsa::{RecoverableSignature, Signature};
 use bitcoin::secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey, Signing};
 use bitcoin::{
-	Amount, ScriptBuf, Transaction, TxOut, Txid, WPubkeyHash, WitnessProgram, WitnessVersion,
+	Amount, FeeRate, OutPoint, ScriptBuf, Transaction, TxOut, Txid, WPubkeyHash, Weight,
+	WitnessProgram, WitnessVersion,
 };
 
-use esplora_client::AsyncClient as EsploraAsyncClient;
-
-use std::ops::{Deref, DerefMut};
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+pub(crate) mod locked_utxos;
 pub(crate) mod persist;
 pub(crate) mod ser;
 
-enum WalletSyncStatus {
-	Completed,
-	InProgress { subscribers: tokio::sync::broadcast::Sender<Result<(), Error>> },
+/// Parameters to use when funding a channel we initiated.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ChannelFundingParams {
+	/// The fee rate to use instead of our estimate for [`ConfirmationTarget::ChannelFunding`].
+	pub fee_rate: Option<FeeRate>,
+	/// Restricts which of our UTXOs may be used to fund the channel.
+	pub utxo_selection: Option<UtxoSelection>,
+	/// Whether the funding transaction will be supplied by the user rather than our wallet.
+	pub external_funding: bool,
+	/// The batch of channels to be funded by the same transaction, if any.
+	pub funding_batch: Option<Arc<Mutex<FundingBatch>>>,
+}
+
+/// A set of channels we initiated that are to be funded by a single transaction.
+#[derive(Debug)]
+pub(crate) struct FundingBatch {
+	/// The `user_channel_id`s of all channels in the batch.
+	pub user_channel_ids: Vec<u128>,
+	/// The temporary channel id, counterparty, and funding output of each channel that is ready to
+	/// be funded.
+	pub ready_channels: HashMap<u128, (ChannelId, PublicKey, ScriptBuf, Amount)>,
+	/// Whether the batch was abandoned as one of its channels failed before being funded.
+	pub failed: bool,
+}
+
+impl FundingBatch {
+	pub(crate) fn new(user_channel_ids: Vec<u128>) -> Self {
+		Self { user_channel_ids, ready_channels: HashMap::new(), failed: false }
+	}
+}
+
+/// A channel funding awaiting a signed funding transaction from the user.
+#[derive(Clone, Debug)]
+pub(crate) struct PendingChannelFunding {
+	pub temporary_channel_id: ChannelId,
+	pub counterparty_node_id: PublicKey,
+	pub output_script: ScriptBuf,
+	pub channel_value_sats: u64,
+	/// The unsigned funding PSBT we created, if the channel is funded by our watch-only wallet.
+	pub psbt: Option<Psbt>,
+}
+
+/// An unconfirmed transaction as found in the mempool of our chain source, along with the
+/// aggregate fee and weight of the transaction and all of its unconfirmed ancestors.
+#[derive(Clone, Debug)]
+pub(crate) struct MempoolPackage {
+	pub tx: Transaction,
+	pub ancestors_fee: Amount,
+	pub ancestors_weight: Weight,
 }
 
 pub(crate) struct Wallet<B: Deref, E: Deref, L: Deref>
@@ -65,11 +123,10 @@ where
 	// A BDK on-chain wallet.
 	inner: Mutex<PersistedWallet<KVStoreWalletPersister>>,
 	persister: Mutex<KVStoreWalletPersister>,
-	esplora_client: EsploraAsyncClient,
 	broadcaster: B,
 	fee_estimator: E,
-	// A Mutex holding the current sync status.
-	sync_status: Mutex<WalletSyncStatus>,
+	payment_store: Arc<PaymentStore<L>>,
+	locked_utxo_store: Arc<LockedUtxoStore<L>>,
 	logger: L,
 }
 
@@ -81,104 +138,91 @@ where
 {
 	pub(crate) fn new(
 		wallet: bdk_wallet::PersistedWallet<KVStoreWalletPersister>,
-		wallet_persister: KVStoreWalletPersister, esplora_client: EsploraAsyncClient,
-		broadcaster: B, fee_estimator: E, logger: L,
+		wallet_persister: KVStoreWalletPersister, broadcaster: B, fee_estimator: E,
+		payment_store: Arc<PaymentStore<L>>, locked_utxo_store: Arc<LockedUtxoStore<L>>, logger: L,
 	) -> Self {
 		let inner = Mutex::new(wallet);
 		let persister = Mutex::new(wallet_persister);
-		let sync_status = Mutex::new(WalletSyncStatus::Completed);
-		Self { inner, persister, esplora_client, broadcaster, fee_estimator, sync_status, logger }
+		Self {
+			inner,
+			persister,
+			broadcaster,
+			fee_estimator,
+			payment_store,
+			locked_utxo_store,
+			logger,
+		}
 	}
 
-	pub(crate) async fn sync(&self) -> Result<(), Error> {
-		if let Some(mut sync_receiver) = self.register_or_subscribe_pending_sync() {
-			log_info!(self.logger, "Sync in progress, skipping.");
-			return sync_receiver.recv().await.map_err(|e| {
-				debug_assert!(false, "Failed to receive wallet sync result: {:?}", e);
-				log_error!(self.logger, "Failed to receive wallet sync result: {:?}", e);
-				Error::WalletOperationFailed
-			})?;
-		}
+	pub(crate) fn get_full_scan_request(&self) -> FullScanRequest<KeychainKind> {
+		self.inner.lock().unwrap().start_full_scan().build()
+	}
 
-		let res = {
-			let full_scan_request = self.inner.lock().unwrap().start_full_scan().build();
+	pub(crate) fn get_incremental_sync_request(&self) -> SyncRequest<(KeychainKind, u32)> {
+		self.inner.lock().unwrap().start_sync_with_revealed_spks().build()
+	}
 
-			let wallet_sync_timeout_fut = tokio::time::timeout(
-				Duration::from_secs(BDK_WALLET_SYNC_TIMEOUT_SECS),
-				self.esplora_client.full_scan(
-					full_scan_request,
-					BDK_CLIENT_STOP_GAP,
-					BDK_CLIENT_CONCURRENCY,
-				),
-			);
+	pub(crate) fn get_watched_scripts(&self) -> Vec<ScriptBuf> {
+		let locked_wallet = self.inner.lock().unwrap();
+		locked_wallet.spk_index().inner().all_spks().values().cloned().collect()
+	}
 
-			match wallet_sync_timeout_fut.await {
-				Ok(res) => match res {
-					Ok(update) => {
-						let mut locked_wallet = self.inner.lock().unwrap();
-						match locked_wallet.apply_update(update) {
-							Ok(()) => {
-								let mut locked_persister = self.persister.lock().unwrap();
-								locked_wallet.persist(&mut locked_persister).map_err(|e| {
-									log_error!(self.logger, "Failed to persist wallet: {}", e);
-									Error::PersistenceFailed
-								})?;
-
-								Ok(())
-							},
-							Err(e) => {
-								log_error!(
-									self.logger,
-									"Sync failed due to chain connection error: {}",
-									e
-								);
-								Err(Error::WalletOperationFailed)
-							},
-						}
-					},
-					Err(e) => match *e {
-						esplora_client::Error::Reqwest(he) => {
-							log_error!(
-								self.logger,
-								"Sync failed due to HTTP connection error: {}",
-								he
-							);
-							Err(Error::WalletOperationFailed)
-						},
-						_ => {
-							log_error!(self.logger, "Sync failed due to Esplora error: {}", e);
-							Err(Error::WalletOperationFailed)
-						},
-					},
-				},
-				Err(e) => {
-					log_error!(self.logger, "On-chain wallet sync timed out: {}", e);
-					Err(Error::WalletOperationTimeout)
-				},
-			}
-		};
+	pub(crate) fn current_best_block(&self) -> BestBlock {
+		let checkpoint = self.inner.lock().unwrap().latest_checkpoint();
+		BestBlock::new(checkpoint.hash(), checkpoint.height())
+	}
 
-		self.propagate_result_to_subscribers(res);
+	pub(crate) fn apply_update(&self, update: impl Into<Update>) -> Result<(), Error> {
+		let mut locked_wallet = self.inner.lock().unwrap();
+		match locked_wallet.apply_update(update) {
+			Ok(()) => {
+				let mut locked_persister = self.persister.lock().unwrap();
+				locked_wallet.persist(&mut locked_persister).map_err(|e| {
+					log_error!(self.logger, "Failed to persist wallet: {}", e);
+					Error::PersistenceFailed
+				})?;
+
+				self.update_payment_store(&locked_wallet).map_err(|e| {
+					log_error!(self.logger, "Failed to update payment store: {}", e);
+					Error::PersistenceFailed
+				})?;
+
+				Ok(())
+			},
+			Err(e) => {
+				log_error!(self.logger, "Sync failed due to chain connection error: {}", e);
+				Err(Error::WalletOperationFailed)
+			},
+		}
+	}
 
-		res
+	/// Inserts the given block into the wallet's local chain, e.g., to be able to rescan from it.
+	pub(crate) fn insert_checkpoint(&self, block_id: BlockId) -> Result<(), Error> {
+		let checkpoint = self.inner.lock().unwrap().latest_checkpoint().insert(block_id);
+		self.apply_update(Update { chain: Some(checkpoint), ..Default::default() })
 	}
 
-	pub(crate) fn create_funding_transaction(
-		&self, output_script: ScriptBuf, amount: Amount, confirmation_target: ConfirmationTarget,
-		locktime: LockTime,
-	) -> Result<Transaction, Error> {
-		let fee_rate = self.fee_estimator.estimate_fee_rate(confirmation_target);
+	/// Returns whether the wallet was set up from public descriptors only, i.e., can't sign.
+	pub(crate) fn is_watch_only(&self) -> bool {
+		self.inner.lock().unwrap().get_signers(KeychainKind::External).signers().is_empty()
+	}
+
+	/// Creates an unsigned PSBT funding the given channel outputs.
+	pub(crate) fn create_funding_psbt(
+		&self, outputs: Vec<(ScriptBuf, Amount)>, confirmation_target: ConfirmationTarget,
+		fee_rate: Option<FeeRate>, utxo_selection: Option<&UtxoSelection>, locktime: LockTime,
+	) -> Result<Psbt, Error> {
+		let fee_rate =
+			fee_rate.unwrap_or_else(|| self.fee_estimator.estimate_fee_rate(confirmation_target));
 
 		let mut locked_wallet = self.inner.lock().unwrap();
 		let mut tx_builder = locked_wallet.build_tx();
 
-		tx_builder
-			.add_recipient(output_script, amount)
-			.fee_rate(fee_rate)
-			.nlocktime(locktime)
-			.enable_rbf();
+		self.apply_utxo_selection(&mut tx_builder, utxo_selection)?;
 
-		let mut psbt = match tx_builder.finish() {
+		tx_builder.set_recipients(outputs).fee_rate(fee_rate).nlocktime(locktime).enable_rbf();
+
+		let psbt = match tx_builder.finish() {
 			Ok(psbt) => {
 				log_trace!(self.logger, "Created funding PSBT: {:?}", psbt);
 				psbt
@@ -189,6 +233,28 @@ where
 			},
 		};
 
+		let mut locked_persister = self.persister.lock().unwrap();
+		locked_wallet.persist(&mut locked_persister).map_err(|e| {
+			log_error!(self.logger, "Failed to persist wallet: {}", e);
+			Error::PersistenceFailed
+		})?;
+
+		Ok(psbt)
+	}
+
+	pub(crate) fn create_funding_transaction(
+		&self, outputs: Vec<(ScriptBuf, Amount)>, confirmation_target: ConfirmationTarget,
+		fee_rate: Option<FeeRate>, utxo_selection: Option<&UtxoSelection>, locktime: LockTime,
+	) -> Result<Transaction, Error> {
+		let mut psbt = self.create_funding_psbt(
+			outputs,
+			confirmation_target,
+			fee_rate,
+			utxo_selection,
+			locktime,
+		)?;
+
+		let locked_wallet = self.inner.lock().unwrap();
 		match locked_wallet.sign(&mut psbt, SignOptions::default()) {
 			Ok(finalized) => {
 				if !finalized {
@@ -201,12 +267,6 @@ where
 			},
 		}
 
-		let mut locked_persister = self.persister.lock().unwrap();
-		locked_wallet.persist(&mut locked_persister).map_err(|e| {
-			log_error!(self.logger, "Failed to persist wallet: {}", e);
-			Error::PersistenceFailed
-		})?;
-
 		let tx = psbt.extract_tx().map_err(|e| {
 			log_error!(self.logger, "Failed to extract transaction: {}", e);
 			e
@@ -215,6 +275,74 @@ where
 		Ok(tx)
 	}
 
+	pub(crate) fn list_utxos(&self) -> Vec<OnchainUtxo> {
+		let locked_wallet = self.inner.lock().unwrap();
+		let network = locked_wallet.network();
+
+		let mut utxos = Vec::new();
+		for u in locked_wallet.list_unspent() {
+			let address = match bitcoin::Address::from_script(&u.txout.script_pubkey, network) {
+				Ok(address) => address,
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Failed to derive address for UTXO {}: {}",
+						u.outpoint,
+						e
+					);
+					continue;
+				},
+			};
+
+			let status = match locked_wallet.get_tx(u.outpoint.txid).map(|t| t.chain_position) {
+				Some(ChainPosition::Confirmed(anchor)) => ConfirmationStatus::Confirmed {
+					block_hash: anchor.block_id.hash,
+					height: anchor.block_id.height,
+					timestamp: anchor.confirmation_time,
+				},
+				_ => ConfirmationStatus::Unconfirmed,
+			};
+
+			utxos.push(OnchainUtxo {
+				outpoint: u.outpoint,
+				value_sats: u.txout.value.to_sat(),
+				status,
+				address,
+				keychain: u.keychain,
+				is_locked: self.locked_utxo_store.is_locked(&u.outpoint),
+			});
+		}
+
+		utxos
+	}
+
+	pub(crate) fn lock_utxo(&self, outpoint: OutPoint) -> Result<(), Error> {
+		if self.inner.lock().unwrap().get_utxo(outpoint).is_none() {
+			log_error!(self.logger, "Failed to lock UTXO {} as it's unknown or spent", outpoint);
+			return Err(Error::UtxoNotFound);
+		}
+
+		self.lock_utxo_unchecked(outpoint)
+	}
+
+	/// Locks the given UTXO even if it's not known to the wallet (yet), e.g., when importing the
+	/// lock state along with labels prior to syncing.
+	pub(crate) fn lock_utxo_unchecked(&self, outpoint: OutPoint) -> Result<(), Error> {
+		self.locked_utxo_store.lock_utxo(outpoint)?;
+		log_info!(self.logger, "Locked UTXO {}", outpoint);
+		Ok(())
+	}
+
+	pub(crate) fn unlock_utxo(&self, outpoint: &OutPoint) -> Result<(), Error> {
+		self.locked_utxo_store.unlock_utxo(outpoint)?;
+		log_info!(self.logger, "Unlocked UTXO {}", outpoint);
+		Ok(())
+	}
+
+	pub(crate) fn list_locked_utxos(&self) -> Vec<OutPoint> {
+		self.locked_utxo_store.list_locked_utxos()
+	}
+
 	pub(crate) fn get_new_address(&self) -> Result<bitcoin::Address, Error> {
 		let mut locked_wallet = self.inner.lock().unwrap();
 		let mut locked_persister = self.persister.lock().unwrap();
@@ -242,14 +370,47 @@ where
 	pub(crate) fn get_balances(
 		&self, total_anchor_channels_reserve_sats: u64,
 	) -> Result<(u64, u64), Error> {
-		let balance = self.inner.lock().unwrap().balance();
+		let locked_wallet = self.inner.lock().unwrap();
+		Ok(self.compute_balances(&locked_wallet, total_anchor_channels_reserve_sats))
+	}
+
+	fn compute_balances(
+		&self, locked_wallet: &PersistedWallet<KVStoreWalletPersister>,
+		total_anchor_channels_reserve_sats: u64,
+	) -> (u64, u64) {
+		let balance = locked_wallet.balance();
+
+		// Locked UTXOs are never spent, so they can't be used to cover the reserve either. We only
+		// deduct the ones that are accounted for as trusted spendable funds to begin with, i.e.,
+		// mature confirmed outputs and unconfirmed outputs on our internal keychain.
+		let cur_height = locked_wallet.latest_checkpoint().height();
+		let locked_amount_sats: u64 = locked_wallet
+			.list_unspent()
+			.filter(|u| self.locked_utxo_store.is_locked(&u.outpoint))
+			.filter(|u| match u.chain_position {
+				ChainPosition::Confirmed(anchor) => {
+					let is_coinbase = locked_wallet
+						.get_tx(u.outpoint.txid)
+						.map_or(false, |wtx| wtx.tx_node.tx.is_coinbase());
+					!is_coinbase
+						|| cur_height.saturating_sub(anchor.block_id.height) + 1
+							>= COINBASE_MATURITY
+				},
+				ChainPosition::Unconfirmed(_) => u.keychain == KeychainKind::Internal,
+			})
+			.map(|u| u.txout.value.to_sat())
+			.sum();
 
 		let (total, spendable) = (
 			balance.total().to_sat(),
-			balance.trusted_spendable().to_sat().saturating_sub(total_anchor_channels_reserve_sats),
+			balance
+				.trusted_spendable()
+				.to_sat()
+				.saturating_sub(locked_amount_sats)
+				.saturating_sub(total_anchor_channels_reserve_sats),
 		);
 
-		Ok((total, spendable))
+		(total, spendable)
 	}
 
 	pub(crate) fn get_spendable_amount_sats(
@@ -262,28 +423,169 @@ where
 	///
 	/// If `amount_msat_or_drain` is `None` the wallet will be drained, i.e., all available funds will be
 	/// spent.
+	///
+	/// If `fee_rate` is `None`, we'll use our current estimate for
+	/// [`ConfirmationTarget::OnchainPayment`].
+	///
+	/// If `utxo_selection` is given, it restricts which UTXOs may be spent.
 	pub(crate) fn send_to_address(
 		&self, address: &bitcoin::Address, amount_or_drain: Option<Amount>,
+		fee_rate: Option<FeeRate>, utxo_selection: Option<&UtxoSelection>,
+	) -> Result<Txid, Error> {
+		let fee_rate = fee_rate.unwrap_or_else(|| {
+			self.fee_estimator.estimate_fee_rate(ConfirmationTarget::OnchainPayment)
+		});
+
+		let tx = {
+			let mut locked_wallet = self.inner.lock().unwrap();
+			let psbt = self.build_and_sign_send_psbt(
+				&mut locked_wallet,
+				address,
+				amount_or_drain,
+				fee_rate,
+				utxo_selection,
+			)?;
+
+			let tx = psbt.extract_tx().map_err(|e| {
+				log_error!(self.logger, "Failed to extract transaction: {}", e);
+				e
+			})?;
+
+			self.insert_sent_tx(&mut locked_wallet, &tx)?;
+
+			tx
+		};
+
+		self.broadcaster.broadcast_transactions(&[&tx]);
+
+		let txid = tx.compute_txid();
+
+		if let Some(amount) = amount_or_drain {
+			log_info!(
+				self.logger,
+				"Created new transaction {} sending {}sats on-chain to address {}",
+				txid,
+				amount.to_sat(),
+				address
+			);
+		} else {
+			log_info!(
+				self.logger,
+				"Created new transaction {} sending all available on-chain funds to address {}",
+				txid,
+				address
+			);
+		}
+
+		Ok(txid)
+	}
+
+	/// Creates an unsigned PSBT paying the given amount to the given address, returning any change
+	/// to us.
+	///
+	/// If `fee_rate` is `None`, we'll use our current estimate for
+	/// [`ConfirmationTarget::OnchainPayment`].
+	pub(crate) fn create_psbt(
+		&self, address: &bitcoin::Address, amount: Amount, fee_rate: Option<FeeRate>,
+		utxo_selection: Option<&UtxoSelection>,
+	) -> Result<Psbt, Error> {
+		let fee_rate = fee_rate.unwrap_or_else(|| {
+			self.fee_estimator.estimate_fee_rate(ConfirmationTarget::OnchainPayment)
+		});
+
+		let mut locked_wallet = self.inner.lock().unwrap();
+		let mut tx_builder = locked_wallet.build_tx();
+
+		self.apply_utxo_selection(&mut tx_builder, utxo_selection)?;
+
+		tx_builder.add_recipient(address.script_pubkey(), amount).fee_rate(fee_rate).enable_rbf();
+
+		let psbt = match tx_builder.finish() {
+			Ok(psbt) => {
+				log_trace!(self.logger, "Created PSBT: {:?}", psbt);
+				psbt
+			},
+			Err(err) => {
+				log_error!(self.logger, "Failed to create PSBT: {}", err);
+				return Err(err.into());
+			},
+		};
+
+		// Make sure the change address we might have revealed isn't handed out again.
+		let mut locked_persister = self.persister.lock().unwrap();
+		locked_wallet.persist(&mut locked_persister).map_err(|e| {
+			log_error!(self.logger, "Failed to persist wallet: {}", e);
+			Error::PersistenceFailed
+		})?;
+
+		Ok(psbt)
+	}
+
+	/// Finalizes the given PSBT, adding our own signatures if we're able to, and extracts the
+	/// final transaction.
+	pub(crate) fn finalize_psbt(&self, mut psbt: Psbt) -> Result<Transaction, Error> {
+		let locked_wallet = self.inner.lock().unwrap();
+
+		match locked_wallet.sign(&mut psbt, SignOptions::default()) {
+			Ok(finalized) => {
+				if !finalized {
+					log_error!(self.logger, "Failed to finalize PSBT as it's not fully signed");
+					return Err(Error::OnchainTxSigningFailed);
+				}
+			},
+			Err(err) => {
+				log_error!(self.logger, "Failed to finalize PSBT: {}", err);
+				return Err(err.into());
+			},
+		}
+
+		let tx = psbt.extract_tx().map_err(|e| {
+			log_error!(self.logger, "Failed to extract transaction: {}", e);
+			e
+		})?;
+
+		Ok(tx)
+	}
+
+	/// Broadcasts the given transaction spending our funds, tracking it in the wallet right away.
+	pub(crate) fn broadcast_transaction(&self, tx: Transaction) -> Result<Txid, Error> {
+		{
+			let mut locked_wallet = self.inner.lock().unwrap();
+			self.insert_sent_tx(&mut locked_wallet, &tx)?;
+		}
+
+		self.broadcaster.broadcast_transactions(&[&tx]);
+
+		let txid = tx.compute_txid();
+		log_info!(self.logger, "Broadcast transaction {}", txid);
+
+		Ok(txid)
+	}
+
+	/// Send funds to the given outputs in a single transaction, returning any change to us.
+	///
+	/// If `fee_rate` is `None`, we'll use our current estimate for
+	/// [`ConfirmationTarget::OnchainPayment`].
+	///
+	/// If `utxo_selection` is given, it restricts which UTXOs may be spent.
+	pub(crate) fn send_to_outputs(
+		&self, outputs: &[(bitcoin::Address, Amount)], fee_rate: Option<FeeRate>,
+		utxo_selection: Option<&UtxoSelection>,
 	) -> Result<Txid, Error> {
-		let confirmation_target = ConfirmationTarget::OnchainPayment;
-		let fee_rate = self.fee_estimator.estimate_fee_rate(confirmation_target);
+		let fee_rate = fee_rate.unwrap_or_else(|| {
+			self.fee_estimator.estimate_fee_rate(ConfirmationTarget::OnchainPayment)
+		});
 
 		let tx = {
 			let mut locked_wallet = self.inner.lock().unwrap();
 			let mut tx_builder = locked_wallet.build_tx();
 
-			if let Some(amount) = amount_or_drain {
-				tx_builder
-					.add_recipient(address.script_pubkey(), amount)
-					.fee_rate(fee_rate)
-					.enable_rbf();
-			} else {
-				tx_builder
-					.drain_wallet()
-					.drain_to(address.script_pubkey())
-					.fee_rate(fee_rate)
-					.enable_rbf();
+			self.apply_utxo_selection(&mut tx_builder, utxo_selection)?;
+
+			for (address, amount) in outputs {
+				tx_builder.add_recipient(address.script_pubkey(), *amount);
 			}
+			tx_builder.fee_rate(fee_rate).enable_rbf();
 
 			let mut psbt = match tx_builder.finish() {
 				Ok(psbt) => {
@@ -308,93 +610,819 @@ where
 				},
 			}
 
+			let tx = psbt.extract_tx().map_err(|e| {
+				log_error!(self.logger, "Failed to extract transaction: {}", e);
+				e
+			})?;
+
+			self.insert_sent_tx(&mut locked_wallet, &tx)?;
+
+			tx
+		};
+
+		self.broadcaster.broadcast_transactions(&[&tx]);
+
+		let txid = tx.compute_txid();
+		log_info!(
+			self.logger,
+			"Created new transaction {} sending {}sats on-chain to {} outputs",
+			txid,
+			outputs.iter().map(|(_, amount)| amount.to_sat()).sum::<u64>(),
+			outputs.len()
+		);
+
+		Ok(txid)
+	}
+
+	// Inserts a transaction we just created right away so that it's known to the wallet, e.g.,
+	// when its fee is to be bumped, before we see it during the next sync.
+	fn insert_sent_tx(
+		&self, locked_wallet: &mut PersistedWallet<KVStoreWalletPersister>, tx: &Transaction,
+	) -> Result<(), Error> {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or(Duration::from_secs(0))
+			.as_secs();
+		locked_wallet.apply_unconfirmed_txs([(tx, now)]);
+
+		let mut locked_persister = self.persister.lock().unwrap();
+		locked_wallet.persist(&mut locked_persister).map_err(|e| {
+			log_error!(self.logger, "Failed to persist wallet: {}", e);
+			Error::PersistenceFailed
+		})?;
+
+		self.update_payment_store(locked_wallet).unwrap_or_else(|e| {
+			log_error!(self.logger, "Failed to update payment store: {}", e);
+		});
+
+		Ok(())
+	}
+
+	/// Returns the fee and size of the transaction [`Wallet::send_to_address`] would create if
+	/// given the same arguments, without broadcasting anything.
+	pub(crate) fn estimate_send_to_address_fee(
+		&self, address: &bitcoin::Address, amount_or_drain: Option<Amount>,
+		fee_rate: Option<FeeRate>, utxo_selection: Option<&UtxoSelection>,
+	) -> Result<OnchainFeeEstimate, Error> {
+		let fee_rate = fee_rate.unwrap_or_else(|| {
+			self.fee_estimator.estimate_fee_rate(ConfirmationTarget::OnchainPayment)
+		});
+
+		// We don't sign the transaction, which allows to preview fees in watch-only mode, too.
+		let mut locked_wallet = self.inner.lock().unwrap();
+		let psbt = self.build_send_psbt(
+			&mut locked_wallet,
+			address,
+			amount_or_drain,
+			fee_rate,
+			utxo_selection,
+		)?;
+
+		// We're not going to use the transaction, so make sure the change address we might have
+		// revealed is handed out again.
+		locked_wallet.cancel_tx(&psbt.unsigned_tx);
+
+		let fee = psbt.fee().map_err(|e| {
+			log_error!(self.logger, "Failed to calculate transaction fee: {}", e);
+			Error::OnchainTxCreationFailed
+		})?;
+		let weight = self.estimate_signed_weight(&locked_wallet, &psbt.unsigned_tx)?;
+
+		Ok(OnchainFeeEstimate { fee_sats: fee.to_sat(), vsize: weight.to_vbytes_ceil(), fee_rate })
+	}
+
+	/// Replaces the given unconfirmed wallet transaction with one paying the given fee rate.
+	///
+	/// Transactions paying to any of the given `channel_funding_scripts` won't be replaced. If the
+	/// replacement needs to spend additional inputs, we make sure the additional fee doesn't cut
+	/// into the given Anchor channel reserve.
+	///
+	/// Returns the txid of the replacement transaction.
+	pub(crate) fn bump_fee_rbf(
+		&self, txid: Txid, fee_rate: FeeRate, channel_funding_scripts: &[ScriptBuf],
+		total_anchor_channels_reserve_sats: u64,
+	) -> Result<Txid, Error> {
+		let tx = {
+			let mut locked_wallet = self.inner.lock().unwrap();
+
+			let original_tx = locked_wallet.get_tx(txid).map(|wtx| Arc::clone(&wtx.tx_node.tx));
+			if let Some(original_tx) = original_tx.as_ref() {
+				if original_tx
+					.output
+					.iter()
+					.any(|o| channel_funding_scripts.contains(&o.script_pubkey))
+				{
+					log_error!(
+						self.logger,
+						"Refusing to bump fee of transaction {} as it funds a channel",
+						txid
+					);
+					return Err(Error::InvalidTransaction);
+				}
+			}
+			let (_, spendable_amount_sats) =
+				self.compute_balances(&locked_wallet, total_anchor_channels_reserve_sats);
+
+			let mut tx_builder = locked_wallet.build_fee_bump(txid).map_err(|e| {
+				log_error!(self.logger, "Failed to bump fee of transaction {}: {}", txid, e);
+				match e {
+					BuildFeeBumpError::TransactionNotFound(_) => Error::TransactionNotFound,
+					BuildFeeBumpError::TransactionConfirmed(_) => {
+						Error::TransactionAlreadyConfirmed
+					},
+					_ => Error::OnchainTxCreationFailed,
+				}
+			})?;
+
+			// Make sure we don't add any locked UTXOs if we need additional inputs.
+			self.apply_utxo_selection(&mut tx_builder, None)?;
+			tx_builder.fee_rate(fee_rate).enable_rbf();
+
+			let mut psbt = match tx_builder.finish() {
+				Ok(psbt) => {
+					log_trace!(self.logger, "Created replacement PSBT: {:?}", psbt);
+					psbt
+				},
+				Err(err) => {
+					log_error!(self.logger, "Failed to create replacement transaction: {}", err);
+					return Err(err.into());
+				},
+			};
+
+			// If we had to add inputs, the replacement spends funds that might be required to
+			// cover the Anchor channel reserve.
+			if let Some(original_tx) = original_tx.as_ref() {
+				let adds_inputs = psbt.unsigned_tx.input.iter().any(|new_input| {
+					!original_tx
+						.input
+						.iter()
+						.any(|i| i.previous_output == new_input.previous_output)
+				});
+				if adds_inputs {
+					let original_fee = locked_wallet.calculate_fee(original_tx).map_err(|e| {
+						log_error!(self.logger, "Failed to calculate transaction fee: {}", e);
+						Error::OnchainTxCreationFailed
+					})?;
+					let new_fee = psbt.fee().map_err(|e| {
+						log_error!(self.logger, "Failed to calculate transaction fee: {}", e);
+						Error::OnchainTxCreationFailed
+					})?;
+					let additional_fee_sats =
+						new_fee.to_sat().saturating_sub(original_fee.to_sat());
+					if spendable_amount_sats < additional_fee_sats {
+						log_error!(self.logger,
+							"Unable to bump fee due to insufficient funds. Available: {}sats, Required: {}sats",
+							spendable_amount_sats, additional_fee_sats
+						);
+						locked_wallet.cancel_tx(&psbt.unsigned_tx);
+						return Err(Error::InsufficientFunds);
+					}
+				}
+			}
+
+			match locked_wallet.sign(&mut psbt, SignOptions::default()) {
+				Ok(finalized) => {
+					if !finalized {
+						return Err(Error::OnchainTxCreationFailed);
+					}
+				},
+				Err(err) => {
+					log_error!(self.logger, "Failed to create replacement transaction: {}", err);
+					return Err(err.into());
+				},
+			}
+
+			let tx = psbt.extract_tx().map_err(|e| {
+				log_error!(self.logger, "Failed to extract transaction: {}", e);
+				e
+			})?;
+
+			// Insert the replacement right away so that BDK considers the original transaction
+			// evicted and won't try to spend its outputs going forward.
+			let now = SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.unwrap_or(Duration::from_secs(0))
+				.as_secs();
+			locked_wallet.apply_unconfirmed_txs([(&tx, now)]);
+
 			let mut locked_persister = self.persister.lock().unwrap();
 			locked_wallet.persist(&mut locked_persister).map_err(|e| {
 				log_error!(self.logger, "Failed to persist wallet: {}", e);
 				Error::PersistenceFailed
 			})?;
 
-			psbt.extract_tx().map_err(|e| {
+			// Link the original payment to its replacement so we don't consider it failed once
+			// it got evicted.
+			let update = PaymentDetailsUpdate {
+				replaced_by: Some(Some(tx.compute_txid())),
+				..PaymentDetailsUpdate::new(PaymentId(txid.to_byte_array()))
+			};
+			self.payment_store.update(&update).unwrap_or_else(|e| {
+				log_error!(self.logger, "Failed to update payment store: {}", e);
+				false
+			});
+
+			self.update_payment_store(&locked_wallet).unwrap_or_else(|e| {
+				log_error!(self.logger, "Failed to update payment store: {}", e);
+			});
+
+			tx
+		};
+
+		self.broadcaster.broadcast_transactions(&[&tx]);
+
+		let new_txid = tx.compute_txid();
+		log_info!(
+			self.logger,
+			"Replaced transaction {} with {} paying {} sat/kwu",
+			txid,
+			new_txid,
+			fee_rate.to_sat_per_kwu()
+		);
+
+		Ok(new_txid)
+	}
+
+	/// Spends one of our outputs of the given unconfirmed transaction in a child transaction
+	/// paying enough fees to lift the fee rate of the package to the given fee rate.
+	///
+	/// If given, the `mempool_package` as found in our chain source's mempool will be used to
+	/// learn about the parent and its unconfirmed ancestors, which is necessary if the wallet
+	/// isn't aware of unconfirmed transactions we didn't create ourselves. We make sure the fee
+	/// paid by the child doesn't cut into the given Anchor channel reserve.
+	///
+	/// Returns the txid of the child transaction.
+	pub(crate) fn bump_fee_cpfp(
+		&self, txid: Txid, fee_rate: FeeRate, mempool_package: Option<MempoolPackage>,
+		total_anchor_channels_reserve_sats: u64,
+	) -> Result<Txid, Error> {
+		let tx = {
+			let mut locked_wallet = self.inner.lock().unwrap();
+
+			let wallet_tx = locked_wallet.get_tx(txid).map(|wallet_tx| {
+				(Arc::clone(&wallet_tx.tx_node.tx), wallet_tx.chain_position.is_confirmed())
+			});
+			let parent_tx = match (wallet_tx, mempool_package.as_ref()) {
+				(Some((tx, is_confirmed)), _) => {
+					if is_confirmed {
+						log_error!(self.logger, "Transaction {} is already confirmed", txid);
+						return Err(Error::TransactionAlreadyConfirmed);
+					}
+					tx
+				},
+				(None, Some(package)) => {
+					// Insert the parent so that we're able to spend our outputs.
+					let now = SystemTime::now()
+						.duration_since(UNIX_EPOCH)
+						.unwrap_or(Duration::from_secs(0))
+						.as_secs();
+					locked_wallet.apply_unconfirmed_txs([(&package.tx, now)]);
+					Arc::new(package.tx.clone())
+				},
+				(None, None) => {
+					log_error!(self.logger, "Transaction {} is unknown to the wallet", txid);
+					return Err(Error::TransactionNotFound);
+				},
+			};
+
+			// We spend the largest of our unspent outputs of the parent that isn't locked.
+			let utxo = (0..parent_tx.output.len() as u32)
+				.map(|vout| OutPoint::new(txid, vout))
+				.filter(|outpoint| !self.locked_utxo_store.is_locked(outpoint))
+				.filter_map(|outpoint| locked_wallet.get_utxo(outpoint))
+				.max_by_key(|utxo| utxo.txout.value)
+				.ok_or_else(|| {
+					log_error!(
+						self.logger,
+						"Transaction {} doesn't have any unspent outputs we could spend",
+						txid
+					);
+					Error::OnchainTxCreationFailed
+				})?;
+
+			// The child needs to pay for the parent as well as for any of its unconfirmed
+			// ancestors, taking into account the fees they already pay. If our chain source
+			// doesn't tell us about them, we consider the ancestors known to our wallet. If we
+			// can't determine the fee of any of them, e.g., as we don't know all of its inputs, we
+			// refuse to guess as we might otherwise vastly overpay.
+			let (ancestors_fee, ancestors_weight) = match mempool_package.as_ref() {
+				Some(package) => (package.ancestors_fee, package.ancestors_weight),
+				None => self.unconfirmed_ancestors_fee_and_weight(&locked_wallet, &parent_tx)?,
+			};
+
+			let drain_script =
+				locked_wallet.next_unused_address(KeychainKind::Internal).script_pubkey();
+
+			// First build the child at the target fee rate to learn about its weight.
+			let child_weight = {
+				let psbt = self.build_and_sign_cpfp_psbt(
+					&mut locked_wallet,
+					utxo.outpoint,
+					drain_script.clone(),
+					fee_rate,
+					None,
+				)?;
+				let child_tx = psbt.extract_tx().map_err(|e| {
+					log_error!(self.logger, "Failed to extract transaction: {}", e);
+					e
+				})?;
+				locked_wallet.cancel_tx(&child_tx);
+				child_tx.weight()
+			};
+
+			let package_fee =
+				fee_rate.fee_wu(ancestors_weight + child_weight).ok_or_else(|| {
+					log_error!(self.logger, "Failed to calculate package fee due to overflow");
+					Error::OnchainTxCreationFailed
+				})?;
+			let min_child_fee = fee_rate.fee_wu(child_weight).unwrap_or(Amount::ZERO);
+			let child_fee =
+				package_fee.checked_sub(ancestors_fee).unwrap_or(Amount::ZERO).max(min_child_fee);
+
+			let psbt = self.build_and_sign_cpfp_psbt(
+				&mut locked_wallet,
+				utxo.outpoint,
+				drain_script,
+				fee_rate,
+				Some(child_fee),
+			)?;
+
+			let tx = psbt.extract_tx().map_err(|e| {
 				log_error!(self.logger, "Failed to extract transaction: {}", e);
 				e
-			})?
+			})?;
+
+			// The child's fee is paid from our funds, so make sure it doesn't cut into the Anchor
+			// channel reserve. The output we spend only counts towards our spendable funds if
+			// it's trusted, i.e., if it's one of our change outputs.
+			let (_, spendable_amount_sats) =
+				self.compute_balances(&locked_wallet, total_anchor_channels_reserve_sats);
+			let available_amount_sats = if utxo.keychain == KeychainKind::External {
+				spendable_amount_sats.saturating_add(utxo.txout.value.to_sat())
+			} else {
+				spendable_amount_sats
+			};
+			if available_amount_sats < child_fee.to_sat() {
+				log_error!(self.logger,
+					"Unable to bump fee due to insufficient funds. Available: {}sats, Required: {}sats",
+					available_amount_sats, child_fee.to_sat()
+				);
+				locked_wallet.cancel_tx(&tx);
+				return Err(Error::InsufficientFunds);
+			}
+
+			let now = SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.unwrap_or(Duration::from_secs(0))
+				.as_secs();
+			locked_wallet.apply_unconfirmed_txs([(&tx, now)]);
+
+			let mut locked_persister = self.persister.lock().unwrap();
+			locked_wallet.persist(&mut locked_persister).map_err(|e| {
+				log_error!(self.logger, "Failed to persist wallet: {}", e);
+				Error::PersistenceFailed
+			})?;
+
+			self.update_payment_store(&locked_wallet).unwrap_or_else(|e| {
+				log_error!(self.logger, "Failed to update payment store: {}", e);
+			});
+
+			tx
 		};
 
 		self.broadcaster.broadcast_transactions(&[&tx]);
 
-		let txid = tx.compute_txid();
+		let child_txid = tx.compute_txid();
+		log_info!(
+			self.logger,
+			"Created child transaction {} to bump the fee of transaction {} to {} sat/kwu",
+			child_txid,
+			txid,
+			fee_rate.to_sat_per_kwu()
+		);
 
-		if let Some(amount) = amount_or_drain {
-			log_info!(
-				self.logger,
-				"Created new transaction {} sending {}sats on-chain to address {}",
-				txid,
-				amount.to_sat(),
-				address
-			);
+		Ok(child_txid)
+	}
+
+	// Returns the aggregate fee and weight of the given unconfirmed transaction and all of its
+	// unconfirmed ancestors known to our wallet.
+	fn unconfirmed_ancestors_fee_and_weight(
+		&self, locked_wallet: &PersistedWallet<KVStoreWalletPersister>, tx: &Arc<Transaction>,
+	) -> Result<(Amount, Weight), Error> {
+		let mut ancestors_fee = Amount::ZERO;
+		let mut ancestors_weight = Weight::ZERO;
+		let mut visited_txids = HashSet::new();
+		let mut to_visit = vec![Arc::clone(tx)];
+		while let Some(ancestor_tx) = to_visit.pop() {
+			let ancestor_txid = ancestor_tx.compute_txid();
+			if !visited_txids.insert(ancestor_txid) {
+				continue;
+			}
+
+			let fee = locked_wallet.calculate_fee(&ancestor_tx).map_err(|e| {
+				log_error!(
+					self.logger,
+					"Failed to determine fee of unconfirmed transaction {}: {}",
+					ancestor_txid,
+					e
+				);
+				Error::OnchainTxCreationFailed
+			})?;
+			ancestors_fee += fee;
+			ancestors_weight += ancestor_tx.weight();
+
+			for input in &ancestor_tx.input {
+				if let Some(wallet_tx) = locked_wallet.get_tx(input.previous_output.txid) {
+					if !wallet_tx.chain_position.is_confirmed() {
+						to_visit.push(Arc::clone(&wallet_tx.tx_node.tx));
+					}
+				}
+			}
+		}
+		Ok((ancestors_fee, ancestors_weight))
+	}
+
+	fn build_and_sign_cpfp_psbt(
+		&self, locked_wallet: &mut PersistedWallet<KVStoreWalletPersister>, outpoint: OutPoint,
+		drain_script: ScriptBuf, fee_rate: FeeRate, absolute_fee: Option<Amount>,
+	) -> Result<Psbt, Error> {
+		let mut tx_builder = locked_wallet.build_tx();
+
+		tx_builder
+			.add_utxo(outpoint)
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to add output {} to transaction: {}", outpoint, e);
+				Error::OnchainTxCreationFailed
+			})?
+			.manually_selected_only()
+			.drain_to(drain_script)
+			.enable_rbf();
+
+		if let Some(absolute_fee) = absolute_fee {
+			tx_builder.fee_absolute(absolute_fee);
 		} else {
-			log_info!(
-				self.logger,
-				"Created new transaction {} sending all available on-chain funds to address {}",
-				txid,
-				address
-			);
+			tx_builder.fee_rate(fee_rate);
 		}
 
-		Ok(txid)
+		let mut psbt = match tx_builder.finish() {
+			Ok(psbt) => {
+				log_trace!(self.logger, "Created CPFP PSBT: {:?}", psbt);
+				psbt
+			},
+			Err(err) => {
+				log_error!(self.logger, "Failed to create CPFP transaction: {}", err);
+				return Err(err.into());
+			},
+		};
+
+		match locked_wallet.sign(&mut psbt, SignOptions::default()) {
+			Ok(finalized) => {
+				if !finalized {
+					return Err(Error::OnchainTxCreationFailed);
+				}
+			},
+			Err(err) => {
+				log_error!(self.logger, "Failed to create CPFP transaction: {}", err);
+				return Err(err.into());
+			},
+		}
+
+		Ok(psbt)
 	}
 
-	fn register_or_subscribe_pending_sync(
-		&self,
-	) -> Option<tokio::sync::broadcast::Receiver<Result<(), Error>>> {
-		let mut sync_status_lock = self.sync_status.lock().unwrap();
-		match sync_status_lock.deref_mut() {
-			WalletSyncStatus::Completed => {
-				// We're first to register for a sync.
-				let (tx, _) = tokio::sync::broadcast::channel(1);
-				*sync_status_lock = WalletSyncStatus::InProgress { subscribers: tx };
-				None
+	fn apply_utxo_selection<Cs>(
+		&self, tx_builder: &mut TxBuilder<'_, Cs>, utxo_selection: Option<&UtxoSelection>,
+	) -> Result<(), Error> {
+		// Locked UTXOs must never be spent.
+		for outpoint in self.locked_utxo_store.list_locked_utxos() {
+			tx_builder.add_unspendable(outpoint);
+		}
+
+		match utxo_selection {
+			Some(UtxoSelection::SpendOnly { utxos }) => {
+				if let Some(outpoint) = utxos.iter().find(|o| self.locked_utxo_store.is_locked(o)) {
+					log_error!(self.logger, "Failed to spend UTXO {} as it's locked", outpoint);
+					return Err(Error::UtxoLocked);
+				}
+
+				tx_builder
+					.add_utxos(utxos)
+					.map_err(|e| {
+						log_error!(
+							self.logger,
+							"Failed to add selected UTXOs to transaction: {}",
+							e
+						);
+						Error::UtxoNotFound
+					})?
+					.manually_selected_only();
 			},
-			WalletSyncStatus::InProgress { subscribers } => {
-				// A sync is in-progress, we subscribe.
-				let rx = subscribers.subscribe();
-				Some(rx)
+			Some(UtxoSelection::Avoid { utxos }) => {
+				for outpoint in utxos {
+					tx_builder.add_unspendable(*outpoint);
+				}
 			},
+			None => {},
 		}
+		Ok(())
 	}
 
-	fn propagate_result_to_subscribers(&self, res: Result<(), Error>) {
-		// Send the notification to any other tasks that might be waiting on it by now.
-		{
-			let mut sync_status_lock = self.sync_status.lock().unwrap();
-			match sync_status_lock.deref_mut() {
-				WalletSyncStatus::Completed => {
-					// No sync in-progress, do nothing.
-					return;
+	fn update_payment_store(
+		&self, locked_wallet: &PersistedWallet<KVStoreWalletPersister>,
+	) -> Result<(), Error> {
+		let cur_height = locked_wallet.latest_checkpoint().height();
+		let network = locked_wallet.network();
+		let mut canonical_txids = HashSet::new();
+
+		for wtx in locked_wallet.transactions() {
+			let txid = wtx.tx_node.txid;
+			canonical_txids.insert(txid);
+
+			let id = PaymentId(txid.to_byte_array());
+
+			let (payment_status, confirmation_status) = match wtx.chain_position {
+				ChainPosition::Confirmed(anchor) => {
+					let confirmation_height = anchor.block_id.height;
+					let payment_status = if cur_height >= confirmation_height + ANTI_REORG_DELAY - 1
+					{
+						PaymentStatus::Succeeded
+					} else {
+						PaymentStatus::Pending
+					};
+					let confirmation_status = ConfirmationStatus::Confirmed {
+						block_hash: anchor.block_id.hash,
+						height: confirmation_height,
+						timestamp: anchor.confirmation_time,
+					};
+					(payment_status, confirmation_status)
+				},
+				ChainPosition::Unconfirmed(_) => {
+					(PaymentStatus::Pending, ConfirmationStatus::Unconfirmed)
 				},
-				WalletSyncStatus::InProgress { subscribers } => {
-					// A sync is in-progress, we notify subscribers.
-					if subscribers.receiver_count() > 0 {
-						match subscribers.send(res) {
-							Ok(_) => (),
-							Err(e) => {
-								debug_assert!(
-									false,
-									"Failed to send wallet sync result to subscribers: {:?}",
-									e
-								);
-								log_error!(
-									self.logger,
-									"Failed to send wallet sync result to subscribers: {:?}",
-									e
-								);
-							},
-						}
+			};
+
+			let tx = &wtx.tx_node.tx;
+			let (sent, received) = locked_wallet.sent_and_received(tx);
+			let (direction, amount, fee, counterparty_address) = if sent > received {
+				// We funded the transaction, so we paid the fee and sent the remainder to the
+				// first output that isn't ours.
+				let fee = locked_wallet.calculate_fee(tx).ok();
+				let amount = (sent - received).checked_sub(fee.unwrap_or(Amount::ZERO));
+				let counterparty_address = tx
+					.output
+					.iter()
+					.find(|o| !locked_wallet.is_mine(o.script_pubkey.clone()))
+					.and_then(|o| bitcoin::Address::from_script(&o.script_pubkey, network).ok())
+					.map(|a| a.to_string());
+				(PaymentDirection::Outbound, amount, fee, counterparty_address)
+			} else {
+				(PaymentDirection::Inbound, Some(received - sent), None, None)
+			};
+
+			let existing_payment = self.payment_store.get(&id);
+			let replaced_by = existing_payment.as_ref().and_then(|p| match p.kind {
+				PaymentKind::Onchain { replaced_by, .. } => replaced_by,
+				_ => None,
+			});
+			let kind = PaymentKind::Onchain {
+				txid,
+				status: confirmation_status,
+				counterparty_address,
+				replaced_by,
+			};
+			let amount_msat = amount.map(|a| a.to_sat() * 1000);
+			let mut payment = PaymentDetails::new(id, kind, amount_msat, direction, payment_status);
+			payment.fee_paid_msat = fee.map(|f| f.to_sat() * 1000);
+
+			if let Some(existing_payment) = existing_payment {
+				let mut unchanged_payment = payment.clone();
+				unchanged_payment.latest_update_timestamp =
+					existing_payment.latest_update_timestamp;
+				if unchanged_payment == existing_payment {
+					continue;
+				}
+			}
+
+			self.payment_store.insert(payment)?;
+		}
+
+		// Any pending transactions that aren't part of the wallet's canonical history anymore were
+		// replaced or otherwise conflicted and won't ever confirm.
+		let evicted_payments = self.payment_store.list_filter(|p| match p.kind {
+			PaymentKind::Onchain { txid, .. } => {
+				p.status == PaymentStatus::Pending && !canonical_txids.contains(&txid)
+			},
+			_ => false,
+		});
+
+		for payment in evicted_payments {
+			let status = match payment.kind {
+				PaymentKind::Onchain { replaced_by: Some(replacement_txid), .. } => {
+					// We replaced the transaction ourselves, so the payment is resolved once its
+					// (latest) replacement is.
+					let status = self.replacement_status(replacement_txid, &canonical_txids);
+					if status == PaymentStatus::Pending {
+						continue;
 					}
-					*sync_status_lock = WalletSyncStatus::Completed;
+					log_debug!(
+						self.logger,
+						"On-chain payment {} was replaced by {}",
+						hex_utils::to_string(&payment.id.0),
+						replacement_txid
+					);
+					status
 				},
+				_ => {
+					log_debug!(
+						self.logger,
+						"On-chain payment {} was conflicted",
+						hex_utils::to_string(&payment.id.0)
+					);
+					PaymentStatus::Failed
+				},
+			};
+			let update = PaymentDetailsUpdate {
+				status: Some(status),
+				..PaymentDetailsUpdate::new(payment.id)
+			};
+			self.payment_store.update(&update)?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns the status of the payment that replaced an on-chain payment, following any further
+	/// replacements of evicted transactions.
+	fn replacement_status(
+		&self, replacement_txid: Txid, canonical_txids: &HashSet<Txid>,
+	) -> PaymentStatus {
+		let mut txid = replacement_txid;
+		let mut visited_txids = HashSet::new();
+		while visited_txids.insert(txid) {
+			match self.payment_store.get(&PaymentId(txid.to_byte_array())) {
+				Some(PaymentDetails {
+					kind: PaymentKind::Onchain { replaced_by: Some(next_txid), .. },
+					..
+				}) if !canonical_txids.contains(&txid) => txid = next_txid,
+				Some(payment) => return payment.status,
+				None => break,
 			}
 		}
+		PaymentStatus::Pending
+	}
+
+	fn build_and_sign_send_psbt(
+		&self, locked_wallet: &mut PersistedWallet<KVStoreWalletPersister>,
+		address: &bitcoin::Address, amount_or_drain: Option<Amount>, fee_rate: FeeRate,
+		utxo_selection: Option<&UtxoSelection>,
+	) -> Result<Psbt, Error> {
+		let mut psbt = self.build_send_psbt(
+			locked_wallet,
+			address,
+			amount_or_drain,
+			fee_rate,
+			utxo_selection,
+		)?;
+
+		match locked_wallet.sign(&mut psbt, SignOptions::default()) {
+			Ok(finalized) => {
+				if !finalized {
+					return Err(Error::OnchainTxCreationFailed);
+				}
+			},
+			Err(err) => {
+				log_error!(self.logger, "Failed to create transaction: {}", err);
+				return Err(err.into());
+			},
+		}
+
+		Ok(psbt)
+	}
+
+	fn build_send_psbt(
+		&self, locked_wallet: &mut PersistedWallet<KVStoreWalletPersister>,
+		address: &bitcoin::Address, amount_or_drain: Option<Amount>, fee_rate: FeeRate,
+		utxo_selection: Option<&UtxoSelection>,
+	) -> Result<Psbt, Error> {
+		let mut tx_builder = locked_wallet.build_tx();
+
+		self.apply_utxo_selection(&mut tx_builder, utxo_selection)?;
+
+		if let Some(amount) = amount_or_drain {
+			tx_builder
+				.add_recipient(address.script_pubkey(), amount)
+				.fee_rate(fee_rate)
+				.enable_rbf();
+		} else {
+			// If we were asked to spend specific UTXOs, we only drain these.
+			if !matches!(utxo_selection, Some(UtxoSelection::SpendOnly { .. })) {
+				tx_builder.drain_wallet();
+			}
+			tx_builder.drain_to(address.script_pubkey()).fee_rate(fee_rate).enable_rbf();
+		}
+
+		match tx_builder.finish() {
+			Ok(psbt) => {
+				log_trace!(self.logger, "Created PSBT: {:?}", psbt);
+				Ok(psbt)
+			},
+			Err(err) => {
+				log_error!(self.logger, "Failed to create transaction: {}", err);
+				Err(err.into())
+			},
+		}
+	}
+
+	// Returns an upper bound for the weight the given unsigned transaction spending our UTXOs
+	// will have once it's signed.
+	fn estimate_signed_weight(
+		&self, locked_wallet: &PersistedWallet<KVStoreWalletPersister>, unsigned_tx: &Transaction,
+	) -> Result<Weight, Error> {
+		// Account for the segwit marker and flag, as well as for each input's witness item count.
+		let mut weight = unsigned_tx.weight() + Weight::from_wu(2 + unsigned_tx.input.len() as u64);
+		for input in &unsigned_tx.input {
+			let utxo = locked_wallet.get_utxo(input.previous_output).ok_or_else(|| {
+				log_error!(self.logger, "Failed to find UTXO {}", input.previous_output);
+				Error::OnchainTxCreationFailed
+			})?;
+			let satisfaction_weight = locked_wallet
+				.public_descriptor(utxo.keychain)
+				.max_weight_to_satisfy()
+				.map_err(|e| {
+					log_error!(self.logger, "Failed to determine satisfaction weight: {}", e);
+					Error::OnchainTxCreationFailed
+				})?;
+			weight += satisfaction_weight;
+		}
+		Ok(weight)
+	}
+}
+
+impl<B: Deref, E: Deref, L: Deref> Listen for Wallet<B, E, L>
+where
+	B::Target: BroadcasterInterface,
+	E::Target: FeeEstimator,
+	L::Target: Logger,
+{
+	fn filtered_block_connected(
+		&self, header: &bitcoin::block::Header,
+		txdata: &lightning::chain::transaction::TransactionData, height: u32,
+	) {
+		// BDK only indexes the transactions it's given and doesn't check them against the
+		// header's merkle root, so we can simply hand it a block made up of the filtered
+		// transactions. If none of them are relevant, this merely advances the local chain.
+		let block = bitcoin::Block {
+			header: *header,
+			txdata: txdata.iter().map(|(_, tx)| (*tx).clone()).collect(),
+		};
+		self.block_connected(&block, height);
+	}
+
+	fn block_connected(&self, block: &bitcoin::Block, height: u32) {
+		let mut locked_wallet = self.inner.lock().unwrap();
+
+		let pre_checkpoint = locked_wallet.latest_checkpoint();
+		if pre_checkpoint.height() != height - 1
+			|| pre_checkpoint.hash() != block.header.prev_blockhash
+		{
+			log_debug!(
+				self.logger,
+				"Detected reorg while applying a connected block to on-chain wallet: new block with hash {} at height {}",
+				block.header.block_hash(),
+				height
+			);
+		}
+
+		match locked_wallet.apply_block(block, height) {
+			Ok(()) => (),
+			Err(e) => {
+				log_error!(
+					self.logger,
+					"Failed to apply connected block to on-chain wallet: {}",
+					e
+				);
+				return;
+			},
+		};
+
+		let mut locked_persister = self.persister.lock().unwrap();
+		match locked_wallet.persist(&mut locked_persister) {
+			Ok(_) => (),
+			Err(e) => {
+				log_error!(self.logger, "Failed to persist on-chain wallet: {}", e);
+				return;
+			},
+		};
+
+		self.update_payment_store(&locked_wallet).unwrap_or_else(|e| {
+			log_error!(self.logger, "Failed to update payment store: {}", e);
+		});
+	}
+
+	fn block_disconnected(&self, _header: &bitcoin::block::Header, _height: u32) {
+		// This is a no-op as we don't have to tell BDK about disconnections. According to the BDK
+		// team, it's sufficient in case of a reorg to always connect blocks starting from the last
+		// point of disagreement.
 	}
 }
 
@@ -412,8 +1440,10 @@ where
 			.filter(|t| matches!(t.chain_position, ChainPosition::Confirmed(_)))
 			.map(|t| t.tx_node.txid)
 			.collect();
-		let unspent_confirmed_utxos =
-			locked_wallet.list_unspent().filter(|u| confirmed_txs.contains(&u.outpoint.txid));
+		let unspent_confirmed_utxos = locked_wallet.list_unspent().filter(|u| {
+			confirmed_txs.contains(&u.outpoint.txid)
+				&& !self.locked_utxo_store.is_locked(&u.outpoint)
+		});
 
 		for u in unspent_confirmed_utxos {
 			let script_pubkey = u.txout.script_pubkey;
@@ -8,22 +8,29 @@
 #![cfg(any(test, cln_test, vss_test))]
 #![allow(dead_code)]
 
+use ldk_node::bdk_chain::spk_client::{FullScanRequest, FullScanResult, SyncRequest, SyncResult};
 use ldk_node::io::sqlite_store::SqliteStore;
 use ldk_node::payment::{PaymentDirection, PaymentKind, PaymentStatus};
 use ldk_node::{
-	Builder, Config, Event, LightningBalance, LogLevel, Node, NodeError, PendingSweepBalance,
+	Builder, Config, CustomChainSource, Event, KeychainKind, LightningBalance, LogLevel, Node,
+	NodeError, PendingSweepBalance,
 };
 
+use lightning::chain::{BestBlock, Confirm, Filter, WatchedOutput};
+
 use lightning::ln::msgs::SocketAddress;
 use lightning::ln::{PaymentHash, PaymentPreimage};
 use lightning::routing::gossip::NodeAlias;
 use lightning::util::persist::KVStore;
-use lightning::util::test_utils::TestStore;
+use lightning::util::test_utils::{TestLogger, TestStore};
 use lightning_persister::fs_store::FilesystemStore;
+use lightning_transaction_sync::ElectrumSyncClient;
+
+use bdk_electrum::BdkElectrumClient;
 
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::Hash;
-use bitcoin::{Address, Amount, Network, OutPoint, Txid};
+use bitcoin::{Address, Amount, FeeRate, Network, OutPoint, Script, Transaction, Txid};
 
 use bitcoincore_rpc::bitcoincore_rpc_json::AddressType;
 use bitcoincore_rpc::Client as BitcoindClient;
@@ -162,8 +169,16 @@ pub(crate) fn setup_bitcoind_and_electrsd() -> (BitcoinD, ElectrsD) {
 		);
 	let mut bitcoind_conf = bitcoind::Conf::default();
 	bitcoind_conf.network = "regtest";
+	// Serve compact block filters via P2P so we can also test sourcing chain data via BIP 157.
+	bitcoind_conf.args.push("-blockfilterindex=1");
+	bitcoind_conf.args.push("-peerblockfilters=1");
+	bitcoind_conf.p2p = bitcoind::P2P::Yes;
 	let bitcoind = BitcoinD::with_conf(bitcoind_exe, &bitcoind_conf).unwrap();
+	let electrsd = setup_electrsd(&bitcoind);
+	(bitcoind, electrsd)
+}
 
+pub(crate) fn setup_electrsd(bitcoind: &BitcoinD) -> ElectrsD {
 	let electrs_exe = env::var("ELECTRS_EXE")
 		.ok()
 		.or_else(electrsd::downloaded_exe_path)
@@ -171,8 +186,7 @@ pub(crate) fn setup_bitcoind_and_electrsd() -> (BitcoinD, ElectrsD) {
 	let mut electrsd_conf = electrsd::Conf::default();
 	electrsd_conf.http_enabled = true;
 	electrsd_conf.network = "regtest";
-	let electrsd = ElectrsD::with_conf(electrs_exe, &bitcoind, &electrsd_conf).unwrap();
-	(bitcoind, electrsd)
+	ElectrsD::with_conf(electrs_exe, bitcoind, &electrsd_conf).unwrap()
 }
 
 pub(crate) fn random_storage_path() -> PathBuf {
@@ -255,12 +269,90 @@ macro_rules! setup_builder {
 
 pub(crate) use setup_builder;
 
+pub(crate) enum TestChainSource<'a> {
+	Esplora(&'a ElectrsD),
+	Electrum(&'a ElectrsD),
+	BitcoindRpc(&'a BitcoinD),
+	CompactBlockFilters(&'a BitcoinD),
+	Custom(&'a ElectrsD),
+}
+
+/// A [`CustomChainSource`] retrieving chain data from an Electrum server.
+pub(crate) struct TestCustomChainSource {
+	electrum_client: electrum_client::Client,
+	bdk_electrum_client: BdkElectrumClient<electrum_client::Client>,
+	tx_sync: ElectrumSyncClient<Arc<TestLogger>>,
+}
+
+impl TestCustomChainSource {
+	pub(crate) fn new(electrum_url: &str) -> Self {
+		let electrum_client = electrum_client::Client::new(electrum_url).unwrap();
+		let bdk_electrum_client =
+			BdkElectrumClient::new(electrum_client::Client::new(electrum_url).unwrap());
+		let tx_sync =
+			ElectrumSyncClient::new(electrum_url.to_string(), Arc::new(TestLogger::new())).unwrap();
+		Self { electrum_client, bdk_electrum_client, tx_sync }
+	}
+}
+
+impl Filter for TestCustomChainSource {
+	fn register_tx(&self, txid: &Txid, script_pubkey: &Script) {
+		self.tx_sync.register_tx(txid, script_pubkey)
+	}
+	fn register_output(&self, output: WatchedOutput) {
+		self.tx_sync.register_output(output)
+	}
+}
+
+impl CustomChainSource for TestCustomChainSource {
+	fn full_scan_wallet(
+		&self, request: FullScanRequest<KeychainKind>, stop_gap: usize,
+	) -> Result<FullScanResult<KeychainKind>, NodeError> {
+		self.bdk_electrum_client
+			.full_scan(request, stop_gap, 5, true)
+			.map_err(|_| NodeError::WalletOperationFailed)
+	}
+
+	fn sync_wallet(
+		&self, request: SyncRequest<(KeychainKind, u32)>,
+	) -> Result<SyncResult, NodeError> {
+		self.bdk_electrum_client
+			.sync(request, 5, true)
+			.map_err(|_| NodeError::WalletOperationFailed)
+	}
+
+	fn sync_confirmables(
+		&self, confirmables: Vec<Arc<dyn Confirm + Sync + Send>>,
+	) -> Result<(), NodeError> {
+		self.tx_sync.sync(confirmables).map_err(|_| NodeError::TxSyncFailed)
+	}
+
+	fn get_best_block(&self) -> Result<BestBlock, NodeError> {
+		let notification =
+			self.electrum_client.block_headers_subscribe().map_err(|_| NodeError::TxSyncFailed)?;
+		Ok(BestBlock::new(notification.header.block_hash(), notification.height as u32))
+	}
+
+	fn estimate_fee_rate(&self, _num_blocks: usize) -> Result<Option<FeeRate>, NodeError> {
+		// We don't get any useful estimates on regtest, so just stick with the defaults.
+		Ok(None)
+	}
+
+	fn broadcast_transaction(&self, tx: &Transaction) -> Result<(), NodeError> {
+		self.electrum_client
+			.transaction_broadcast(tx)
+			.map(|_| ())
+			.map_err(|_| NodeError::ConnectionFailed)
+	}
+}
+
 pub(crate) fn setup_two_nodes(
-	electrsd: &ElectrsD, allow_0conf: bool, anchor_channels: bool, anchors_trusted_no_reserve: bool,
+	chain_source: &TestChainSource, allow_0conf: bool, anchor_channels: bool,
+	anchors_trusted_no_reserve: bool,
 ) -> (TestNode, TestNode) {
 	println!("== Node A ==");
 	let config_a = random_config(anchor_channels);
-	let node_a = setup_node(electrsd, config_a);
+	let node_a = setup_node(chain_source, config_a);
 
 	println!("\n== Node B ==");
 	let mut config_b = random_config(anchor_channels);
@@ -275,19 +367,50 @@ pub(crate) fn setup_two_nodes(
 			.trusted_peers_no_reserve
 			.push(node_a.node_id());
 	}
-	let node_b = setup_node(electrsd, config_b);
+	let node_b = setup_node(chain_source, config_b);
 	(node_a, node_b)
 }
 
-pub(crate) fn setup_node(electrsd: &ElectrsD, config: Config) -> TestNode {
-	let esplora_url = format!("http://{}", electrsd.esplora_url.as_ref().unwrap());
+pub(crate) fn setup_node(chain_source: &TestChainSource, config: Config) -> TestNode {
 	setup_builder!(builder, config);
-	builder.set_esplora_server(esplora_url.clone());
+	match chain_source {
+		TestChainSource::Esplora(electrsd) => {
+			let esplora_url = format!("http://{}", electrsd.esplora_url.as_ref().unwrap());
+			builder.set_esplora_server(esplora_url.clone());
+		},
+		TestChainSource::Electrum(electrsd) => {
+			let electrum_url = format!("tcp://{}", electrsd.electrum_url);
+			builder.set_chain_source_electrum(electrum_url.clone());
+		},
+		TestChainSource::BitcoindRpc(bitcoind) => {
+			let rpc_host = bitcoind.params.rpc_socket.ip().to_string();
+			let rpc_port = bitcoind.params.rpc_socket.port();
+			let values = bitcoind.params.get_cookie_values().unwrap().unwrap();
+			let rpc_user = values.user;
+			let rpc_password = values.password;
+			builder.set_chain_source_bitcoind_rpc(rpc_host, rpc_port, rpc_user, rpc_password);
+		},
+		TestChainSource::CompactBlockFilters(bitcoind) => {
+			let p2p_socket = bitcoind.params.p2p_socket.unwrap();
+			let peer =
+				SocketAddress::TcpIpV4 { addr: p2p_socket.ip().octets(), port: p2p_socket.port() };
+			builder.set_chain_source_compact_block_filters(vec![peer]);
+		},
+		TestChainSource::Custom(electrsd) => {
+			let electrum_url = format!("tcp://{}", electrsd.electrum_url);
+			let custom_source = Arc::new(TestCustomChainSource::new(&electrum_url));
+			builder.set_chain_source_custom(custom_source);
+		},
+	}
+
 	let test_sync_store = Arc::new(TestSyncStore::new(config.storage_dir_path.into()));
 	let node = builder.build_with_store(test_sync_store).unwrap();
 	node.start().unwrap();
 	assert!(node.status().is_running);
-	assert!(node.status().latest_fee_rate_cache_update_timestamp.is_some());
+	if !matches!(chain_source, TestChainSource::CompactBlockFilters(_)) {
+		// BIP 157 peers don't provide fee rate estimates.
+		assert!(node.status().latest_fee_rate_cache_update_timestamp.is_some());
+	}
 	node
 }
 
@@ -410,6 +533,8 @@ pub fn open_channel(
 				funding_amount_sat,
 				None,
 				None,
+				None,
+				None,
 			)
 			.unwrap();
 	} else {
@@ -420,6 +545,8 @@ pub fn open_channel(
 				funding_amount_sat,
 				None,
 				None,
+				None,
+				None,
 			)
 			.unwrap();
 	}
@@ -465,6 +592,8 @@ pub(crate) fn do_channel_full_cycle<E: ElectrumApi>(
 			funding_amount_sat,
 			Some(push_msat),
 			None,
+			None,
+			None,
 		)
 		.unwrap();
 
@@ -527,22 +656,28 @@ pub(crate) fn do_channel_full_cycle<E: ElectrumApi>(
 	let payment_id = node_a.bolt11_payment().send(&invoice, None).unwrap();
 	assert_eq!(node_a.bolt11_payment().send(&invoice, None), Err(NodeError::DuplicatePayment));
 
-	assert_eq!(node_a.list_payments().first().unwrap().id, payment_id);
+	let bolt11_payments_a =
+		node_a.list_payments_with_filter(|p| matches!(p.kind, PaymentKind::Bolt11 { .. }));
+	assert_eq!(bolt11_payments_a.first().unwrap().id, payment_id);
 
-	let outbound_payments_a =
-		node_a.list_payments_with_filter(|p| p.direction == PaymentDirection::Outbound);
+	let outbound_payments_a = node_a.list_payments_with_filter(|p| {
+		p.direction == PaymentDirection::Outbound && matches!(p.kind, PaymentKind::Bolt11 { .. })
+	});
 	assert_eq!(outbound_payments_a.len(), 1);
 
-	let inbound_payments_a =
-		node_a.list_payments_with_filter(|p| p.direction == PaymentDirection::Inbound);
+	let inbound_payments_a = node_a.list_payments_with_filter(|p| {
+		p.direction == PaymentDirection::Inbound && matches!(p.kind, PaymentKind::Bolt11 { .. })
+	});
 	assert_eq!(inbound_payments_a.len(), 0);
 
-	let outbound_payments_b =
-		node_b.list_payments_with_filter(|p| p.direction == PaymentDirection::Outbound);
+	let outbound_payments_b = node_b.list_payments_with_filter(|p| {
+		p.direction == PaymentDirection::Outbound && matches!(p.kind, PaymentKind::Bolt11 { .. })
+	});
 	assert_eq!(outbound_payments_b.len(), 0);
 
-	let inbound_payments_b =
-		node_b.list_payments_with_filter(|p| p.direction == PaymentDirection::Inbound);
+	let inbound_payments_b = node_b.list_payments_with_filter(|p| {
+		p.direction == PaymentDirection::Inbound && matches!(p.kind, PaymentKind::Bolt11 { .. })
+	});
 	assert_eq!(inbound_payments_b.len(), 1);
 
 	expect_event!(node_a, PaymentSuccessful);
@@ -753,8 +888,14 @@ pub(crate) fn do_channel_full_cycle<E: ElectrumApi>(
 		node_b.payment(&keysend_payment_id).unwrap().kind,
 		PaymentKind::Spontaneous { .. }
 	));
-	assert_eq!(node_a.list_payments().len(), 6);
-	assert_eq!(node_b.list_payments().len(), 7);
+	assert_eq!(
+		node_a.list_payments_with_filter(|p| !matches!(p.kind, PaymentKind::Onchain { .. })).len(),
+		6
+	);
+	assert_eq!(
+		node_b.list_payments_with_filter(|p| !matches!(p.kind, PaymentKind::Onchain { .. })).len(),
+		7
+	);
 
 	println!("\nB close_channel (force: {})", force_close);
 	if force_close {
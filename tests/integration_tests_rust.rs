@@ -8,61 +8,81 @@
 mod common;
 
 use common::{
-	do_channel_full_cycle, expect_channel_ready_event, expect_event, expect_payment_received_event,
-	expect_payment_successful_event, generate_blocks_and_wait, open_channel,
-	premine_and_distribute_funds, random_config, setup_bitcoind_and_electrsd, setup_builder,
-	setup_node, setup_two_nodes, wait_for_tx, TestSyncStore,
+	do_channel_full_cycle, expect_channel_pending_event, expect_channel_ready_event, expect_event,
+	expect_payment_received_event, expect_payment_successful_event, generate_blocks_and_wait,
+	open_channel, premine_and_distribute_funds, random_config, setup_bitcoind_and_electrsd,
+	setup_builder, setup_electrsd, setup_node, setup_two_nodes, wait_for_outpoint_spend,
+	wait_for_tx, TestChainSource, TestSyncStore,
 };
 
-use ldk_node::payment::{PaymentKind, QrPaymentResult, SendingParameters};
-use ldk_node::{Builder, Event, NodeError};
+use ldk_node::payment::{
+	ConfirmationStatus, PaymentDirection, PaymentKind, PaymentStatus, QrPaymentResult,
+	SendingParameters, UtxoSelection,
+};
+use ldk_node::{
+	Builder, ChannelCloseOptions, ChannelConfig, ChannelTypeFeature, Event, KeychainKind, LabelRef,
+	NodeError, WalletDescriptorTemplate,
+};
 
+use lightning::events::ClosureReason;
 use lightning::ln::channelmanager::PaymentId;
 use lightning::util::persist::KVStore;
 
-use bitcoin::{Amount, Network};
+use bitcoin::hashes::Hash;
+use bitcoin::{Address, Amount, FeeRate, Network, OutPoint, Txid};
+use bitcoincore_rpc::bitcoincore_rpc_json::{AddressType, CreateRawTransactionInput};
+use bitcoincore_rpc::RpcApi;
 
+use electrsd::{bitcoind::BitcoinD, ElectrsD};
+
+use std::collections::HashMap;
 use std::sync::Arc;
 
 #[test]
 fn channel_full_cycle() {
 	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, false, true, false);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
 	do_channel_full_cycle(node_a, node_b, &bitcoind.client, &electrsd.client, false, true, false);
 }
 
 #[test]
 fn channel_full_cycle_force_close() {
 	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, false, true, false);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
 	do_channel_full_cycle(node_a, node_b, &bitcoind.client, &electrsd.client, false, true, true);
 }
 
 #[test]
 fn channel_full_cycle_force_close_trusted_no_reserve() {
 	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, false, true, true);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, true);
 	do_channel_full_cycle(node_a, node_b, &bitcoind.client, &electrsd.client, false, true, true);
 }
 
 #[test]
 fn channel_full_cycle_0conf() {
 	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, true, true, false);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, true, true, false);
 	do_channel_full_cycle(node_a, node_b, &bitcoind.client, &electrsd.client, true, true, false)
 }
 
 #[test]
 fn channel_full_cycle_legacy_staticremotekey() {
 	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, false, false, false);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, false, false);
 	do_channel_full_cycle(node_a, node_b, &bitcoind.client, &electrsd.client, false, false, false);
 }
 
 #[test]
 fn channel_open_fails_when_funds_insufficient() {
 	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, false, true, false);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
 
 	let addr_a = node_a.onchain_payment().new_address().unwrap();
 	let addr_b = node_b.onchain_payment().new_address().unwrap();
@@ -89,8 +109,160 @@ fn channel_open_fails_when_funds_insufficient() {
 			120000,
 			None,
 			None,
+			None,
+			None,
+		)
+	);
+}
+
+#[test]
+fn inbound_channel_policy() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let node_a = setup_node(&chain_source, random_config(true));
+
+	let mut config_b = random_config(true);
+	config_b.inbound_channel_policy.min_funding_sats = Some(150_000);
+	config_b.inbound_channel_policy.max_channels_per_peer = Some(1);
+	let node_b = setup_node(&chain_source, config_b);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+	let premine_amount_sat = 1_000_000;
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_a, addr_b],
+		Amount::from_sat(premine_amount_sat),
+	);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	let open_channel_to_b = |funding_amount_sat: u64| {
+		node_a
+			.open_channel(
+				node_b.node_id(),
+				node_b.listening_addresses().unwrap().first().unwrap().clone(),
+				funding_amount_sat,
+				None,
+				None,
+				None,
+				None,
+			)
+			.unwrap();
+	};
+
+	// Channels below the minimum funding amount are rejected.
+	open_channel_to_b(100_000);
+	expect_event!(node_a, ChannelClosed);
+	assert!(node_b.list_channels().is_empty());
+
+	// Channels satisfying the policy are accepted.
+	open_channel_to_b(200_000);
+	let funding_txo_a = expect_channel_pending_event!(node_a, node_b.node_id());
+	let funding_txo_b = expect_channel_pending_event!(node_b, node_a.node_id());
+	assert_eq!(funding_txo_a, funding_txo_b);
+
+	// Channels exceeding the per-peer limit are rejected.
+	open_channel_to_b(200_000);
+	expect_event!(node_a, ChannelClosed);
+	assert_eq!(node_b.list_channels().len(), 1);
+}
+
+#[test]
+fn inbound_channel_manual_approval() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let node_a = setup_node(&chain_source, random_config(true));
+
+	let mut config_b = random_config(true);
+	config_b.inbound_channel_policy.require_manual_approval = true;
+	let node_b = setup_node(&chain_source, config_b);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+	let premine_amount_sat = 1_000_000;
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_a, addr_b],
+		Amount::from_sat(premine_amount_sat),
+	);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	let open_channel_to_b = || {
+		node_a
+			.open_channel(
+				node_b.node_id(),
+				node_b.listening_addresses().unwrap().first().unwrap().clone(),
+				200_000,
+				None,
+				None,
+				None,
+				None,
+			)
+			.unwrap();
+	};
+
+	let expect_channel_open_request = || match node_b.wait_next_event() {
+		Event::ChannelOpenRequest {
+			temporary_channel_id,
+			counterparty_node_id,
+			funding_sats,
+			channel_type,
+			is_announced,
+		} => {
+			assert_eq!(counterparty_node_id, node_a.node_id());
+			assert_eq!(funding_sats, 200_000);
+			assert!(channel_type.contains(&ChannelTypeFeature::AnchorsZeroFeeHtlcTx));
+			assert!(!is_announced);
+			node_b.event_handled();
+			temporary_channel_id
+		},
+		e => panic!("Unexpected event: {:?}", e),
+	};
+
+	// A rejected request has the counterparty close the channel.
+	open_channel_to_b();
+	let temporary_channel_id = expect_channel_open_request();
+	node_b.reject_inbound_channel(&temporary_channel_id, node_a.node_id()).unwrap();
+	expect_event!(node_a, ChannelClosed);
+	assert!(node_b.list_channels().is_empty());
+
+	// An accepted request proceeds as usual, using the given channel config.
+	open_channel_to_b();
+	let temporary_channel_id = expect_channel_open_request();
+	let mut channel_config = ChannelConfig::default();
+	channel_config.forwarding_fee_base_msat = 4242;
+	let user_channel_id = node_b
+		.accept_inbound_channel(
+			&temporary_channel_id,
+			node_a.node_id(),
+			Some(channel_config),
+			false,
 		)
+		.unwrap();
+	expect_channel_pending_event!(node_a, node_b.node_id());
+	expect_channel_pending_event!(node_b, node_a.node_id());
+
+	let channel_b = node_b.list_channels().into_iter().next().unwrap();
+	assert_eq!(channel_b.user_channel_id, user_channel_id);
+	assert_eq!(channel_b.config.forwarding_fee_base_msat, 4242);
+
+	// The request is checked again when accepting, so we can't accept it if we spent the funds
+	// required for the Anchor channel reserve in the meantime.
+	open_channel_to_b();
+	let temporary_channel_id = expect_channel_open_request();
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	node_b.onchain_payment().send_all_to_address(&addr_a, None, None).unwrap();
+	assert_eq!(
+		Err(NodeError::ChannelCreationFailed),
+		node_b.accept_inbound_channel(&temporary_channel_id, node_a.node_id(), None, false)
 	);
+	node_b.reject_inbound_channel(&temporary_channel_id, node_a.node_id()).unwrap();
+	expect_event!(node_a, ChannelClosed);
+	assert_eq!(node_b.list_channels().len(), 1);
 }
 
 #[test]
@@ -259,7 +431,8 @@ fn start_stop_reinit() {
 #[test]
 fn onchain_spend_receive() {
 	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, false, true, false);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
 
 	let addr_a = node_a.onchain_payment().new_address().unwrap();
 	let addr_b = node_b.onchain_payment().new_address().unwrap();
@@ -277,10 +450,28 @@ fn onchain_spend_receive() {
 
 	assert_eq!(
 		Err(NodeError::InsufficientFunds),
-		node_a.onchain_payment().send_to_address(&addr_b, 1000)
+		node_a.onchain_payment().send_to_address(&addr_b, 1000, None, None)
 	);
 
-	let txid = node_b.onchain_payment().send_to_address(&addr_a, 1000).unwrap();
+	let txid = node_b.onchain_payment().send_to_address(&addr_a, 1000, None, None).unwrap();
+
+	// The outbound payment is tracked right away.
+	let payment_id = PaymentId(txid.to_byte_array());
+	let outbound_payment = node_b.payment(&payment_id).unwrap();
+	assert_eq!(outbound_payment.direction, PaymentDirection::Outbound);
+	assert_eq!(outbound_payment.status, PaymentStatus::Pending);
+	assert_eq!(outbound_payment.amount_msat, Some(1000 * 1000));
+	assert!(outbound_payment.fee_paid_msat.unwrap() > 0);
+	match outbound_payment.kind {
+		PaymentKind::Onchain { txid: payment_txid, status, counterparty_address, replaced_by } => {
+			assert_eq!(payment_txid, txid);
+			assert_eq!(status, ConfirmationStatus::Unconfirmed);
+			assert_eq!(counterparty_address, Some(addr_a.to_string()));
+			assert_eq!(replaced_by, None);
+		},
+		_ => panic!("Unexpected payment kind"),
+	}
+
 	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
 	wait_for_tx(&electrsd.client, txid);
 
@@ -291,8 +482,20 @@ fn onchain_spend_receive() {
 	assert!(node_b.list_balances().spendable_onchain_balance_sats > 98000);
 	assert!(node_b.list_balances().spendable_onchain_balance_sats < 100000);
 
+	// After syncing, both sides see the payment as confirmed.
+	let inbound_payment = node_a.payment(&payment_id).unwrap();
+	assert_eq!(inbound_payment.direction, PaymentDirection::Inbound);
+	assert_eq!(inbound_payment.status, PaymentStatus::Succeeded);
+	assert_eq!(inbound_payment.amount_msat, Some(1000 * 1000));
+	assert_eq!(inbound_payment.fee_paid_msat, None);
+	assert!(matches!(
+		inbound_payment.kind,
+		PaymentKind::Onchain { status: ConfirmationStatus::Confirmed { .. }, .. }
+	));
+	assert_eq!(node_b.payment(&payment_id).unwrap().status, PaymentStatus::Succeeded);
+
 	let addr_b = node_b.onchain_payment().new_address().unwrap();
-	let txid = node_a.onchain_payment().send_all_to_address(&addr_b).unwrap();
+	let txid = node_a.onchain_payment().send_all_to_address(&addr_b, None, None).unwrap();
 	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
 	wait_for_tx(&electrsd.client, txid);
 
@@ -304,11 +507,1228 @@ fn onchain_spend_receive() {
 	assert!(node_b.list_balances().spendable_onchain_balance_sats < 100000);
 }
 
+#[test]
+fn onchain_send_with_custom_fee_rate() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_b.clone()],
+		Amount::from_sat(100000),
+	);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000);
+
+	// A higher fee rate results in a higher fee for the same transaction.
+	let cheap_fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+	let fast_fee_rate = FeeRate::from_sat_per_vb(20).unwrap();
+	let cheap_estimate = node_b
+		.onchain_payment()
+		.estimate_send_to_address_fee(&addr_a, 10000, Some(cheap_fee_rate), None)
+		.unwrap();
+	let fast_estimate = node_b
+		.onchain_payment()
+		.estimate_send_to_address_fee(&addr_a, 10000, Some(fast_fee_rate), None)
+		.unwrap();
+	assert_eq!(cheap_estimate.fee_rate, cheap_fee_rate);
+	assert_eq!(fast_estimate.fee_rate, fast_fee_rate);
+	assert!(cheap_estimate.vsize > 0);
+	assert!(fast_estimate.fee_sats > cheap_estimate.fee_sats);
+
+	// Previewing the fee doesn't spend anything.
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000);
+
+	let txid = node_b
+		.onchain_payment()
+		.send_to_address(&addr_a, 10000, Some(fast_fee_rate), None)
+		.unwrap();
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	wait_for_tx(&electrsd.client, txid);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	assert_eq!(node_a.list_balances().spendable_onchain_balance_sats, 10000);
+	assert_eq!(
+		node_b.list_balances().spendable_onchain_balance_sats,
+		100000 - 10000 - fast_estimate.fee_sats
+	);
+
+	let drain_estimate = node_a
+		.onchain_payment()
+		.estimate_send_all_to_address_fee(&addr_b, Some(cheap_fee_rate), None)
+		.unwrap();
+	let txid =
+		node_a.onchain_payment().send_all_to_address(&addr_b, Some(cheap_fee_rate), None).unwrap();
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	wait_for_tx(&electrsd.client, txid);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	assert_eq!(node_a.list_balances().total_onchain_balance_sats, 0);
+	assert_eq!(
+		node_b.list_balances().spendable_onchain_balance_sats,
+		100000 - fast_estimate.fee_sats - drain_estimate.fee_sats
+	);
+}
+
+#[test]
+fn onchain_fee_bump_rbf() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_b.clone()],
+		Amount::from_sat(100000),
+	);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	let low_fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+	let high_fee_rate = FeeRate::from_sat_per_vb(10).unwrap();
+	let low_fee_estimate = node_b
+		.onchain_payment()
+		.estimate_send_to_address_fee(&addr_a, 10000, Some(low_fee_rate), None)
+		.unwrap();
+	let txid =
+		node_b.onchain_payment().send_to_address(&addr_a, 10000, Some(low_fee_rate), None).unwrap();
+	wait_for_tx(&electrsd.client, txid);
+
+	// We can't bump with a lower fee rate.
+	assert_eq!(
+		Err(NodeError::OnchainTxCreationFailed),
+		node_b.onchain_payment().bump_fee_rbf(txid, FeeRate::from_sat_per_vb(1).unwrap())
+	);
+
+	let replacement_txid = node_b.onchain_payment().bump_fee_rbf(txid, high_fee_rate).unwrap();
+	assert_ne!(txid, replacement_txid);
+	wait_for_tx(&electrsd.client, replacement_txid);
+
+	// The original payment is linked to its replacement rather than considered failed.
+	let original_payment_id = PaymentId(txid.to_byte_array());
+	let replacement_payment_id = PaymentId(replacement_txid.to_byte_array());
+	let original_payment = node_b.payment(&original_payment_id).unwrap();
+	assert_eq!(original_payment.status, PaymentStatus::Pending);
+	assert!(matches!(
+		original_payment.kind,
+		PaymentKind::Onchain { replaced_by: Some(by), .. } if by == replacement_txid
+	));
+	assert_eq!(node_b.payment(&replacement_payment_id).unwrap().status, PaymentStatus::Pending);
+
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	// The replacement got confirmed, so it can't be bumped anymore.
+	assert_eq!(
+		Err(NodeError::TransactionAlreadyConfirmed),
+		node_b.onchain_payment().bump_fee_rbf(replacement_txid, high_fee_rate)
+	);
+
+	assert_eq!(node_a.list_balances().spendable_onchain_balance_sats, 10000);
+	let node_b_balance = node_b.list_balances().spendable_onchain_balance_sats;
+	assert!(node_b_balance < 100000 - 10000 - low_fee_estimate.fee_sats);
+
+	// Once the replacement confirmed, both payments are considered successful.
+	let original_payment = node_b.payment(&original_payment_id).unwrap();
+	assert_eq!(original_payment.status, PaymentStatus::Succeeded);
+	assert!(matches!(
+		original_payment.kind,
+		PaymentKind::Onchain { replaced_by: Some(by), .. } if by == replacement_txid
+	));
+	let replacement_payment = node_b.payment(&replacement_payment_id).unwrap();
+	assert_eq!(replacement_payment.status, PaymentStatus::Succeeded);
+	assert!(matches!(replacement_payment.kind, PaymentKind::Onchain { replaced_by: None, .. }));
+}
+
+#[test]
+fn onchain_fee_bump_cpfp() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	do_onchain_fee_bump_cpfp(&bitcoind, &electrsd, &chain_source, true);
+}
+
+#[test]
+fn onchain_fee_bump_cpfp_electrum() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Electrum(&electrsd);
+	do_onchain_fee_bump_cpfp(&bitcoind, &electrsd, &chain_source, true);
+}
+
+#[test]
+fn onchain_fee_bump_cpfp_bitcoind_rpc() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::BitcoindRpc(&bitcoind);
+	do_onchain_fee_bump_cpfp(&bitcoind, &electrsd, &chain_source, true);
+}
+
+#[test]
+fn onchain_fee_bump_cpfp_compact_block_filters() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::CompactBlockFilters(&bitcoind);
+	// We don't learn about unconfirmed incoming payments via compact block filters.
+	do_onchain_fee_bump_cpfp(&bitcoind, &electrsd, &chain_source, false);
+}
+
+#[test]
+fn onchain_fee_bump_cpfp_custom_chain_source() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Custom(&electrsd);
+	do_onchain_fee_bump_cpfp(&bitcoind, &electrsd, &chain_source, true);
+}
+
+fn do_onchain_fee_bump_cpfp(
+	bitcoind: &BitcoinD, electrsd: &ElectrsD, chain_source: &TestChainSource,
+	supports_unconfirmed_incoming: bool,
+) {
+	let (node_a, node_b) = setup_two_nodes(chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_b.clone()],
+		Amount::from_sat(100000),
+	);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	// Unknown transactions can't be bumped.
+	let high_fee_rate = FeeRate::from_sat_per_vb(20).unwrap();
+	assert_eq!(
+		Err(NodeError::TransactionNotFound),
+		node_a.onchain_payment().bump_fee_cpfp(Txid::all_zeros(), high_fee_rate)
+	);
+
+	let low_fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+	let txid =
+		node_b.onchain_payment().send_to_address(&addr_a, 50000, Some(low_fee_rate), None).unwrap();
+	wait_for_tx(&electrsd.client, txid);
+
+	node_a.sync_wallets().unwrap();
+	let child_txid = if supports_unconfirmed_incoming {
+		// The receiver bumps the incoming payment.
+		node_a.onchain_payment().bump_fee_cpfp(txid, high_fee_rate).unwrap()
+	} else {
+		assert_eq!(
+			Err(NodeError::TransactionNotFound),
+			node_a.onchain_payment().bump_fee_cpfp(txid, high_fee_rate)
+		);
+
+		// The sender can still bump the payment by spending its change.
+		node_b.onchain_payment().bump_fee_cpfp(txid, high_fee_rate).unwrap()
+	};
+	wait_for_tx(&electrsd.client, child_txid);
+
+	let child_tx = bitcoind.client.get_raw_transaction(&child_txid, None).unwrap();
+	assert_eq!(child_tx.input.len(), 1);
+	assert_eq!(child_tx.input[0].previous_output.txid, txid);
+
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	let node_a_balance = node_a.list_balances().spendable_onchain_balance_sats;
+	if supports_unconfirmed_incoming {
+		assert!(node_a_balance < 50000);
+		assert!(node_a_balance > 45000);
+	} else {
+		assert_eq!(node_a_balance, 50000);
+	}
+
+	assert_eq!(
+		Err(NodeError::TransactionAlreadyConfirmed),
+		node_a.onchain_payment().bump_fee_cpfp(txid, high_fee_rate)
+	);
+
+	node_a.stop().unwrap();
+	node_b.stop().unwrap();
+}
+
+#[test]
+fn onchain_send_to_multiple_outputs() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a_1 = node_a.onchain_payment().new_address().unwrap();
+	let addr_a_2 = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_b],
+		Amount::from_sat(100000),
+	);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000);
+
+	assert_eq!(
+		Err(NodeError::InvalidAmount),
+		node_b.onchain_payment().send_to_outputs(Vec::new(), None, None)
+	);
+	assert_eq!(
+		Err(NodeError::InsufficientFunds),
+		node_b.onchain_payment().send_to_outputs(
+			vec![
+				(addr_a_1.clone(), Amount::from_sat(60000)),
+				(addr_a_2.clone(), Amount::from_sat(60000))
+			],
+			None,
+			None
+		)
+	);
+
+	let outputs = vec![(addr_a_1, Amount::from_sat(10000)), (addr_a_2, Amount::from_sat(20000))];
+	let txid = node_b.onchain_payment().send_to_outputs(outputs, None, None).unwrap();
+
+	let payment = node_b.payment(&PaymentId(txid.to_byte_array())).unwrap();
+	assert_eq!(payment.amount_msat, Some(30000 * 1000));
+
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	wait_for_tx(&electrsd.client, txid);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	assert_eq!(node_a.list_balances().spendable_onchain_balance_sats, 30000);
+	assert_eq!(node_a.onchain_payment().list_utxos().len(), 2);
+	assert!(node_a.onchain_payment().list_utxos().iter().all(|u| u.outpoint.txid == txid));
+
+	let fee_sats = payment.fee_paid_msat.unwrap() / 1000;
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000 - 30000 - fee_sats);
+}
+
+#[test]
+fn batch_channel_open() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let node_a = setup_node(&chain_source, random_config(true));
+	let node_b = setup_node(&chain_source, random_config(true));
+	let node_c = setup_node(&chain_source, random_config(true));
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let premine_amount_sat = 1_000_000;
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_a],
+		Amount::from_sat(premine_amount_sat),
+	);
+	node_a.sync_wallets().unwrap();
+
+	let channels = vec![
+		(
+			node_b.node_id(),
+			node_b.listening_addresses().unwrap().first().unwrap().clone(),
+			100_000,
+			None,
+			None,
+		),
+		(
+			node_c.node_id(),
+			node_c.listening_addresses().unwrap().first().unwrap().clone(),
+			200_000,
+			None,
+			None,
+		),
+	];
+	let user_channel_ids = node_a.open_channels(channels, None, None).unwrap();
+	assert_eq!(user_channel_ids.len(), 2);
+
+	let mut funding_txos = Vec::new();
+	for _ in 0..2 {
+		match node_a.wait_next_event() {
+			Event::ChannelPending { user_channel_id, funding_txo, .. } => {
+				assert!(user_channel_ids.contains(&user_channel_id));
+				funding_txos.push(funding_txo);
+				node_a.event_handled();
+			},
+			e => panic!("Unexpected event: {:?}", e),
+		}
+	}
+	assert_eq!(funding_txos[0].txid, funding_txos[1].txid);
+	assert_ne!(funding_txos[0].vout, funding_txos[1].vout);
+
+	let funding_txo_b = expect_channel_pending_event!(node_b, node_a.node_id());
+	let funding_txo_c = expect_channel_pending_event!(node_c, node_a.node_id());
+	assert_eq!(funding_txo_b.txid, funding_txo_c.txid);
+	wait_for_tx(&electrsd.client, funding_txo_b.txid);
+
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+	node_c.sync_wallets().unwrap();
+
+	expect_event!(node_a, ChannelReady);
+	expect_event!(node_a, ChannelReady);
+	expect_channel_ready_event!(node_b, node_a.node_id());
+	expect_channel_ready_event!(node_c, node_a.node_id());
+
+	assert_eq!(node_a.list_channels().len(), 2);
+	assert!(node_a
+		.list_channels()
+		.iter()
+		.all(|c| c.funding_txo.unwrap().txid == funding_txo_b.txid));
+}
+
+#[test]
+fn channel_close_with_options() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	// Closing to a custom address requires not committing to a shutdown script upfront.
+	let mut config_a = random_config(true);
+	config_a.commit_upfront_shutdown_script = false;
+	let node_a = setup_node(&chain_source, config_a);
+	let node_b = setup_node(&chain_source, random_config(true));
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+	let premine_amount_sat = 1_000_000;
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_a, addr_b],
+		Amount::from_sat(premine_amount_sat),
+	);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	let funding_amount_sat = 200_000;
+	open_channel(&node_a, &node_b, funding_amount_sat, false, &electrsd);
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+	let user_channel_id = expect_channel_ready_event!(node_a, node_b.node_id());
+	expect_channel_ready_event!(node_b, node_a.node_id());
+	let funding_txo = node_a.list_channels()[0].funding_txo.unwrap();
+
+	let external_addr = bitcoind
+		.client
+		.get_new_address(None, Some(AddressType::Bech32))
+		.unwrap()
+		.require_network(Network::Regtest)
+		.unwrap();
+
+	// Addresses of the wrong network are rejected.
+	let testnet_addr =
+		Address::from_script(&external_addr.script_pubkey(), Network::Testnet).unwrap();
+	let invalid_options =
+		ChannelCloseOptions { destination_address: Some(testnet_addr), ..Default::default() };
+	assert_eq!(
+		Err(NodeError::InvalidAddress),
+		node_a.close_channel_with_options(&user_channel_id, node_b.node_id(), invalid_options)
+	);
+
+	// Close to an external address, at a fixed fee rate.
+	let close_options = ChannelCloseOptions {
+		destination_address: Some(external_addr.clone()),
+		target_fee_rate: Some(FeeRate::from_sat_per_vb(10).unwrap()),
+		force_close_timeout_secs: Some(600),
+	};
+	node_a.close_channel_with_options(&user_channel_id, node_b.node_id(), close_options).unwrap();
+
+	expect_event!(node_a, ChannelClosed);
+	expect_event!(node_b, ChannelClosed);
+
+	wait_for_outpoint_spend(&electrsd.client, funding_txo);
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 1);
+
+	let received_amount = bitcoind.client.get_received_by_address(&external_addr, Some(1)).unwrap();
+	assert!(received_amount > Amount::from_sat(funding_amount_sat - 10_000));
+	assert!(received_amount < Amount::from_sat(funding_amount_sat));
+}
+
+#[test]
+fn closed_channel_history() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+	let premine_amount_sat = 1_000_000;
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_a, addr_b],
+		Amount::from_sat(premine_amount_sat),
+	);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	let funding_amount_sat = 200_000;
+	open_channel(&node_a, &node_b, funding_amount_sat, false, &electrsd);
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+	let user_channel_id = expect_channel_ready_event!(node_a, node_b.node_id());
+	expect_channel_ready_event!(node_b, node_a.node_id());
+	let channel_id = node_a.list_channels()[0].channel_id;
+	let funding_txo = node_a.list_channels()[0].funding_txo.unwrap();
+	assert!(node_a.list_closed_channels().is_empty());
+
+	node_a.close_channel(&user_channel_id, node_b.node_id()).unwrap();
+	expect_event!(node_a, ChannelClosed);
+	expect_event!(node_b, ChannelClosed);
+
+	let closed_channels_a = node_a.list_closed_channels();
+	assert_eq!(closed_channels_a.len(), 1);
+	let closed_channel_a = &closed_channels_a[0];
+	assert_eq!(closed_channel_a.channel_id, channel_id);
+	assert_eq!(closed_channel_a.user_channel_id, user_channel_id);
+	assert_eq!(closed_channel_a.counterparty_node_id, Some(node_b.node_id()));
+	assert_eq!(closed_channel_a.channel_capacity_sats, Some(funding_amount_sat));
+	assert_eq!(closed_channel_a.funding_txo, Some(funding_txo));
+	assert_eq!(
+		closed_channel_a.closure_reason,
+		Some(ClosureReason::LocallyInitiatedCooperativeClosure)
+	);
+	assert!(closed_channel_a.is_locally_initiated);
+	assert!(closed_channel_a.opened_at.unwrap() <= closed_channel_a.closed_at);
+	assert!(closed_channel_a.final_balance_sats.unwrap() < funding_amount_sat);
+	assert_eq!(closed_channel_a.closing_txid, None);
+
+	let closed_channels_b = node_b.list_closed_channels();
+	assert_eq!(closed_channels_b.len(), 1);
+	assert!(!closed_channels_b[0].is_locally_initiated);
+	assert_eq!(closed_channels_b[0].final_balance_sats, Some(0));
+
+	// Once the closing transaction confirms, it's recorded in the channel history.
+	wait_for_outpoint_spend(&electrsd.client, funding_txo);
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 1);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	let closing_txid = node_a.list_closed_channels()[0].closing_txid.unwrap();
+	assert_ne!(closing_txid, funding_txo.txid);
+	assert_eq!(node_b.list_closed_channels()[0].closing_txid, Some(closing_txid));
+}
+
+#[test]
+fn externally_funded_channel() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let external_addr = bitcoind
+		.client
+		.get_new_address(None, Some(AddressType::Bech32))
+		.unwrap()
+		.require_network(Network::Regtest)
+		.unwrap();
+
+	let premine_amount_sat = 500_000;
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_a, external_addr.clone()],
+		Amount::from_sat(premine_amount_sat),
+	);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	let funding_amount_sat = 100_000;
+	let user_channel_id = node_a
+		.open_externally_funded_channel(
+			node_b.node_id(),
+			node_b.listening_addresses().unwrap().first().unwrap().clone(),
+			funding_amount_sat,
+			None,
+			None,
+		)
+		.unwrap();
+
+	let output_script = match node_a.wait_next_event() {
+		Event::FundingTransactionRequired {
+			user_channel_id: event_user_channel_id,
+			counterparty_node_id,
+			output_script,
+			channel_value_sats,
+		} => {
+			assert_eq!(event_user_channel_id, user_channel_id);
+			assert_eq!(counterparty_node_id, node_b.node_id());
+			assert_eq!(channel_value_sats, funding_amount_sat);
+			node_a.event_handled();
+			output_script
+		},
+		e => panic!("Unexpected event: {:?}", e),
+	};
+
+	// Fund the channel from the bitcoind wallet, spending a SegWit output.
+	let utxo = bitcoind
+		.client
+		.list_unspent(None, None, Some(&[&external_addr]), None, None)
+		.unwrap()
+		.into_iter()
+		.next()
+		.unwrap();
+	let funding_addr = Address::from_script(&output_script, Network::Regtest).unwrap();
+	let change_amount = utxo.amount - Amount::from_sat(funding_amount_sat) - Amount::from_sat(1000);
+	let mut outputs = HashMap::new();
+	outputs.insert(funding_addr.to_string(), Amount::from_sat(funding_amount_sat));
+	outputs.insert(external_addr.to_string(), change_amount);
+	let input = CreateRawTransactionInput { txid: utxo.txid, vout: utxo.vout, sequence: None };
+	let raw_tx =
+		bitcoind.client.create_raw_transaction_hex(&[input], &outputs, None, None).unwrap();
+	let signed_tx = bitcoind.client.sign_raw_transaction_with_wallet(raw_tx, None, None).unwrap();
+	assert!(signed_tx.complete);
+	let funding_tx = signed_tx.transaction().unwrap();
+	let funding_txid = funding_tx.compute_txid();
+
+	// Transactions not paying to the funding output are rejected.
+	let mut invalid_tx = funding_tx.clone();
+	invalid_tx.output.retain(|o| o.script_pubkey != output_script);
+	assert_eq!(
+		Err(NodeError::InvalidTransaction),
+		node_a.fund_channel_with_transaction(&user_channel_id, invalid_tx)
+	);
+
+	node_a.fund_channel_with_transaction(&user_channel_id, funding_tx).unwrap();
+
+	let funding_txo_a = expect_channel_pending_event!(node_a, node_b.node_id());
+	let funding_txo_b = expect_channel_pending_event!(node_b, node_a.node_id());
+	assert_eq!(funding_txo_a, funding_txo_b);
+	assert_eq!(funding_txo_a.txid, funding_txid);
+	wait_for_tx(&electrsd.client, funding_txid);
+
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	expect_channel_ready_event!(node_a, node_b.node_id());
+	expect_channel_ready_event!(node_b, node_a.node_id());
+
+	// Our own on-chain funds were left untouched.
+	assert_eq!(node_a.list_balances().total_onchain_balance_sats, premine_amount_sat);
+}
+
+#[test]
+fn taproot_onchain_wallet() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let config = random_config(true);
+
+	let esplora_url = format!("http://{}", electrsd.esplora_url.as_ref().unwrap());
+
+	let test_sync_store: Arc<dyn KVStore + Sync + Send> =
+		Arc::new(TestSyncStore::new(config.storage_dir_path.clone().into()));
+
+	setup_builder!(builder, config);
+	builder.set_esplora_server(esplora_url.clone());
+	builder.set_wallet_descriptor_template(WalletDescriptorTemplate::Bip86);
+
+	let node = builder.build_with_store(Arc::clone(&test_sync_store)).unwrap();
+	node.start().unwrap();
+
+	let addr = node.onchain_payment().new_address().unwrap();
+	assert!(addr.script_pubkey().is_p2tr());
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr],
+		Amount::from_sat(100000),
+	);
+	node.sync_wallets().unwrap();
+	assert_eq!(node.list_balances().spendable_onchain_balance_sats, 100000);
+
+	// Check we're able to spend from, and receive change on, Taproot outputs.
+	let external_addr = bitcoind
+		.client
+		.get_new_address(None, Some(AddressType::Bech32))
+		.unwrap()
+		.require_network(Network::Regtest)
+		.unwrap();
+	let txid = node.onchain_payment().send_to_address(&external_addr, 50000, None, None).unwrap();
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	wait_for_tx(&electrsd.client, txid);
+	node.sync_wallets().unwrap();
+	assert!(node.onchain_payment().list_utxos().iter().all(|u| u
+		.address
+		.script_pubkey()
+		.is_p2tr()
+		&& u.outpoint.txid == txid));
+	let balance_before_restart = node.list_balances().total_onchain_balance_sats;
+	node.stop().unwrap();
+	drop(node);
+
+	// Check the existing wallet keeps using its Taproot descriptors.
+	setup_builder!(builder, config);
+	builder.set_esplora_server(esplora_url.clone());
+	builder.set_wallet_descriptor_template(WalletDescriptorTemplate::Bip84);
+
+	let reinitialized_node = builder.build_with_store(Arc::clone(&test_sync_store)).unwrap();
+	reinitialized_node.start().unwrap();
+	reinitialized_node.sync_wallets().unwrap();
+
+	assert!(reinitialized_node.onchain_payment().new_address().unwrap().script_pubkey().is_p2tr());
+	assert_eq!(
+		reinitialized_node.list_balances().total_onchain_balance_sats,
+		balance_before_restart
+	);
+	reinitialized_node.stop().unwrap();
+}
+
+#[test]
+fn onchain_wallet_rescan_and_birthday() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let rpc_host = bitcoind.params.rpc_socket.ip().to_string();
+	let rpc_port = bitcoind.params.rpc_socket.port();
+	let values = bitcoind.params.get_cookie_values().unwrap().unwrap();
+	let seed_bytes = vec![42u8; 64];
+
+	let setup_restored_node = |birthday_height: Option<u32>| {
+		let config = random_config(false);
+		setup_builder!(builder, config);
+		builder.set_entropy_seed_bytes(seed_bytes.clone()).unwrap();
+		builder.set_chain_source_bitcoind_rpc(
+			rpc_host.clone(),
+			rpc_port,
+			values.user.clone(),
+			values.password.clone(),
+		);
+		if let Some(height) = birthday_height {
+			builder.set_wallet_birthday_height(height);
+		}
+		let test_sync_store = Arc::new(TestSyncStore::new(config.storage_dir_path.into()));
+		let node = builder.build_with_store(test_sync_store).unwrap();
+		node.start().unwrap();
+		node
+	};
+
+	let node = setup_restored_node(None);
+	let addr = node.onchain_payment().new_address().unwrap();
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr],
+		Amount::from_sat(100000),
+	);
+	let funding_height = bitcoind.client.get_block_count().unwrap() as u32;
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	node.sync_wallets().unwrap();
+	assert_eq!(node.list_balances().total_onchain_balance_sats, 100000);
+	node.stop().unwrap();
+
+	// A wallet restored without a birthday only syncs from the chain tip, but can be rescanned.
+	let restored_node = setup_restored_node(None);
+	restored_node.sync_wallets().unwrap();
+	assert_eq!(restored_node.list_balances().total_onchain_balance_sats, 0);
+
+	restored_node.rescan_onchain_wallet(funding_height).unwrap();
+	assert_eq!(restored_node.list_balances().total_onchain_balance_sats, 100000);
+	restored_node.stop().unwrap();
+
+	// A wallet restored with a birthday prior to its funding picks up the funds right away.
+	let birthday_node = setup_restored_node(Some(funding_height - 1));
+	birthday_node.sync_wallets().unwrap();
+	assert_eq!(birthday_node.list_balances().total_onchain_balance_sats, 100000);
+	birthday_node.stop().unwrap();
+}
+
+#[test]
+fn onchain_wallet_incremental_sync() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let esplora_url = format!("http://{}", electrsd.esplora_url.as_ref().unwrap());
+	let seed_bytes = vec![42u8; 64];
+
+	let setup_node_with_seed = || {
+		let config = random_config(false);
+		setup_builder!(builder, config);
+		builder.set_entropy_seed_bytes(seed_bytes.clone()).unwrap();
+		builder.set_esplora_server(esplora_url.clone());
+		let test_sync_store = Arc::new(TestSyncStore::new(config.storage_dir_path.into()));
+		let node = builder.build_with_store(test_sync_store).unwrap();
+		node.start().unwrap();
+		node
+	};
+
+	// Have the first node finish its initial full scan before any funds arrive.
+	let node_a = setup_node_with_seed();
+	node_a.sync_wallets().unwrap();
+
+	// Fund an address the first node didn't reveal yet.
+	let node_b = setup_node_with_seed();
+	let _ = node_b.onchain_payment().new_address().unwrap();
+	let addr = node_b.onchain_payment().new_address().unwrap();
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr],
+		Amount::from_sat(100000),
+	);
+	node_b.sync_wallets().unwrap();
+	assert_eq!(node_b.list_balances().total_onchain_balance_sats, 100000);
+
+	// Regular syncs only look at revealed addresses, while a rescan does a full scan.
+	node_a.sync_wallets().unwrap();
+	assert_eq!(node_a.list_balances().total_onchain_balance_sats, 0);
+
+	node_a.rescan_onchain_wallet(0).unwrap();
+	assert_eq!(node_a.list_balances().total_onchain_balance_sats, 100000);
+
+	node_a.stop().unwrap();
+	node_b.stop().unwrap();
+}
+
+#[test]
+fn wallet_labels() {
+	let (_bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let address = node_a.onchain_payment().new_address().unwrap();
+	let txid = Txid::from_slice(&[42u8; 32]).unwrap();
+	let address_ref = LabelRef::Address { address: address.clone() };
+	let output_ref = LabelRef::Output { outpoint: OutPoint::new(txid, 1) };
+	let payment_ref = LabelRef::Payment { payment_id: PaymentId([42u8; 32]) };
+
+	node_a.set_label(address_ref.clone(), "Donations".to_string()).unwrap();
+	node_a.set_label(LabelRef::Transaction { txid }, "Rent".to_string()).unwrap();
+	node_a.set_label(output_ref.clone(), "Change".to_string()).unwrap();
+	node_a.set_label(payment_ref.clone(), "Coffee".to_string()).unwrap();
+	assert_eq!(node_a.list_labels().len(), 4);
+	assert_eq!(node_a.label(&address_ref), Some("Donations".to_string()));
+
+	// Addresses on other networks are rejected.
+	let mainnet_address = Address::from_script(&address.script_pubkey(), Network::Bitcoin).unwrap();
+	assert_eq!(
+		Err(NodeError::InvalidAddress),
+		node_a.set_label(LabelRef::Address { address: mainnet_address }, "Foo".to_string())
+	);
+
+	node_a.remove_label(&output_ref).unwrap();
+	assert_eq!(node_a.label(&output_ref), None);
+
+	// Payment labels aren't part of BIP 329 and hence aren't exported.
+	let exported = node_a.export_labels();
+	assert_eq!(exported.lines().count(), 2);
+
+	node_b.import_labels(exported).unwrap();
+	assert_eq!(node_b.list_labels().len(), 2);
+	assert_eq!(node_b.label(&address_ref), Some("Donations".to_string()));
+	assert_eq!(node_b.label(&LabelRef::Transaction { txid }), Some("Rent".to_string()));
+	assert_eq!(node_b.label(&payment_ref), None);
+
+	// Outputs marked as not spendable get locked on import and are exported as such.
+	let locked_outpoint = OutPoint::new(txid, 2);
+	let unspendable_record =
+		format!(r#"{{"type":"output","ref":"{}","spendable":false}}"#, locked_outpoint);
+	node_b.import_labels(unspendable_record).unwrap();
+	assert_eq!(node_b.onchain_payment().list_locked_utxos(), vec![locked_outpoint]);
+	assert!(node_b.export_labels().contains(r#""spendable":false"#));
+
+	let spendable_record =
+		format!(r#"{{"type":"output","ref":"{}","spendable":true}}"#, locked_outpoint);
+	node_b.import_labels(spendable_record).unwrap();
+	assert!(node_b.onchain_payment().list_locked_utxos().is_empty());
+
+	let malformed_record = r#"{"type":"tx","label":"Missing ref"}"#.to_string();
+	assert_eq!(Err(NodeError::InvalidLabel), node_b.import_labels(malformed_record));
+
+	node_a.stop().unwrap();
+	node_b.stop().unwrap();
+}
+
+#[test]
+fn onchain_psbt_send() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_b],
+		Amount::from_sat(100000),
+	);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	let psbt = node_b.onchain_payment().create_psbt(&addr_a, 50000, None, None).unwrap();
+	assert!(psbt.inputs.iter().all(|i| i.final_script_witness.is_none()));
+
+	// Creating the PSBT doesn't spend anything yet.
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000);
+
+	let unsigned_txid = psbt.unsigned_tx.compute_txid();
+	let txid = node_b.onchain_payment().finalize_and_broadcast_psbt(psbt).unwrap();
+	assert_eq!(txid, unsigned_txid);
+
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	wait_for_tx(&electrsd.client, txid);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	assert_eq!(node_a.list_balances().spendable_onchain_balance_sats, 50000);
+	let payment = node_b.payment(&PaymentId(txid.to_byte_array())).unwrap();
+	let fee_sats = payment.fee_paid_msat.unwrap() / 1000;
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000 - 50000 - fee_sats);
+}
+
+#[test]
+fn onchain_coin_control() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b_1 = node_b.onchain_payment().new_address().unwrap();
+	let addr_b_2 = node_b.onchain_payment().new_address().unwrap();
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_b_1.clone(), addr_b_2.clone()],
+		Amount::from_sat(100000),
+	);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 200000);
+
+	let utxos = node_b.onchain_payment().list_utxos();
+	assert_eq!(utxos.len(), 2);
+	for utxo in &utxos {
+		assert_eq!(utxo.value_sats, 100000);
+		assert_eq!(utxo.keychain, KeychainKind::External);
+		assert!(matches!(utxo.status, ConfirmationStatus::Confirmed { .. }));
+		assert!(utxo.address == addr_b_1 || utxo.address == addr_b_2);
+	}
+
+	// Sweep only the first UTXO, leaving the other one untouched.
+	let swept_utxo = utxos[0].clone();
+	let kept_utxo = utxos[1].clone();
+	let utxo_selection = UtxoSelection::SpendOnly { utxos: vec![swept_utxo.outpoint] };
+	let txid =
+		node_b.onchain_payment().send_all_to_address(&addr_a, None, Some(utxo_selection)).unwrap();
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	wait_for_tx(&electrsd.client, txid);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	let node_a_balance = node_a.list_balances().spendable_onchain_balance_sats;
+	assert!(node_a_balance > 99000);
+	assert!(node_a_balance < 100000);
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000);
+
+	let utxos = node_b.onchain_payment().list_utxos();
+	assert_eq!(utxos.len(), 1);
+	assert_eq!(utxos[0].outpoint, kept_utxo.outpoint);
+
+	// We can't spend a UTXO that's already spent.
+	assert_eq!(
+		Err(NodeError::UtxoNotFound),
+		node_b.onchain_payment().send_to_address(
+			&addr_a,
+			1000,
+			None,
+			Some(UtxoSelection::SpendOnly { utxos: vec![swept_utxo.outpoint] })
+		)
+	);
+
+	// Avoiding our only UTXO leaves us without anything to spend.
+	assert_eq!(
+		Err(NodeError::OnchainTxCreationFailed),
+		node_b.onchain_payment().send_to_address(
+			&addr_a,
+			1000,
+			None,
+			Some(UtxoSelection::Avoid { utxos: vec![kept_utxo.outpoint] })
+		)
+	);
+}
+
+#[test]
+fn onchain_utxo_locking() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b_1 = node_b.onchain_payment().new_address().unwrap();
+	let addr_b_2 = node_b.onchain_payment().new_address().unwrap();
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_b_1, addr_b_2],
+		Amount::from_sat(100000),
+	);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 200000);
+
+	let locked_utxo = node_b.onchain_payment().list_utxos()[0].clone();
+	node_b.onchain_payment().lock_utxo(locked_utxo.outpoint).unwrap();
+	assert_eq!(node_b.onchain_payment().list_locked_utxos(), vec![locked_utxo.outpoint]);
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000);
+	assert_eq!(node_b.list_balances().total_onchain_balance_sats, 200000);
+
+	let utxos = node_b.onchain_payment().list_utxos();
+	assert_eq!(utxos.iter().filter(|u| u.is_locked).count(), 1);
+
+	// Locked UTXOs can't be selected explicitly.
+	assert_eq!(
+		Err(NodeError::UtxoLocked),
+		node_b.onchain_payment().send_to_address(
+			&addr_a,
+			1000,
+			None,
+			Some(UtxoSelection::SpendOnly { utxos: vec![locked_utxo.outpoint] })
+		)
+	);
+
+	// Draining the wallet leaves the locked UTXO untouched.
+	let txid = node_b.onchain_payment().send_all_to_address(&addr_a, None, None).unwrap();
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+	wait_for_tx(&electrsd.client, txid);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	let utxos = node_b.onchain_payment().list_utxos();
+	assert_eq!(utxos.len(), 1);
+	assert_eq!(utxos[0].outpoint, locked_utxo.outpoint);
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 0);
+	assert_eq!(
+		Err(NodeError::InsufficientFunds),
+		node_b.onchain_payment().send_to_address(&addr_a, 1000, None, None)
+	);
+
+	node_b.onchain_payment().unlock_utxo(locked_utxo.outpoint).unwrap();
+	assert!(node_b.onchain_payment().list_locked_utxos().is_empty());
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000);
+	node_b.onchain_payment().send_to_address(&addr_a, 1000, None, None).unwrap();
+}
+
+#[test]
+fn onchain_spend_receive_bitcoind_rpc() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::BitcoindRpc(&bitcoind);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_b.clone()],
+		Amount::from_sat(100000),
+	);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000);
+
+	let txid = node_b.onchain_payment().send_to_address(&addr_a, 1000, None, None).unwrap();
+	wait_for_tx(&electrsd.client, txid);
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	assert_eq!(node_a.list_balances().spendable_onchain_balance_sats, 1000);
+	assert!(node_b.list_balances().spendable_onchain_balance_sats > 98000);
+	assert!(node_b.list_balances().spendable_onchain_balance_sats < 100000);
+
+	node_a.stop().unwrap();
+	node_b.stop().unwrap();
+}
+
+#[test]
+fn onchain_spend_receive_electrum() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Electrum(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_b.clone()],
+		Amount::from_sat(100000),
+	);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000);
+
+	let txid = node_b.onchain_payment().send_to_address(&addr_a, 1000, None, None).unwrap();
+	wait_for_tx(&electrsd.client, txid);
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	assert_eq!(node_a.list_balances().spendable_onchain_balance_sats, 1000);
+	assert!(node_b.list_balances().spendable_onchain_balance_sats > 98000);
+	assert!(node_b.list_balances().spendable_onchain_balance_sats < 100000);
+
+	node_a.stop().unwrap();
+	node_b.stop().unwrap();
+}
+
+#[test]
+fn onchain_spend_receive_compact_block_filters() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::CompactBlockFilters(&bitcoind);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_b.clone()],
+		Amount::from_sat(100000),
+	);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000);
+
+	let txid = node_b.onchain_payment().send_to_address(&addr_a, 1000, None, None).unwrap();
+	wait_for_tx(&electrsd.client, txid);
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	assert_eq!(node_a.list_balances().spendable_onchain_balance_sats, 1000);
+	assert!(node_b.list_balances().spendable_onchain_balance_sats > 98000);
+	assert!(node_b.list_balances().spendable_onchain_balance_sats < 100000);
+
+	node_a.stop().unwrap();
+	node_b.stop().unwrap();
+}
+
+#[test]
+fn onchain_spend_receive_custom_chain_source() {
+	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Custom(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
+
+	let addr_a = node_a.onchain_payment().new_address().unwrap();
+	let addr_b = node_b.onchain_payment().new_address().unwrap();
+
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd.client,
+		vec![addr_b.clone()],
+		Amount::from_sat(100000),
+	);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+	assert_eq!(node_b.list_balances().spendable_onchain_balance_sats, 100000);
+
+	let txid = node_b.onchain_payment().send_to_address(&addr_a, 1000, None, None).unwrap();
+	wait_for_tx(&electrsd.client, txid);
+	generate_blocks_and_wait(&bitcoind.client, &electrsd.client, 6);
+
+	node_a.sync_wallets().unwrap();
+	node_b.sync_wallets().unwrap();
+
+	assert_eq!(node_a.list_balances().spendable_onchain_balance_sats, 1000);
+	assert!(node_b.list_balances().spendable_onchain_balance_sats > 98000);
+	assert!(node_b.list_balances().spendable_onchain_balance_sats < 100000);
+
+	node_a.stop().unwrap();
+	node_b.stop().unwrap();
+}
+
+#[test]
+fn onchain_sync_esplora_failover() {
+	let (bitcoind, mut electrsd_a) = setup_bitcoind_and_electrsd();
+	let electrsd_b = setup_electrsd(&bitcoind);
+	let electrsd_c = setup_electrsd(&bitcoind);
+
+	let config = random_config(true);
+	setup_builder!(builder, config);
+	let esplora_urls = [&electrsd_a, &electrsd_b, &electrsd_c]
+		.iter()
+		.map(|e| format!("http://{}", e.esplora_url.as_ref().unwrap()))
+		.collect();
+	builder.set_esplora_servers(esplora_urls, true).unwrap();
+	let test_sync_store = Arc::new(TestSyncStore::new(config.storage_dir_path.clone().into()));
+	let node = builder.build_with_store(test_sync_store).unwrap();
+	node.start().unwrap();
+
+	let addr = node.onchain_payment().new_address().unwrap();
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd_b.client,
+		vec![addr],
+		Amount::from_sat(100000),
+	);
+	node.sync_wallets().unwrap();
+	assert_eq!(node.list_balances().spendable_onchain_balance_sats, 100000);
+
+	// Kill our primary server and make sure we keep syncing and broadcasting via the others.
+	electrsd_a.kill().unwrap();
+
+	let addr = node.onchain_payment().new_address().unwrap();
+	premine_and_distribute_funds(
+		&bitcoind.client,
+		&electrsd_b.client,
+		vec![addr],
+		Amount::from_sat(100000),
+	);
+	node.sync_wallets().unwrap();
+	assert_eq!(node.list_balances().spendable_onchain_balance_sats, 200000);
+
+	let bitcoind_addr = bitcoind.client.get_new_address(None, None).unwrap().assume_checked();
+	let txid = node.onchain_payment().send_to_address(&bitcoind_addr, 10000, None, None).unwrap();
+	wait_for_tx(&electrsd_c.client, txid);
+	generate_blocks_and_wait(&bitcoind.client, &electrsd_c.client, 6);
+
+	node.sync_wallets().unwrap();
+	assert!(node.list_balances().spendable_onchain_balance_sats < 190000);
+
+	node.stop().unwrap();
+}
+
 #[test]
 fn sign_verify_msg() {
 	let (_bitcoind, electrsd) = setup_bitcoind_and_electrsd();
+	let chain_source = TestChainSource::Esplora(&electrsd);
 	let config = random_config(true);
-	let node = setup_node(&electrsd, config);
+	let node = setup_node(&chain_source, config);
 
 	// Tests arbitrary message signing and later verification
 	let msg = "OK computer".as_bytes();
@@ -325,7 +1745,8 @@ fn connection_restart_behavior() {
 
 fn do_connection_restart_behavior(persist: bool) {
 	let (_bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, false, false, false);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, false, false);
 
 	let node_id_a = node_a.node_id();
 	let node_id_b = node_b.node_id();
@@ -376,7 +1797,8 @@ fn do_connection_restart_behavior(persist: bool) {
 #[test]
 fn concurrent_connections_succeed() {
 	let (_bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, false, true, false);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
 
 	let node_a = Arc::new(node_a);
 	let node_b = Arc::new(node_b);
@@ -406,7 +1828,8 @@ fn concurrent_connections_succeed() {
 #[test]
 fn simple_bolt12_send_receive() {
 	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, false, true, false);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
 
 	let address_a = node_a.onchain_payment().new_address().unwrap();
 	let premine_amount_sat = 5_000_000;
@@ -447,7 +1870,8 @@ fn simple_bolt12_send_receive() {
 		.unwrap();
 
 	expect_payment_successful_event!(node_a, Some(payment_id), None);
-	let node_a_payments = node_a.list_payments();
+	let node_a_payments =
+		node_a.list_payments_with_filter(|p| matches!(p.kind, PaymentKind::Bolt12Offer { .. }));
 	assert_eq!(node_a_payments.len(), 1);
 	match node_a_payments.first().unwrap().kind {
 		PaymentKind::Bolt12Offer {
@@ -473,7 +1897,8 @@ fn simple_bolt12_send_receive() {
 	assert_eq!(node_a_payments.first().unwrap().amount_msat, Some(expected_amount_msat));
 
 	expect_payment_received_event!(node_b, expected_amount_msat);
-	let node_b_payments = node_b.list_payments();
+	let node_b_payments =
+		node_b.list_payments_with_filter(|p| matches!(p.kind, PaymentKind::Bolt12Offer { .. }));
 	assert_eq!(node_b_payments.len(), 1);
 	match node_b_payments.first().unwrap().kind {
 		PaymentKind::Bolt12Offer { hash, preimage, secret, offer_id, .. } => {
@@ -613,7 +2038,8 @@ fn simple_bolt12_send_receive() {
 #[test]
 fn generate_bip21_uri() {
 	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, false, true, false);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
 
 	let address_a = node_a.onchain_payment().new_address().unwrap();
 	let premined_sats = 5_000_000;
@@ -654,7 +2080,8 @@ fn generate_bip21_uri() {
 #[test]
 fn unified_qr_send_receive() {
 	let (bitcoind, electrsd) = setup_bitcoind_and_electrsd();
-	let (node_a, node_b) = setup_two_nodes(&electrsd, false, true, false);
+	let chain_source = TestChainSource::Esplora(&electrsd);
+	let (node_a, node_b) = setup_two_nodes(&chain_source, false, true, false);
 
 	let address_a = node_a.onchain_payment().new_address().unwrap();
 	let premined_sats = 5_000_000;